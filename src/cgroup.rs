@@ -0,0 +1,143 @@
+//! Places the consumer processes in cgroups, see `--cgroup-parent`. Every consumer gets a cgroup
+//! below the parent, or all of them share one with `--shared-cgroup`, with the limits of
+//! `--worker-cpu-max` and `--worker-memory-max` enforced by the kernel.
+//!
+//! Only cgroup v2 is supported, and the daemon needs write access to the parent. As cgroup v2 only
+//! enables controllers for cgroups without processes of their own, the parent can't be the cgroup
+//! of the daemon itself. The processes join their cgroup between fork and exec, so their own child
+//! processes are placed in it too.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::config::{DaemonConfig, EnvironmentError};
+
+// The period of cpu.max in microseconds, the kernel default
+const CPU_PERIOD: u64 = 100_000;
+// The name of the cgroup with `--shared-cgroup`
+const SHARED_CGROUP: &str = "consumers";
+
+/// Checks that the parent is a writable cgroup v2 directory, and enables the controllers of the
+/// limits for the cgroups below it.
+pub fn prepare_parent(config: &DaemonConfig) -> Result<(), EnvironmentError> {
+    let parent = match config.cgroup_parent {
+        Some(ref parent) => parent,
+        None => return Ok(()),
+    };
+    if !cfg!(target_os = "linux") {
+        return Err(EnvironmentError::new(
+            "Can't use --cgroup-parent: cgroups are only supported on Linux",
+        ));
+    }
+    let unwritable = |err: std::io::Error| {
+        EnvironmentError::new(format!(
+            "Can't use cgroup {}: {}. The daemon needs write access to the cgroup hierarchy, for example by running it as root",
+            parent.display(),
+            err
+        ))
+    };
+    fs::create_dir_all(parent).map_err(unwritable)?;
+    let controllers = match fs::read_to_string(parent.join("cgroup.controllers")) {
+        Ok(controllers) => controllers,
+        Err(_) => {
+            return Err(EnvironmentError::new(format!(
+                "Can't use cgroup {}: it isn't a cgroup v2 directory, like below /sys/fs/cgroup",
+                parent.display()
+            )))
+        }
+    };
+    let mut required = Vec::new();
+    if config.worker_cpu_max.is_some() {
+        required.push("cpu");
+    }
+    if config.worker_memory_max.is_some() {
+        required.push("memory");
+    }
+    for &controller in required.iter() {
+        if !controllers.split_whitespace().any(|x| x == controller) {
+            return Err(EnvironmentError::new(format!(
+                "Can't use cgroup {}: the {} controller isn't available in it, enable it in cgroup.subtree_control of the cgroup above it",
+                parent.display(),
+                controller
+            )));
+        }
+    }
+    if !required.is_empty() {
+        let enable: Vec<String> = required.iter().map(|x| format!("+{}", x)).collect();
+        fs::write(parent.join("cgroup.subtree_control"), enable.join(" ")).map_err(unwritable)?;
+    }
+    Ok(())
+}
+
+/// The cgroup of the processes of the consumer, if any.
+pub fn consumer_cgroup(config: &DaemonConfig, consumer: &str) -> Option<PathBuf> {
+    let parent = config.cgroup_parent.as_ref()?;
+    if config.shared_cgroup {
+        return Some(parent.join(SHARED_CGROUP));
+    }
+    // Like `label_consumer` with multiple installations, as the name can't contain a colon
+    let name: String = config
+        .qualified_name(consumer)
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect();
+    Some(parent.join(name))
+}
+
+/// Creates the cgroup when missing, and sets its limits, which may have changed since it was
+/// created.
+fn prepare_cgroup(config: &DaemonConfig, cgroup: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(cgroup)?;
+    if let Some(cpus) = config.worker_cpu_max {
+        let quota = (cpus * CPU_PERIOD as f64).round() as u64;
+        fs::write(cgroup.join("cpu.max"), format!("{} {}", quota, CPU_PERIOD))?;
+    }
+    if let Some(bytes) = config.worker_memory_max {
+        fs::write(cgroup.join("memory.max"), bytes.to_string())?;
+    }
+    Ok(())
+}
+
+/// Places the process spawned by the command in the cgroup of the consumer. Fails when the cgroup
+/// can't be prepared, and the spawn fails when the process can't join it.
+pub fn set_cgroup(
+    command: &mut Command,
+    config: &DaemonConfig,
+    consumer: &str,
+) -> std::io::Result<()> {
+    use std::os::unix::{io::AsRawFd, process::CommandExt};
+    let cgroup = match consumer_cgroup(config, consumer) {
+        Some(cgroup) => cgroup,
+        None => return Ok(()),
+    };
+    let wrap = |err: std::io::Error| {
+        std::io::Error::new(
+            err.kind(),
+            format!("failed to prepare cgroup {}: {}", cgroup.display(), err),
+        )
+    };
+    prepare_cgroup(config, &cgroup).map_err(wrap)?;
+    // Opened here, as the child can't allocate, and with the permissions of the daemon, which the
+    // child may have dropped with --run-as-user
+    let procs = fs::OpenOptions::new()
+        .write(true)
+        .open(cgroup.join("cgroup.procs"))
+        .map_err(wrap)?;
+    // SAFETY: the closure only makes system calls, which is safe between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            // Writing 0 moves the writing process
+            if libc::write(procs.as_raw_fd(), b"0".as_ptr().cast(), 1) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    Ok(())
+}
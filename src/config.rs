@@ -1,73 +1,761 @@
-use std::{collections::HashMap, env, path::Path, process::Command};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs, io,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    time::Duration,
+};
 
-use input::Args as InputArgs;
+use input::{Args as InputArgs, Instance, KillStep, RabbitMqOnUnknown, StuckWorkerAction};
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize, Serializer};
 
-use crate::input;
+use crate::{
+    cgroup, input,
+    util::{
+        describe_exit_status, glob_match, output_with_timeout, resolve_credentials,
+        set_credentials, signal_name, Credentials, BYTES_PER_MB,
+    },
+};
 
-#[derive(Clone, Debug)]
+// Warn when the total number of consumer processes exceeds this many per CPU
+const OVERPROVISIONED_PROCESSES_PER_CPU: usize = 4;
+
+const PHP_QUERY_ATTEMPTS: u32 = 3;
+const PHP_QUERY_RETRY_DELAY: Duration = Duration::from_secs(1);
+// The longest delay of the backoff settings of a consumer, a day
+const MAX_BACKOFF_SECS: f64 = 86400.0;
+
+/// The environment variables that configure the daemon, for containers. The command line options
+/// take precedence over them, and they take precedence over `app/etc/env.php`.
+pub const MAX_MESSAGES_VAR: &str = "MWD_MAX_MESSAGES";
+pub const CONSUMERS_VAR: &str = "MWD_CONSUMERS";
+pub const MULTIPLE_PROCESSES_VAR: &str = "MWD_MULTIPLE_PROCESSES";
+
+// The consumers that require RabbitMQ, extended by the --rabbitmq-consumer option
+const DEFAULT_RABBITMQ_CONSUMER_NAMES: [&str; 1] = ["async.operations.all"];
+const AMQP_CONNECTION: &str = "amqp";
+
+// Durations are serialized in seconds, and environment variable values are redacted, so the
+// configuration printed by --print-config can be shared in bug reports.
+#[derive(Clone, Debug, Serialize)]
 pub struct DaemonConfig {
     pub magento_dir: String,
+    // The label of the Magento installation, only set when supervising multiple installations
+    pub instance: Option<String>,
+    // Whether the consumers that require RabbitMQ are run, from the rabbitmq_detection
     pub rabbitmq_configured: bool,
+    pub rabbitmq_detection: RabbitMqDetection,
+    // The consumers that are assumed to require RabbitMQ when their connection isn't detected
+    pub rabbitmq_consumers: Vec<String>,
+    // The connection of every consumer as configured in Magento, like `amqp` or `db`. Empty when
+    // it couldn't be detected.
+    pub consumer_connections: HashMap<String, String>,
+    #[serde(serialize_with = "serialize_secs")]
+    pub startup_stagger: Duration,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    // The consumers to run instead of the ones found in Magento, empty to find them
+    pub consumers: Vec<String>,
+    // The consumers of the environment, which replace cron_consumers_runner.consumers as the
+    // consumers found in Magento that are run, empty to use the ones of app/etc/env.php
+    pub env_consumers: Vec<String>,
+    // The max_messages and multiple_processes given with the command line options or the
+    // environment variables, which take precedence over app/etc/env.php
+    pub max_messages: Option<u32>,
+    pub multiple_processes: HashMap<String, u32>,
+    // The memory limit per consumer process in bytes
+    pub max_memory: Option<u64>,
+    // The time after which consumer processes are recycled
+    #[serde(serialize_with = "serialize_optional_secs")]
+    pub max_lifetime: Option<Duration>,
+    // The time after which consumer processes that use no CPU time are considered stalled
+    #[serde(serialize_with = "serialize_optional_secs")]
+    pub stall_timeout: Option<Duration>,
+    // The CPU time after which consumer processes are recycled
+    #[serde(serialize_with = "serialize_optional_secs")]
+    pub max_cpu_time: Option<Duration>,
+    #[serde(serialize_with = "serialize_secs")]
+    pub shutdown_timeout: Duration,
+    // The signals to stop the consumer processes with before they're killed, empty for SIGTERM
+    // with the grace period or the shutdown timeout
+    #[serde(serialize_with = "serialize_kill_sequence")]
+    pub kill_sequence: Vec<KillStep>,
+    // What to do with the consumer processes that are still running after the kill sequence
+    pub stuck_worker_action: StuckWorkerAction,
+    // The pattern of the output line with the number of processed messages, see
+    // `--processed-pattern`
+    pub processed_pattern: Option<String>,
+    // The output lines forwarded per second per consumer, 0 for no limit
+    pub output_rate_limit: u32,
+    // Whether the standard output of the consumers is discarded rather than logged
+    pub forward_stderr_only: bool,
+    // How long all consumers have to be drained before the daemon exits
+    #[serde(serialize_with = "serialize_optional_secs")]
+    pub idle_shutdown: Option<Duration>,
+    #[serde(serialize_with = "serialize_secs")]
+    pub consumer_refresh_interval: Duration,
+    // The consecutive refresh failures after which the refresh backs off, 0 to never back off
+    pub refresh_failure_threshold: u32,
+    pub exit_after_refresh_failures: Option<u32>,
+    // The percentage of the expected processes that has to be running, and for how long it may
+    // be below it before it's reported
+    pub min_running_percent: Option<u32>,
+    #[serde(serialize_with = "serialize_secs")]
+    pub min_running_grace: Duration,
+    pub exit_when_degraded: bool,
+    #[serde(serialize_with = "serialize_secs")]
+    pub consumer_list_timeout: Duration,
+    #[serde(serialize_with = "serialize_secs")]
+    pub startup_timeout: Duration,
+    // The interval at which the status of the consumers is logged as JSON
+    #[serde(serialize_with = "serialize_optional_secs")]
+    pub status_interval: Option<Duration>,
+    #[serde(serialize_with = "serialize_redacted_env")]
+    pub env: Vec<(String, String)>,
+    // The PHP binary to run, `php` from the PATH when not set
+    pub php_binary: Option<String>,
+    pub php_args: Vec<String>,
+    pub max_processes_per_consumer: u32,
+    // The maximum number of processes of all consumers of the installation together
+    pub max_total_processes: Option<u32>,
+    // The interval at which the consumers are scaled to their queue backlog, `None` without
+    // --autoscale
+    #[serde(serialize_with = "serialize_optional_secs")]
+    pub autoscale_interval: Option<Duration>,
+    // The waiting messages per process of the consumers without autoscale messages_per_process
+    pub messages_per_process: u64,
+    // Whether consumers with an invalid configuration are skipped, rather than refusing to start
+    pub skip_invalid: bool,
+    // The shell command to run when a consumer process exits unsuccessfully
+    pub on_crash: Option<String>,
+    // The arguments appended to the queue:consumers:start command of every consumer
+    pub consumer_args: Vec<String>,
+    // Whether the consumers are started with --single-thread or --multi-process
+    pub strict_mode: bool,
+    // Whether to stop when the Magento cron worker is enabled while running
+    pub exit_on_cron_run: bool,
+    pub max_restarts_per_minute: u32,
+    // The percentage of the consumers crashing within `storm_window` that is a restart storm
+    pub storm_threshold: u32,
+    #[serde(serialize_with = "serialize_secs")]
+    pub storm_window: Duration,
+    // The approximate number of messages to process before stopping
+    pub max_total_messages: Option<u64>,
+    // The nice value of the consumer processes
+    pub nice: Option<i32>,
+    // The CPUs to pin the consumer processes to, empty for all CPUs
+    pub cpu_affinity: Vec<usize>,
+    // The cgroup v2 directory below which the consumer processes are placed, and the limits of
+    // the cgroups, the memory in bytes
+    pub cgroup_parent: Option<PathBuf>,
+    pub shared_cgroup: bool,
+    pub worker_cpu_max: Option<f64>,
+    pub worker_memory_max: Option<u64>,
+    // The user and group to run the Magento commands as, resolved into `credentials`
+    pub run_as_user: Option<String>,
+    pub run_as_group: Option<String>,
+    #[serde(skip)]
+    pub credentials: Option<Credentials>,
+    // Whether to stop consumer processes left behind by a killed daemon before starting
+    pub reclaim_orphans: bool,
+    // How long a consumer process has to run before an exit doesn't count as a crash
+    #[serde(serialize_with = "serialize_secs")]
+    pub min_healthy_runtime: Duration,
+    // How long after starting a consumer process an exit is restarted without counting as a crash
+    #[serde(serialize_with = "serialize_secs")]
+    pub startup_grace: Duration,
+    // The file with the consumers to run, which is watched for changes
+    pub consumer_list_file: Option<PathBuf>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct MagentoConsumerConfig {
     #[serde(default = "default_cron_run")]
     cron_run: bool,
-    #[serde(default = "default_max_messages")]
+    // The messages a consumer processes before it's restarted, where 0 means unlimited
+    #[serde(
+        default = "default_max_messages",
+        deserialize_with = "deserialize_max_messages"
+    )]
     pub max_messages: u32,
     #[serde(default)]
     pub consumers: Vec<String>,
+    // As configured, including invalid values, which are left out of `multiple_processes`
+    #[serde(default, rename = "multiple_processes", skip_serializing)]
+    configured_processes: HashMap<String, i64>,
+    #[serde(skip_deserializing)]
+    pub multiple_processes: HashMap<String, u32>,
+    #[serde(default)]
+    pub max_messages_per_consumer: HashMap<String, u32>,
+    #[serde(default, serialize_with = "serialize_redacted_env_map")]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub consumer_args: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub priority: HashMap<String, i32>,
     #[serde(default)]
-    pub multiple_processes: HashMap<String, i32>,
+    pub restart_policy: HashMap<String, RestartPolicy>,
+    #[serde(default)]
+    pub autoscale: HashMap<String, AutoscaleConfig>,
+    #[serde(default)]
+    pub backoff: HashMap<String, BackoffConfig>,
+    // Whether single process consumers are started with --single-thread, overriding the strict
+    // mode
+    #[serde(default)]
+    pub single_thread: HashMap<String, bool>,
 }
 
-#[derive(Debug)]
+/// The range a consumer is scaled in with `--autoscale`, from `cron_consumers_runner.autoscale`.
+/// Unset values fall back to one process at least, the `multiple_processes` of the consumer at
+/// most, and `--autoscale-messages-per-process`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AutoscaleConfig {
+    pub min_processes: Option<u32>,
+    pub max_processes: Option<u32>,
+    pub messages_per_process: Option<u64>,
+}
+
+/// The restart backoff of a consumer, from `cron_consumers_runner.backoff`, in seconds. Unset
+/// values fall back to the defaults: 1 second, doubled after every failure up to a minute, and
+/// `--min-healthy-runtime`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct BackoffConfig {
+    pub initial_delay: Option<f64>,
+    pub multiplier: Option<f64>,
+    pub max_delay: Option<f64>,
+    // How long a process has to run for its exit not to count as a crash, after which the
+    // consecutive crashes start over
+    pub min_healthy_runtime: Option<f64>,
+}
+
+/// The backoff of a consumer after it crashed or failed to start repeatedly, with the defaults
+/// applied, see `MagentoConsumerConfig::backoff_for`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Backoff {
+    pub initial_delay: Duration,
+    pub multiplier: f64,
+    pub max_delay: Duration,
+    pub min_healthy_runtime: Duration,
+}
+
+impl Backoff {
+    pub const DEFAULT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+    pub const DEFAULT_MULTIPLIER: f64 = 2.0;
+    pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+    /// The delay after the given number of consecutive failures, of which the first waits the
+    /// initial delay.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let secs = self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent);
+        // Also when it overflowed to infinity
+        if secs < self.max_delay.as_secs_f64() {
+            Duration::from_secs_f64(secs)
+        } else {
+            self.max_delay
+        }
+    }
+}
+
+/// A problem with the configuration of a single consumer, which only skips that consumer with
+/// `--skip-invalid`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConsumerIssue {
+    pub consumer: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for ConsumerIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Whether RabbitMQ is configured in Magento, or `Unknown` when the query failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RabbitMqDetection {
+    Configured,
+    NotConfigured,
+    Unknown,
+}
+
+/// Whether a consumer is restarted when a process exits, like `Restart=` of systemd.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    #[default]
+    Always,
+    // Only restart when a process exits unsuccessfully
+    OnFailure,
+    Never,
+}
+
+impl std::fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            RestartPolicy::Always => "always",
+            RestartPolicy::OnFailure => "on-failure",
+            RestartPolicy::Never => "never",
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
 pub struct DaemonContext {
     pub daemon_config: DaemonConfig,
     pub consumer_config: MagentoConsumerConfig,
+    // Read from --consumer-list-file, `None` without it or while it doesn't exist
+    pub consumer_list: Option<ConsumerList>,
+    // The number of processes of the consumers scaled down for --max-total-processes
+    pub process_caps: HashMap<String, u32>,
+    // The number of processes of the consumers scaled to their queue backlog with --autoscale
+    pub autoscaled: HashMap<String, u32>,
+}
+
+/// The consumers to run according to `--consumer-list-file`. Every line is a consumer name or
+/// glob pattern to run, or not to run when prefixed with `!`, and `#` starts a comment. Without
+/// consumers to run, all consumers are run except the ones not to run.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ConsumerList {
+    pub run: Vec<String>,
+    pub skip: Vec<String>,
+}
+
+impl ConsumerList {
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut list = Self::default();
+        for (index, line) in contents.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(comment) => &line[..comment],
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (patterns, pattern) = match line.strip_prefix('!') {
+                Some(pattern) => (&mut list.skip, pattern.trim_start()),
+                None => (&mut list.run, line),
+            };
+            // Consumer names never contain whitespace
+            if pattern.is_empty() || pattern.contains(char::is_whitespace) {
+                return Err(format!(
+                    "invalid consumer \"{}\" on line {}",
+                    line,
+                    index + 1
+                ));
+            }
+            patterns.push(pattern.to_owned());
+        }
+        Ok(list)
+    }
+
+    /// Reads the list from the file, or returns `None` when the file doesn't exist.
+    pub fn read(path: &Path) -> Result<Option<Self>, EnvironmentError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(EnvironmentError::new(format!(
+                    "Failed to read consumer list file {}: {}",
+                    path.display(),
+                    e
+                )))
+            }
+        };
+        Self::parse(&contents).map(Some).map_err(|e| {
+            EnvironmentError::new(format!(
+                "Invalid consumer list file {}: {}",
+                path.display(),
+                e
+            ))
+        })
+    }
+
+    /// Whether the consumer is run according to the list.
+    pub fn allows(&self, consumer: &str) -> bool {
+        if self.skip.iter().any(|p| glob_match(p, consumer)) {
+            return false;
+        }
+        self.run.is_empty() || self.run.iter().any(|p| glob_match(p, consumer))
+    }
 }
 
 impl DaemonConfig {
-    pub fn new(args: &InputArgs) -> Result<Self, EnvironmentError> {
-        let magento_dir = match args.working_directory {
-            Some(ref path) => path.to_str().unwrap().to_string(),
-            None => env::current_dir().unwrap().to_str().unwrap().to_string(),
+    pub fn new(
+        args: &InputArgs,
+        working_directory: Option<&Path>,
+        instance: Option<String>,
+    ) -> Result<Self, EnvironmentError> {
+        let mut result = Self::resolve(args, working_directory, instance)?;
+        result.validate()?;
+        result.validate_php()?;
+        if result.cgroup_parent.is_some() {
+            cgroup::prepare_parent(&result)?;
+        }
+        result.rabbitmq_detection = match magento_has_rabbitmq_configured(&result) {
+            Ok(true) => RabbitMqDetection::Configured,
+            Ok(false) => RabbitMqDetection::NotConfigured,
+            Err(err) => {
+                log::warn!(
+                    "{}. {} the consumers that require RabbitMQ, see --rabbitmq-on-unknown",
+                    err.message,
+                    match args.rabbitmq_on_unknown {
+                        RabbitMqOnUnknown::Include => "Running",
+                        RabbitMqOnUnknown::Exclude => "Skipping",
+                    }
+                );
+                if let Some(ref stderr) = err.stderr {
+                    log::debug!("Error output:\n{}", stderr);
+                }
+                RabbitMqDetection::Unknown
+            }
+        };
+        result.rabbitmq_configured = match result.rabbitmq_detection {
+            RabbitMqDetection::Configured => true,
+            RabbitMqDetection::NotConfigured => false,
+            RabbitMqDetection::Unknown => args.rabbitmq_on_unknown == RabbitMqOnUnknown::Include,
         };
-        let rabbitmq_configured = magento_has_rabbitmq_configured(&magento_dir);
+        result.consumer_connections = magento_consumer_connections(&result);
+        Ok(result)
+    }
+
+    /// The configuration from the command line options, without checking the Magento
+    /// installation and PHP, and without querying Magento.
+    pub fn resolve(
+        args: &InputArgs,
+        working_directory: Option<&Path>,
+        instance: Option<String>,
+    ) -> Result<Self, EnvironmentError> {
+        let magento_dir = match working_directory {
+            Some(path) => path.to_path_buf(),
+            None => env::current_dir().map_err(|e| {
+                EnvironmentError::new(format!("Failed to determine working directory: {}", e))
+            })?,
+        };
+        // Resolved to an absolute path, so the commands run in the same directory no matter what
+        // the current directory is, and the errors show which directory is used.
+        let magento_dir = fs::canonicalize(&magento_dir).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => EnvironmentError::new(format!(
+                "Magento directory {} not found",
+                magento_dir.display()
+            )),
+            _ => EnvironmentError::new(format!(
+                "Failed to resolve Magento directory {}: {}",
+                magento_dir.display(),
+                e
+            )),
+        })?;
+        let magento_dir = magento_dir
+            .to_str()
+            .ok_or_else(|| {
+                EnvironmentError::new(format!(
+                    "Magento directory {} is not valid UTF-8",
+                    magento_dir.display()
+                ))
+            })?
+            .to_string();
+
+        let env_config = EnvConfig::from_env()?;
+        let mut multiple_processes = env_config.multiple_processes;
+        multiple_processes.extend(args.processes.iter().cloned());
 
-        let result = Self {
+        let mut result = Self {
             magento_dir,
-            rabbitmq_configured,
+            instance,
+            rabbitmq_configured: false,
+            rabbitmq_detection: RabbitMqDetection::Unknown,
+            rabbitmq_consumers: DEFAULT_RABBITMQ_CONSUMER_NAMES
+                .iter()
+                .map(|x| x.to_string())
+                .chain(args.rabbitmq_consumer.iter().cloned())
+                .collect(),
+            consumer_connections: HashMap::new(),
+            startup_stagger: Duration::from_millis(args.startup_stagger),
+            include: args.include.clone(),
+            exclude: args.exclude.clone(),
+            consumers: args.consumer.clone(),
+            env_consumers: env_config.consumers,
+            max_messages: args.max_messages.or(env_config.max_messages),
+            multiple_processes,
+            max_memory: args.max_memory.map(|mb| mb * BYTES_PER_MB),
+            max_lifetime: args.max_lifetime.map(Duration::from_secs),
+            stall_timeout: args.stall_timeout.map(Duration::from_secs),
+            max_cpu_time: args.max_cpu_seconds.map(Duration::from_secs),
+            shutdown_timeout: Duration::from_secs(args.shutdown_timeout),
+            kill_sequence: args
+                .kill_sequence
+                .as_ref()
+                .map(|x| x.0.clone())
+                .unwrap_or_default(),
+            stuck_worker_action: args.stuck_worker_action,
+            processed_pattern: args.processed_pattern.clone(),
+            output_rate_limit: args.output_rate_limit,
+            forward_stderr_only: args.forward_stderr_only,
+            idle_shutdown: args.idle_shutdown.map(Duration::from_secs),
+            consumer_refresh_interval: Duration::from_secs(args.consumer_refresh_interval),
+            refresh_failure_threshold: args.refresh_failure_threshold,
+            exit_after_refresh_failures: args.exit_after_refresh_failures,
+            min_running_percent: args.min_running_percent,
+            min_running_grace: Duration::from_secs(args.min_running_grace),
+            exit_when_degraded: args.exit_when_degraded,
+            consumer_list_timeout: Duration::from_secs(args.consumer_list_timeout),
+            startup_timeout: Duration::from_secs(args.startup_timeout),
+            status_interval: args.status_interval.map(Duration::from_secs),
+            env: args.env.clone(),
+            php_binary: args.php_binary.clone(),
+            php_args: args.php_arg.clone(),
+            max_processes_per_consumer: args.max_processes_per_consumer,
+            max_total_processes: args.max_total_processes,
+            autoscale_interval: args
+                .autoscale
+                .then(|| Duration::from_secs(args.autoscale_interval)),
+            messages_per_process: args.autoscale_messages_per_process,
+            skip_invalid: args.skip_invalid,
+            on_crash: args.on_crash.clone(),
+            consumer_args: args.consumer_arg.clone(),
+            strict_mode: !args.no_strict_mode,
+            exit_on_cron_run: args.exit_on_cron_run,
+            max_restarts_per_minute: args.max_restarts_per_minute,
+            storm_threshold: args.storm_threshold,
+            storm_window: Duration::from_secs(args.storm_window),
+            max_total_messages: args.max_total_messages,
+            nice: args.nice,
+            cpu_affinity: args
+                .cpu_affinity
+                .as_ref()
+                .map(|x| x.0.clone())
+                .unwrap_or_default(),
+            cgroup_parent: args.cgroup_parent.clone(),
+            shared_cgroup: args.shared_cgroup,
+            worker_cpu_max: args.worker_cpu_max,
+            worker_memory_max: args.worker_memory_max.map(|mb| mb * BYTES_PER_MB),
+            run_as_user: args.run_as_user.clone(),
+            run_as_group: args.run_as_group.clone(),
+            credentials: None,
+            reclaim_orphans: args.reclaim_orphans,
+            min_healthy_runtime: Duration::from_secs(args.min_healthy_runtime),
+            startup_grace: Duration::from_secs(args.startup_grace),
+            consumer_list_file: args.consumer_list_file.clone(),
         };
-        result.validate()?;
+        if result.run_as_user.is_some() || result.run_as_group.is_some() {
+            let credentials = resolve_credentials(
+                result.run_as_user.as_deref(),
+                result.run_as_group.as_deref(),
+            )
+            .map_err(|e| EnvironmentError::new(format!("Can't run the consumers: {}", e)))?;
+            result.credentials = Some(credentials);
+        }
         Ok(result)
     }
 
     pub fn validate(&self) -> Result<(), EnvironmentError> {
         // Check if magento dir exists
         let magento_dir_path = Path::new(&self.magento_dir);
-        if !magento_dir_path.exists() {
-            return Err(EnvironmentError {
-                message: "Magento directory not found".to_owned(),
-            });
+        if !magento_dir_path.is_dir() {
+            return Err(EnvironmentError::new(format!(
+                "Magento directory {} not found",
+                self.magento_dir
+            )));
         }
 
         // Check if bin/magento exists
-        if !magento_dir_path.join("bin/magento").exists() {
-            return Err(EnvironmentError {
-                message: "Magento bin not found".to_owned(),
-            });
+        let magento_bin = magento_dir_path.join("bin/magento");
+        if !magento_bin.exists() {
+            return Err(EnvironmentError::new(format!(
+                "Magento bin not found in {}",
+                self.magento_dir
+            )));
+        }
+
+        // Without a PHP binary, bin/magento is run directly, so it has to be executable
+        if self.php_binary.is_none() && self.php_args.is_empty() {
+            use std::os::unix::fs::PermissionsExt;
+            let executable = fs::metadata(&magento_bin)
+                .map(|x| x.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false);
+            if !executable {
+                return Err(EnvironmentError::new(format!(
+                    "{} exists but is not executable - run chmod +x bin/magento, check the mount options, or use --php-binary to run it with PHP",
+                    magento_bin.display()
+                )));
+            }
         }
 
         Ok(())
     }
+
+    /// Checks that PHP can be run, before it's used to query the Magento configuration.
+    pub fn validate_php(&self) -> Result<(), EnvironmentError> {
+        let version = self.php_version()?;
+        log::debug!("Using {}", version);
+        Ok(())
+    }
+
+    /// The first line of `php --version`, like `PHP 8.2.0 (cli)`.
+    pub fn php_version(&self) -> Result<String, EnvironmentError> {
+        let php_binary = self.php_binary.as_deref().unwrap_or("php");
+        let mut command = self.php_command();
+        command.arg("--version");
+        let output = output_with_timeout(&mut command, self.startup_timeout).map_err(|e| {
+            EnvironmentError::new(format!(
+                "PHP not found or not executable at {}: {}. Use --php-binary to set the PHP binary",
+                php_binary, e
+            ))
+        })?;
+        if !output.status.success() {
+            return Err(EnvironmentError::new(format!(
+                "PHP at {} failed to run with {}. Use --php-binary to set the PHP binary",
+                php_binary, output.status
+            ))
+            .with_stderr(&output.stderr));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .next()
+            .unwrap_or(php_binary)
+            .to_owned())
+    }
+
+    /// The PHP command, run in the Magento directory as `--run-as-user`.
+    pub fn php_command(&self) -> Command {
+        let mut command = Command::new(self.php_binary.as_deref().unwrap_or("php"));
+        command.current_dir(&self.magento_dir).args(&self.php_args);
+        if let Some(ref credentials) = self.credentials {
+            set_credentials(&mut command, credentials);
+        }
+        command
+    }
+
+    /// The program and arguments to run bin/magento with. Without a custom PHP binary or PHP
+    /// arguments bin/magento is run directly.
+    pub fn magento_command_line(&self) -> Vec<String> {
+        let mut command_line = Vec::new();
+        if self.php_binary.is_some() || !self.php_args.is_empty() {
+            command_line.push(self.php_binary.as_deref().unwrap_or("php").to_owned());
+            command_line.extend(self.php_args.iter().cloned());
+        }
+        command_line.push("bin/magento".to_owned());
+        command_line
+    }
+
+    /// The bin/magento command, run in the Magento directory as `--run-as-user`, so the files
+    /// Magento writes, like its cache, aren't owned by the user of the daemon.
+    pub fn magento_command(&self) -> Command {
+        let command_line = self.magento_command_line();
+        let mut command = Command::new(&command_line[0]);
+        command
+            .current_dir(&self.magento_dir)
+            .args(&command_line[1..]);
+        if let Some(ref credentials) = self.credentials {
+            set_credentials(&mut command, credentials);
+        }
+        command
+    }
+
+    /// The consumer name, prefixed with the instance label when supervising multiple Magento
+    /// installations, to tell consumers of different installations apart in the logs.
+    pub fn qualified_name(&self, consumer: &str) -> String {
+        match self.instance {
+            Some(ref instance) => format!("{}:{}", instance, consumer),
+            None => consumer.to_owned(),
+        }
+    }
+
+    /// Whether the consumer requires RabbitMQ, according to its connection in Magento. When the
+    /// connection wasn't detected, it falls back to the consumers given with `--rabbitmq-consumer`
+    /// and `async.operations.all`.
+    pub fn is_amqp_consumer(&self, consumer: &str) -> bool {
+        match self.consumer_connections.get(consumer) {
+            Some(connection) => connection == AMQP_CONNECTION,
+            None => self.rabbitmq_consumers.iter().any(|x| x == consumer),
+        }
+    }
+
+    /// Whether the consumer is selected by the include and exclude patterns. Exclude patterns take
+    /// precedence over include patterns, and no include patterns means all consumers are included.
+    pub fn consumer_matches_patterns(&self, consumer: &str) -> bool {
+        matches_patterns(consumer, &self.include, &self.exclude)
+    }
+}
+
+/// Whether the consumer is selected by the include patterns and not by the exclude patterns, see
+/// `DaemonConfig::consumer_matches_patterns`.
+fn matches_patterns(consumer: &str, include: &[String], exclude: &[String]) -> bool {
+    if exclude.iter().any(|p| glob_match(p, consumer)) {
+        return false;
+    }
+    include.is_empty() || include.iter().any(|p| glob_match(p, consumer))
+}
+
+/// The configuration given with the environment variables, see `MAX_MESSAGES_VAR`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EnvConfig {
+    pub max_messages: Option<u32>,
+    pub consumers: Vec<String>,
+    pub multiple_processes: HashMap<String, u32>,
+}
+
+impl EnvConfig {
+    pub fn from_env() -> Result<Self, EnvironmentError> {
+        Self::from_vars(|name| env::var(name).ok())
+    }
+
+    /// Parses the variables `var` returns, where empty ones count as unset. The consumers are
+    /// comma separated, like the `consumer=n` pairs of the processes.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, EnvironmentError> {
+        let var = |name: &str| var(name).filter(|x| !x.trim().is_empty());
+        let invalid = |name: &str, value: &str, err: String| {
+            EnvironmentError::new(format!("Invalid {} `{}`: {}", name, value, err))
+        };
+        let max_messages = match var(MAX_MESSAGES_VAR) {
+            Some(value) => Some(value.trim().parse().map_err(|_| {
+                invalid(
+                    MAX_MESSAGES_VAR,
+                    &value,
+                    "expected a number of messages".to_owned(),
+                )
+            })?),
+            None => None,
+        };
+        let consumers = match var(CONSUMERS_VAR) {
+            Some(value) => list_items(&value).map(|x| x.to_owned()).collect(),
+            None => Vec::new(),
+        };
+        let mut multiple_processes = HashMap::new();
+        if let Some(value) = var(MULTIPLE_PROCESSES_VAR) {
+            for item in list_items(&value) {
+                let (consumer, processes) = input::parse_processes(item)
+                    .map_err(|err| invalid(MULTIPLE_PROCESSES_VAR, &value, err))?;
+                multiple_processes.insert(consumer, processes);
+            }
+        }
+        Ok(Self {
+            max_messages,
+            consumers,
+            multiple_processes,
+        })
+    }
+}
+
+fn list_items(value: &str) -> impl Iterator<Item = &str> {
+    value.split(',').map(str::trim).filter(|x| !x.is_empty())
 }
 
 impl MagentoConsumerConfig {
     pub fn new(config: &DaemonConfig) -> Result<Self, EnvironmentError> {
+        let consumer_config = Self::query(config)?;
+        if consumer_config.cron_run {
+            return Err(EnvironmentError::new("Magento cron worker is enabled. Please see https://experienceleague.adobe.com/docs/commerce-operations/configuration-guide/message-queues/manage-message-queues.html#configuration to see how to disable the cron_run variable."));
+        }
+        consumer_config.validate(config)?;
+        Ok(consumer_config)
+    }
+
+    /// Queries the configuration, without refusing it when the Magento cron worker is enabled.
+    pub(crate) fn query(config: &DaemonConfig) -> Result<Self, EnvironmentError> {
         const CRON_RUN_QUERY: &str = r#"
         $config = include 'app/etc/env.php';
         $v = $config['cron_consumers_runner'] ?? [];
@@ -80,46 +768,436 @@ impl MagentoConsumerConfig {
         }
         echo json_encode($v);
         "#;
-        let output = Command::new("php")
-            .current_dir(&config.magento_dir)
-            .args(&["-r", CRON_RUN_QUERY])
-            .output()
-            .expect("Can query Magento consumer configuration");
-
-        let consumer_config: Self = serde_json::from_slice(&output.stdout).unwrap();
-        consumer_config.validate()?;
+        let output = run_php_query(config, CRON_RUN_QUERY)
+            .map_err(|e| e.prefixed("Failed to query Magento consumer configuration"))?;
+
+        let mut consumer_config: Self = serde_json::from_slice(&output.stdout).map_err(|e| {
+            EnvironmentError::new(format!(
+                "Failed to parse Magento consumer configuration: {}. Output was: {}",
+                e,
+                String::from_utf8_lossy(&output.stdout).trim()
+            ))
+            .with_stderr(&output.stderr)
+        })?;
+        // Given with the command line options or the environment variables
+        if let Some(max_messages) = config.max_messages {
+            consumer_config.max_messages = max_messages;
+        }
+        for (consumer, processes) in config.multiple_processes.iter() {
+            consumer_config
+                .configured_processes
+                .insert(consumer.clone(), i64::from(*processes));
+        }
+        consumer_config.multiple_processes = consumer_config
+            .configured_processes
+            .iter()
+            .filter_map(|(consumer, processes)| {
+                Some((consumer.clone(), u32::try_from(*processes).ok()?))
+            })
+            .collect();
         Ok(consumer_config)
     }
 
-    pub fn validate(&self) -> Result<(), EnvironmentError> {
-        if self.cron_run {
-            return Err(EnvironmentError{
-                message: "Magento cron worker is enabled. Please see https://experienceleague.adobe.com/docs/commerce-operations/configuration-guide/message-queues/manage-message-queues.html#configuration to see how to disable the cron_run variable.".to_owned()
-            });
+    /// Whether the Magento cron worker starts the consumers too.
+    pub fn cron_run(&self) -> bool {
+        self.cron_run
+    }
+
+    /// Checks the configuration. Problems with the configuration of a single consumer are
+    /// logged and the consumer is skipped with `--skip-invalid`, and are errors otherwise.
+    pub fn validate(&self, config: &DaemonConfig) -> Result<(), EnvironmentError> {
+        let issues = self.consumer_issues(config);
+        if !config.skip_invalid {
+            if let Some(issue) = issues.first() {
+                return Err(EnvironmentError::new(format!(
+                    "{}. Use --skip-invalid to skip invalid consumers and run the others",
+                    issue
+                )));
+            }
+        }
+        for issue in issues.iter() {
+            log::error!("Skipping consumer {}: {}", issue.consumer, issue);
         }
-        if self.multiple_processes.values().any(|x| *x < 0) {
-            return Err(EnvironmentError {
-                message: "Magento consumer multiple_processes values must be greater than zero"
-                    .to_owned(),
-            });
+        let total_processes: u32 = self
+            .multiple_processes
+            .values()
+            .filter(|x| **x <= config.max_processes_per_consumer)
+            .sum();
+        if let Ok(cpus) = std::thread::available_parallelism() {
+            if total_processes as usize > cpus.get() * OVERPROVISIONED_PROCESSES_PER_CPU {
+                log::warn!(
+                    "Magento consumer multiple_processes adds up to {} processes, which is a lot for {} CPUs",
+                    total_processes,
+                    cpus
+                );
+            }
         }
         Ok(())
     }
+
+    /// The problems with the configuration of single consumers, ordered by consumer.
+    pub fn consumer_issues(&self, config: &DaemonConfig) -> Vec<ConsumerIssue> {
+        let consumers: HashSet<&String> = self
+            .configured_processes
+            .keys()
+            .chain(self.autoscale.keys())
+            .chain(self.backoff.keys())
+            .collect();
+        let mut issues: Vec<_> = consumers
+            .into_iter()
+            .filter_map(|consumer| self.consumer_issue(config, consumer))
+            .collect();
+        issues.sort_by(|a, b| a.consumer.cmp(&b.consumer));
+        issues
+    }
+
+    /// The problem with the configuration of the given consumer, if any.
+    pub fn consumer_issue(&self, config: &DaemonConfig, consumer: &str) -> Option<ConsumerIssue> {
+        let processes = match self.configured_processes.get(consumer) {
+            Some(processes) => *processes,
+            None => {
+                return self
+                    .autoscale_issue(config, consumer)
+                    .or_else(|| self.backoff_issue(config, consumer))
+            }
+        };
+        let message = if processes < 0 {
+            format!(
+                "Magento consumer multiple_processes value {} for {} must not be negative",
+                processes, consumer
+            )
+        } else if processes > i64::from(config.max_processes_per_consumer) {
+            format!(
+                "Magento consumer multiple_processes value {} for {} exceeds the maximum of {}, see --max-processes-per-consumer",
+                processes, consumer, config.max_processes_per_consumer
+            )
+        } else {
+            return self
+                .autoscale_issue(config, consumer)
+                .or_else(|| self.backoff_issue(config, consumer));
+        };
+        Some(ConsumerIssue {
+            consumer: consumer.to_owned(),
+            message,
+        })
+    }
+
+    /// The problem with the autoscale range of the given consumer, if any.
+    fn autoscale_issue(&self, config: &DaemonConfig, consumer: &str) -> Option<ConsumerIssue> {
+        let autoscale = self.autoscale.get(consumer)?;
+        let message = if autoscale.min_processes == Some(0) {
+            format!(
+                "Magento consumer autoscale min_processes for {} must be at least 1",
+                consumer
+            )
+        } else if autoscale.messages_per_process == Some(0) {
+            format!(
+                "Magento consumer autoscale messages_per_process for {} must be at least 1",
+                consumer
+            )
+        } else if let Some(max) = autoscale
+            .max_processes
+            .filter(|x| *x > config.max_processes_per_consumer)
+        {
+            format!(
+                "Magento consumer autoscale max_processes value {} for {} exceeds the maximum of {}, see --max-processes-per-consumer",
+                max, consumer, config.max_processes_per_consumer
+            )
+        } else if matches!(
+            (autoscale.min_processes, autoscale.max_processes),
+            (Some(min), Some(max)) if min > max
+        ) {
+            format!(
+                "Magento consumer autoscale min_processes for {} exceeds its max_processes",
+                consumer
+            )
+        } else {
+            return None;
+        };
+        Some(ConsumerIssue {
+            consumer: consumer.to_owned(),
+            message,
+        })
+    }
+
+    /// The problem with the backoff of the given consumer, if any. The delays have to be positive
+    /// and at most a day, and the maximum delay at least the initial one.
+    fn backoff_issue(&self, config: &DaemonConfig, consumer: &str) -> Option<ConsumerIssue> {
+        let backoff = self.backoff.get(consumer)?;
+        let valid_secs = |x: f64, min: f64| x >= min && x <= MAX_BACKOFF_SECS;
+        let resolved = self.backoff_for(config, consumer);
+        let message = if let Some(x) = backoff.initial_delay.filter(|x| !valid_secs(*x, 0.001)) {
+            format!(
+                "Magento consumer backoff initial_delay {} for {} must be between 0.001 and {} seconds",
+                x, consumer, MAX_BACKOFF_SECS
+            )
+        } else if let Some(x) = backoff.max_delay.filter(|x| !valid_secs(*x, 0.001)) {
+            format!(
+                "Magento consumer backoff max_delay {} for {} must be between 0.001 and {} seconds",
+                x, consumer, MAX_BACKOFF_SECS
+            )
+        } else if let Some(x) = backoff.min_healthy_runtime.filter(|x| !valid_secs(*x, 0.0)) {
+            format!(
+                "Magento consumer backoff min_healthy_runtime {} for {} must be between 0 and {} seconds",
+                x, consumer, MAX_BACKOFF_SECS
+            )
+        } else if let Some(x) = backoff.multiplier.filter(|x| !(*x >= 1.0 && x.is_finite())) {
+            format!(
+                "Magento consumer backoff multiplier {} for {} must be at least 1",
+                x, consumer
+            )
+        } else if resolved.max_delay < resolved.initial_delay {
+            format!(
+                "Magento consumer backoff max_delay for {} is less than its initial_delay",
+                consumer
+            )
+        } else {
+            return None;
+        };
+        Some(ConsumerIssue {
+            consumer: consumer.to_owned(),
+            message,
+        })
+    }
+
+    /// The backoff of the given consumer, falling back to the defaults for the values that aren't
+    /// set, or are invalid.
+    pub fn backoff_for(&self, config: &DaemonConfig, consumer: &str) -> Backoff {
+        let backoff = self.backoff.get(consumer).copied().unwrap_or_default();
+        let secs = |x: Option<f64>, default: Duration| {
+            x.filter(|x| *x <= MAX_BACKOFF_SECS)
+                .and_then(|x| Duration::try_from_secs_f64(x).ok())
+                .unwrap_or(default)
+        };
+        Backoff {
+            initial_delay: secs(backoff.initial_delay, Backoff::DEFAULT_INITIAL_DELAY),
+            multiplier: backoff
+                .multiplier
+                .filter(|x| x.is_finite() && *x >= 1.0)
+                .unwrap_or(Backoff::DEFAULT_MULTIPLIER),
+            max_delay: secs(backoff.max_delay, Backoff::DEFAULT_MAX_DELAY),
+            min_healthy_runtime: secs(backoff.min_healthy_runtime, config.min_healthy_runtime),
+        }
+    }
+
+    /// The priority of the given consumer, where consumers with a higher priority are started and
+    /// restarted first. Defaults to 0.
+    pub fn priority_for(&self, consumer: &str) -> i32 {
+        self.priority.get(consumer).copied().unwrap_or(0)
+    }
+
+    /// The restart policy of the given consumer, falling back to `always`.
+    pub fn restart_policy_for(&self, consumer: &str) -> RestartPolicy {
+        self.restart_policy
+            .get(consumer)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Whether the given consumer is started with `--single-thread` when it runs a single
+    /// process, falling back to whether the strict mode is enabled.
+    pub fn single_thread_for(&self, config: &DaemonConfig, consumer: &str) -> bool {
+        match self.single_thread.get(consumer) {
+            Some(single_thread) => *single_thread,
+            None => config.strict_mode,
+        }
+    }
+
+    /// The `multiple_processes` value of the given consumer as configured, including invalid
+    /// values, or `None` when it isn't set.
+    pub fn configured_processes_for(&self, consumer: &str) -> Option<i64> {
+        self.configured_processes.get(consumer).copied()
+    }
+
+    /// The consumers `multiple_processes` is set for, including the ones with invalid values.
+    pub fn configured_process_consumers(&self) -> impl Iterator<Item = &str> {
+        self.configured_processes.keys().map(String::as_str)
+    }
+
+    /// The max messages for the given consumer, falling back to the global `max_messages`. Zero
+    /// means the consumer runs until it's stopped, without `--max-messages`.
+    pub fn max_messages_for(&self, consumer: &str) -> u32 {
+        match self.max_messages_per_consumer.get(consumer) {
+            Some(max_messages) => *max_messages,
+            None => self.max_messages,
+        }
+    }
 }
 
 impl DaemonContext {
-    pub fn new(args: &InputArgs) -> Result<Self, EnvironmentError> {
-        let config = DaemonConfig::new(args)?;
+    pub fn new(
+        args: &InputArgs,
+        working_directory: Option<&Path>,
+        instance: Option<String>,
+    ) -> Result<Self, EnvironmentError> {
+        let config = DaemonConfig::new(args, working_directory, instance)?;
         let consumer_config = MagentoConsumerConfig::new(&config)?;
+        let consumer_list = match config.consumer_list_file {
+            Some(ref path) => {
+                let consumer_list = ConsumerList::read(path)?;
+                if consumer_list.is_none() {
+                    log::warn!(
+                        "Consumer list file {} not found, running all consumers until it's created",
+                        path.display()
+                    );
+                }
+                consumer_list
+            }
+            None => None,
+        };
         Ok(Self {
             daemon_config: config,
             consumer_config,
+            consumer_list,
+            process_caps: HashMap::new(),
+            autoscaled: HashMap::new(),
         })
     }
+
+    /// Reads the Magento consumer configuration again, for example after `cron_consumers_runner`
+    /// was changed in `app/etc/env.php`. Unlike on startup, the configuration is returned when
+    /// the Magento cron worker is enabled, so the caller can tell that apart from other errors.
+    pub fn reload(&self) -> Result<Self, EnvironmentError> {
+        let consumer_config = MagentoConsumerConfig::query(&self.daemon_config)?;
+        consumer_config.validate(&self.daemon_config)?;
+        Ok(Self {
+            daemon_config: self.daemon_config.clone(),
+            consumer_config,
+            consumer_list: self.consumer_list.clone(),
+            process_caps: self.process_caps.clone(),
+            autoscaled: self.autoscaled.clone(),
+        })
+    }
+
+    /// The context with the consumer list read from `--consumer-list-file` replaced.
+    pub fn with_consumer_list(&self, consumer_list: Option<ConsumerList>) -> Self {
+        Self {
+            consumer_list,
+            ..self.clone()
+        }
+    }
+
+    /// Creates a context for every Magento installation given with `--working-directory`, or for
+    /// the current directory when none is given. When there are multiple installations, each one
+    /// is labeled with its given label or its path.
+    pub fn from_args(args: &InputArgs) -> Result<Vec<Self>, EnvironmentError> {
+        if args.working_directory.len() <= 1 {
+            let instance = args.working_directory.first();
+            let context = Self::new(
+                args,
+                instance.map(|i| i.path.as_path()),
+                instance.and_then(|i| i.label.clone()),
+            )?;
+            return Ok(vec![context]);
+        }
+
+        let mut labels = HashSet::new();
+        let mut contexts = Vec::new();
+        for Instance { label, path } in args.working_directory.iter() {
+            let label = label.clone().unwrap_or_else(|| path.display().to_string());
+            if !labels.insert(label.clone()) {
+                return Err(EnvironmentError::new(format!(
+                    "Magento installation {} is given multiple times",
+                    label
+                )));
+            }
+            let context =
+                Self::new(args, Some(path), Some(label.clone())).map_err(|e| e.prefixed(&label))?;
+            contexts.push(context);
+        }
+        Ok(contexts)
+    }
+
+    /// The environment variables to set for the consumers. Variables given on the command line
+    /// take precedence over the ones in the Magento configuration.
+    pub fn worker_env(&self) -> HashMap<String, String> {
+        let mut env = self.consumer_config.env.clone();
+        env.extend(self.daemon_config.env.iter().cloned());
+        env
+    }
 }
 
+#[derive(Debug)]
 pub struct EnvironmentError {
     pub message: String,
+    // The error output of the PHP process, if the error originates from a PHP query
+    pub stderr: Option<String>,
+}
+
+impl EnvironmentError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            stderr: None,
+        }
+    }
+
+    pub fn with_stderr(mut self, stderr: &[u8]) -> Self {
+        let stderr = String::from_utf8_lossy(stderr).trim().to_string();
+        self.stderr = if stderr.is_empty() {
+            None
+        } else {
+            Some(stderr)
+        };
+        self
+    }
+
+    fn prefixed(mut self, prefix: &str) -> Self {
+        self.message = format!("{}: {}", prefix, self.message);
+        self
+    }
+}
+
+/// Deserializes `max_messages`, where `null` means unlimited like 0, rather than the default.
+fn deserialize_max_messages<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(Option::<u32>::deserialize(deserializer)?.unwrap_or(0))
+}
+
+fn serialize_secs<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+fn serialize_optional_secs<S: Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match duration {
+        Some(duration) => serializer.serialize_some(&duration.as_secs_f64()),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Serializes the environment variables as a map, with the values redacted, as they may contain
+/// secrets like license keys.
+fn serialize_redacted_env<S: Serializer>(
+    env: &[(String, String)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(env.iter().map(|(key, _)| (key, "<redacted>")))
+}
+
+// Like `--kill-sequence`, as `SIGTERM:5` strings
+fn serialize_kill_sequence<S: Serializer>(
+    steps: &[KillStep],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(steps.iter().map(|step| {
+        format!(
+            "{}:{}",
+            signal_name(step.signal).unwrap_or("signal"),
+            step.wait.as_secs_f64()
+        )
+    }))
+}
+
+fn serialize_redacted_env_map<S: Serializer>(
+    env: &HashMap<String, String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(env.keys().map(|key| (key, "<redacted>")))
 }
 
 fn default_cron_run() -> bool {
@@ -130,17 +1208,294 @@ fn default_max_messages() -> u32 {
     10000
 }
 
-fn magento_has_rabbitmq_configured(magento_dir: &String) -> bool {
+/// Runs the PHP code in the Magento directory, and returns its output when it succeeded. When it
+/// failed, for example on a fatal error in `app/etc/env.php`, the error carries the PHP error
+/// output. PHP is killed when it doesn't finish within `--startup-timeout`.
+fn run_php_query(config: &DaemonConfig, query: &str) -> Result<Output, EnvironmentError> {
+    let mut command = config.php_command();
+    command.args(["-r", query]);
+    let output =
+        output_with_timeout(&mut command, config.startup_timeout).map_err(|e| match e.kind() {
+            io::ErrorKind::TimedOut => EnvironmentError::new(format!(
+                "php {}, the database or cache may be unreachable. See --startup-timeout",
+                e
+            )),
+            _ => EnvironmentError::new(format!("Failed to run php: {}", e)),
+        })?;
+    if !output.status.success() {
+        // PHP prints errors to stdout when display_errors is enabled, which is the default of
+        // the CLI, and to stderr when log_errors is enabled.
+        let error_output = if output.stderr.iter().all(u8::is_ascii_whitespace) {
+            &output.stdout
+        } else {
+            &output.stderr
+        };
+        return Err(
+            EnvironmentError::new(format!("php {}", describe_exit_status(output.status)))
+                .with_stderr(error_output),
+        );
+    }
+    Ok(output)
+}
+
+/// Runs `f` until it succeeds, at most `attempts` times, doubling the delay between the attempts.
+pub fn with_retries<T, F>(attempts: u32, delay: Duration, mut f: F) -> Result<T, EnvironmentError>
+where
+    F: FnMut() -> Result<T, EnvironmentError>,
+{
+    let mut retry_delay = delay;
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Ok(result) => return Ok(result),
+            Err(err) if attempt < attempts => {
+                log::warn!(
+                    "{}, retrying in {}s",
+                    err.message,
+                    retry_delay.as_secs_f32()
+                );
+                std::thread::sleep(retry_delay);
+                retry_delay *= 2;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// The number of messages waiting in the queue of every consumer, read from the
+/// `queue_message_status` table for the database queues, and with a passive declare of the queue
+/// for the RabbitMQ ones. Magento has to be bootstrapped for it, like for `bin/magento`, so it's
+/// about as expensive as running a Magento command. Consumers of which the backlog can't be
+/// read are left out.
+pub fn queue_backlogs(config: &DaemonConfig) -> Result<HashMap<String, u64>, EnvironmentError> {
+    // Messages that are new or have to be retried, see \Magento\MysqlMq\Model\QueueManagement
+    const QUEUE_BACKLOGS_QUERY: &str = r#"
+    require 'app/bootstrap.php';
+    $objectManager = \Magento\Framework\App\Bootstrap::create(BP, $_SERVER)->getObjectManager();
+    $consumerConfig = $objectManager
+        ->get(\Magento\Framework\MessageQueue\Consumer\ConfigInterface::class);
+    $backlogs = [];
+    foreach ($consumerConfig->getConsumers() as $consumer) {
+        try {
+            if ($consumer->getConnection() === 'db') {
+                $resource = $objectManager->get(\Magento\Framework\App\ResourceConnection::class);
+                $db = $resource->getConnection();
+                $messages = $db->fetchOne($db->select()
+                    ->from(['s' => $resource->getTableName('queue_message_status')], 'COUNT(*)')
+                    ->join(['q' => $resource->getTableName('queue')], 'q.id = s.queue_id', [])
+                    ->where('q.name = ?', $consumer->getQueue())
+                    ->where('s.status IN (?)', [2, 5]));
+            } else {
+                $channel = $objectManager->get(\Magento\Framework\Amqp\ConfigPool::class)
+                    ->get($consumer->getConnection())->getChannel();
+                [, $messages] = $channel->queue_declare($consumer->getQueue(), true);
+            }
+            $backlogs[$consumer->getName()] = (int) $messages;
+        } catch (\Throwable $e) {
+            fwrite(STDERR, $consumer->getName() . ': ' . $e->getMessage() . "\n");
+        }
+    }
+    echo json_encode((object) $backlogs);
+    "#;
+
+    let output = run_php_query(config, QUEUE_BACKLOGS_QUERY)
+        .map_err(|e| e.prefixed("Failed to query the queue backlogs"))?;
+    // Only the last line is parsed, in case PHP prints notices before the result
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let result = stdout.lines().last().unwrap_or_default().trim();
+    let backlogs = serde_json::from_str::<HashMap<String, u64>>(result).map_err(|e| {
+        EnvironmentError::new(format!(
+            "Failed to parse the queue backlogs: {}. Output was: {}",
+            e,
+            stdout.trim()
+        ))
+        .with_stderr(&output.stderr)
+    })?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !stderr.trim().is_empty() {
+        log::debug!("Failed to read some queue backlogs:\n{}", stderr.trim_end());
+    }
+    Ok(backlogs)
+}
+
+/// Whether RabbitMQ is configured in Magento. It's only detected on startup, and the result is
+/// stored in `DaemonConfig::rabbitmq_detection`.
+pub(crate) fn magento_has_rabbitmq_configured(
+    config: &DaemonConfig,
+) -> Result<bool, EnvironmentError> {
     const RABBITMQ_CONFIGURED_QUERY: &str = r#"
     $config = include 'app/etc/env.php';
-    $v = isset($config['queue']['amqp']);
-    var_dump($v);
+    echo json_encode(isset($config['queue']['amqp']));
     "#;
 
-    let output = Command::new("php")
-        .current_dir(magento_dir)
-        .args(&["-r", RABBITMQ_CONFIGURED_QUERY])
-        .output()
-        .expect("Failed to query rabbitmq configuration");
-    output.stdout.eq(b"bool(true)\n")
+    let configured = with_retries(PHP_QUERY_ATTEMPTS, PHP_QUERY_RETRY_DELAY, || {
+        let output = run_php_query(config, RABBITMQ_CONFIGURED_QUERY)
+            .map_err(|e| e.prefixed("Failed to query RabbitMQ configuration"))?;
+        // Only the last line is parsed, in case PHP prints notices before the result
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let result = stdout.lines().last().unwrap_or_default().trim();
+        serde_json::from_str::<bool>(result).map_err(|e| {
+            EnvironmentError::new(format!(
+                "Failed to parse RabbitMQ configuration: {}. Output was: {}",
+                e,
+                stdout.trim()
+            ))
+            .with_stderr(&output.stderr)
+        })
+    })?;
+    log::debug!("RabbitMQ configured: {}", configured);
+    Ok(configured)
+}
+
+/// The connection of every consumer, from the consumer configuration of Magento, which combines
+/// the `queue_consumer.xml` of all modules and the `queue` settings in `app/etc/env.php`. It's
+/// only detected on startup. Magento has to be bootstrapped for it, so when that fails, like
+/// when the generated code is outdated, it's logged and an empty map is returned.
+pub(crate) fn magento_consumer_connections(config: &DaemonConfig) -> HashMap<String, String> {
+    const CONSUMER_CONNECTIONS_QUERY: &str = r#"
+    require 'app/bootstrap.php';
+    $bootstrap = \Magento\Framework\App\Bootstrap::create(BP, $_SERVER);
+    $consumerConfig = $bootstrap->getObjectManager()
+        ->get(\Magento\Framework\MessageQueue\Consumer\ConfigInterface::class);
+    $connections = [];
+    foreach ($consumerConfig->getConsumers() as $consumer) {
+        $connections[$consumer->getName()] = $consumer->getConnection();
+    }
+    echo json_encode((object) $connections);
+    "#;
+
+    let result = run_php_query(config, CONSUMER_CONNECTIONS_QUERY).and_then(|output| {
+        // Only the last line is parsed, in case PHP prints notices before the result
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let result = stdout.lines().last().unwrap_or_default().trim();
+        serde_json::from_str::<HashMap<String, String>>(result).map_err(|e| {
+            EnvironmentError::new(format!("{}. Output was: {}", e, stdout.trim()))
+                .with_stderr(&output.stderr)
+        })
+    });
+    match result {
+        Ok(connections) => {
+            log::debug!("Consumer connections: {:?}", connections);
+            connections
+        }
+        Err(err) => {
+            log::warn!(
+                "Failed to detect the connections of the consumers, assuming {} require RabbitMQ: {}",
+                config.rabbitmq_consumers.join(", "),
+                err.message
+            );
+            if let Some(stderr) = err.stderr {
+                log::debug!("Error output:\n{}", stderr);
+            }
+            HashMap::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_the_consumers_with_include_and_exclude_patterns() {
+        let patterns = |x: &[&str]| -> Vec<String> { x.iter().map(|x| x.to_string()).collect() };
+        // Without include patterns every consumer is included
+        assert!(matches_patterns("exportProcessor", &[], &[]));
+
+        let include = patterns(&["export*", "?odegeneratorProcessor"]);
+        assert!(matches_patterns("exportProcessor", &include, &[]));
+        assert!(matches_patterns("codegeneratorProcessor", &include, &[]));
+        assert!(!matches_patterns("async.operations.all", &include, &[]));
+
+        // Exclude patterns take precedence over include patterns
+        let exclude = patterns(&["*Processor"]);
+        assert!(!matches_patterns(
+            "exportProcessor",
+            &patterns(&["export*"]),
+            &exclude
+        ));
+        let exclude = patterns(&["async.*"]);
+        assert!(!matches_patterns("async.operations.all", &[], &exclude));
+        assert!(matches_patterns("exportProcessor", &[], &exclude));
+    }
+
+    #[test]
+    fn parses_the_consumer_list_file() {
+        let consumer_list = ConsumerList::parse(
+            "# Incident 42\n\
+             product_action_attribute.*\n\
+             \n\
+             ! product_action_attribute.website.update  # Too slow\n",
+        )
+        .unwrap();
+        assert!(consumer_list.allows("product_action_attribute.update"));
+        assert!(!consumer_list.allows("product_action_attribute.website.update"));
+        assert!(!consumer_list.allows("async.operations.all"));
+
+        let consumer_list = ConsumerList::parse("!media.storage.catalog.image.resize\n").unwrap();
+        assert!(consumer_list.allows("async.operations.all"));
+        assert!(!consumer_list.allows("media.storage.catalog.image.resize"));
+
+        let err = ConsumerList::parse("first\nfirst second\n").unwrap_err();
+        assert_eq!(err, "invalid consumer \"first second\" on line 2");
+    }
+
+    #[test]
+    fn parses_the_configuration_from_the_environment() {
+        let parse = |vars: &[(&str, &str)]| {
+            let vars: HashMap<_, _> = vars.iter().copied().collect();
+            EnvConfig::from_vars(|name| vars.get(name).map(|x| x.to_string()))
+        };
+        let config = parse(&[
+            ("MWD_MAX_MESSAGES", "500"),
+            ("MWD_CONSUMERS", "first, second,"),
+            ("MWD_MULTIPLE_PROCESSES", "first=2,second = 3"),
+        ])
+        .unwrap();
+        assert_eq!(config.max_messages, Some(500));
+        assert_eq!(config.consumers, ["first", "second"]);
+        assert_eq!(
+            config.multiple_processes,
+            HashMap::from([("first".to_owned(), 2), ("second".to_owned(), 3)])
+        );
+        // Empty variables count as unset
+        assert_eq!(
+            parse(&[("MWD_MAX_MESSAGES", ""), ("MWD_CONSUMERS", " ")]).unwrap(),
+            EnvConfig::default()
+        );
+
+        let err = parse(&[("MWD_MAX_MESSAGES", "many")]).unwrap_err();
+        assert_eq!(
+            err.message,
+            "Invalid MWD_MAX_MESSAGES `many`: expected a number of messages"
+        );
+        let err = parse(&[("MWD_MULTIPLE_PROCESSES", "first=2,second")]).unwrap_err();
+        assert_eq!(
+            err.message,
+            "Invalid MWD_MULTIPLE_PROCESSES `first=2,second`: expected CONSUMER=N, got `second`"
+        );
+    }
+
+    #[test]
+    fn multiplies_the_backoff_delay_up_to_the_maximum() {
+        let backoff = Backoff {
+            initial_delay: Backoff::DEFAULT_INITIAL_DELAY,
+            multiplier: Backoff::DEFAULT_MULTIPLIER,
+            max_delay: Backoff::DEFAULT_MAX_DELAY,
+            min_healthy_runtime: Duration::from_secs(10),
+        };
+        let delays: Vec<_> = (1..=8).map(|n| backoff.delay(n).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+
+        let backoff = Backoff {
+            initial_delay: Duration::from_millis(500),
+            multiplier: 3.0,
+            max_delay: Duration::from_secs(10),
+            ..backoff
+        };
+        let delays: Vec<_> = (1..=5).map(|n| backoff.delay(n).as_secs_f64()).collect();
+        assert_eq!(delays, [0.5, 1.5, 4.5, 10.0, 10.0]);
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(10));
+    }
 }
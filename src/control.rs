@@ -0,0 +1,317 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::{
+        net::{UnixListener, UnixStream},
+        process::ExitStatusExt,
+    },
+    path::Path,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    util::signal_name,
+    worker::{ProcessCount, WorkerProcess},
+};
+
+/// A command sent over the control socket. Commands are either plain text lines like
+/// `restart async.operations.all`, or JSON objects like
+/// `{"command": "restart", "consumer": "async.operations.all"}`.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "command", content = "consumer", rename_all = "lowercase")]
+pub enum Command {
+    // Returns the status of every consumer
+    Status,
+    // Restarts the consumer, or starts it when it was stopped
+    Restart(String),
+    // Stops the consumer until it's restarted
+    Stop(String),
+    // Stops the processes of the consumer, or of all consumers, until it's resumed
+    Pause(String),
+    Resume(String),
+    // Refreshes the consumer list
+    Reload,
+    // Restarts all consumers one at a time, for example after a deployment
+    #[serde(rename = "restart-all")]
+    RestartAll,
+    // Stops restarting the consumers, and shuts down once their processes exited
+    Drain,
+}
+
+/// A command and the channel to send its response to.
+pub struct ControlRequest {
+    pub command: Command,
+    pub reply: Sender<Response>,
+}
+
+/// The response to a command, sent back as a single JSON line.
+#[derive(Debug, Serialize)]
+pub struct Response {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    consumers: Option<Vec<ConsumerStatus>>,
+}
+
+/// What a consumer is doing, as reported in its status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsumerState {
+    // The processes are running, or being restarted
+    Running,
+    // Restarting is delayed with a backoff, after the consumer crashed repeatedly
+    BackingOff,
+    // Starting the consumer failed, and is retried with a backoff
+    Retrying,
+    // The consumer drained its queue with --idle-shutdown
+    Drained,
+    // All processes exited and are left down because of the restart policy
+    Down,
+    // Stopped through the control socket
+    Stopped,
+    // Paused through the control socket
+    Paused,
+    // The daemon drains, and the processes still run until they exit on their own
+    Draining,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConsumerStatus {
+    // The label of the Magento installation, when supervising multiple installations
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+    consumer: String,
+    // The number of processes to run
+    processes: usize,
+    // The multiple_processes value, and why it isn't the number of processes to run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    configured_processes: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    processes_reason: Option<String>,
+    // The number and the PIDs of the running processes
+    running: usize,
+    pids: Vec<u32>,
+    // The processes left running with --stuck-worker-action leave
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stuck_pids: Vec<u32>,
+    state: ConsumerState,
+    restarts: u64,
+    // The number of processes recycled for exceeding the memory, CPU time or lifetime limit, or
+    // for stalling
+    recycles: u64,
+    // The messages the processes reported to have processed in their output
+    processed_messages: u64,
+    // The seconds since the consumer was last (re)started
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uptime_secs: Option<u64>,
+    // The exit code or signal name of the last process that exited unexpectedly
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_exit_signal: Option<String>,
+    stopped: bool,
+}
+
+impl Response {
+    pub fn success() -> Self {
+        Self {
+            ok: true,
+            error: None,
+            consumers: None,
+        }
+    }
+
+    pub fn error(message: String) -> Self {
+        Self {
+            ok: false,
+            error: Some(message),
+            consumers: None,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.ok
+    }
+
+    pub fn status(consumers: Vec<ConsumerStatus>) -> Self {
+        Self {
+            ok: true,
+            error: None,
+            consumers: Some(consumers),
+        }
+    }
+}
+
+impl ConsumerStatus {
+    pub fn new(instance: Option<&str>, worker: &mut WorkerProcess, count: ProcessCount) -> Self {
+        let pids = worker.running_pids();
+        let state = if worker.draining() {
+            if pids.is_empty() {
+                ConsumerState::Down
+            } else {
+                ConsumerState::Draining
+            }
+        } else if worker.spawn_failures() > 0 {
+            ConsumerState::Retrying
+        } else if worker.backing_off() {
+            ConsumerState::BackingOff
+        } else if worker.left_down_count() > 0 && worker.left_down_count() == worker.process_count()
+        {
+            ConsumerState::Down
+        } else if worker.drained_at().is_some() {
+            ConsumerState::Drained
+        } else {
+            ConsumerState::Running
+        };
+        Self {
+            instance: instance.map(|x| x.to_owned()),
+            consumer: worker.consumer().to_owned(),
+            processes: worker.process_count(),
+            configured_processes: count.configured,
+            processes_reason: count.reason,
+            running: pids.len(),
+            pids,
+            stuck_pids: worker.stuck_pids(),
+            state,
+            restarts: worker.restart_count(),
+            recycles: worker.recycle_count(),
+            processed_messages: worker.processed_messages(),
+            uptime_secs: Some(worker.uptime().as_secs()),
+            last_exit_code: worker.last_exit().and_then(|s| s.code()),
+            last_exit_signal: worker.last_exit().and_then(|s| s.signal()).map(|signal| {
+                signal_name(signal)
+                    .map(|name| name.to_owned())
+                    .unwrap_or_else(|| signal.to_string())
+            }),
+            stopped: false,
+        }
+    }
+
+    pub fn paused(instance: Option<&str>, worker: &WorkerProcess, count: ProcessCount) -> Self {
+        Self {
+            instance: instance.map(|x| x.to_owned()),
+            consumer: worker.consumer().to_owned(),
+            processes: 0,
+            configured_processes: count.configured,
+            processes_reason: count.reason,
+            running: 0,
+            pids: Vec::new(),
+            stuck_pids: worker.stuck_pids(),
+            state: ConsumerState::Paused,
+            restarts: worker.restart_count(),
+            recycles: worker.recycle_count(),
+            processed_messages: worker.processed_messages(),
+            uptime_secs: None,
+            last_exit_code: None,
+            last_exit_signal: None,
+            stopped: false,
+        }
+    }
+
+    pub fn stopped(instance: Option<&str>, consumer: &str) -> Self {
+        Self {
+            instance: instance.map(|x| x.to_owned()),
+            consumer: consumer.to_owned(),
+            processes: 0,
+            configured_processes: None,
+            processes_reason: None,
+            running: 0,
+            pids: Vec::new(),
+            stuck_pids: Vec::new(),
+            state: ConsumerState::Stopped,
+            restarts: 0,
+            recycles: 0,
+            processed_messages: 0,
+            uptime_secs: None,
+            last_exit_code: None,
+            last_exit_signal: None,
+            stopped: true,
+        }
+    }
+}
+
+pub fn parse_command(line: &str) -> Result<Command, String> {
+    let line = line.trim();
+    if line.starts_with('{') {
+        return serde_json::from_str(line).map_err(|e| format!("Invalid command: {}", e));
+    }
+
+    let mut parts = line.split_whitespace();
+    let command = match (parts.next(), parts.next()) {
+        (Some("status"), None) => Command::Status,
+        (Some("reload"), None) => Command::Reload,
+        (Some("restart-all"), None) => Command::RestartAll,
+        (Some("drain"), None) => Command::Drain,
+        (Some("restart"), Some(consumer)) => Command::Restart(consumer.to_owned()),
+        (Some("stop"), Some(consumer)) => Command::Stop(consumer.to_owned()),
+        (Some("pause"), Some(consumer)) => Command::Pause(consumer.to_owned()),
+        (Some("resume"), Some(consumer)) => Command::Resume(consumer.to_owned()),
+        (Some("restart" | "stop" | "pause" | "resume"), None) => {
+            return Err("Missing consumer name".to_owned())
+        }
+        _ => return Err(format!("Unknown command: {}", line)),
+    };
+    if parts.next().is_some() {
+        return Err(format!("Too many arguments: {}", line));
+    }
+    Ok(command)
+}
+
+/// Opens the control socket and serves it on a separate thread. The received commands are passed
+/// to the returned receiver, which is handled by the supervisor.
+pub fn listen(path: &Path) -> std::io::Result<Receiver<ControlRequest>> {
+    // Remove a stale socket of a previous run
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let (sender, receiver) = mpsc::channel();
+
+    thread::Builder::new()
+        .name("control socket".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(err) = handle_connection(stream, &sender) {
+                            log::debug!("Control socket connection failed: {}", err);
+                        }
+                    }
+                    Err(err) => log::error!("Failed to accept control socket connection: {}", err),
+                }
+            }
+        })?;
+
+    Ok(receiver)
+}
+
+fn handle_connection(stream: UnixStream, sender: &Sender<ControlRequest>) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match parse_command(&line) {
+            Ok(command) => {
+                log::debug!("Received control command: {:?}", command);
+                let (reply, response) = mpsc::channel();
+                if sender.send(ControlRequest { command, reply }).is_err() {
+                    // The supervisor has stopped
+                    return Ok(());
+                }
+                response
+                    .recv()
+                    .unwrap_or_else(|_| Response::error("Daemon is shutting down".to_owned()))
+            }
+            Err(message) => Response::error(message),
+        };
+        let mut response = serde_json::to_vec(&response)?;
+        response.push(b'\n');
+        writer.write_all(&response)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,116 @@
+//! Runs the daemon in the background with `--daemonize`, for init systems without a process
+//! supervisor. The process forks twice and detaches from the terminal with `setsid`, and the
+//! original process waits until the daemon reports that it started the consumers, so its exit
+//! code tells whether the startup succeeded.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    path::Path,
+};
+
+use crate::util::set_close_on_exec;
+
+/// The process running in the background, which tells the waiting parent when it started.
+pub struct Daemonized {
+    // The write end of the pipe the parent waits on, until the daemon started
+    ready: Option<File>,
+}
+
+/// Forks into the background, where the standard input and output are redirected to `/dev/null`.
+/// The error output is kept until the daemon started, so startup errors are still shown when
+/// logging to stderr. Only returns in the daemonized process, as the original one exits once the
+/// daemon is `ready`, with code 0, or when it exits before, with code 1. Has to be called before
+/// any thread is started, as only the calling thread is forked.
+pub fn daemonize() -> std::io::Result<Daemonized> {
+    let mut fds: [RawFd; 2] = [0; 2];
+    // SAFETY: pipe writes two descriptors to the array, which are owned by the files right after
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let (mut read_end, write_end) =
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    // The consumers shouldn't keep the pipe open, so the parent sees the daemon exit
+    set_close_on_exec(write_end.as_raw_fd(), true)?;
+
+    // SAFETY: no other threads are running, so the child can't inherit a lock held by one
+    match unsafe { libc::fork() } {
+        -1 => return Err(std::io::Error::last_os_error()),
+        0 => {}
+        child => {
+            drop(write_end);
+            let mut status = 0;
+            // SAFETY: waitpid only writes to the status integer
+            unsafe { libc::waitpid(child, &mut status, 0) };
+            let mut byte = [0; 1];
+            let code = match read_end.read(&mut byte) {
+                Ok(1) => 0,
+                _ => 1,
+            };
+            std::process::exit(code);
+        }
+    }
+    drop(read_end);
+    // SAFETY: setsid has no preconditions
+    if unsafe { libc::setsid() } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // Forked again, so the daemon isn't the session leader and never gets a controlling terminal
+    match unsafe { libc::fork() } {
+        -1 => return Err(std::io::Error::last_os_error()),
+        0 => {}
+        // SAFETY: _exit doesn't run the exit handlers the parent shares with the daemon
+        _ => unsafe { libc::_exit(0) },
+    }
+    redirect_to_null(libc::STDIN_FILENO, false)?;
+    redirect_to_null(libc::STDOUT_FILENO, true)?;
+    Ok(Daemonized {
+        ready: Some(write_end),
+    })
+}
+
+impl Daemonized {
+    /// Tells the waiting parent that the daemon started, after which the error output is
+    /// redirected to `/dev/null` too, as the parent's terminal may be gone. Log to a file with
+    /// `--log-file` to keep the log of the daemon.
+    pub fn ready(&mut self) {
+        if let Some(mut ready) = self.ready.take() {
+            if let Err(err) = ready.write_all(b"1") {
+                log::warn!(
+                    "Failed to report the startup to the parent process: {}",
+                    err
+                );
+            }
+            if let Err(err) = redirect_to_null(libc::STDERR_FILENO, true) {
+                log::warn!("Failed to redirect the error output: {}", err);
+            }
+        }
+    }
+}
+
+fn redirect_to_null(fd: RawFd, write: bool) -> std::io::Result<()> {
+    let null = OpenOptions::new()
+        .read(!write)
+        .write(write)
+        .open("/dev/null")?;
+    // SAFETY: dup2 replaces the descriptor, which refers to the same file afterwards
+    if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Writes the PID of the daemon to `path`, for init scripts.
+pub fn write_pid_file(path: &Path) -> std::io::Result<()> {
+    std::fs::write(path, format!("{}\n", std::process::id()))
+}
+
+/// Removes the PID file when the daemon stops, unless another process replaced it in the meantime.
+pub fn remove_pid_file(path: &Path) {
+    let own =
+        std::fs::read_to_string(path).is_ok_and(|x| x.trim() == std::process::id().to_string());
+    if own {
+        let _ = std::fs::remove_file(path);
+    }
+}
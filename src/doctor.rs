@@ -0,0 +1,282 @@
+//! The `doctor` command, which checks the environment the daemon runs in, and reports every
+//! problem with a hint to fix it, instead of stopping at the first one like the startup does.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::{
+    config::{
+        magento_consumer_connections, magento_has_rabbitmq_configured, ConsumerList, DaemonConfig,
+        DaemonContext, EnvironmentError, MagentoConsumerConfig, RabbitMqDetection,
+    },
+    input::{Args as InputArgs, RabbitMqOnUnknown},
+    worker,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// The result of a single check.
+struct Check {
+    status: Status,
+    name: &'static str,
+    detail: String,
+    hint: Option<&'static str>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            status: Status::Pass,
+            name,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(name: &'static str, detail: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            status: Status::Warn,
+            name,
+            detail: detail.into(),
+            hint: Some(hint),
+        }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, hint: &'static str) -> Self {
+        Self {
+            status: Status::Fail,
+            name,
+            detail: detail.into(),
+            hint: Some(hint),
+        }
+    }
+
+    fn print(&self) {
+        let status = match self.status {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        };
+        println!("[{}] {}: {}", status, self.name, self.detail);
+        if let Some(hint) = self.hint {
+            println!("       hint: {}", hint);
+        }
+    }
+}
+
+/// Checks every Magento installation given with `--working-directory`, or the current directory,
+/// and prints the report. Returns whether none of the checks failed.
+pub fn run(args: &InputArgs) -> bool {
+    let instances: Vec<(Option<&Path>, Option<String>)> = match args.working_directory.len() {
+        0 => vec![(None, None)],
+        1 => vec![(Some(args.working_directory[0].path.as_path()), None)],
+        _ => args
+            .working_directory
+            .iter()
+            .map(|i| {
+                let label = i
+                    .label
+                    .clone()
+                    .unwrap_or_else(|| i.path.display().to_string());
+                (Some(i.path.as_path()), Some(label))
+            })
+            .collect(),
+    };
+    let mut success = true;
+    for (i, (path, label)) in instances.into_iter().enumerate() {
+        if let Some(ref label) = label {
+            if i > 0 {
+                println!();
+            }
+            println!("{}", label);
+        }
+        for check in checks(args, path, label) {
+            check.print();
+            success &= check.status != Status::Fail;
+        }
+    }
+    success
+}
+
+/// Runs the checks of a single installation. Checks that depend on a failed one are left out.
+fn checks(args: &InputArgs, path: Option<&Path>, label: Option<String>) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let config = match DaemonConfig::resolve(args, path, label) {
+        Ok(config) => config,
+        Err(err) => {
+            checks.push(Check::fail(
+                "Magento directory",
+                err.message,
+                "Run the daemon in the Magento root directory, or set it with --working-directory",
+            ));
+            return checks;
+        }
+    };
+    match config.validate() {
+        Ok(()) => checks.push(Check::pass("Magento directory", &config.magento_dir)),
+        Err(err) => {
+            checks.push(Check::fail(
+                "Magento directory",
+                err.message,
+                "The directory has to contain bin/magento, check --working-directory",
+            ));
+            return checks;
+        }
+    }
+    match config.php_version() {
+        Ok(version) => checks.push(Check::pass("PHP", version)),
+        Err(err) => {
+            checks.push(Check::fail(
+                "PHP",
+                describe(&err),
+                "Install PHP, add it to the PATH of the daemon, or set it with --php-binary",
+            ));
+            return checks;
+        }
+    }
+    let env_php = Path::new(&config.magento_dir).join("app/etc/env.php");
+    match fs::File::open(&env_php) {
+        Ok(_) => checks.push(Check::pass("app/etc/env.php", "readable")),
+        Err(err) => {
+            checks.push(Check::fail(
+                "app/etc/env.php",
+                format!("{}: {}", env_php.display(), err),
+                "Install Magento with bin/magento setup:install, or run the daemon as a user that can read the file, see --run-as-user",
+            ));
+            return checks;
+        }
+    }
+
+    let consumer_config = match MagentoConsumerConfig::query(&config) {
+        Ok(consumer_config) => consumer_config,
+        Err(err) => {
+            checks.push(Check::fail(
+                "cron_consumers_runner",
+                describe(&err),
+                "Check app/etc/env.php for errors, for example with php -l app/etc/env.php",
+            ));
+            return checks;
+        }
+    };
+    if consumer_config.cron_run() {
+        checks.push(Check::fail(
+            "cron_consumers_runner",
+            "cron_run is enabled, so Magento cron starts the consumers too",
+            "Set cron_consumers_runner.cron_run to false in app/etc/env.php",
+        ));
+    } else {
+        checks.push(Check::pass("cron_consumers_runner", "cron_run is disabled"));
+    }
+    for issue in consumer_config.consumer_issues(&config) {
+        checks.push(Check::warn(
+            "cron_consumers_runner",
+            issue.message,
+            "Fix the setting of the consumer, or use --skip-invalid to skip it",
+        ));
+    }
+
+    let mut config = config;
+    match magento_has_rabbitmq_configured(&config) {
+        Ok(configured) => {
+            config.rabbitmq_configured = configured;
+            config.rabbitmq_detection = if configured {
+                RabbitMqDetection::Configured
+            } else {
+                RabbitMqDetection::NotConfigured
+            };
+            checks.push(Check::pass(
+                "RabbitMQ",
+                if configured {
+                    "configured in app/etc/env.php"
+                } else {
+                    "not configured, the consumers with an amqp connection are skipped"
+                },
+            ));
+        }
+        Err(err) => {
+            config.rabbitmq_configured = args.rabbitmq_on_unknown == RabbitMqOnUnknown::Include;
+            checks.push(Check::warn(
+                "RabbitMQ",
+                describe(&err),
+                "Check app/etc/env.php for errors, for example with php -l app/etc/env.php. Until then the consumers that require RabbitMQ are run according to --rabbitmq-on-unknown",
+            ))
+        }
+    }
+    config.consumer_connections = magento_consumer_connections(&config);
+    if config.consumer_connections.is_empty() {
+        checks.push(Check::warn(
+            "Consumer connections",
+            format!(
+                "not detected, assuming {} require RabbitMQ",
+                config.rabbitmq_consumers.join(", ")
+            ),
+            "Check that Magento bootstraps, for example after bin/magento setup:di:compile, or give the consumers that require RabbitMQ with --rabbitmq-consumer",
+        ));
+    } else {
+        checks.push(Check::pass(
+            "Consumer connections",
+            format!(
+                "detected for {} consumers",
+                config.consumer_connections.len()
+            ),
+        ));
+    }
+
+    let known = match worker::known_consumers(&config) {
+        Ok(known) => known,
+        Err(err) => {
+            checks.push(Check::fail(
+                "Consumer list",
+                describe(&err),
+                "Check the output of bin/magento queue:consumers:list, or raise --consumer-list-timeout",
+            ));
+            return checks;
+        }
+    };
+    checks.push(Check::pass(
+        "Consumer list",
+        format!("{} consumers found", known.len()),
+    ));
+    // An invalid consumer list file fails the startup, but isn't part of the environment
+    let consumer_list = match config.consumer_list_file {
+        Some(ref path) => ConsumerList::read(path).ok().flatten(),
+        None => None,
+    };
+    let context = DaemonContext {
+        daemon_config: config,
+        consumer_config,
+        consumer_list,
+        process_caps: HashMap::new(),
+        autoscaled: HashMap::new(),
+    };
+    let applicable = worker::filter_applicable(&context, known);
+    if applicable.is_empty() {
+        checks.push(Check::warn(
+            "Applicable consumers",
+            "none of the consumers would be run",
+            "Check the consumer filters, like --include, --exclude, MWD_CONSUMERS and cron_consumers_runner.consumers",
+        ));
+    } else {
+        checks.push(Check::pass(
+            "Applicable consumers",
+            format!("{} consumers would be run", applicable.len()),
+        ));
+    }
+    checks
+}
+
+/// The error message, with the first line of the error output of PHP, if any.
+fn describe(err: &EnvironmentError) -> String {
+    match err
+        .stderr
+        .as_deref()
+        .and_then(|x| x.lines().find(|l| !l.trim().is_empty()))
+    {
+        Some(line) => format!("{} ({})", err.message, line.trim()),
+        None => err.message.clone(),
+    }
+}
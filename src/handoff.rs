@@ -0,0 +1,130 @@
+//! Upgrades the daemon in place without stopping the consumers. On `SIGUSR2` the daemon executes
+//! its binary again, typically a new version installed at the same path, and hands off the
+//! running consumer processes to it. As `exec` keeps the PID, the consumers are still children
+//! of the new daemon, which adopts them instead of starting them again.
+//!
+//! The processes are handed off in the `MAGENTO2_WORKER_DAEMON_HANDOFF` environment variable, a
+//! JSON list of `HandedOffProcess`. The pipes of their output are kept open across `exec`, and
+//! the new daemon forwards the output from the file descriptors in the list.
+
+use std::{
+    os::unix::{io::RawFd, process::CommandExt},
+    path::Path,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::util::{kill_process_group, set_close_on_exec, terminate_process_child, try_wait_child};
+
+/// The environment variable in which the processes are handed off.
+pub const HANDOFF_VAR: &str = "MAGENTO2_WORKER_DAEMON_HANDOFF";
+const POLL_RESOLUTION: Duration = Duration::from_millis(20);
+
+/// A running consumer process handed off to the new daemon.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HandedOffProcess {
+    /// The Magento directory of the consumer, which tells the installations apart.
+    pub magento_dir: String,
+    pub consumer: String,
+    /// The process index, passed to `--multi-process`.
+    pub index: u32,
+    pub pid: u32,
+    /// The seconds since the process was started, so `--max-lifetime` keeps counting.
+    pub uptime: u64,
+    /// The read ends of the pipes of the process output, when they're forwarded.
+    pub stdout: Option<RawFd>,
+    pub stderr: Option<RawFd>,
+}
+
+impl HandedOffProcess {
+    fn fds(&self) -> impl Iterator<Item = RawFd> {
+        self.stdout.into_iter().chain(self.stderr)
+    }
+}
+
+/// Takes the processes handed off by the daemon this one replaced. The environment variable is
+/// removed and the pipes are closed on `exec` again, so the consumers and PHP don't inherit them.
+/// Should be called before any process is started.
+pub fn take_handed_off() -> Vec<HandedOffProcess> {
+    let value = match std::env::var(HANDOFF_VAR) {
+        Ok(value) => value,
+        Err(_) => return Vec::new(),
+    };
+    std::env::remove_var(HANDOFF_VAR);
+    let processes: Vec<HandedOffProcess> = match serde_json::from_str(&value) {
+        Ok(processes) => processes,
+        Err(err) => {
+            log::error!("Ignoring the invalid {}: {}", HANDOFF_VAR, err);
+            return Vec::new();
+        }
+    };
+    for fd in processes.iter().flat_map(HandedOffProcess::fds) {
+        let _ = set_close_on_exec(fd, true);
+    }
+    processes
+}
+
+/// Executes `exe` with the arguments of the daemon, handing off the processes. Only returns when
+/// executing it failed, after which the daemon can keep supervising the processes.
+pub fn exec(exe: &Path, processes: &[HandedOffProcess]) -> std::io::Error {
+    let fds: Vec<RawFd> = processes.iter().flat_map(HandedOffProcess::fds).collect();
+    let mut result = Ok(());
+    for fd in fds.iter() {
+        result = result.and_then(|_| set_close_on_exec(*fd, false));
+    }
+    let err = match result {
+        Ok(()) => Command::new(exe)
+            .args(std::env::args_os().skip(1))
+            .env(HANDOFF_VAR, serde_json::to_string(processes).unwrap())
+            .exec(),
+        Err(err) => err,
+    };
+    for fd in fds {
+        let _ = set_close_on_exec(fd, true);
+    }
+    err
+}
+
+/// Stops handed-off processes that aren't adopted, like the consumers that are no longer
+/// applicable, or all of them when the new daemon fails to start. They get SIGTERM and `timeout`
+/// to exit, after which they're killed.
+pub fn stop(processes: &[HandedOffProcess], timeout: Duration) {
+    if processes.is_empty() {
+        return;
+    }
+    for p in processes.iter() {
+        // Nothing reads the output anymore, which would block the process once the pipe is full
+        for fd in p.fds() {
+            // SAFETY: the descriptor was handed off for this process and isn't used elsewhere
+            unsafe { libc::close(fd) };
+        }
+        log::info!(
+            "Stopping handed-off process {} of consumer {}",
+            p.pid,
+            p.consumer
+        );
+        if let Err(err) = terminate_process_child(p.pid) {
+            log::error!("Failed to SIGTERM process {}: {}", p.pid, err);
+        }
+    }
+    let mut remaining: Vec<u32> = processes.iter().map(|p| p.pid).collect();
+    let deadline = Instant::now() + timeout;
+    while !remaining.is_empty() && Instant::now() < deadline {
+        remaining.retain(|pid| matches!(try_wait_child(*pid), Ok(None)));
+        std::thread::sleep(POLL_RESOLUTION);
+    }
+    for pid in remaining.iter() {
+        log::warn!("Force killing handed-off process {}", pid);
+        if let Err(err) = kill_process_group(*pid) {
+            log::error!("Failed to kill process {}: {}", pid, err);
+        }
+    }
+    // Reaped, so they don't linger in the process table
+    let deadline = Instant::now() + timeout;
+    while !remaining.is_empty() && Instant::now() < deadline {
+        remaining.retain(|pid| matches!(try_wait_child(*pid), Ok(None)));
+        std::thread::sleep(POLL_RESOLUTION);
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// The supervision loop is considered stalled when it hasn't updated the status for this long
+const LIVENESS_TIMEOUT: Duration = Duration::from_secs(30);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The health of the daemon, updated by the supervision loop and served by the health server.
+pub struct Health {
+    created_at: Instant,
+    // The time of the last update by the supervision loop, in milliseconds since `created_at`
+    last_update: AtomicU64,
+    // Set once all consumers are started and the supervision loop is running
+    ready: AtomicBool,
+    running_processes: AtomicUsize,
+    expected_processes: AtomicUsize,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            created_at: Instant::now(),
+            last_update: AtomicU64::new(0),
+            ready: AtomicBool::new(false),
+            running_processes: AtomicUsize::new(0),
+            expected_processes: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Health {
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+
+    /// Records that the supervision loop is alive, with the number of running processes and the
+    /// number of processes that should be running.
+    pub fn update(&self, running_processes: usize, expected_processes: usize) {
+        self.running_processes
+            .store(running_processes, Ordering::Relaxed);
+        self.expected_processes
+            .store(expected_processes, Ordering::Relaxed);
+        self.last_update.store(
+            self.created_at.elapsed().as_millis() as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    /// Whether the supervision loop is alive and all expected processes are running. While the
+    /// consumers are being started, the daemon is considered healthy.
+    fn is_healthy(&self) -> bool {
+        if !self.is_ready() {
+            return true;
+        }
+        let last_update = Duration::from_millis(self.last_update.load(Ordering::Relaxed));
+        let alive = self.created_at.elapsed().saturating_sub(last_update) < LIVENESS_TIMEOUT;
+        alive
+            && self.running_processes.load(Ordering::Relaxed)
+                >= self.expected_processes.load(Ordering::Relaxed)
+    }
+}
+
+/// Serves the `/healthz` and `/readyz` endpoints on a separate thread.
+pub fn serve(addr: &str, health: Arc<Health>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    thread::Builder::new()
+        .name("health server".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(err) = handle_connection(stream, &health) {
+                            log::debug!("Health check connection failed: {}", err);
+                        }
+                    }
+                    Err(err) => log::error!("Failed to accept health check connection: {}", err),
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn handle_connection(stream: TcpStream, health: &Health) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let mut request_line = String::new();
+    BufReader::new(stream).read_line(&mut request_line)?;
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/healthz")) if health.is_healthy() => ("200 OK", "ok"),
+        (Some("GET"), Some("/healthz")) => ("503 Service Unavailable", "unhealthy"),
+        (Some("GET"), Some("/readyz")) if health.is_ready() => ("200 OK", "ready"),
+        (Some("GET"), Some("/readyz")) => ("503 Service Unavailable", "not ready"),
+        (Some("GET"), _) => ("404 Not Found", "not found"),
+        _ => ("405 Method Not Allowed", "method not allowed"),
+    };
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        status,
+        body.len() + 1,
+        body
+    )
+}
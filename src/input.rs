@@ -1,12 +1,731 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Whether the consumers that require RabbitMQ are run when it can't be detected whether RabbitMQ
+/// is configured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum RabbitMqOnUnknown {
+    Include,
+    Exclude,
+}
+
+/// What to do with a consumer process that is still running after it was asked to stop, with
+/// SIGTERM and the grace period or the steps of `--kill-sequence`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StuckWorkerAction {
+    /// Kill it with SIGKILL
+    Kill,
+    /// Log an error and leave it running for manual handling
+    Leave,
+    /// Run the --on-crash hook, then kill it
+    Alert,
+}
+
+/// What the daemon does when it receives a signal, see `--signal`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SignalAction {
+    /// Stop the consumers and exit
+    Terminate,
+    /// Log the status of every consumer
+    Status,
+    /// Log the diagnostic dump
+    Dump,
+    /// Upgrade the daemon in place
+    Upgrade,
+    /// Refresh the consumer list and configuration
+    Reload,
+    /// Restart all consumers one at a time
+    RestartAll,
+    /// Stop restarting the consumers, and exit once they exited on their own
+    Drain,
+    /// Do nothing
+    Ignore,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Subcommand)]
+pub enum Command {
+    /// Print the consumers found in Magento, their configuration and whether they are run
+    ListConsumers,
+    /// Check the Magento installation, PHP and the configuration, and report the problems
+    Doctor,
+}
+
+/// A Magento installation to supervise, given as `PATH` or `LABEL=PATH`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instance {
+    pub label: Option<String>,
+    pub path: std::path::PathBuf,
+}
+
+/// A list of CPU numbers, given like `0-3,6`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuList(pub Vec<usize>);
+
+/// A step of `--kill-sequence`: the signal to send to a consumer process, and how long to wait
+/// for it to exit before the next step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KillStep {
+    pub signal: i32,
+    pub wait: std::time::Duration,
+}
+
+/// The steps to stop a consumer process with, given like `TERM:5,INT:3`. It's killed after the
+/// last step.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KillSequence(pub Vec<KillStep>);
 
 #[derive(Parser, Debug)]
-#[command(author, about, version)]
+#[command(author, about, version = env!("LONG_VERSION"))]
 pub struct Args {
-    #[arg(short, long, help = "Enable verbose logging", default_value_t = false)]
-    pub verbose: bool,
-    #[arg(short, long, help = "Magento 2 working directory")]
-    pub working_directory: Option<std::path::PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[arg(
+        short,
+        long,
+        action = clap::ArgAction::Count,
+        help = "Enable verbose logging, -vv to also log trace messages"
+    )]
+    pub verbose: u8,
+    #[arg(
+        short,
+        long,
+        conflicts_with = "verbose",
+        help = "Only log warnings and errors, like crashes and restarts",
+        default_value_t = false
+    )]
+    pub quiet: bool,
+    #[arg(long, help = "Log output format", value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+    #[arg(
+        long,
+        value_name = "FILTER",
+        help = "Log levels per module like `magento2_worker_daemon::worker=debug,info`, overrides RUST_LOG"
+    )]
+    pub log_level: Option<String>,
+    #[arg(long, value_name = "PATH", help = "Log to this file instead of stderr")]
+    pub log_file: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        value_name = "MB",
+        help = "Rotate the log file when it exceeds this size, 0 to never rotate it",
+        default_value_t = 100
+    )]
+    pub log_max_size: u64,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Number of rotated log files to keep",
+        default_value_t = 5
+    )]
+    pub log_max_files: u32,
+    #[cfg(feature = "otlp")]
+    #[arg(
+        long,
+        value_name = "URL",
+        value_parser = crate::otlp::Endpoint::parse,
+        help = "Export the consumer lifecycle as OpenTelemetry traces to this OTLP/HTTP collector, like http://localhost:4318"
+    )]
+    pub otlp_endpoint: Option<crate::otlp::Endpoint>,
+    #[arg(
+        short,
+        long,
+        value_name = "[LABEL=]PATH",
+        value_parser = parse_instance,
+        help = "Magento 2 working directory, can be repeated to supervise multiple installations"
+    )]
+    pub working_directory: Vec<Instance>,
+    #[arg(
+        long,
+        value_name = "MS",
+        help = "Delay in milliseconds between starting consumers",
+        default_value_t = 0
+    )]
+    pub startup_stagger: u64,
+    #[arg(
+        long,
+        help = "Print the consumer commands that would be started and exit",
+        default_value_t = false
+    )]
+    pub dry_run: bool,
+    #[arg(
+        long,
+        help = "Print the resolved configuration as JSON and exit",
+        default_value_t = false
+    )]
+    pub print_config: bool,
+    #[arg(
+        long,
+        help = "Check that the daemon would start, validating the configuration and reading the consumers from Magento, and exit without starting them",
+        default_value_t = false
+    )]
+    pub config_check: bool,
+    #[arg(
+        long,
+        help = "Run every consumer once and exit when all of them are done",
+        default_value_t = false
+    )]
+    pub once: bool,
+    #[arg(
+        long,
+        help = "Run in the background, exiting once the consumers are started",
+        default_value_t = false
+    )]
+    pub daemonize: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Write the PID of the daemon to this file once the consumers are started"
+    )]
+    pub pid_file: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        help = "Keep running when no applicable consumers are found",
+        default_value_t = false
+    )]
+    pub allow_empty: bool,
+    #[arg(
+        long,
+        help = "Raise the soft limit of open files to the hard limit at startup, for many consumer processes",
+        default_value_t = false
+    )]
+    pub raise_fd_limit: bool,
+    #[arg(
+        long,
+        help = "Stop consumer processes in the Magento directory that aren't run by a daemon, like ones left behind by a killed daemon, before starting the consumers (Linux only)",
+        default_value_t = false
+    )]
+    pub reclaim_orphans: bool,
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        help = "Only run consumers matching the glob pattern, can be repeated"
+    )]
+    pub include: Vec<String>,
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        help = "Don't run consumers matching the glob pattern, can be repeated"
+    )]
+    pub exclude: Vec<String>,
+    #[arg(
+        long,
+        value_name = "CONSUMER",
+        conflicts_with_all = ["include", "exclude"],
+        help = "Run exactly this consumer instead of the consumers found in Magento, can be repeated"
+    )]
+    pub consumer: Vec<String>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Messages a consumer processes before it's restarted, 0 for unlimited, instead of max_messages in app/etc/env.php [env: MWD_MAX_MESSAGES]"
+    )]
+    pub max_messages: Option<u32>,
+    #[arg(
+        long,
+        value_name = "CONSUMER=N",
+        value_parser = parse_processes,
+        help = "Number of processes of the consumer instead of multiple_processes in app/etc/env.php, can be repeated [env: MWD_MULTIPLE_PROCESSES]"
+    )]
+    pub processes: Vec<(String, u32)>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "File with the consumers to run, or not to run when prefixed with !, one per line. Changes are applied while running"
+    )]
+    pub consumer_list_file: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        value_name = "CONSUMER",
+        help = "Consumer that requires RabbitMQ when its connection can't be detected, skipped when RabbitMQ is not configured, can be repeated"
+    )]
+    pub rabbitmq_consumer: Vec<String>,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "ACTION",
+        default_value = "include",
+        help = "Whether to run the consumers that require RabbitMQ when the detection of RabbitMQ fails"
+    )]
+    pub rabbitmq_on_unknown: RabbitMqOnUnknown,
+    #[arg(
+        long,
+        value_name = "MB",
+        help = "Recycle consumer processes using more memory than this (Linux only)"
+    )]
+    pub max_memory: Option<u64>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Recycle consumer processes running longer than this"
+    )]
+    pub max_lifetime: Option<u64>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Restart consumer processes that used no CPU time for this long while messages are waiting (Linux only)"
+    )]
+    pub stall_timeout: Option<u64>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Recycle consumer processes that used more CPU time than this (Linux only)"
+    )]
+    pub max_cpu_seconds: Option<u64>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "Time consumers get to finish their current message on shutdown before they are killed",
+        default_value_t = 10
+    )]
+    pub shutdown_timeout: u64,
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "Time a consumer process has to run before exiting doesn't count as a crash; repeated crashes are restarted with a backoff",
+        default_value_t = 10
+    )]
+    pub min_healthy_runtime: u64,
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "Time after starting a consumer process during which exiting restarts it without counting as a crash or backing off",
+        default_value_t = 0
+    )]
+    pub startup_grace: u64,
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Exit when all consumers have drained their queue and exited successfully for this long, instead of restarting them"
+    )]
+    pub idle_shutdown: Option<u64>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "Interval for refreshing the consumer list and configuration to start new, stop removed and scale changed consumers, 0 to disable",
+        default_value_t = 300
+    )]
+    pub consumer_refresh_interval: u64,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Consecutive refresh failures after which the last known consumers keep running and the refresh backs off, 0 to never back off",
+        default_value_t = 3
+    )]
+    pub refresh_failure_threshold: u32,
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Exit when the refresh failed this many times in a row and none of the consumers is running"
+    )]
+    pub exit_after_refresh_failures: Option<u32>,
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        value_parser = clap::value_parser!(u32).range(1..=100),
+        help = "Log an error when fewer than this percentage of the consumer processes is running for --min-running-grace seconds"
+    )]
+    pub min_running_percent: Option<u32>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "How long the running processes have to stay below --min-running-percent before it's reported",
+        default_value_t = 60
+    )]
+    pub min_running_grace: u64,
+    #[arg(
+        long,
+        help = "Exit when the running processes stayed below --min-running-percent, so a process manager can replace the daemon",
+        default_value_t = false
+    )]
+    pub exit_when_degraded: bool,
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "Timeout for listing the consumers with bin/magento",
+        default_value_t = 60
+    )]
+    pub consumer_list_timeout: u64,
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "Timeout for the PHP queries of the Magento configuration, which hang when for example the database is unreachable",
+        default_value_t = 30
+    )]
+    pub startup_timeout: u64,
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Log the status of every consumer as a single JSON line at this interval"
+    )]
+    pub status_interval: Option<u64>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Path of a Unix socket to open for controlling the daemon"
+    )]
+    pub control_socket: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        value_name = "HOST:PORT",
+        help = "Address to serve the /healthz and /readyz health check endpoints on"
+    )]
+    pub health_addr: Option<String>,
+    #[arg(
+        long = "env",
+        value_name = "KEY=VALUE",
+        value_parser = parse_key_value,
+        help = "Environment variable to set for the consumers, can be repeated"
+    )]
+    pub env: Vec<(String, String)>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "PHP binary to run Magento with [default: php]"
+    )]
+    pub php_binary: Option<String>,
+    #[arg(
+        long,
+        value_name = "ARG",
+        allow_hyphen_values = true,
+        help = "Argument to pass to PHP, like `-d memory_limit=2G`, can be repeated"
+    )]
+    pub php_arg: Vec<String>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Maximum number of processes per consumer in multiple_processes",
+        default_value_t = 32
+    )]
+    pub max_processes_per_consumer: u32,
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Maximum number of processes of all consumers together, scaling the consumers down proportionally when multiple_processes adds up to more"
+    )]
+    pub max_total_processes: Option<u32>,
+    #[arg(
+        long,
+        help = "Scale the number of processes of every consumer to the messages waiting in its queue, between its autoscale min_processes and max_processes",
+        default_value_t = false
+    )]
+    pub autoscale: bool,
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Interval for querying the queue backlogs with --autoscale",
+        default_value_t = 60
+    )]
+    pub autoscale_interval: u64,
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Waiting messages per process with --autoscale, unless the consumer sets autoscale messages_per_process",
+        default_value_t = 100
+    )]
+    pub autoscale_messages_per_process: u64,
+    #[arg(
+        long,
+        help = "Skip the consumers with an invalid configuration, like a too large multiple_processes value, and run the others",
+        default_value_t = false
+    )]
+    pub skip_invalid: bool,
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        help = "Shell command to run when a consumer process exits unsuccessfully"
+    )]
+    pub on_crash: Option<String>,
+    #[arg(
+        long,
+        value_name = "ARG",
+        allow_hyphen_values = true,
+        help = "Argument to append to queue:consumers:start, like `--batch-size=100`, can be repeated"
+    )]
+    pub consumer_arg: Vec<String>,
+    #[arg(
+        long,
+        help = "Don't pass --single-thread or --multi-process to the consumers",
+        default_value_t = false
+    )]
+    pub no_strict_mode: bool,
+    #[arg(
+        long,
+        help = "Stop when cron_consumers_runner.cron_run gets enabled while running, so consumers aren't also started by Magento cron",
+        default_value_t = false
+    )]
+    pub exit_on_cron_run: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "Maximum number of consumer restarts per minute across all consumers, including the recycled processes, 0 for no limit",
+        default_value_t = 60
+    )]
+    pub max_restarts_per_minute: u32,
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        value_parser = clap::value_parser!(u32).range(0..=100),
+        help = "Report a restart storm instead of the single crashes when this percentage of the consumers crashes within --storm-window, 0 to disable",
+        default_value_t = 50
+    )]
+    pub storm_threshold: u32,
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Window in which the crashes of the consumers count towards a restart storm",
+        default_value_t = 10
+    )]
+    pub storm_window: u64,
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Stop after processing about this many messages across all consumers, counting the max messages of every consumer process that exited successfully"
+    )]
+    pub max_total_messages: Option<u64>,
+    #[arg(
+        long,
+        value_name = "PATTERN",
+        value_parser = parse_processed_pattern,
+        help = "Pattern of the output line in which a consumer reports the messages it processed, with {n} in place of the number and * matching anything, like '*Processed {n} messages*'. Defaults to any line mentioning messages with a number next to \"processed\""
+    )]
+    pub processed_pattern: Option<String>,
+    #[arg(
+        long,
+        value_name = "LINES",
+        help = "Maximum number of output lines forwarded per second per consumer, 0 for no limit. The lines above it are dropped, and reported in a summary",
+        default_value_t = 0
+    )]
+    pub output_rate_limit: u32,
+    #[arg(
+        long,
+        help = "Only forward the error output of the consumers to the log, and discard their standard output"
+    )]
+    pub forward_stderr_only: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        allow_negative_numbers = true,
+        value_parser = clap::value_parser!(i32).range(-20..=19),
+        help = "Nice value of the consumer processes, to run them at a lower scheduling priority"
+    )]
+    pub nice: Option<i32>,
+    #[arg(
+        long,
+        value_name = "CPUS",
+        value_parser = parse_cpu_list,
+        help = "Pin the consumer processes to these CPUs, like 0-3,6 (Linux only)"
+    )]
+    pub cpu_affinity: Option<CpuList>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Place the processes of every consumer in a cgroup below this cgroup v2 directory, like /sys/fs/cgroup/magento-workers, created when missing. The daemon needs write access to it (Linux only)"
+    )]
+    pub cgroup_parent: Option<std::path::PathBuf>,
+    #[arg(
+        long,
+        requires = "cgroup_parent",
+        help = "Place the processes of all consumers in a single cgroup below --cgroup-parent, instead of one per consumer"
+    )]
+    pub shared_cgroup: bool,
+    #[arg(
+        long,
+        value_name = "CPUS",
+        requires = "cgroup_parent",
+        value_parser = parse_cpu_max,
+        help = "Maximum number of CPUs the processes in a cgroup of --cgroup-parent use together, like 0.5, enforced by the kernel"
+    )]
+    pub worker_cpu_max: Option<f64>,
+    #[arg(
+        long,
+        value_name = "MB",
+        requires = "cgroup_parent",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Maximum memory in MB the processes in a cgroup of --cgroup-parent use together, above which the kernel kills them"
+    )]
+    pub worker_memory_max: Option<u64>,
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Run the consumers and the other Magento commands as this user, when the daemon runs as root (Unix only)"
+    )]
+    pub run_as_user: Option<String>,
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Run the consumers and the other Magento commands as this group, instead of the primary group of --run-as-user (Unix only)"
+    )]
+    pub run_as_group: Option<String>,
+    #[arg(
+        long,
+        value_name = "STEPS",
+        value_parser = parse_kill_sequence,
+        help = "Signals to stop the consumer processes with and the seconds to wait after each, like TERM:5,INT:3, after which they're killed. Replaces SIGTERM with the grace period, or --shutdown-timeout on shutdown"
+    )]
+    pub kill_sequence: Option<KillSequence>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = StuckWorkerAction::Kill,
+        help = "What to do with a consumer process that didn't exit after the grace period or --kill-sequence"
+    )]
+    pub stuck_worker_action: StuckWorkerAction,
+    #[arg(
+        long = "signal",
+        value_name = "SIGNAL=ACTION",
+        value_parser = parse_signal_action,
+        help = "Map a signal to terminate, status, dump, upgrade, reload, restart-all, drain or ignore, like INT=ignore or HUP=reload, replacing its default action. Can be given multiple times"
+    )]
+    pub signals: Vec<(i32, SignalAction)>,
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_owned(), value.to_owned())),
+        _ => Err(format!("expected KEY=VALUE, got `{}`", s)),
+    }
+}
+
+/// Parses a `consumer=n` pair of `--processes` and `MWD_MULTIPLE_PROCESSES`.
+pub(crate) fn parse_processes(s: &str) -> Result<(String, u32), String> {
+    match s.split_once('=') {
+        Some((consumer, processes)) if !consumer.trim().is_empty() => {
+            match processes.trim().parse() {
+                Ok(processes) => Ok((consumer.trim().to_owned(), processes)),
+                Err(_) => Err(format!(
+                    "expected a number of processes, got `{}`",
+                    processes
+                )),
+            }
+        }
+        _ => Err(format!("expected CONSUMER=N, got `{}`", s)),
+    }
+}
+
+fn parse_processed_pattern(s: &str) -> Result<String, String> {
+    match s.matches("{n}").count() {
+        1 => Ok(s.to_owned()),
+        _ => Err(format!("expected one {{n}} in the pattern, got `{}`", s)),
+    }
+}
+
+fn parse_signal_action(s: &str) -> Result<(i32, SignalAction), String> {
+    let (name, action) = match s.split_once('=') {
+        Some((name, action)) => (name.trim(), action.trim()),
+        None => return Err(format!("expected SIGNAL=ACTION, got `{}`", s)),
+    };
+    let signal = match crate::util::signal_number(name) {
+        Some(signal) if signal_hook::consts::FORBIDDEN.contains(&signal) => {
+            return Err(format!("{} can't be handled", name))
+        }
+        Some(signal) => signal,
+        None => return Err(format!("unknown signal `{}`", name)),
+    };
+    Ok((signal, SignalAction::from_str(action, true)?))
+}
+
+fn parse_cpu_list(s: &str) -> Result<CpuList, String> {
+    // The size of the CPU set of sched_setaffinity
+    const MAX_CPUS: usize = 1024;
+    let parse_cpu = |cpu: &str| match cpu.trim().parse::<usize>() {
+        Ok(cpu) if cpu < MAX_CPUS => Ok(cpu),
+        Ok(_) => Err(format!("CPU numbers must be below {}", MAX_CPUS)),
+        Err(_) => Err(format!("expected a list of CPUs like 0-3,6, got `{}`", s)),
+    };
+    let mut cpus = Vec::new();
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => cpus.extend(parse_cpu(first)?..=parse_cpu(last)?),
+            None => cpus.push(parse_cpu(part)?),
+        }
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(CpuList(cpus))
+}
+
+fn parse_cpu_max(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        // The quota of cpu.max can't be below 1ms per 100ms period
+        Ok(cpus) if cpus.is_finite() && cpus >= 0.01 => Ok(cpus),
+        Ok(_) => Err("the CPUs must be at least 0.01".to_owned()),
+        Err(_) => Err(format!("expected a number of CPUs like 0.5, got `{}`", s)),
+    }
+}
+
+fn parse_kill_sequence(s: &str) -> Result<KillSequence, String> {
+    let mut steps = Vec::new();
+    for step in s.split(',') {
+        let (name, wait) = match step.split_once(':') {
+            Some((name, wait)) => (name.trim(), wait.trim()),
+            None => return Err(format!("expected SIGNAL:SECS, got `{}`", step)),
+        };
+        let signal = match crate::util::signal_number(name) {
+            Some(libc::SIGKILL) => {
+                return Err("SIGKILL is always sent after the last step".to_owned())
+            }
+            Some(signal) => signal,
+            None => return Err(format!("unknown signal `{}`", name)),
+        };
+        let wait = match wait.parse::<f64>() {
+            Ok(wait) if wait.is_finite() && wait >= 0.0 => std::time::Duration::from_secs_f64(wait),
+            _ => return Err(format!("expected a number of seconds, got `{}`", wait)),
+        };
+        steps.push(KillStep { signal, wait });
+    }
+    Ok(KillSequence(steps))
+}
+
+fn parse_instance(s: &str) -> Result<Instance, String> {
+    // A label can't contain a slash, so paths containing `=` can still be given as they are.
+    match s.split_once('=') {
+        Some((label, path)) if !label.is_empty() && !label.contains('/') => {
+            if path.is_empty() {
+                return Err(format!("expected LABEL=PATH, got `{}`", s));
+            }
+            Ok(Instance {
+                label: Some(label.to_owned()),
+                path: path.into(),
+            })
+        }
+        _ => Ok(Instance {
+            label: None,
+            path: s.into(),
+        }),
+    }
+}
+
+impl Args {
+    /// The default log level, set by `--verbose` and `--quiet`.
+    pub fn log_level_filter(&self) -> log::LevelFilter {
+        if self.quiet {
+            return log::LevelFilter::Warn;
+        }
+        match self.verbose {
+            0 => log::LevelFilter::Info,
+            1 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        }
+    }
 }
 
 pub fn parse_args() -> Args {
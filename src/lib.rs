@@ -0,0 +1,21 @@
+//! Supervises the queue consumers of Magento 2 installations. The `magento2-worker-daemon`
+//! binary is a thin wrapper around `Daemon`, which can also be embedded in other services.
+
+pub mod cgroup;
+pub mod config;
+pub mod control;
+pub mod daemonize;
+pub mod doctor;
+pub mod handoff;
+pub mod health;
+pub mod input;
+pub mod logging;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod signals;
+pub mod supervisor;
+pub mod util;
+pub mod worker;
+
+pub use config::{DaemonContext, EnvironmentError};
+pub use supervisor::Daemon;
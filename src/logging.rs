@@ -0,0 +1,486 @@
+use std::{
+    cell::RefCell,
+    fmt::{self, Write as _},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::Mutex,
+};
+
+use serde::Serialize;
+
+use crate::util::signal_name;
+use time::{
+    format_description::{well_known::Rfc3339, FormatItem},
+    OffsetDateTime,
+};
+
+thread_local! {
+    // The instance, consumer and process the current thread is working for, added to the log
+    // records
+    static CONTEXT: RefCell<LogContext> = RefCell::new(LogContext::default());
+    // The lifecycle event of the record being logged, see `log_event!`
+    static EVENT: RefCell<Option<Event>> = const { RefCell::new(None) };
+}
+
+#[derive(Clone, Debug, Default)]
+struct LogContext {
+    instance: Option<String>,
+    consumer: Option<String>,
+    pid: Option<u32>,
+}
+
+impl LogContext {
+    fn new(instance: Option<&str>, consumer: &str, pid: Option<u32>) -> Self {
+        Self {
+            instance: instance.map(|x| x.to_owned()),
+            consumer: Some(consumer.to_owned()),
+            pid,
+        }
+    }
+
+    /// The context of the record being logged, of which the process of the event takes
+    /// precedence over the process of the thread.
+    fn current() -> Self {
+        let mut context = CONTEXT.with(|c| c.borrow().clone());
+        if let Some(pid) = EVENT.with(|e| e.borrow().as_ref().and_then(|e| e.pid)) {
+            context.pid = Some(pid);
+        }
+        context
+    }
+
+    /// The fields appended to a text record, like ` consumer=async.operations.all pid=1234`.
+    fn text_fields(&self) -> String {
+        let mut fields = String::new();
+        if let Some(ref instance) = self.instance {
+            let _ = write!(fields, " instance={}", instance);
+        }
+        if let Some(ref consumer) = self.consumer {
+            let _ = write!(fields, " consumer={}", consumer);
+        }
+        if let Some(pid) = self.pid {
+            let _ = write!(fields, " pid={}", pid);
+        }
+        fields
+    }
+}
+
+/// Sets the consumer, and optionally the Magento installation and the process, the current
+/// thread is working for. Every record logged from this thread afterwards carries them as
+/// structured fields.
+pub fn set_context(instance: Option<&str>, consumer: &str, pid: Option<u32>) {
+    CONTEXT.with(|c| *c.borrow_mut() = LogContext::new(instance, consumer, pid));
+}
+
+/// Sets the context like `set_context` until the guard is dropped, for the work done for a single
+/// consumer on a thread that works for all of them, like the main thread.
+#[must_use]
+pub fn enter_context(instance: Option<&str>, consumer: &str, pid: Option<u32>) -> ContextGuard {
+    let previous = CONTEXT.with(|c| c.replace(LogContext::new(instance, consumer, pid)));
+    ContextGuard { previous }
+}
+
+/// Restores the previous context of the thread when it's dropped, see `enter_context`.
+pub struct ContextGuard {
+    previous: LogContext,
+}
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        let previous = std::mem::take(&mut self.previous);
+        CONTEXT.with(|c| *c.borrow_mut() = previous);
+    }
+}
+
+/// Log levels per module, parsed from a `RUST_LOG` style directive like
+/// `magento2_worker_daemon::worker=debug,info`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogFilter {
+    pub default_level: log::LevelFilter,
+    // The module levels, with the most specific modules first
+    pub module_levels: Vec<(String, log::LevelFilter)>,
+}
+
+impl LogFilter {
+    pub fn new(default_level: log::LevelFilter) -> Self {
+        Self {
+            default_level,
+            module_levels: Vec::new(),
+        }
+    }
+
+    /// Applies the comma separated `module=level` and `level` directives, where a bare level sets
+    /// the default level.
+    pub fn parse(mut self, directives: &str) -> Result<Self, String> {
+        for directive in directives
+            .split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+        {
+            let (module, level) = match directive.split_once('=') {
+                Some((module, level)) => (Some(module.trim()), level.trim()),
+                None => (None, directive),
+            };
+            let level: log::LevelFilter = level
+                .parse()
+                .map_err(|_| format!("invalid log level `{}` in `{}`", level, directive))?;
+            match module {
+                Some(module) => self.module_levels.push((module.to_owned(), level)),
+                None => self.default_level = level,
+            }
+        }
+        // Like simple_logger, match the longest module first
+        self.module_levels
+            .sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Ok(self)
+    }
+
+    /// The highest level any module logs at.
+    pub fn max_level(&self) -> log::LevelFilter {
+        self.module_levels
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default_level, std::cmp::max)
+    }
+
+    fn level_for(&self, target: &str) -> log::LevelFilter {
+        self.module_levels
+            .iter()
+            .find(|(module, _)| target.starts_with(module.as_str()))
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default_level)
+    }
+}
+
+/// A consumer lifecycle event, like a process being started or exiting. Its fields are added to
+/// the JSON log records, so the lifecycle of a flapping consumer can be followed as a timeline.
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    pub(crate) event: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) pid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) signal: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) restarts: Option<u64>,
+}
+
+impl Event {
+    pub fn new(event: &'static str) -> Self {
+        Self {
+            event,
+            pid: None,
+            exit_code: None,
+            signal: None,
+            restarts: None,
+        }
+    }
+
+    pub fn pid(mut self, pid: u32) -> Self {
+        self.pid = Some(pid);
+        self
+    }
+
+    pub fn exit_status(mut self, status: ExitStatus) -> Self {
+        use std::os::unix::process::ExitStatusExt;
+        self.exit_code = status.code();
+        self.signal = status.signal().map(|signal| {
+            signal_name(signal)
+                .map(|name| name.to_owned())
+                .unwrap_or_else(|| signal.to_string())
+        });
+        self
+    }
+
+    pub fn restarts(mut self, restarts: u64) -> Self {
+        self.restarts = Some(restarts);
+        self
+    }
+}
+
+/// Runs `f`, which logs a record with the message, with the event attached to the record. With
+/// `--otlp-endpoint` the event of a consumer is exported too, regardless of the log level.
+pub fn with_event(event: Event, message: fmt::Arguments, f: impl FnOnce(fmt::Arguments)) {
+    #[cfg(feature = "otlp")]
+    CONTEXT.with(|c| {
+        let context = c.borrow();
+        if let Some(ref consumer) = context.consumer {
+            crate::otlp::record(context.instance.as_deref(), consumer, &event, message);
+        }
+    });
+    EVENT.with(|e| *e.borrow_mut() = Some(event));
+    f(message);
+    EVENT.with(|e| *e.borrow_mut() = None);
+}
+
+/// Logs a record like `log::log!`, with the fields of the lifecycle event.
+macro_rules! log_event {
+    ($level:expr, $event:expr, $($arg:tt)+) => {
+        $crate::logging::with_event($event, format_args!($($arg)+), |message| {
+            log::log!($level, "{}", message)
+        })
+    };
+}
+pub(crate) use log_event;
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: &'a str,
+    target: &'a str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    consumer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pid: Option<u32>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    event: Option<Event>,
+}
+
+/// A log file that is rotated when it exceeds its maximum size. The rotated files are numbered,
+/// like `daemon.log.1` for the most recent one, and only the configured number of them is kept.
+pub struct LogFile {
+    path: PathBuf,
+    // The size after which the file is rotated in bytes, zero to never rotate it
+    max_size: u64,
+    // The number of rotated files to keep
+    max_files: u32,
+    state: Mutex<LogFileState>,
+}
+
+struct LogFileState {
+    file: File,
+    size: u64,
+}
+
+impl LogFile {
+    pub fn open(path: &Path, max_size: u64, max_files: u32) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            max_files,
+            state: Mutex::new(LogFileState { file, size }),
+        })
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    /// Writes the line at once, so records of different threads don't interleave.
+    fn write_line(&self, line: &[u8]) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if self.max_size > 0 && state.size > 0 && state.size + line.len() as u64 > self.max_size {
+            match self.rotate() {
+                Ok(file) => {
+                    state.file = file;
+                    state.size = 0;
+                }
+                // Keep writing to the current file, rather than losing the records
+                Err(err) => eprintln!("Failed to rotate log file {}: {}", self.path.display(), err),
+            }
+        }
+        if state.file.write_all(line).is_ok() {
+            state.size += line.len() as u64;
+        }
+    }
+
+    /// Shifts the rotated files, dropping the oldest one, and returns the reopened log file.
+    fn rotate(&self) -> io::Result<File> {
+        if self.max_files == 0 {
+            return File::create(&self.path);
+        }
+        let _ = fs::remove_file(self.rotated_path(self.max_files));
+        for index in (1..self.max_files).rev() {
+            let path = self.rotated_path(index);
+            if path.exists() {
+                fs::rename(&path, self.rotated_path(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))?;
+        File::create(&self.path)
+    }
+
+    fn flush(&self) {
+        let _ = self
+            .state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .file
+            .flush();
+    }
+}
+
+/// Where the log records are written to.
+pub enum LogOutput {
+    Stderr,
+    File(LogFile),
+}
+
+impl LogOutput {
+    fn write_line(&self, line: &[u8]) {
+        match self {
+            // Write the line at once, so records of different threads don't interleave.
+            LogOutput::Stderr => {
+                let _ = std::io::stderr().lock().write_all(line);
+            }
+            LogOutput::File(file) => file.write_line(line),
+        }
+    }
+
+    fn flush(&self) {
+        match self {
+            LogOutput::Stderr => {
+                let _ = std::io::stderr().flush();
+            }
+            LogOutput::File(file) => file.flush(),
+        }
+    }
+}
+
+/// Logs every record as a single line JSON object.
+struct JsonLogger {
+    filter: LogFilter,
+    output: LogOutput,
+}
+
+impl log::Log for JsonLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.filter.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let context = LogContext::current();
+        let event = EVENT.with(|e| e.borrow().clone());
+        let record = JsonRecord {
+            timestamp: OffsetDateTime::now_utc()
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            level: record.level().as_str(),
+            target: record.target(),
+            message: record.args().to_string(),
+            instance: context.instance,
+            consumer: context.consumer,
+            pid: context.pid,
+            event: event.map(|event| Event { pid: None, ..event }),
+        };
+        if let Ok(mut line) = serde_json::to_vec(&record) {
+            line.push(b'\n');
+            self.output.write_line(&line);
+        }
+    }
+
+    fn flush(&self) {
+        self.output.flush();
+    }
+}
+
+/// Logs every record as a line of text to a log file, in the format of `simple_logger`, with the
+/// context of the thread appended as `key=value` fields.
+struct TextLogger {
+    filter: LogFilter,
+    output: LogOutput,
+    timestamp_format: Vec<FormatItem<'static>>,
+}
+
+impl log::Log for TextLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.filter.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!(
+            "{} {:<5} [{}] {}{}\n",
+            OffsetDateTime::now_utc()
+                .format(&self.timestamp_format)
+                .unwrap_or_default(),
+            record.level().to_string(),
+            record.target(),
+            record.args(),
+            LogContext::current().text_fields()
+        );
+        self.output.write_line(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        self.output.flush();
+    }
+}
+
+/// Logs every record to stderr with `simple_logger`, with the context of the thread appended as
+/// `key=value` fields like `TextLogger`.
+struct ContextLogger {
+    inner: simple_logger::SimpleLogger,
+}
+
+impl log::Log for ContextLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        let fields = LogContext::current().text_fields();
+        if fields.is_empty() {
+            return self.inner.log(record);
+        }
+        self.inner.log(
+            &log::Record::builder()
+                .args(format_args!("{}{}", record.args(), fields))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+pub fn init_json(filter: LogFilter, output: LogOutput) -> Result<(), log::SetLoggerError> {
+    let max_level = filter.max_level();
+    log::set_boxed_logger(Box::new(JsonLogger { filter, output }))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+pub fn init_text(filter: LogFilter, output: LogOutput) -> Result<(), log::SetLoggerError> {
+    if let LogOutput::File(_) = output {
+        let max_level = filter.max_level();
+        let timestamp_format = time::format_description::parse(
+            "[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:3]Z",
+        )
+        .expect("Invalid timestamp format");
+        log::set_boxed_logger(Box::new(TextLogger {
+            filter,
+            output,
+            timestamp_format,
+        }))?;
+        log::set_max_level(max_level);
+        return Ok(());
+    }
+    let inner = filter.module_levels.iter().fold(
+        simple_logger::SimpleLogger::new().with_level(filter.default_level),
+        |logger, (module, level)| logger.with_module_level(module, *level),
+    );
+    log::set_boxed_logger(Box::new(ContextLogger { inner }))?;
+    log::set_max_level(filter.max_level());
+    Ok(())
+}
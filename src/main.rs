@@ -1,69 +1,415 @@
-mod config;
-mod input;
-mod util;
-mod worker;
-
-use std::{
-    sync::{atomic::AtomicBool, Arc},
-    thread,
-    time::Duration,
+use std::{sync::Arc, thread, time::Duration};
+
+use magento2_worker_daemon::{
+    config, control,
+    daemonize::{self, Daemonized},
+    doctor,
+    handoff::{self, HandedOffProcess},
+    health,
+    input::{self, Args as InputArgs, Command as InputCommand, LogFormat},
+    logging,
+    signals::{self, Signals},
+    supervisor::{Daemon, TICK_INTERVAL},
+    util, worker,
 };
 
-use signal_hook::consts::TERM_SIGNALS;
+#[cfg(feature = "otlp")]
+use magento2_worker_daemon::otlp;
 
-use input::Args as InputArgs;
+fn configure_logging(args: &InputArgs) {
+    let level = args.log_level_filter();
+    let directives = args
+        .log_level
+        .clone()
+        .or_else(|| std::env::var("RUST_LOG").ok())
+        .unwrap_or_default();
+    let filter = logging::LogFilter::new(level)
+        .parse(&directives)
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid log level filter: {}", e);
+            std::process::exit(2);
+        });
+    let output = match args.log_file {
+        Some(ref path) => logging::LogFile::open(
+            path,
+            args.log_max_size * util::BYTES_PER_MB,
+            args.log_max_files,
+        )
+        .map(logging::LogOutput::File)
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to open log file {}: {}", path.display(), e);
+            std::process::exit(1);
+        }),
+        None => logging::LogOutput::Stderr,
+    };
+    match args.log_format {
+        LogFormat::Text => logging::init_text(filter, output).unwrap(),
+        LogFormat::Json => logging::init_json(filter, output).unwrap(),
+    }
+}
 
-use crate::worker::WorkerProcess;
+fn exit_with_error(error: config::EnvironmentError) -> ! {
+    log::error!("{}", error.message);
+    if let Some(stderr) = error.stderr {
+        log::error!("Error output:\n{}", stderr);
+    }
+    std::process::exit(1);
+}
 
-fn configure_logging(args: &InputArgs) {
-    if args.verbose {
-        simple_logger::init_with_level(log::Level::Debug).unwrap();
-    } else {
-        simple_logger::init_with_level(log::Level::Info).unwrap();
+fn print_dry_run(context: &config::DaemonContext, consumers: &[String]) {
+    let magento_command_line = context.daemon_config.magento_command_line().join(" ");
+    for consumer in consumers {
+        let number_of_processes = worker::number_of_processes(context, consumer);
+        println!(
+            "{} ({} processes)",
+            context.daemon_config.qualified_name(consumer),
+            number_of_processes
+        );
+        for i in 0..number_of_processes {
+            let args = worker::worker_command_args(context, consumer, i);
+            println!("  {} {}", magento_command_line, args.join(" "));
+        }
+    }
+}
+
+/// The context for `--print-config`, with the numbers of processes of the applicable consumers as
+/// configured and as run. Without the consumer list, only the configured consumers are included.
+fn printed_config(mut context: config::DaemonContext) -> serde_json::Value {
+    let mut applicable = match worker::known_consumers(&context.daemon_config) {
+        Ok(consumers) => worker::filter_applicable(&context, consumers),
+        Err(e) => {
+            log::warn!("{}", e.message);
+            Vec::new()
+        }
+    };
+    worker::cap_processes(&mut context, &mut applicable);
+    let processes = worker::process_counts(&context, &applicable);
+    let mut value = serde_json::to_value(&context).unwrap();
+    value["processes"] = serde_json::to_value(processes).unwrap();
+    value
+}
+
+fn print_consumer_list(instances: &[(config::DaemonContext, Vec<String>)]) {
+    let rows: Vec<[String; 6]> = instances
+        .iter()
+        .flat_map(|(context, consumers)| consumers.iter().map(move |c| (context, c)))
+        .map(|(context, consumer)| {
+            let config = &context.daemon_config;
+            let connection = match config.consumer_connections.get(consumer) {
+                Some(connection) => connection.clone(),
+                None => "unknown".to_owned(),
+            };
+            let amqp = config.is_amqp_consumer(consumer);
+            let status = match worker::skip_reason(context, consumer) {
+                Some(reason) => format!("skipped: {}", reason),
+                None if worker::number_of_processes(context, consumer) == 0 => {
+                    "skipped: exceeds --max-total-processes".to_owned()
+                }
+                None => "run".to_owned(),
+            };
+            [
+                config.qualified_name(consumer),
+                connection,
+                if amqp { "yes" } else { "no" }.to_owned(),
+                worker::number_of_processes(context, consumer).to_string(),
+                match context.consumer_config.max_messages_for(consumer) {
+                    0 => "unlimited".to_owned(),
+                    max_messages => max_messages.to_string(),
+                },
+                status,
+            ]
+        })
+        .collect();
+
+    let header = [
+        "CONSUMER",
+        "CONNECTION",
+        "AMQP",
+        "PROCESSES",
+        "MAX MESSAGES",
+        "STATUS",
+    ]
+    .map(String::from);
+    let mut widths = header.clone().map(|x| x.len());
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
     }
 }
 
+/// Stops the processes handed off by the daemon this one replaced, when the startup failed or was
+/// interrupted, so they're not left running unsupervised.
+fn stop_handed_off(args: &InputArgs, handed_off: &[HandedOffProcess]) {
+    handoff::stop(handed_off, Duration::from_secs(args.shutdown_timeout));
+}
+
+/// Writes the PID file once the consumers are started, and lets the parent of a daemonized
+/// process exit.
+fn started(args: &InputArgs, daemonized: Option<&mut Daemonized>) {
+    if let Some(path) = args.pid_file.as_ref() {
+        if let Err(e) = daemonize::write_pid_file(path) {
+            log::error!("Failed to write the PID file {}: {}", path.display(), e);
+        }
+    }
+    if let Some(daemonized) = daemonized {
+        daemonized.ready();
+    }
+}
+
+/// Exports the spans of the consumers with `--otlp-endpoint` once they're stopped, before the
+/// daemon exits.
+fn export_remaining_spans() {
+    #[cfg(feature = "otlp")]
+    otlp::shutdown(Duration::from_secs(5));
+}
+
+/// Whether a termination signal was received while starting up.
+fn startup_interrupted(signals: &Signals) -> bool {
+    let interrupted = signals.is_terminating();
+    if interrupted {
+        log::info!("Received a termination signal during startup, stopping");
+    }
+    interrupted
+}
+
 fn main() {
     let args = input::parse_args();
     configure_logging(&args);
+    let signal_actions = signals::signal_actions(&args.signals).unwrap_or_else(|e| {
+        log::error!("Invalid --signal: {}", e);
+        std::process::exit(2);
+    });
+    if args.command == Some(InputCommand::Doctor) {
+        std::process::exit(if doctor::run(&args) { 0 } else { 1 });
+    }
+    if args.raise_fd_limit {
+        match util::raise_fd_limit() {
+            Ok((previous, limit)) if previous < limit => {
+                log::info!("Raised the open file limit from {} to {}", previous, limit)
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Failed to raise the open file limit: {}", e),
+        }
+    }
 
-    let context = config::DaemonContext::new(&args).unwrap_or_else(|e| {
-        log::error!("{}", e.message);
-        std::process::exit(1);
+    if cfg!(not(target_os = "linux")) && args.max_cpu_seconds.is_some() {
+        log::warn!("--max-cpu-seconds is only supported on Linux, ignoring it");
+    }
+
+    // Forked before any thread is started. An upgraded daemon runs in the background already.
+    let mut daemonized = if args.daemonize
+        && args.command.is_none()
+        && !args.print_config
+        && !args.dry_run
+        && !args.config_check
+        && std::env::var_os(handoff::HANDOFF_VAR).is_none()
+    {
+        match daemonize::daemonize() {
+            Ok(daemonized) => Some(daemonized),
+            Err(e) => {
+                log::error!("Failed to run in the background: {}", e);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    // Registered before anything is started, so a termination signal during the startup doesn't
+    // kill the daemon and orphan the consumers that were already started.
+    let signals = Signals::register(&signal_actions).unwrap();
+    // Taken before any process is started, so they don't inherit the handed-off pipes
+    let handed_off = handoff::take_handed_off();
+    if !handed_off.is_empty() {
+        log::info!(
+            "Upgraded the daemon, adopting {} consumer processes",
+            handed_off.len()
+        );
+    }
+    // Resolved at the start, as the path of a running binary that was replaced isn't available
+    let exe = std::env::current_exe();
+
+    let contexts = config::DaemonContext::from_args(&args).unwrap_or_else(|e| {
+        stop_handed_off(&args, &handed_off);
+        exit_with_error(e)
     });
 
+    if args.print_config {
+        // A list, as multiple Magento installations can be supervised
+        let printed: Vec<_> = contexts.into_iter().map(printed_config).collect();
+        println!("{}", serde_json::to_string_pretty(&printed).unwrap());
+        return;
+    }
+
+    if args.command == Some(InputCommand::ListConsumers) {
+        let instances: Vec<_> = contexts
+            .into_iter()
+            .map(|mut context| {
+                let consumers = worker::known_consumers(&context.daemon_config)
+                    .unwrap_or_else(|e| exit_with_error(e));
+                let mut applicable = worker::filter_applicable(&context, consumers.clone());
+                worker::cap_processes(&mut context, &mut applicable);
+                (context, consumers)
+            })
+            .collect();
+        print_consumer_list(&instances);
+        return;
+    }
+
+    if startup_interrupted(&signals) {
+        stop_handed_off(&args, &handed_off);
+        return;
+    }
+
+    for context in contexts.iter() {
+        worker::warn_about_given_consumers(context);
+    }
     log::debug!("Fetching consumer list...");
-    let consumers = worker::read_consumer_list(&context.daemon_config);
-    let consumers = consumers
-        .iter()
-        .filter(|x| {
-            context.consumer_config.consumers.is_empty()
-                || context.consumer_config.consumers.contains(x)
+    let instances: Vec<_> = contexts
+        .into_iter()
+        .map(|mut context| {
+            let mut consumers = worker::applicable_consumers(&context).unwrap_or_else(|e| {
+                stop_handed_off(&args, &handed_off);
+                exit_with_error(e)
+            });
+            worker::cap_processes(&mut context, &mut consumers);
+            (context, consumers)
         })
-        .collect::<Vec<_>>();
-    log::info!("Found {} applicable consumers", consumers.len());
-
-    let mut processes: Vec<WorkerProcess> = consumers
-        .iter()
-        .map(|consumer| worker::run_worker(&context, consumer))
         .collect();
-    log::info!("Started {} consumers", processes.len());
+    let consumer_count: usize = instances.iter().map(|(_, c)| c.len()).sum();
+    log::info!("Found {} applicable consumers", consumer_count);
+    if consumer_count == 0 && !args.allow_empty {
+        log::error!(
+            "No applicable consumers found - check --working-directory and the consumer filters, or use --allow-empty"
+        );
+        stop_handed_off(&args, &handed_off);
+        std::process::exit(1);
+    }
 
-    let term = Arc::new(AtomicBool::new(false));
-    for sig in TERM_SIGNALS {
-        signal_hook::flag::register(*sig, Arc::clone(&term)).unwrap();
+    if startup_interrupted(&signals) {
+        stop_handed_off(&args, &handed_off);
+        return;
     }
 
-    while !term.load(std::sync::atomic::Ordering::Relaxed) {
-        // If any of the processes have exited, restart them
-        for process in &mut processes {
-            process.ensure_running(&context);
+    if args.dry_run {
+        for (context, consumers) in instances.iter() {
+            print_dry_run(context, consumers);
         }
-        thread::sleep(Duration::from_secs(2));
+        return;
+    }
+
+    if args.config_check {
+        println!(
+            "Configuration OK, {} applicable consumers would be started",
+            consumer_count
+        );
+        return;
+    }
+
+    // Started before the consumers, so readiness probes can tell the daemon is starting
+    let health = args.health_addr.as_ref().map(|addr| {
+        let health = Arc::new(health::Health::default());
+        health::serve(addr, Arc::clone(&health)).unwrap_or_else(|e| {
+            log::error!("Failed to serve health checks on {}: {}", addr, e);
+            std::process::exit(1);
+        });
+        health
+    });
+
+    #[cfg(feature = "otlp")]
+    if let Some(ref endpoint) = args.otlp_endpoint {
+        otlp::init(endpoint.clone()).unwrap_or_else(|e| {
+            log::error!("Failed to start exporting spans to {}: {}", endpoint, e);
+            std::process::exit(1);
+        });
     }
 
-    log::info!("Stopping {} consumers", processes.len());
-    for mut process in processes {
-        process.terminate();
+    let mut daemon = Daemon::new(instances, Arc::clone(&signals.term));
+    daemon.set_handed_off(handed_off);
+    if let Some(health) = health {
+        daemon.set_health(health);
+    }
+    if let Err(err) = daemon.start() {
+        daemon.shutdown();
+        export_remaining_spans();
+        exit_with_error(err);
+    }
+    if startup_interrupted(&signals) {
+        daemon.shutdown();
+        export_remaining_spans();
+        return;
+    }
+    log::info!("Started {} consumers", consumer_count);
+
+    if args.once {
+        started(&args, daemonized.as_mut());
+        let success = daemon.wait_for_completion();
+        daemon.shutdown();
+        export_remaining_spans();
+        if let Some(path) = args.pid_file.as_ref() {
+            daemonize::remove_pid_file(path);
+        }
+        if !success {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(path) = args.control_socket.as_ref() {
+        match control::listen(path) {
+            Ok(control) => daemon.set_control(control),
+            Err(e) => {
+                log::error!("Failed to open control socket {}: {}", path.display(), e);
+                daemon.shutdown();
+                export_remaining_spans();
+                std::process::exit(1);
+            }
+        }
+    }
+
+    started(&args, daemonized.as_mut());
+    daemon.supervise();
+    while daemon.tick() {
+        if signals::take(&signals.status) {
+            daemon.log_status();
+        }
+        if signals::take(&signals.dump) {
+            daemon.dump();
+        }
+        if signals::take(&signals.reload) {
+            daemon.reload();
+        }
+        if signals::take(&signals.restart_all) {
+            daemon.restart_all();
+        }
+        if signals::take(&signals.drain) {
+            daemon.drain();
+        }
+        if signals::take(&signals.upgrade) {
+            match exe {
+                Ok(ref exe) => daemon.upgrade(exe),
+                Err(ref err) => log::error!("Failed to upgrade the daemon: {}", err),
+            }
+        }
+        thread::sleep(TICK_INTERVAL);
+    }
+    let reason = daemon.shutdown();
+    export_remaining_spans();
+
+    if let Some(path) = args.control_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    if let Some(path) = args.pid_file.as_ref() {
+        daemonize::remove_pid_file(path);
     }
+    std::process::exit(reason.exit_code());
 }
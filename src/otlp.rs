@@ -0,0 +1,502 @@
+//! Exports the lifecycle of the consumers as OpenTelemetry traces over OTLP, see
+//! `--otlp-endpoint`. Every consumer gets a span covering the time it's supervised, with a child
+//! span per process from its start until it exits or is stopped. The lifecycle events logged with
+//! `log_event!`, like crashes and restarts, are added to the span of their process, or to the
+//! span of the consumer when they aren't about a single process.
+//!
+//! The spans are encoded as JSON and posted to `<endpoint>/v1/traces` by a separate thread, so a
+//! slow or unavailable collector doesn't hold up the supervision. Spans that fail to export are
+//! dropped. Only plain HTTP is supported, for a collector on the same host or network.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io::{BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Mutex, MutexGuard, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use crate::logging::Event;
+
+// The default port of OTLP over HTTP
+const DEFAULT_PORT: u16 = 4318;
+// The finished spans are exported at least this often, or once this many are waiting
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_BATCH: usize = 512;
+// The events kept per span, so the span of a consumer that keeps crashing doesn't grow unbounded
+const MAX_EVENTS: usize = 128;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// SPAN_KIND_INTERNAL and STATUS_CODE_ERROR of the OTLP protocol
+const SPAN_KIND_INTERNAL: u8 = 1;
+const STATUS_CODE_ERROR: u8 = 2;
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+// The instance and the name of a consumer
+type ConsumerKey = (Option<String>, String);
+
+/// The collector to export the spans to, parsed from an URL like `http://localhost:4318`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    // The host and port to connect to
+    pub authority: String,
+    // The path the spans are posted to
+    pub path: String,
+}
+
+impl Endpoint {
+    /// Parses the URL of the collector. Like `OTEL_EXPORTER_OTLP_ENDPOINT`, `/v1/traces` is
+    /// appended to its path, and the port defaults to 4318.
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None => {
+                return Err(format!(
+                    "expected an http:// URL like http://localhost:4318, got `{}`",
+                    url
+                ))
+            }
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+        if authority.is_empty() {
+            return Err(format!("missing the host in `{}`", url));
+        }
+        // The colons of an IPv6 address are within its brackets
+        let has_port = match authority.rsplit_once(':') {
+            Some((_, port)) => !port.ends_with(']'),
+            None => false,
+        };
+        let authority = match has_port {
+            true => authority.to_owned(),
+            false => format!("{}:{}", authority, DEFAULT_PORT),
+        };
+        Ok(Self {
+            authority,
+            path: format!("{}/v1/traces", path.trim_end_matches('/')),
+        })
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}{}", self.authority, self.path)
+    }
+}
+
+/// Starts exporting the spans to the collector. Until then, or when it's never called, recording
+/// the lifecycle events is skipped.
+pub fn init(endpoint: Endpoint) -> std::io::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    thread::Builder::new()
+        .name("otlp exporter".to_owned())
+        .spawn(move || export_spans(endpoint, receiver))?;
+    let _ = EXPORTER.set(Exporter {
+        consumers: Mutex::new(HashMap::new()),
+        sender,
+    });
+    Ok(())
+}
+
+/// Adds the lifecycle event of the consumer to the span of its process or of the consumer, which
+/// is started when it doesn't exist yet. A spawned process starts the span of the process, and
+/// its exit ends it.
+pub(crate) fn record(
+    instance: Option<&str>,
+    consumer: &str,
+    event: &Event,
+    message: fmt::Arguments,
+) {
+    let exporter = match EXPORTER.get() {
+        Some(exporter) => exporter,
+        None => return,
+    };
+    let mut consumers = exporter.lock_consumers();
+    let key = (instance.map(|x| x.to_owned()), consumer.to_owned());
+    let consumer_span = consumers
+        .entry(key)
+        .or_insert_with(|| ConsumerSpan::new(instance, consumer));
+    let message = message.to_string();
+    let span_event = SpanEvent::new(event, &message);
+    let pid = match event.pid {
+        Some(pid) => pid,
+        None => return consumer_span.span.add_event(span_event),
+    };
+    if matches!(event.event, "spawn" | "adopt") {
+        let mut span = Span::new(
+            "process",
+            &consumer_span.span.trace_id,
+            Some(&consumer_span.span.span_id),
+        );
+        span.attributes = consumer_span.span.attributes.clone();
+        span.attributes.push(KeyValue::int("pid", pid as i64));
+        consumer_span.processes.insert(pid, span);
+    }
+    let span = match consumer_span.processes.get_mut(&pid) {
+        Some(span) => span,
+        None => return consumer_span.span.add_event(span_event),
+    };
+    let crashed = event.event == "exit" && (event.exit_code != Some(0) || event.signal.is_some());
+    if crashed {
+        span.status = Some(Status {
+            code: STATUS_CODE_ERROR,
+            message,
+        });
+    }
+    span.add_event(span_event);
+    if matches!(event.event, "exit" | "stopped") {
+        if let Some(span) = consumer_span.processes.remove(&pid) {
+            exporter.send(vec![span.end()]);
+        }
+    }
+}
+
+/// Ends the span of the consumer, and the spans of its processes, when it's no longer supervised.
+pub(crate) fn end_consumer(instance: Option<&str>, consumer: &str) {
+    let exporter = match EXPORTER.get() {
+        Some(exporter) => exporter,
+        None => return,
+    };
+    let key = (instance.map(|x| x.to_owned()), consumer.to_owned());
+    let consumer_span = exporter.lock_consumers().remove(&key);
+    if let Some(consumer_span) = consumer_span {
+        consumer_span.end(exporter);
+    }
+}
+
+/// Ends the spans of all consumers, and waits until `timeout` for the spans to be exported, for
+/// when the daemon exits.
+pub fn shutdown(timeout: Duration) {
+    let exporter = match EXPORTER.get() {
+        Some(exporter) => exporter,
+        None => return,
+    };
+    let consumers: Vec<_> = exporter.lock_consumers().drain().collect();
+    for (_, consumer_span) in consumers {
+        consumer_span.end(exporter);
+    }
+    let (done, exported) = mpsc::channel();
+    exporter.send_message(Message::Flush(done));
+    let _ = exported.recv_timeout(timeout);
+}
+
+struct Exporter {
+    // The spans of the supervised consumers, by instance and consumer
+    consumers: Mutex<HashMap<ConsumerKey, ConsumerSpan>>,
+    sender: mpsc::Sender<Message>,
+}
+
+impl Exporter {
+    fn lock_consumers(&self) -> MutexGuard<'_, HashMap<ConsumerKey, ConsumerSpan>> {
+        self.consumers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn send(&self, spans: Vec<Span>) {
+        self.send_message(Message::Spans(spans));
+    }
+
+    fn send_message(&self, message: Message) {
+        let _ = self.sender.send(message);
+    }
+}
+
+enum Message {
+    Spans(Vec<Span>),
+    // Exports the waiting spans, and replies once they are
+    Flush(mpsc::Sender<()>),
+}
+
+/// The span of a consumer, and the spans of its processes that are running, by PID.
+struct ConsumerSpan {
+    span: Span,
+    processes: HashMap<u32, Span>,
+}
+
+impl ConsumerSpan {
+    fn new(instance: Option<&str>, consumer: &str) -> Self {
+        let name = match instance {
+            Some(instance) => format!("{}:{}", instance, consumer),
+            None => consumer.to_owned(),
+        };
+        let mut span = Span::new(&name, &random_id(16), None);
+        span.attributes.push(KeyValue::string("consumer", consumer));
+        if let Some(instance) = instance {
+            span.attributes.push(KeyValue::string("instance", instance));
+        }
+        Self {
+            span,
+            processes: HashMap::new(),
+        }
+    }
+
+    fn end(self, exporter: &Exporter) {
+        let mut spans: Vec<Span> = self.processes.into_values().map(Span::end).collect();
+        spans.push(self.span.end());
+        exporter.send(spans);
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Span {
+    trace_id: String,
+    span_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_span_id: Option<String>,
+    name: String,
+    kind: u8,
+    // The timestamps are strings, as the JSON encoding of OTLP has 64 bit integers as strings
+    start_time_unix_nano: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_time_unix_nano: Option<String>,
+    attributes: Vec<KeyValue>,
+    events: Vec<SpanEvent>,
+    dropped_events_count: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<Status>,
+}
+
+impl Span {
+    fn new(name: &str, trace_id: &str, parent_span_id: Option<&str>) -> Self {
+        Self {
+            trace_id: trace_id.to_owned(),
+            span_id: random_id(8),
+            parent_span_id: parent_span_id.map(|x| x.to_owned()),
+            name: name.to_owned(),
+            kind: SPAN_KIND_INTERNAL,
+            start_time_unix_nano: now_unix_nano(),
+            end_time_unix_nano: None,
+            attributes: Vec::new(),
+            events: Vec::new(),
+            dropped_events_count: 0,
+            status: None,
+        }
+    }
+
+    fn add_event(&mut self, event: SpanEvent) {
+        if self.events.len() < MAX_EVENTS {
+            self.events.push(event);
+        } else {
+            self.dropped_events_count += 1;
+        }
+    }
+
+    fn end(mut self) -> Self {
+        self.end_time_unix_nano = Some(now_unix_nano());
+        self
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SpanEvent {
+    time_unix_nano: String,
+    name: &'static str,
+    attributes: Vec<KeyValue>,
+}
+
+impl SpanEvent {
+    /// The event with its fields as attributes, like in the JSON log records, and the message of
+    /// the record it was logged with.
+    fn new(event: &Event, message: &str) -> Self {
+        let mut attributes = vec![KeyValue::string("message", message)];
+        if let Some(pid) = event.pid {
+            attributes.push(KeyValue::int("pid", pid as i64));
+        }
+        if let Some(exit_code) = event.exit_code {
+            attributes.push(KeyValue::int("exit_code", exit_code as i64));
+        }
+        if let Some(ref signal) = event.signal {
+            attributes.push(KeyValue::string("signal", signal));
+        }
+        if let Some(restarts) = event.restarts {
+            attributes.push(KeyValue::int("restarts", restarts as i64));
+        }
+        Self {
+            time_unix_nano: now_unix_nano(),
+            name: event.event,
+            attributes,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Status {
+    code: u8,
+    message: String,
+}
+
+#[derive(Clone, Serialize)]
+struct KeyValue {
+    key: &'static str,
+    value: AnyValue,
+}
+
+impl KeyValue {
+    fn string(key: &'static str, value: &str) -> Self {
+        Self {
+            key,
+            value: AnyValue::String(value.to_owned()),
+        }
+    }
+
+    fn int(key: &'static str, value: i64) -> Self {
+        Self {
+            key,
+            value: AnyValue::Int(value.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Serialize)]
+enum AnyValue {
+    #[serde(rename = "stringValue")]
+    String(String),
+    #[serde(rename = "intValue")]
+    Int(String),
+}
+
+/// Exports the finished spans in batches, until the daemon exits.
+fn export_spans(endpoint: Endpoint, receiver: mpsc::Receiver<Message>) {
+    let mut pending = Vec::new();
+    let mut exported_at = Instant::now();
+    let mut failing = false;
+    loop {
+        let flushed = match receiver.recv_timeout(EXPORT_INTERVAL) {
+            Ok(Message::Spans(spans)) => {
+                pending.extend(spans);
+                None
+            }
+            Ok(Message::Flush(done)) => Some(done),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if flushed.is_none() && pending.len() < MAX_BATCH && exported_at.elapsed() < EXPORT_INTERVAL
+        {
+            continue;
+        }
+        exported_at = Instant::now();
+        if !pending.is_empty() {
+            let spans = std::mem::take(&mut pending);
+            // Only the first failure and the recovery are logged, not every batch while failing
+            match post(&endpoint, &request_body(spans)) {
+                Ok(()) if failing => {
+                    log::info!("Exporting spans to {} works again", endpoint);
+                    failing = false;
+                }
+                Ok(()) => {}
+                Err(err) if !failing => {
+                    log::warn!("Failed to export spans to {}: {}", endpoint, err);
+                    failing = true;
+                }
+                Err(_) => {}
+            }
+        }
+        if let Some(done) = flushed {
+            let _ = done.send(());
+        }
+    }
+}
+
+/// The `ExportTraceServiceRequest` of the spans, in the JSON encoding of OTLP.
+fn request_body(spans: Vec<Span>) -> Vec<u8> {
+    let resource = vec![
+        KeyValue::string("service.name", env!("CARGO_PKG_NAME")),
+        KeyValue::string("service.version", env!("CARGO_PKG_VERSION")),
+    ];
+    serde_json::to_vec(&serde_json::json!({
+        "resourceSpans": [{
+            "resource": { "attributes": resource },
+            "scopeSpans": [{
+                "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    }))
+    .unwrap_or_default()
+}
+
+fn post(endpoint: &Endpoint, body: &[u8]) -> std::io::Result<()> {
+    let addr = match endpoint.authority.to_socket_addrs()?.next() {
+        Some(addr) => addr,
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "the host has no address",
+            ))
+        }
+    };
+    let mut stream = TcpStream::connect_timeout(&addr, REQUEST_TIMEOUT)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        endpoint.path,
+        endpoint.authority,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(std::io::Error::other(format!(
+            "the collector responded with `{}`",
+            status_line.trim()
+        ))),
+    }
+}
+
+/// A random ID of `bytes` bytes as hex, like the trace and span IDs of OTLP.
+fn random_id(bytes: usize) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut id = String::new();
+    while id.len() < bytes * 2 {
+        // RandomState is seeded randomly, so hashing a counter gives unpredictable values
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        id.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    id.truncate(bytes * 2);
+    id
+}
+
+fn now_unix_nano() -> String {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_collector_endpoint() {
+        let endpoint = Endpoint::parse("http://localhost:4318").unwrap();
+        assert_eq!(endpoint.authority, "localhost:4318");
+        assert_eq!(endpoint.path, "/v1/traces");
+        // The port defaults to the one of OTLP over HTTP, and the path is kept as the base path
+        let endpoint = Endpoint::parse("http://collector/otlp/").unwrap();
+        assert_eq!(endpoint.authority, "collector:4318");
+        assert_eq!(endpoint.path, "/otlp/v1/traces");
+        let endpoint = Endpoint::parse("http://[::1]").unwrap();
+        assert_eq!(endpoint.authority, "[::1]:4318");
+        assert!(Endpoint::parse("https://localhost:4318").is_err());
+        assert!(Endpoint::parse("http:///v1/traces").is_err());
+    }
+}
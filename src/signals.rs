@@ -0,0 +1,143 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use signal_hook::consts::{SIGINT, SIGQUIT, SIGTERM, SIGUSR1, SIGUSR2};
+
+use crate::{input::SignalAction, util::signal_name};
+
+/// The actions of the signals that aren't given with `--signal`.
+pub const DEFAULT_ACTIONS: [(i32, SignalAction); 5] = [
+    (SIGTERM, SignalAction::Terminate),
+    (SIGINT, SignalAction::Terminate),
+    (SIGQUIT, SignalAction::Dump),
+    (SIGUSR1, SignalAction::Status),
+    (SIGUSR2, SignalAction::Upgrade),
+];
+
+/// The flags set by the signal handlers, checked by the supervision loop.
+#[derive(Clone, Debug, Default)]
+pub struct Signals {
+    // Set by the signals that terminate, SIGTERM and SIGINT by default, stops the daemon
+    pub term: Arc<AtomicBool>,
+    // Set by SIGUSR1 by default, logs the status of the daemon
+    pub status: Arc<AtomicBool>,
+    // Set by SIGQUIT by default, logs the diagnostic dump of the daemon
+    pub dump: Arc<AtomicBool>,
+    // Set by SIGUSR2 by default, upgrades the daemon in place, see `handoff`
+    pub upgrade: Arc<AtomicBool>,
+    // Refreshes the consumers, and restarts all of them one at a time
+    pub reload: Arc<AtomicBool>,
+    pub restart_all: Arc<AtomicBool>,
+    // Drains the daemon, which exits once the consumers exited on their own
+    pub drain: Arc<AtomicBool>,
+}
+
+impl Signals {
+    /// Registers the handlers of the signals, see `signal_actions`.
+    pub fn register(actions: &BTreeMap<i32, SignalAction>) -> std::io::Result<Self> {
+        let signals = Self::default();
+        for (&signal, &action) in actions.iter() {
+            let flag = match action {
+                SignalAction::Terminate => &signals.term,
+                SignalAction::Status => &signals.status,
+                SignalAction::Dump => &signals.dump,
+                SignalAction::Upgrade => &signals.upgrade,
+                SignalAction::Reload => &signals.reload,
+                SignalAction::RestartAll => &signals.restart_all,
+                SignalAction::Drain => &signals.drain,
+                // A handler that does nothing rather than SIG_IGN, which the consumers would
+                // inherit, so they'd ignore the signal too
+                SignalAction::Ignore => {
+                    signal_hook::flag::register(signal, Arc::new(AtomicBool::new(false)))?;
+                    continue;
+                }
+            };
+            // A dump requested while the previous one wasn't handled yet means the supervision
+            // loop is stuck, so the signal gets its default action, like a core dump for SIGQUIT.
+            // Registered first, so the flag isn't set by this signal yet.
+            if action == SignalAction::Dump {
+                signal_hook::flag::register_conditional_default(signal, Arc::clone(flag))?;
+            }
+            signal_hook::flag::register(signal, Arc::clone(flag))?;
+        }
+        Ok(signals)
+    }
+
+    pub fn is_terminating(&self) -> bool {
+        self.term.load(Ordering::Relaxed)
+    }
+}
+
+/// The action of every handled signal: the defaults, replaced by the actions given with
+/// `--signal`. A signal given with two different actions is an error, and so is leaving no
+/// signal to terminate the daemon gracefully.
+pub fn signal_actions(
+    given: &[(i32, SignalAction)],
+) -> Result<BTreeMap<i32, SignalAction>, String> {
+    let mut actions: BTreeMap<i32, SignalAction> = BTreeMap::new();
+    for &(signal, action) in given.iter() {
+        match actions.insert(signal, action) {
+            Some(previous) if previous != action => {
+                return Err(format!(
+                    "{} is mapped to both {} and {}",
+                    signal_name(signal).unwrap_or("The signal"),
+                    action_name(previous),
+                    action_name(action)
+                ))
+            }
+            _ => {}
+        }
+    }
+    for (signal, action) in DEFAULT_ACTIONS {
+        actions.entry(signal).or_insert(action);
+    }
+    if !actions.values().any(|x| *x == SignalAction::Terminate) {
+        return Err("No signal terminates the daemon, map one to terminate".to_owned());
+    }
+    Ok(actions)
+}
+
+fn action_name(action: SignalAction) -> String {
+    use clap::ValueEnum;
+    match action.to_possible_value() {
+        Some(value) => value.get_name().to_owned(),
+        None => format!("{:?}", action),
+    }
+}
+
+/// Returns whether the signal flag was set, and resets it.
+pub fn take(flag: &AtomicBool) -> bool {
+    flag.swap(false, Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_the_signal_actions() {
+        let actions = signal_actions(&[(libc::SIGINT, SignalAction::Ignore)]).unwrap();
+        assert_eq!(actions[&libc::SIGINT], SignalAction::Ignore);
+        assert_eq!(actions[&libc::SIGTERM], SignalAction::Terminate);
+        assert_eq!(actions[&libc::SIGQUIT], SignalAction::Dump);
+
+        let err = signal_actions(&[
+            (libc::SIGHUP, SignalAction::Reload),
+            (libc::SIGHUP, SignalAction::RestartAll),
+        ])
+        .unwrap_err();
+        assert_eq!(err, "SIGHUP is mapped to both reload and restart-all");
+        // Giving the same action twice isn't a conflict
+        assert!(signal_actions(&[(libc::SIGHUP, SignalAction::Reload); 2]).is_ok());
+        assert!(signal_actions(&[
+            (libc::SIGTERM, SignalAction::Status),
+            (libc::SIGINT, SignalAction::Ignore),
+        ])
+        .is_err());
+    }
+}
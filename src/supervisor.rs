@@ -0,0 +1,1962 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet, VecDeque},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Receiver,
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::Serialize;
+
+use crate::{
+    config::{self, ConsumerList, DaemonContext, EnvironmentError},
+    control::{Command, ConsumerStatus, ControlRequest, Response},
+    handoff::{self, HandedOffProcess},
+    health::Health,
+    logging::{self, log_event, Event},
+    util::{
+        describe_exit_status, format_duration, reap_child, zombie_children, MessageBudget,
+        RestartLimiter, Stagger,
+    },
+    worker::{self, SkipReason, StopSummary, WorkerProcess},
+};
+
+const SUPERVISION_INTERVAL: Duration = Duration::from_secs(2);
+const REAP_INTERVAL: Duration = Duration::from_secs(10);
+// How long an untracked zombie process has to exist before it's reaped. Other threads reap their
+// own short-lived child processes right away, so this avoids stealing their exit status.
+const ZOMBIE_GRACE_PERIOD: Duration = Duration::from_secs(30);
+const TERM_POLL_RESOLUTION: Duration = Duration::from_millis(100);
+/// The interval at which `Daemon::tick` should be called.
+pub const TICK_INTERVAL: Duration = TERM_POLL_RESOLUTION;
+const HEALTH_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+const DIRECTORY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const CONSUMER_LIST_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// How often the CPU time of the consumer processes is sampled for --stall-timeout
+const STALL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// How often the running processes are compared to --min-running-percent
+const RUNNING_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// The longest backoff of the consumer refresh after repeated failures
+const MAX_REFRESH_BACKOFF: Duration = Duration::from_secs(3600);
+// The target of the pause and resume commands for all consumers
+const ALL_CONSUMERS: &str = "all";
+
+/// Why the daemon stopped supervising the consumers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutdownReason {
+    /// `term` was set, typically by a termination signal.
+    Terminated,
+    /// The consumers processed `--max-total-messages`.
+    MessageBudgetExhausted,
+    /// All consumers were idle for `--idle-shutdown`.
+    Idle,
+    /// All consumer processes exited on their own after `Daemon::drain`.
+    Drained,
+    /// The Magento cron worker was enabled while running, with `--exit-on-cron-run`.
+    CronRunEnabled,
+    /// The Magento directory, or its bin/magento, was removed while running.
+    MagentoDirRemoved,
+    /// Refreshing the consumers failed repeatedly while none of them was running, with
+    /// `--exit-after-refresh-failures`.
+    RefreshFailed,
+    /// The running processes stayed below `--min-running-percent`, with `--exit-when-degraded`.
+    Degraded,
+}
+
+impl ShutdownReason {
+    /// The exit code of the daemon, which is non-zero when it stopped because of a failure, so
+    /// a process manager can restart it on failure only.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Terminated | Self::MessageBudgetExhausted | Self::Idle | Self::Drained => 0,
+            Self::CronRunEnabled => 3,
+            Self::MagentoDirRemoved => 4,
+            Self::RefreshFailed => 5,
+            Self::Degraded => 6,
+        }
+    }
+}
+
+/// The status logged with `--status-interval`.
+#[derive(Serialize)]
+struct StatusLine {
+    // The seconds since the supervision started
+    uptime_secs: u64,
+    consumers: Vec<ConsumerStatus>,
+    // The number of restart storms, when a large part of the consumers crashed at once
+    restart_storms: u64,
+    // The consecutive failures to refresh the consumers, of the installation that failed most
+    refresh_failures: u32,
+    // Whether the running processes stayed below --min-running-percent
+    degraded: bool,
+    // Whether the daemon drains, waiting for the consumers to exit without restarting them
+    draining: bool,
+}
+
+/// The summary of the run logged on shutdown.
+#[derive(Serialize)]
+struct ShutdownReport {
+    // The seconds since the supervision started
+    uptime_secs: u64,
+    reason: ShutdownReason,
+    exit_code: i32,
+    // The restarts of all consumers
+    restarts: u64,
+    restart_storms: u64,
+    consumers: Vec<ConsumerReport>,
+    processes: StopSummary,
+}
+
+/// A consumer in the `ShutdownReport`.
+#[derive(Serialize)]
+struct ConsumerReport {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+    consumer: String,
+    restarts: u64,
+    recycles: u64,
+    // Whether restarting it was delayed with a backoff after it crashed repeatedly, or it was
+    // left down because of the restart policy
+    backing_off: bool,
+    down: bool,
+}
+
+impl ConsumerReport {
+    fn new(worker: &WorkerProcess) -> Self {
+        Self {
+            instance: worker.instance().map(str::to_owned),
+            consumer: worker.consumer().to_owned(),
+            restarts: worker.restart_count(),
+            recycles: worker.recycle_count(),
+            backing_off: worker.backing_off(),
+            down: worker.left_down_count() > 0
+                && worker.left_down_count() == worker.process_count(),
+        }
+    }
+}
+
+/// A worker supervised on its own thread.
+struct SupervisorThread {
+    consumer: String,
+    // Shared with the supervisor thread, which locks it while checking the worker
+    worker: Arc<Mutex<WorkerProcess>>,
+    // Stops this supervisor thread only, used when the consumer is removed or stopped
+    stop: Arc<AtomicBool>,
+    // When the supervisor thread started checking the worker, while it does
+    busy_since: Arc<Mutex<Option<Instant>>>,
+    handle: JoinHandle<()>,
+}
+
+impl SupervisorThread {
+    /// Supervises the worker with the configuration and the shared state of `supervisor`.
+    fn spawn(supervisor: &Supervisor, worker: WorkerProcess) -> Self {
+        let consumer = worker.consumer().to_owned();
+        let worker = Arc::new(Mutex::new(worker));
+        let stop = Arc::new(AtomicBool::new(false));
+        let busy_since = Arc::new(Mutex::new(None));
+        let handle = {
+            let context = Arc::clone(&supervisor.context);
+            let worker = Arc::clone(&worker);
+            let stagger = Arc::clone(&supervisor.stagger);
+            let limiter = Arc::clone(&supervisor.limiter);
+            let budget = Arc::clone(&supervisor.budget);
+            let term = Arc::clone(&supervisor.term);
+            let idle = Arc::clone(&supervisor.idle);
+            let draining = Arc::clone(&supervisor.draining);
+            let stop = Arc::clone(&stop);
+            let busy_since = Arc::clone(&busy_since);
+            thread::Builder::new()
+                .name(format!("supervise {}", consumer))
+                .spawn(move || {
+                    let is_stopping =
+                        || term.load(Ordering::Relaxed) || stop.load(Ordering::Relaxed);
+                    supervise_worker(&context, &worker, &busy_since, is_stopping, |worker| {
+                        if draining.load(Ordering::Relaxed) {
+                            worker.drain()
+                        } else {
+                            worker.ensure_running(&context, &stagger, &limiter, &idle, &budget)
+                        }
+                    })
+                })
+                .expect("Failed to spawn supervisor thread")
+        };
+        Self {
+            consumer,
+            worker,
+            stop,
+            busy_since,
+            handle,
+        }
+    }
+
+    /// Stops the supervisor thread and returns the worker, so it can be stopped.
+    fn join(self) -> Option<WorkerProcess> {
+        self.stop.store(true, Ordering::Relaxed);
+        if self.handle.join().is_err() {
+            log::error!("Supervisor thread of consumer {} panicked", self.consumer);
+        }
+        // The thread has finished, so this is the last reference to the worker.
+        match Arc::try_unwrap(self.worker) {
+            Ok(worker) => Some(worker.into_inner().unwrap_or_else(|e| e.into_inner())),
+            Err(_) => None,
+        }
+    }
+
+    fn status(&self, context: &DaemonContext, instance: Option<&str>) -> ConsumerStatus {
+        let count = worker::process_count(context, &self.consumer);
+        let mut worker = self.worker.lock().unwrap_or_else(|e| e.into_inner());
+        ConsumerStatus::new(instance, &mut worker, count)
+    }
+}
+
+/// Keeps track of the supervised workers of a single Magento installation.
+struct Supervisor {
+    context: Arc<DaemonContext>,
+    stagger: Arc<Stagger>,
+    limiter: Arc<RestartLimiter>,
+    budget: Arc<MessageBudget>,
+    term: Arc<AtomicBool>,
+    // Set while all consumers are drained with --idle-shutdown, so they're not restarted
+    idle: Arc<AtomicBool>,
+    // Set while the daemon drains, so exited processes are left down
+    draining: Arc<AtomicBool>,
+    threads: Vec<SupervisorThread>,
+    // Consumers stopped through the control socket, which are not started by a refresh
+    stopped: HashSet<String>,
+    // The workers of the consumers paused through the control socket, without processes, which
+    // are kept until they're resumed
+    paused: Vec<WorkerProcess>,
+    // Whether the Magento cron worker was enabled at the last refresh
+    cron_run: bool,
+    // The consumers read from Magento at the last refresh, before they're filtered
+    known: Option<Vec<String>>,
+    // The consecutive failures to refresh the consumers, and when the refresh is tried again
+    // when it backs off
+    refresh_failures: u32,
+    refresh_retry_at: Option<Instant>,
+}
+
+impl Supervisor {
+    fn new(
+        context: Arc<DaemonContext>,
+        stagger: Arc<Stagger>,
+        limiter: Arc<RestartLimiter>,
+        budget: Arc<MessageBudget>,
+        term: Arc<AtomicBool>,
+        idle: Arc<AtomicBool>,
+        draining: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            context,
+            stagger,
+            limiter,
+            budget,
+            term,
+            idle,
+            draining,
+            threads: Vec::new(),
+            stopped: HashSet::new(),
+            paused: Vec::new(),
+            cron_run: false,
+            known: None,
+            refresh_failures: 0,
+            refresh_retry_at: None,
+        }
+    }
+
+    fn instance(&self) -> Option<&str> {
+        self.context.daemon_config.instance.as_deref()
+    }
+
+    /// Adds the consumer to the records logged until the guard is dropped.
+    fn log_context(&self, consumer: &str) -> logging::ContextGuard {
+        logging::enter_context(self.instance(), consumer, None)
+    }
+
+    fn start_thread(&mut self, worker: WorkerProcess) {
+        let thread = SupervisorThread::spawn(self, worker);
+        self.threads.push(thread);
+    }
+
+    /// Removes the supervisor thread of the consumer, and returns its worker.
+    fn take_worker(&mut self, consumer: &str) -> Option<WorkerProcess> {
+        let index = self.threads.iter().position(|t| t.consumer == consumer)?;
+        self.threads.remove(index).join()
+    }
+
+    /// Whether the consumer is supervised, or was stopped or paused through the control socket.
+    fn has_consumer(&self, consumer: &str) -> bool {
+        self.stopped.contains(consumer)
+            || self.is_paused(consumer)
+            || self.threads.iter().any(|t| t.consumer == consumer)
+    }
+
+    fn is_paused(&self, consumer: &str) -> bool {
+        self.paused.iter().any(|w| w.consumer() == consumer)
+    }
+
+    /// Reloads the Magento consumer configuration and the consumer list. New consumers are
+    /// started, removed consumers are stopped, and consumers of which the number of processes
+    /// changed are scaled.
+    fn refresh_consumers(&mut self) {
+        log::debug!("Refreshing consumer list...");
+        let previous = Arc::clone(&self.context);
+        let mut failed = false;
+        match self.context.reload() {
+            // A deployment, like setup:upgrade or app:config:import, may enable it again
+            Ok(context) if context.consumer_config.cron_run() => {
+                log::error!(
+                    "Magento cron worker was enabled in {}, so consumers are also started by Magento cron and messages may be processed twice. Set cron_consumers_runner.cron_run to false again",
+                    self.context.daemon_config.magento_dir
+                );
+                self.cron_run = true;
+            }
+            Ok(context) => {
+                self.context = Arc::new(context);
+                self.cron_run = false;
+            }
+            // The consumer list is still refreshed with the previous configuration
+            Err(err) => {
+                log::error!(
+                    "Failed to reload Magento consumer configuration: {}",
+                    err.message
+                );
+                failed = true;
+            }
+        }
+        match worker::known_consumers(&self.context.daemon_config) {
+            Ok(known) => self.known = Some(known),
+            Err(err) => {
+                log::error!("Failed to refresh consumer list: {}", err.message);
+                self.refresh_failed();
+                return;
+            }
+        };
+        if failed {
+            self.refresh_failed();
+        } else {
+            self.refresh_succeeded();
+        }
+        self.apply_consumers(&previous);
+    }
+
+    /// Refreshes the consumers when a consumer reported that Magento doesn't know it, like after a
+    /// deployment removed it. A removed consumer is stopped by the refresh, instead of crashing on
+    /// every restart, and one that is still listed is restarted.
+    fn refresh_unknown_consumers(&mut self) {
+        let unknown: Vec<String> = self
+            .threads
+            .iter()
+            .filter(|t| t.worker.try_lock().is_ok_and(|w| w.reported_unknown()))
+            .map(|t| t.consumer.clone())
+            .collect();
+        if unknown.is_empty() {
+            return;
+        }
+        let failures = self.refresh_failures;
+        self.refresh_consumers();
+        for thread in self.threads.iter() {
+            if !unknown.contains(&thread.consumer) {
+                continue;
+            }
+            let mut worker = thread.worker.lock().unwrap_or_else(|e| e.into_inner());
+            // Restarting it for a changed configuration already cleared the report
+            if !worker.reported_unknown() {
+                continue;
+            }
+            let _context = self.log_context(&thread.consumer);
+            let name = self.context.daemon_config.qualified_name(&thread.consumer);
+            if self.refresh_failures > failures {
+                log::warn!(
+                    "Restarting consumer {}, as the consumer list couldn't be refreshed",
+                    name
+                );
+            } else {
+                log::warn!(
+                    "Consumer {} is still listed by Magento, restarting it",
+                    name
+                );
+            }
+            let _ = worker.restart_unknown(&self.context);
+        }
+    }
+
+    /// Whether the periodic refresh is due, which it isn't while it backs off after failing.
+    fn refresh_due(&self) -> bool {
+        self.refresh_retry_at.is_none_or(|at| Instant::now() >= at)
+    }
+
+    /// Counts a failed refresh. After `--refresh-failure-threshold` consecutive failures, the
+    /// refresh backs off exponentially, while the last known consumers keep running.
+    fn refresh_failed(&mut self) {
+        self.refresh_failures += 1;
+        let config = &self.context.daemon_config;
+        let threshold = config.refresh_failure_threshold;
+        if threshold == 0 || self.refresh_failures < threshold {
+            return;
+        }
+        let exponent = (self.refresh_failures - threshold).min(16);
+        let delay = config
+            .consumer_refresh_interval
+            .saturating_mul(1 << exponent)
+            .min(MAX_REFRESH_BACKOFF);
+        self.refresh_retry_at = Some(Instant::now() + delay);
+        if self.refresh_failures == threshold {
+            log::error!(
+                "Refreshing the consumers of {} failed {} times in a row, keeping the last known consumers running. The refresh backs off up to every {}, use the reload command of the control socket to refresh them sooner",
+                config.magento_dir,
+                self.refresh_failures,
+                format_duration(MAX_REFRESH_BACKOFF)
+            );
+        } else {
+            log::warn!(
+                "Refreshing the consumers of {} failed {} times in a row, retrying in {}",
+                config.magento_dir,
+                self.refresh_failures,
+                format_duration(delay)
+            );
+        }
+    }
+
+    fn refresh_succeeded(&mut self) {
+        if self.refresh_failures > 0 {
+            log::info!(
+                "Refreshed the consumers of {} again after {} failures",
+                self.context.daemon_config.magento_dir,
+                self.refresh_failures
+            );
+        }
+        self.refresh_failures = 0;
+        self.refresh_retry_at = None;
+    }
+
+    /// Applies a changed `--consumer-list-file`, without reading the consumer list from Magento
+    /// again when it was read before, as Magento may be unavailable.
+    fn set_consumer_list(&mut self, consumer_list: Option<ConsumerList>) {
+        let previous = Arc::clone(&self.context);
+        self.context = Arc::new(self.context.with_consumer_list(consumer_list));
+        match self.known {
+            Some(_) => self.apply_consumers(&previous),
+            None => self.refresh_consumers(),
+        }
+    }
+
+    /// Starts, stops, scales and restarts the consumers to match the consumers read from Magento
+    /// and the current configuration, where `previous` is the configuration they run with.
+    fn apply_consumers(&mut self, previous: &DaemonContext) {
+        let known = self.known.clone().unwrap_or_default();
+        let mut consumers = worker::filter_applicable(&self.context, known);
+        let mut context = (*self.context).clone();
+        worker::cap_processes(&mut context, &mut consumers);
+        self.context = Arc::new(context);
+
+        let (kept, removed): (Vec<_>, Vec<_>) = self
+            .threads
+            .drain(..)
+            .partition(|t| consumers.contains(&t.consumer));
+        self.threads = kept;
+
+        for thread in removed.iter() {
+            let _context = self.log_context(&thread.consumer);
+            let name = self.context.daemon_config.qualified_name(&thread.consumer);
+            match worker::skip_reason(&self.context, &thread.consumer) {
+                Some(reason) => log::info!("Stopping consumer {}: {}", name, reason),
+                None => log::info!("Consumer {} was removed, stopping it", name),
+            }
+            thread.stop.store(true, Ordering::Relaxed);
+        }
+        let mut removed_workers: Vec<_> = removed
+            .into_iter()
+            .filter_map(SupervisorThread::join)
+            .collect();
+        worker::drain_workers(
+            &mut removed_workers,
+            self.context.daemon_config.shutdown_timeout,
+        );
+
+        let changed: Vec<_> = self
+            .threads
+            .iter()
+            .map(|t| t.consumer.clone())
+            .filter(|c| {
+                worker::number_of_processes(previous, c)
+                    != worker::number_of_processes(&self.context, c)
+                    || worker::worker_command_args(previous, c, 0)
+                        != worker::worker_command_args(&self.context, c, 0)
+            })
+            .collect();
+        for consumer in changed {
+            // Like switching from --single-thread to --multi-process, which needs all processes
+            // to be restarted
+            let restart = worker::worker_command_args(previous, &consumer, 0)
+                != worker::worker_command_args(&self.context, &consumer, 0);
+            // The supervisor thread is restarted with the new configuration, and retries starting
+            // the processes when it failed
+            let _context = self.log_context(&consumer);
+            if let Some(mut worker) = self.take_worker(&consumer) {
+                if restart {
+                    log::info!(
+                        "Restarting consumer {}: its configuration changed",
+                        self.context.daemon_config.qualified_name(&consumer)
+                    );
+                    let _ = worker.restart(&self.context);
+                } else {
+                    let processes = worker::number_of_processes(&self.context, &consumer);
+                    let _ = worker.scale_to(processes, &self.context);
+                }
+                self.start_thread(worker);
+            }
+        }
+
+        for consumer in consumers {
+            if self.stopped.contains(&consumer)
+                || self.is_paused(&consumer)
+                || self.threads.iter().any(|t| t.consumer == consumer)
+            {
+                continue;
+            }
+            let _context = self.log_context(&consumer);
+            let name = self.context.daemon_config.qualified_name(&consumer);
+            match worker::skip_reason(previous, &consumer) {
+                Some(SkipReason::NotInConsumerListFile) => log::info!(
+                    "Starting consumer {}: enabled in --consumer-list-file",
+                    name
+                ),
+                _ => log::info!("Found new consumer {}, starting it", name),
+            }
+            self.stagger.wait();
+            match worker::run_worker(&self.context, &consumer) {
+                Ok(worker) => self.start_thread(worker),
+                // Starting it is retried on the next refresh
+                Err(err) => log::error!("{}", err.message),
+            }
+        }
+    }
+
+    /// Scales the consumers to the messages waiting in their queues with `--autoscale`. The
+    /// consumers of which the backlog can't be read are left as they are.
+    fn autoscale(&mut self) {
+        let backlogs = match config::queue_backlogs(&self.context.daemon_config) {
+            Ok(backlogs) => backlogs,
+            Err(err) => {
+                log::warn!("{}", err.message);
+                if let Some(stderr) = err.stderr {
+                    log::debug!("Error output:\n{}", stderr);
+                }
+                return;
+            }
+        };
+        let mut context = (*self.context).clone();
+        let mut scaled = Vec::new();
+        for thread in self.threads.iter() {
+            let backlog = match backlogs.get(&thread.consumer) {
+                Some(backlog) => *backlog,
+                None => continue,
+            };
+            let current = worker::number_of_processes(&context, &thread.consumer);
+            let limits = worker::autoscale_limits(&context, &thread.consumer);
+            let processes = worker::autoscale_processes(backlog, current, limits);
+            if processes != current {
+                let _context = self.log_context(&thread.consumer);
+                log::info!(
+                    "Consumer {} has {} messages waiting, scaling it to {} processes",
+                    context.daemon_config.qualified_name(&thread.consumer),
+                    backlog,
+                    processes
+                );
+                context
+                    .autoscaled
+                    .insert(thread.consumer.clone(), processes);
+                scaled.push((thread.consumer.clone(), processes));
+            }
+        }
+        // Stored in the context, so restarts and refreshes keep the number of processes
+        self.context = Arc::new(context);
+        for (consumer, processes) in scaled {
+            if let Some(mut worker) = self.take_worker(&consumer) {
+                let _ = worker.scale_to(processes, &self.context);
+                self.start_thread(worker);
+            }
+        }
+    }
+
+    /// Restarts the consumer processes that are stalled with `--stall-timeout`, as they used no CPU
+    /// time for the timeout while messages are waiting in their queue. When the backlog of a
+    /// consumer can't be read, the CPU time decides alone. Busy workers are checked next time.
+    fn restart_stalled(&mut self, timeout: Duration) {
+        let mut stalled = Vec::new();
+        for thread in self.threads.iter() {
+            if let Ok(mut worker) = thread.worker.try_lock() {
+                let indices = worker.stalled_processes(timeout);
+                if !indices.is_empty() {
+                    stalled.push((Arc::clone(&thread.worker), indices));
+                }
+            }
+        }
+        if stalled.is_empty() {
+            return;
+        }
+        let backlogs = match config::queue_backlogs(&self.context.daemon_config) {
+            Ok(backlogs) => backlogs,
+            Err(err) => {
+                log::debug!("{}", err.message);
+                HashMap::new()
+            }
+        };
+        for (worker, indices) in stalled {
+            let mut worker = worker.lock().unwrap_or_else(|e| e.into_inner());
+            let backlog = backlogs.get(worker.consumer()).copied();
+            worker.restart_stalled(&indices, &self.context, &self.limiter, backlog);
+        }
+    }
+
+    /// The PIDs of the processes of all workers, or `None` when any of the workers is busy.
+    fn tracked_pids(&self) -> Option<Vec<u32>> {
+        let mut pids = Vec::new();
+        for thread in self.threads.iter() {
+            pids.extend(thread.worker.try_lock().ok()?.pids());
+        }
+        Some(pids)
+    }
+
+    /// The number of running processes and the number of processes that should be running. Busy
+    /// workers, for example restarting, are assumed to be running, and drained workers are not
+    /// expected to be running.
+    fn process_counts(&self) -> (usize, usize) {
+        let mut running = 0;
+        let mut expected = 0;
+        for thread in self.threads.iter() {
+            let processes = worker::number_of_processes(&self.context, &thread.consumer) as usize;
+            match thread.worker.try_lock() {
+                Ok(worker) if worker.drained_at().is_some() => {}
+                Ok(mut worker) => {
+                    expected += processes.saturating_sub(worker.left_down_count());
+                    running += worker.running_pids().len();
+                }
+                Err(_) => {
+                    expected += processes;
+                    running += processes;
+                }
+            }
+        }
+        (running, expected)
+    }
+
+    /// The time since which all consumers are drained, or `None` when any consumer is running or
+    /// busy. Stopped consumers are considered drained.
+    fn drained_since(&self, started_at: Instant) -> Option<Instant> {
+        let mut since = started_at;
+        for thread in self.threads.iter() {
+            since = since.max(thread.worker.try_lock().ok()?.drained_at()?);
+        }
+        Some(since)
+    }
+
+    /// Logs the status of every consumer. Consumers that are busy, for example restarting, are
+    /// reported as such rather than waited for, so the status can't stall the supervision.
+    fn log_status(&self) {
+        let config = &self.context.daemon_config;
+        if self.refresh_failures > 0 {
+            log::info!(
+                "  Refreshing the consumers of {} failed {} times in a row",
+                config.magento_dir,
+                self.refresh_failures
+            );
+        }
+        for thread in self.threads.iter() {
+            let name = config.qualified_name(&thread.consumer);
+            let mut worker = match thread.worker.try_lock() {
+                Ok(worker) => worker,
+                Err(_) => {
+                    log::info!("  {}: busy", name);
+                    continue;
+                }
+            };
+            let pids = worker.running_pids();
+            let last_exit = match worker.last_exit() {
+                Some(status) => format!(", last process {}", describe_exit_status(status)),
+                None => String::new(),
+            };
+            let failures = match worker.spawn_failures() {
+                0 => String::new(),
+                n => format!(", failed to start {} times", n),
+            };
+            let stuck = worker.stuck_pids();
+            let stuck = match stuck.len() {
+                0 => String::new(),
+                _ => format!(", left running stuck {:?}", stuck),
+            };
+            let count = worker::process_count(&self.context, &thread.consumer);
+            let scaled = match (count.configured, count.reason) {
+                (Some(configured), Some(reason)) => {
+                    format!(" (configured {}, {})", configured, reason)
+                }
+                (None, Some(reason)) => format!(" ({})", reason),
+                (_, None) => String::new(),
+            };
+            log::info!(
+                "  {}: {}/{} processes running {:?}{}, {} restarts, {} recycles, {} messages processed, up {}{}{}{}",
+                name,
+                pids.len(),
+                worker.process_count(),
+                pids,
+                scaled,
+                worker.restart_count(),
+                worker.recycle_count(),
+                worker.processed_messages(),
+                format_duration(worker.uptime()),
+                last_exit,
+                failures,
+                stuck
+            );
+        }
+        for worker in self.paused.iter() {
+            log::info!("  {}: paused", config.qualified_name(worker.consumer()));
+        }
+        for consumer in self.stopped.iter() {
+            log::info!("  {}: stopped", config.qualified_name(consumer));
+        }
+    }
+
+    /// Logs the internal state of the installation for the `SIGQUIT` dump. Unlike `log_status`,
+    /// it tells how long a busy consumer has been checked, as its supervisor thread may be stuck.
+    fn dump(&self) {
+        let config = &self.context.daemon_config;
+        log::warn!(
+            "  {}: {} consumers supervised, {} paused, {} stopped, {} known at the last refresh, cron_run {}",
+            config.magento_dir,
+            self.threads.len(),
+            self.paused.len(),
+            self.stopped.len(),
+            self.known.as_ref().map_or(0, |x| x.len()),
+            if self.cron_run { "enabled" } else { "disabled" }
+        );
+        if self.refresh_failures > 0 {
+            let retry = match self.refresh_retry_at {
+                Some(at) => format!(
+                    ", retrying in {}",
+                    format_duration(at.saturating_duration_since(Instant::now()))
+                ),
+                None => String::new(),
+            };
+            log::warn!(
+                "  Refreshing the consumers failed {} times in a row{}",
+                self.refresh_failures,
+                retry
+            );
+        }
+        for thread in self.threads.iter() {
+            let name = config.qualified_name(&thread.consumer);
+            if thread.handle.is_finished() {
+                log::warn!("  {}: the supervisor thread exited", name);
+                continue;
+            }
+            let mut worker = match thread.worker.try_lock() {
+                Ok(worker) => worker,
+                Err(_) => {
+                    match *thread.busy_since.lock().unwrap_or_else(|e| e.into_inner()) {
+                        Some(since) => log::warn!(
+                            "  {}: busy, its supervisor thread has been checking it for {}",
+                            name,
+                            format_duration(since.elapsed())
+                        ),
+                        None => log::warn!("  {}: busy", name),
+                    }
+                    continue;
+                }
+            };
+            let pids = worker.running_pids();
+            log::warn!(
+                "  {}: {}/{} processes running {:?}, {} restarts, {} recycles, up {}",
+                name,
+                pids.len(),
+                worker.process_count(),
+                pids,
+                worker.restart_count(),
+                worker.recycle_count(),
+                format_duration(worker.uptime())
+            );
+            for line in worker.diagnostics() {
+                log::warn!("    {}", line);
+            }
+            for pid in worker.stuck_pids() {
+                log::warn!("    process {} left running stuck", pid);
+            }
+        }
+        for worker in self.paused.iter() {
+            log::warn!("  {}: paused", config.qualified_name(worker.consumer()));
+        }
+        for consumer in self.stopped.iter() {
+            log::warn!("  {}: stopped", config.qualified_name(consumer));
+        }
+    }
+
+    fn statuses(&self) -> Vec<ConsumerStatus> {
+        let mut consumers: Vec<_> = self
+            .threads
+            .iter()
+            .map(|t| t.status(&self.context, self.instance()))
+            .collect();
+        consumers.extend(self.paused.iter().map(|w| {
+            let count = worker::process_count(&self.context, w.consumer());
+            ConsumerStatus::paused(self.instance(), w, count)
+        }));
+        consumers.extend(
+            self.stopped
+                .iter()
+                .map(|c| ConsumerStatus::stopped(self.instance(), c)),
+        );
+        consumers
+    }
+
+    fn restart(&mut self, consumer: &str) -> Response {
+        let _context = self.log_context(consumer);
+        let name = self.context.daemon_config.qualified_name(consumer);
+        if self.stopped.remove(consumer) {
+            log::info!("Starting stopped consumer {}", name);
+            return match worker::run_worker(&self.context, consumer) {
+                Ok(worker) => {
+                    self.start_thread(worker);
+                    Response::success()
+                }
+                Err(err) => {
+                    self.stopped.insert(consumer.to_owned());
+                    Response::error(err.message)
+                }
+            };
+        }
+        if self.is_paused(consumer) {
+            return self.resume(consumer);
+        }
+        match self.take_worker(consumer) {
+            Some(mut worker) => {
+                log::info!("Restarting consumer {} on request", name);
+                let result = worker.restart(&self.context);
+                // The thread keeps retrying when the restart failed
+                self.start_thread(worker);
+                match result {
+                    Ok(()) => Response::success(),
+                    Err(err) => Response::error(err.message),
+                }
+            }
+            None => Response::error(format!("Unknown consumer {}", name)),
+        }
+    }
+
+    fn stop(&mut self, consumer: &str) -> Response {
+        let _context = self.log_context(consumer);
+        if let Some(index) = self.paused.iter().position(|w| w.consumer() == consumer) {
+            log::info!(
+                "Stopping paused consumer {} on request",
+                self.context.daemon_config.qualified_name(consumer)
+            );
+            self.paused.remove(index);
+            self.stopped.insert(consumer.to_owned());
+            return Response::success();
+        }
+        match self.take_worker(consumer) {
+            Some(worker) => {
+                log::info!(
+                    "Stopping consumer {} on request",
+                    self.context.daemon_config.qualified_name(consumer)
+                );
+                worker::drain_workers(&mut [worker], self.context.daemon_config.shutdown_timeout);
+                self.stopped.insert(consumer.to_owned());
+                Response::success()
+            }
+            None => Response::error(format!(
+                "Unknown consumer {}",
+                self.context.daemon_config.qualified_name(consumer)
+            )),
+        }
+    }
+
+    /// Stops the processes of the consumer, but keeps its worker with the restart counts, so it's
+    /// not restarted, also not by a refresh, until it's resumed.
+    fn pause(&mut self, consumer: &str) -> Response {
+        let _context = self.log_context(consumer);
+        let name = self.context.daemon_config.qualified_name(consumer);
+        if self.is_paused(consumer) {
+            return Response::success();
+        }
+        if self.stopped.contains(consumer) {
+            return Response::error(format!("Consumer {} is stopped", name));
+        }
+        match self.take_worker(consumer) {
+            Some(mut worker) => {
+                log::info!("Pausing consumer {} on request", name);
+                worker::drain_workers(
+                    std::slice::from_mut(&mut worker),
+                    self.context.daemon_config.shutdown_timeout,
+                );
+                self.paused.push(worker);
+                Response::success()
+            }
+            None => Response::error(format!("Unknown consumer {}", name)),
+        }
+    }
+
+    /// Pauses all consumers, which share the grace period to stop.
+    fn pause_all(&mut self) -> Response {
+        let mut workers: Vec<_> = std::mem::take(&mut self.threads)
+            .into_iter()
+            .filter_map(SupervisorThread::join)
+            .collect();
+        if !workers.is_empty() {
+            log::info!("Pausing {} consumers on request", workers.len());
+        }
+        worker::drain_workers(&mut workers, self.context.daemon_config.shutdown_timeout);
+        self.paused.extend(workers);
+        Response::success()
+    }
+
+    /// Starts the processes of a paused consumer again.
+    fn resume(&mut self, consumer: &str) -> Response {
+        let _context = self.log_context(consumer);
+        let name = self.context.daemon_config.qualified_name(consumer);
+        let index = match self.paused.iter().position(|w| w.consumer() == consumer) {
+            Some(index) => index,
+            None if self.has_consumer(consumer) => {
+                return Response::error(format!("Consumer {} is not paused", name))
+            }
+            None => return Response::error(format!("Unknown consumer {}", name)),
+        };
+        let mut worker = self.paused.remove(index);
+        log::info!("Resuming consumer {} on request", name);
+        let result = worker.resume(&self.context);
+        // The thread keeps retrying when starting the processes failed
+        self.start_thread(worker);
+        match result {
+            Ok(()) => Response::success(),
+            Err(err) => Response::error(err.message),
+        }
+    }
+
+    /// Resumes all paused consumers, with the startup stagger in between.
+    fn resume_all(&mut self) -> Response {
+        let mut error = None;
+        for (i, mut worker) in std::mem::take(&mut self.paused).into_iter().enumerate() {
+            if i > 0 {
+                self.stagger.wait();
+            }
+            let _context = worker.log_context();
+            log::info!(
+                "Resuming consumer {} on request",
+                self.context.daemon_config.qualified_name(worker.consumer())
+            );
+            if let Err(err) = worker.resume(&self.context) {
+                error.get_or_insert(err.message);
+            }
+            self.start_thread(worker);
+        }
+        match error {
+            Some(message) => Response::error(message),
+            None => Response::success(),
+        }
+    }
+
+    /// Stops the supervisor threads and returns the workers, so they can be stopped.
+    fn join(self) -> Vec<WorkerProcess> {
+        self.threads
+            .into_iter()
+            .filter_map(SupervisorThread::join)
+            .collect()
+    }
+}
+
+/// Defensively reaps exited child processes that aren't tracked by any worker, for example
+/// because the worker was lost to a panic, so they don't linger in the process table.
+#[derive(Default)]
+struct ZombieReaper {
+    // Untracked zombie processes, and when they were first seen
+    zombies: HashMap<u32, Instant>,
+}
+
+impl ZombieReaper {
+    fn reap(&mut self, supervisors: &[Supervisor]) {
+        let mut tracked = HashSet::new();
+        for supervisor in supervisors.iter() {
+            match supervisor.tracked_pids() {
+                Some(pids) => tracked.extend(pids),
+                // A worker is busy, possibly with processes that are about to be reaped.
+                None => return,
+            }
+        }
+
+        let zombies = match zombie_children() {
+            Ok(zombies) => zombies,
+            Err(err) => {
+                log::debug!("Failed to list zombie processes: {}", err);
+                return;
+            }
+        };
+        self.zombies.retain(|pid, _| zombies.contains(pid));
+        for pid in zombies.into_iter().filter(|pid| !tracked.contains(pid)) {
+            let first_seen = *self.zombies.entry(pid).or_insert_with(Instant::now);
+            if first_seen.elapsed() >= ZOMBIE_GRACE_PERIOD && reap_child(pid) {
+                log::warn!("Reaped untracked zombie process {}", pid);
+                self.zombies.remove(&pid);
+            }
+        }
+    }
+}
+
+fn log_status(supervisors: &[Supervisor], uptime: Duration, draining: bool) {
+    let consumers: usize = supervisors.iter().map(|s| s.threads.len()).sum();
+    let draining = if draining { ", draining" } else { "" };
+    if supervisors.len() > 1 {
+        log::info!(
+            "Status: up {}, supervising {} consumers of {} Magento installations{}",
+            format_duration(uptime),
+            consumers,
+            supervisors.len(),
+            draining
+        );
+    } else {
+        log::info!(
+            "Status: up {}, supervising {} consumers{}",
+            format_duration(uptime),
+            consumers,
+            draining
+        );
+    }
+    for supervisor in supervisors.iter() {
+        supervisor.log_status();
+    }
+}
+
+/// Applies a command to every Magento installation, and reports the first error, if any.
+fn for_all<F>(supervisors: &mut [Supervisor], mut f: F) -> Response
+where
+    F: FnMut(&mut Supervisor) -> Response,
+{
+    let mut error = None;
+    for supervisor in supervisors.iter_mut() {
+        let response = f(supervisor);
+        if !response.is_ok() && error.is_none() {
+            error = Some(response);
+        }
+    }
+    error.unwrap_or_else(Response::success)
+}
+
+fn for_consumer<F>(supervisors: &mut [Supervisor], target: &str, mut f: F) -> Response
+where
+    F: FnMut(&mut Supervisor, &str) -> Response,
+{
+    // Consumer names don't contain colons, but instance labels might.
+    let (instance, consumer) = match target.rsplit_once(':') {
+        Some((instance, consumer)) => (Some(instance), consumer),
+        None => (None, target),
+    };
+    let mut found = false;
+    let mut error = None;
+    for supervisor in supervisors.iter_mut() {
+        if instance.is_some_and(|i| supervisor.instance() != Some(i))
+            || !supervisor.has_consumer(consumer)
+        {
+            continue;
+        }
+        found = true;
+        let response = f(supervisor, consumer);
+        // Report the first error, or success when the command succeeded everywhere
+        if !response.is_ok() && error.is_none() {
+            error = Some(response);
+        }
+    }
+    if !found {
+        return Response::error(format!("Unknown consumer {}", target));
+    }
+    error.unwrap_or_else(Response::success)
+}
+
+/// The daemon, which starts the consumers of one or more Magento installations and supervises
+/// them until it's stopped.
+///
+/// The consumers are started with `start`, and are supervised after `supervise`, each on its own
+/// thread, so a slow restart of one consumer doesn't delay the others. `tick` is called
+/// periodically to refresh the consumer lists, handle the requests of the control socket and
+/// decide whether the daemon should keep running. `shutdown` stops all consumers, and returns why
+/// the daemon stopped. The daemon stops when `term` is set, typically by a termination signal.
+pub struct Daemon {
+    // The consumers to start per Magento installation
+    instances: Vec<(Arc<DaemonContext>, Vec<String>)>,
+    // The started workers, until they're supervised
+    started: Vec<(Arc<DaemonContext>, Vec<WorkerProcess>)>,
+    supervisors: Vec<Supervisor>,
+    stagger: Arc<Stagger>,
+    limiter: Arc<RestartLimiter>,
+    budget: Arc<MessageBudget>,
+    term: Arc<AtomicBool>,
+    // Set while all consumers are drained with --idle-shutdown, so they're not restarted
+    idle: Arc<AtomicBool>,
+    // Set by `drain`, after which the consumers aren't restarted, and the number of processes
+    // that were still running when that was last logged
+    draining: Arc<AtomicBool>,
+    draining_processes: Option<usize>,
+    control: Option<Receiver<ControlRequest>>,
+    health: Option<Arc<Health>>,
+    reaper: ZombieReaper,
+    // When the supervision started
+    started_at: Instant,
+    last_refresh: Instant,
+    last_reap: Instant,
+    last_health_update: Option<Instant>,
+    last_directory_check: Instant,
+    // The modification time and size of --consumer-list-file when it was last read, to detect
+    // changes. `None` when it didn't exist.
+    consumer_list_stamp: Option<(SystemTime, u64)>,
+    last_consumer_list_check: Instant,
+    last_status: Instant,
+    last_autoscale: Instant,
+    last_stall_check: Instant,
+    last_running_check: Instant,
+    // Since when the running processes are below --min-running-percent, and whether that was
+    // reported
+    degraded_since: Option<Instant>,
+    degraded: bool,
+    // Why `tick` stopped the daemon, if it did
+    shutdown_reason: Option<ShutdownReason>,
+    // The consumers still to restart in the rolling restart, by supervisor index
+    rolling_restart: VecDeque<(usize, String)>,
+    // The processes handed off by the daemon this one replaced, adopted by `start`
+    handed_off: Vec<HandedOffProcess>,
+}
+
+impl Daemon {
+    /// Creates the daemon for the consumers of every Magento installation. The command line
+    /// options are shared by all installations, so they're taken from the first one.
+    pub fn new(instances: Vec<(DaemonContext, Vec<String>)>, term: Arc<AtomicBool>) -> Self {
+        let config = instances.first().map(|(context, _)| &context.daemon_config);
+        // The stagger is shared by the startup and the restarts, so a mass failure doesn't
+        // restart every consumer at the same time either.
+        let stagger = Stagger::new(config.map_or(Duration::ZERO, |c| c.startup_stagger));
+        let limiter = match config {
+            Some(c) => RestartLimiter::new(c.max_restarts_per_minute)
+                .with_storm_detection(c.storm_threshold, c.storm_window),
+            None => RestartLimiter::new(0),
+        };
+        let budget = MessageBudget::new(config.and_then(|c| c.max_total_messages));
+        let consumer_list_stamp = config
+            .and_then(|c| c.consumer_list_file.as_deref())
+            .and_then(file_stamp);
+        Self {
+            instances: instances
+                .into_iter()
+                .map(|(context, consumers)| (Arc::new(context), consumers))
+                .collect(),
+            started: Vec::new(),
+            supervisors: Vec::new(),
+            stagger: Arc::new(stagger),
+            limiter: Arc::new(limiter),
+            budget: Arc::new(budget),
+            term,
+            idle: Arc::new(AtomicBool::new(false)),
+            draining: Arc::new(AtomicBool::new(false)),
+            draining_processes: None,
+            control: None,
+            health: None,
+            reaper: ZombieReaper::default(),
+            started_at: Instant::now(),
+            last_refresh: Instant::now(),
+            last_reap: Instant::now(),
+            last_health_update: None,
+            last_directory_check: Instant::now(),
+            consumer_list_stamp,
+            last_consumer_list_check: Instant::now(),
+            last_status: Instant::now(),
+            last_autoscale: Instant::now(),
+            last_stall_check: Instant::now(),
+            last_running_check: Instant::now(),
+            degraded_since: None,
+            degraded: false,
+            shutdown_reason: None,
+            rolling_restart: VecDeque::new(),
+            handed_off: Vec::new(),
+        }
+    }
+
+    /// Sets the processes handed off by the daemon this one replaced on an upgrade, which `start`
+    /// adopts instead of starting the consumers again.
+    pub fn set_handed_off(&mut self, processes: Vec<HandedOffProcess>) {
+        self.handed_off = processes;
+    }
+
+    /// Sets the health that is updated while supervising, and served by the health server.
+    pub fn set_health(&mut self, health: Arc<Health>) {
+        self.health = Some(health);
+    }
+
+    /// Sets the requests of the control socket to handle while supervising.
+    pub fn set_control(&mut self, control: Receiver<ControlRequest>) {
+        self.control = Some(control);
+    }
+
+    fn is_terminating(&self) -> bool {
+        self.term.load(Ordering::Relaxed)
+    }
+
+    fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    fn shutdown_timeout(&self) -> Duration {
+        match self.instances.first() {
+            Some((context, _)) => context.daemon_config.shutdown_timeout,
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Starts the consumers, one at a time with the startup stagger and the highest priority
+    /// first, after stopping orphaned consumer processes with `--reclaim-orphans`. Stops starting
+    /// them when `term` is set. When a consumer fails to start, the error is returned, and the
+    /// consumers started so far are stopped by `shutdown`.
+    ///
+    /// The processes handed off by `set_handed_off` are adopted instead, and the ones of consumers
+    /// that aren't started are stopped.
+    pub fn start(&mut self) -> Result<(), EnvironmentError> {
+        let result = self.start_consumers();
+        handoff::stop(
+            &std::mem::take(&mut self.handed_off),
+            self.shutdown_timeout(),
+        );
+        result
+    }
+
+    fn start_consumers(&mut self) -> Result<(), EnvironmentError> {
+        for (context, _) in self.instances.iter() {
+            if context.daemon_config.reclaim_orphans {
+                worker::reclaim_orphans(&context.daemon_config);
+            }
+        }
+        self.started = self
+            .instances
+            .iter()
+            .map(|(context, _)| (Arc::clone(context), Vec::new()))
+            .collect();
+        for (index, consumer) in self.start_order() {
+            let context = Arc::clone(&self.instances[index].0);
+            let (handed_off, remaining) = std::mem::take(&mut self.handed_off)
+                .into_iter()
+                .partition::<Vec<_>, _>(|p| {
+                    p.magento_dir == context.daemon_config.magento_dir && p.consumer == consumer
+                });
+            self.handed_off = remaining;
+            // Adopted consumers are already running, so they don't wait for the stagger
+            if !handed_off.is_empty() {
+                let worker = worker::adopt_worker(&context, &consumer, &handed_off);
+                self.started[index].1.push(worker);
+                continue;
+            }
+            self.stagger.wait();
+            if self.term.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            let worker = worker::run_worker(&context, &consumer)?;
+            self.started[index].1.push(worker);
+        }
+        Ok(())
+    }
+
+    /// The consumers of all instances with the index of their instance, with the highest
+    /// priority first.
+    fn start_order(&self) -> Vec<(usize, String)> {
+        let mut order: Vec<_> = self
+            .instances
+            .iter()
+            .enumerate()
+            .flat_map(|(index, (_, consumers))| consumers.iter().map(move |c| (index, c.clone())))
+            .collect();
+        // Stable, so consumers with the same priority keep the order of their instance
+        order.sort_by_key(|(index, consumer)| {
+            Reverse(
+                self.instances[*index]
+                    .0
+                    .consumer_config
+                    .priority_for(consumer),
+            )
+        });
+        order
+    }
+
+    /// Waits until all started consumers have exited on their own, without restarting them, or
+    /// until `term` is set. Returns whether all consumers exited successfully.
+    pub fn wait_for_completion(&mut self) -> bool {
+        let workers = self
+            .started
+            .iter_mut()
+            .flat_map(|(_, workers)| workers.iter_mut())
+            .collect();
+        wait_for_completion(workers, &self.term)
+    }
+
+    /// Starts supervising the started consumers.
+    pub fn supervise(&mut self) {
+        for (context, workers) in std::mem::take(&mut self.started) {
+            let mut supervisor = Supervisor::new(
+                context,
+                Arc::clone(&self.stagger),
+                Arc::clone(&self.limiter),
+                Arc::clone(&self.budget),
+                Arc::clone(&self.term),
+                Arc::clone(&self.idle),
+                Arc::clone(&self.draining),
+            );
+            for worker in workers {
+                supervisor.start_thread(worker);
+            }
+            self.supervisors.push(supervisor);
+        }
+        self.started_at = Instant::now();
+        self.last_refresh = Instant::now();
+        self.last_reap = Instant::now();
+        self.last_directory_check = Instant::now();
+        self.last_status = Instant::now();
+    }
+
+    /// Does the periodic work of the supervision, and returns whether the daemon should keep
+    /// running. It should be called every `TICK_INTERVAL`.
+    pub fn tick(&mut self) -> bool {
+        match self.check_shutdown() {
+            Some(reason) => {
+                self.shutdown_reason = Some(reason);
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Does the periodic work of `tick`, and returns the reason to stop the daemon, if any.
+    fn check_shutdown(&mut self) -> Option<ShutdownReason> {
+        if self.is_terminating() {
+            return Some(ShutdownReason::Terminated);
+        }
+        // The command line options are shared by all installations
+        let (
+            refresh_interval,
+            idle_shutdown,
+            exit_on_cron_run,
+            status_interval,
+            autoscale_interval,
+            stall_timeout,
+            exit_after_refresh_failures,
+        ) = match self.instances.first() {
+            Some((context, _)) => (
+                context.daemon_config.consumer_refresh_interval,
+                context.daemon_config.idle_shutdown,
+                context.daemon_config.exit_on_cron_run,
+                context.daemon_config.status_interval,
+                context.daemon_config.autoscale_interval,
+                context.daemon_config.stall_timeout,
+                context.daemon_config.exit_after_refresh_failures,
+            ),
+            None => (Duration::ZERO, None, false, None, None, None, None),
+        };
+        let consumer_list_file = self
+            .instances
+            .first()
+            .and_then(|(context, _)| context.daemon_config.consumer_list_file.clone());
+        let min_running = self.instances.first().and_then(|(context, _)| {
+            let config = &context.daemon_config;
+            let percent = config.min_running_percent?;
+            Some((percent, config.min_running_grace, config.exit_when_degraded))
+        });
+
+        if let Some(ref health) = self.health {
+            if self
+                .last_health_update
+                .is_none_or(|t: Instant| t.elapsed() >= HEALTH_UPDATE_INTERVAL)
+            {
+                let (running, expected) = self
+                    .supervisors
+                    .iter()
+                    .map(Supervisor::process_counts)
+                    .fold((0, 0), |(r, e), (running, expected)| {
+                        (r + running, e + expected)
+                    });
+                health.update(running, expected);
+                // Only ready after the first update, so it's not considered stalled
+                health.set_ready(true);
+                self.last_health_update = Some(Instant::now());
+            }
+        }
+        let draining = self.is_draining();
+        // The running processes decrease on purpose while draining
+        if let Some((percent, grace, exit_when_degraded)) = min_running.filter(|_| !draining) {
+            if self.last_running_check.elapsed() >= RUNNING_CHECK_INTERVAL {
+                self.last_running_check = Instant::now();
+                if self.check_degraded(percent, grace) && exit_when_degraded {
+                    log::error!(
+                        "Stopping because the running consumer processes stayed below --min-running-percent, see --exit-when-degraded"
+                    );
+                    return Some(ShutdownReason::Degraded);
+                }
+            }
+        }
+        if let Some(status_interval) = status_interval {
+            if self.last_status.elapsed() >= status_interval {
+                self.log_status_line();
+                self.last_status = Instant::now();
+            }
+        }
+        self.limiter
+            .set_consumers(self.supervisors.iter().map(|s| s.threads.len()).sum());
+        self.limiter.check_storm();
+        if self.last_reap.elapsed() >= REAP_INTERVAL {
+            self.reaper.reap(&self.supervisors);
+            self.last_reap = Instant::now();
+        }
+        if self.last_directory_check.elapsed() >= DIRECTORY_CHECK_INTERVAL {
+            // For example an old release removed by a deployment, as the directory is resolved
+            // once at startup
+            for (context, _) in self.instances.iter() {
+                let magento_dir = &context.daemon_config.magento_dir;
+                if !std::path::Path::new(magento_dir)
+                    .join("bin/magento")
+                    .exists()
+                {
+                    log::error!(
+                        "Stopping because the Magento bin was removed from {}",
+                        magento_dir
+                    );
+                    return Some(ShutdownReason::MagentoDirRemoved);
+                }
+            }
+            self.last_directory_check = Instant::now();
+        }
+        if draining {
+            return self.check_drained();
+        }
+        if let Some(path) = consumer_list_file {
+            if self.last_consumer_list_check.elapsed() >= CONSUMER_LIST_FILE_CHECK_INTERVAL {
+                let stamp = file_stamp(&path);
+                if stamp != self.consumer_list_stamp {
+                    self.consumer_list_stamp = stamp;
+                    self.reload_consumer_list(&path);
+                }
+                self.last_consumer_list_check = Instant::now();
+            }
+        }
+        for supervisor in self.supervisors.iter_mut() {
+            supervisor.refresh_unknown_consumers();
+        }
+        if !refresh_interval.is_zero() && self.last_refresh.elapsed() >= refresh_interval {
+            for supervisor in self.supervisors.iter_mut() {
+                if supervisor.refresh_due() {
+                    supervisor.refresh_consumers();
+                }
+            }
+            self.last_refresh = Instant::now();
+        }
+        if let Some(autoscale_interval) = autoscale_interval {
+            if self.last_autoscale.elapsed() >= autoscale_interval {
+                for supervisor in self.supervisors.iter_mut() {
+                    supervisor.autoscale();
+                }
+                self.last_autoscale = Instant::now();
+            }
+        }
+        if let Some(stall_timeout) = stall_timeout {
+            if self.last_stall_check.elapsed() >= STALL_CHECK_INTERVAL {
+                for supervisor in self.supervisors.iter_mut() {
+                    supervisor.restart_stalled(stall_timeout);
+                }
+                self.last_stall_check = Instant::now();
+            }
+        }
+        if self.budget.is_exhausted() {
+            log::info!(
+                "Processed about {} messages, reaching --max-total-messages, shutting down",
+                self.budget.processed()
+            );
+            return Some(ShutdownReason::MessageBudgetExhausted);
+        }
+        if let Some(idle_shutdown) = idle_shutdown {
+            let drained_since = self
+                .supervisors
+                .iter()
+                .map(|s| s.drained_since(self.started_at))
+                .try_fold(self.started_at, |since, s| Some(since.max(s?)));
+            self.idle.store(drained_since.is_some(), Ordering::Relaxed);
+            if drained_since.is_some_and(|since| since.elapsed() >= idle_shutdown) {
+                log::info!(
+                    "All consumers have been idle for {}, shutting down",
+                    format_duration(idle_shutdown)
+                );
+                return Some(ShutdownReason::Idle);
+            }
+        }
+        if exit_on_cron_run && self.supervisors.iter().any(|s| s.cron_run) {
+            log::error!(
+                "Stopping because the Magento cron worker was enabled, see --exit-on-cron-run"
+            );
+            return Some(ShutdownReason::CronRunEnabled);
+        }
+        if let Some(max_failures) = exit_after_refresh_failures {
+            let failed = self
+                .supervisors
+                .iter()
+                .find(|s| s.refresh_failures >= max_failures && s.process_counts().0 == 0);
+            if let Some(supervisor) = failed {
+                log::error!(
+                    "Stopping because refreshing the consumers of {} failed {} times in a row and none of them is running, see --exit-after-refresh-failures",
+                    supervisor.context.daemon_config.magento_dir,
+                    supervisor.refresh_failures
+                );
+                return Some(ShutdownReason::RefreshFailed);
+            }
+        }
+        while let Some(request) = self.control.as_ref().and_then(|c| c.try_recv().ok()) {
+            let response = self.handle_command(request.command);
+            // The client may have disconnected in the meantime, which is fine.
+            let _ = request.reply.send(response);
+        }
+        self.continue_rolling_restart();
+        None
+    }
+
+    /// Does the periodic work of `tick` while draining, instead of refreshing, scaling and
+    /// restarting the consumers. Logs how many processes are still running when that changed,
+    /// and returns `ShutdownReason::Drained` once none is. The control socket is still handled.
+    fn check_drained(&mut self) -> Option<ShutdownReason> {
+        let running = self.supervisors.iter().map(|s| s.process_counts().0).sum();
+        if running == 0 {
+            log::info!("All consumer processes exited while draining, shutting down");
+            return Some(ShutdownReason::Drained);
+        }
+        if self.draining_processes != Some(running) {
+            log::info!("Draining, {} consumer processes are still running", running);
+            self.draining_processes = Some(running);
+        }
+        while let Some(request) = self.control.as_ref().and_then(|c| c.try_recv().ok()) {
+            let response = self.handle_command(request.command);
+            let _ = request.reply.send(response);
+        }
+        None
+    }
+
+    /// Applies the changed `--consumer-list-file` to the consumers of every installation. When
+    /// it can't be read, or was removed, the consumers keep running as they are.
+    fn reload_consumer_list(&mut self, path: &Path) {
+        match ConsumerList::read(path) {
+            Ok(Some(consumer_list)) => {
+                log::info!("Consumer list file {} changed, applying it", path.display());
+                for supervisor in self.supervisors.iter_mut() {
+                    supervisor.set_consumer_list(Some(consumer_list.clone()));
+                }
+            }
+            Ok(None) => log::warn!(
+                "Consumer list file {} was removed, keeping the consumers as they are",
+                path.display()
+            ),
+            Err(err) => log::error!("{}, keeping the consumers as they are", err.message),
+        }
+    }
+
+    /// Handles a control command. Consumers can be given as `<instance>:<consumer>` to select the
+    /// consumer of a single Magento installation, and otherwise the command applies to the
+    /// consumer of every installation.
+    fn handle_command(&mut self, command: Command) -> Response {
+        if self.is_draining()
+            && matches!(
+                command,
+                Command::Restart(_) | Command::Resume(_) | Command::Reload | Command::RestartAll
+            )
+        {
+            return Response::error(
+                "The daemon is draining, so the consumers aren't started anymore".to_owned(),
+            );
+        }
+        let supervisors = &mut self.supervisors;
+        match command {
+            Command::Status => {
+                Response::status(supervisors.iter().flat_map(|s| s.statuses()).collect())
+            }
+            Command::Restart(target) => {
+                for_consumer(supervisors, &target, |supervisor, consumer| {
+                    supervisor.restart(consumer)
+                })
+            }
+            Command::Stop(target) => for_consumer(supervisors, &target, |supervisor, consumer| {
+                supervisor.stop(consumer)
+            }),
+            Command::Pause(target) if target == ALL_CONSUMERS => {
+                for_all(supervisors, Supervisor::pause_all)
+            }
+            Command::Pause(target) => for_consumer(supervisors, &target, |supervisor, consumer| {
+                supervisor.pause(consumer)
+            }),
+            Command::Resume(target) if target == ALL_CONSUMERS => {
+                for_all(supervisors, Supervisor::resume_all)
+            }
+            Command::Resume(target) => {
+                for_consumer(supervisors, &target, |supervisor, consumer| {
+                    supervisor.resume(consumer)
+                })
+            }
+            Command::Reload => {
+                for supervisor in supervisors.iter_mut() {
+                    supervisor.refresh_consumers();
+                }
+                Response::success()
+            }
+            Command::RestartAll => self.start_rolling_restart(),
+            Command::Drain => {
+                self.drain();
+                Response::success()
+            }
+        }
+    }
+
+    /// Starts restarting all running consumers one at a time, with the startup stagger, so the
+    /// consumers don't all stop at the same time. The restarts are done by `tick`.
+    fn start_rolling_restart(&mut self) -> Response {
+        if !self.rolling_restart.is_empty() {
+            return Response::error("A rolling restart is already in progress".to_owned());
+        }
+        let mut order: Vec<_> = self
+            .supervisors
+            .iter()
+            .enumerate()
+            .flat_map(|(index, s)| s.threads.iter().map(move |t| (index, t.consumer.clone())))
+            .collect();
+        order.sort_by_key(|(index, consumer)| {
+            Reverse(
+                self.supervisors[*index]
+                    .context
+                    .consumer_config
+                    .priority_for(consumer),
+            )
+        });
+        self.rolling_restart.extend(order);
+        log::info!(
+            "Rolling restart of {} consumers initiated",
+            self.rolling_restart.len()
+        );
+        if self.rolling_restart.is_empty() {
+            log::info!("Rolling restart completed");
+        }
+        Response::success()
+    }
+
+    /// Restarts the next consumer of the rolling restart.
+    fn continue_rolling_restart(&mut self) {
+        let (index, consumer) = match self.rolling_restart.pop_front() {
+            Some(next) => next,
+            None => return,
+        };
+        let supervisor = &mut self.supervisors[index];
+        // Consumers stopped or removed in the meantime are skipped
+        if supervisor.threads.iter().any(|t| t.consumer == consumer) {
+            self.stagger.wait();
+            // A consumer that fails to start is retried by its supervisor thread
+            let _ = supervisor.restart(&consumer);
+        }
+        if self.rolling_restart.is_empty() {
+            log::info!("Rolling restart completed");
+        }
+    }
+
+    /// Refreshes the consumers of every installation, like the `reload` command.
+    pub fn reload(&mut self) {
+        if self.is_draining() {
+            log::warn!("Not refreshing the consumers while draining");
+            return;
+        }
+        log::info!("Refreshing the consumers");
+        self.handle_command(Command::Reload);
+    }
+
+    /// Restarts all consumers one at a time, like the `restart-all` command.
+    pub fn restart_all(&mut self) {
+        if self.is_draining() {
+            log::warn!("Not restarting the consumers while draining");
+            return;
+        }
+        if !self.rolling_restart.is_empty() {
+            log::warn!("A rolling restart is already in progress");
+            return;
+        }
+        self.handle_command(Command::RestartAll);
+    }
+
+    /// Drains the daemon, like the `drain` command: the consumers aren't restarted, refreshed or
+    /// scaled anymore, and the running processes finish their batch and exit on their own. Once
+    /// all of them exited, `tick` stops the daemon with `ShutdownReason::Drained`. Unlike
+    /// `term`, no process is terminated, and a rolling restart in progress is cancelled.
+    pub fn drain(&mut self) {
+        if self.draining.swap(true, Ordering::Relaxed) {
+            log::info!("The daemon is already draining");
+            return;
+        }
+        self.rolling_restart.clear();
+        log_event!(
+            log::Level::Info,
+            Event::new("drain"),
+            "Draining: not restarting the consumers anymore, and shutting down once their processes exited"
+        );
+    }
+
+    /// Logs the status of every consumer.
+    pub fn log_status(&self) {
+        log_status(
+            &self.supervisors,
+            self.started_at.elapsed(),
+            self.is_draining(),
+        );
+    }
+
+    /// Logs everything the daemon knows about its state on `SIGQUIT`, for debugging a daemon that
+    /// seems stuck: the supervisor threads and how long they've been busy, every process with
+    /// its resource usage, and the last exits of every consumer. Busy consumers aren't waited
+    /// for, so the dump is logged even when a consumer check hangs.
+    pub fn dump(&self) {
+        let (restarts, throttled) = self.limiter.recent_restarts();
+        log_event!(
+            log::Level::Warn,
+            Event::new("dump"),
+            "Diagnostic dump of daemon {}: up {}, supervising {} Magento installations, {} restarts in the last minute{}, {} restart storms",
+            std::process::id(),
+            format_duration(self.started_at.elapsed()),
+            self.supervisors.len(),
+            restarts,
+            if throttled { " (throttled)" } else { "" },
+            self.limiter.storms()
+        );
+        if !self.rolling_restart.is_empty() {
+            log::warn!(
+                "  Rolling restart in progress, {} consumers remaining",
+                self.rolling_restart.len()
+            );
+        }
+        for supervisor in self.supervisors.iter() {
+            supervisor.dump();
+        }
+        log::warn!("End of the diagnostic dump");
+    }
+
+    /// Tracks whether fewer than `min_percent` of the expected consumer processes are running,
+    /// and returns whether that lasted for `grace`, like when most consumers keep crashing or
+    /// fail to start. A short dip, like during a restart, isn't reported.
+    fn check_degraded(&mut self, min_percent: u32, grace: Duration) -> bool {
+        let (running, expected) = self
+            .supervisors
+            .iter()
+            .map(Supervisor::process_counts)
+            .fold((0, 0), |(r, e), (running, expected)| {
+                (r + running, e + expected)
+            });
+        if running * 100 >= expected * min_percent as usize {
+            if self.degraded {
+                log::info!(
+                    "{} of {} consumer processes are running again, no longer below --min-running-percent {}",
+                    running,
+                    expected,
+                    min_percent
+                );
+            }
+            self.degraded_since = None;
+            self.degraded = false;
+            return false;
+        }
+        let since = *self.degraded_since.get_or_insert_with(Instant::now);
+        if since.elapsed() < grace {
+            return false;
+        }
+        if !self.degraded {
+            log_event!(
+                log::Level::Error,
+                Event::new("degraded"),
+                "Only {} of {} consumer processes have been running for {}, below --min-running-percent {}",
+                running,
+                expected,
+                format_duration(since.elapsed()),
+                min_percent
+            );
+            self.degraded = true;
+        }
+        true
+    }
+
+    /// Logs the status of every consumer as a single JSON line, for `--status-interval`. In the
+    /// JSON log format the line is the message of a record with the `status` event.
+    fn log_status_line(&self) {
+        let status = StatusLine {
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            consumers: self.supervisors.iter().flat_map(|s| s.statuses()).collect(),
+            restart_storms: self.limiter.storms(),
+            refresh_failures: self
+                .supervisors
+                .iter()
+                .map(|s| s.refresh_failures)
+                .max()
+                .unwrap_or(0),
+            degraded: self.degraded,
+            draining: self.is_draining(),
+        };
+        if let Ok(line) = serde_json::to_string(&status) {
+            log_event!(log::Level::Info, Event::new("status"), "{}", line);
+        }
+    }
+
+    /// Replaces the daemon with a new process of `exe`, typically an upgraded binary installed at
+    /// the same path, which adopts the running consumer processes instead of starting them again,
+    /// see `handoff`. The supervision is paused while the processes are handed off, so none of
+    /// them is restarted in the meantime. Only returns when the upgrade failed, after which the
+    /// consumers are supervised again.
+    pub fn upgrade(&mut self, exe: &Path) {
+        // The new daemon would restart the consumers again
+        if self.is_draining() {
+            log::warn!("Not upgrading the daemon while draining");
+            return;
+        }
+        log::info!(
+            "Upgrading the daemon to {}, handing off the consumers",
+            exe.display()
+        );
+        let mut paused: Vec<Vec<WorkerProcess>> = self
+            .supervisors
+            .iter_mut()
+            .map(|s| {
+                std::mem::take(&mut s.threads)
+                    .into_iter()
+                    .filter_map(SupervisorThread::join)
+                    .collect()
+            })
+            .collect();
+        let processes: Vec<HandedOffProcess> = self
+            .supervisors
+            .iter()
+            .zip(paused.iter_mut())
+            .flat_map(|(supervisor, workers)| {
+                let config = &supervisor.context.daemon_config;
+                workers.iter_mut().flat_map(move |w| w.hand_off(config))
+            })
+            .collect();
+        let err = handoff::exec(exe, &processes);
+        log::error!("Failed to upgrade the daemon to {}: {}", exe.display(), err);
+        for (supervisor, workers) in self.supervisors.iter_mut().zip(paused) {
+            for worker in workers {
+                supervisor.start_thread(worker);
+            }
+        }
+    }
+
+    /// Stops all consumers. They share a grace period of `--shutdown-timeout` to finish their
+    /// current message, after which the remaining ones are killed. Returns why the daemon
+    /// stopped, which is `Terminated` when it wasn't stopped by `tick`.
+    pub fn shutdown(mut self) -> ShutdownReason {
+        if let Some(ref health) = self.health {
+            health.set_ready(false);
+        }
+        let mut workers: Vec<WorkerProcess> = std::mem::take(&mut self.started)
+            .into_iter()
+            .flat_map(|(_, workers)| workers)
+            .collect();
+        if !self.supervisors.is_empty() {
+            let supervised: Vec<WorkerProcess> = std::mem::take(&mut self.supervisors)
+                .into_iter()
+                .flat_map(Supervisor::join)
+                .collect();
+            log::info!("Stopping {} consumers", supervised.len());
+            workers.extend(supervised);
+        }
+        let consumers: Vec<ConsumerReport> = workers.iter().map(ConsumerReport::new).collect();
+        let processes = worker::drain_workers(&mut workers, self.shutdown_timeout());
+        let reason = self.shutdown_reason.unwrap_or(ShutdownReason::Terminated);
+        if !consumers.is_empty() {
+            self.log_shutdown_report(reason, consumers, processes);
+        }
+        reason
+    }
+
+    /// Logs the summary of the run as a single JSON line: the restarts of every consumer, which
+    /// of them were backing off or down, and how the processes stopped. In the JSON log format
+    /// the line is the message of a record with the `shutdown_report` event.
+    fn log_shutdown_report(
+        &self,
+        reason: ShutdownReason,
+        consumers: Vec<ConsumerReport>,
+        processes: StopSummary,
+    ) {
+        let report = ShutdownReport {
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            reason,
+            exit_code: reason.exit_code(),
+            restarts: consumers.iter().map(|c| c.restarts).sum(),
+            restart_storms: self.limiter.storms(),
+            consumers,
+            processes,
+        };
+        if let Ok(line) = serde_json::to_string(&report) {
+            log_event!(log::Level::Info, Event::new("shutdown_report"), "{}", line);
+        }
+    }
+}
+
+/// The modification time and size of the file, or `None` when it doesn't exist.
+fn file_stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Checks the worker with `check` every supervision interval, until `is_stopping` returns true.
+fn supervise_worker(
+    context: &DaemonContext,
+    worker: &Mutex<WorkerProcess>,
+    busy_since: &Mutex<Option<Instant>>,
+    is_stopping: impl Fn() -> bool,
+    mut check: impl FnMut(&mut WorkerProcess),
+) {
+    logging::set_context(
+        context.daemon_config.instance.as_deref(),
+        worker.lock().unwrap_or_else(|e| e.into_inner()).consumer(),
+        None,
+    );
+    while !is_stopping() {
+        // If any of the processes have exited, restart them
+        *busy_since.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+        check(&mut worker.lock().unwrap_or_else(|e| e.into_inner()));
+        *busy_since.lock().unwrap_or_else(|e| e.into_inner()) = None;
+
+        let tick_start = Instant::now();
+        while tick_start.elapsed() < SUPERVISION_INTERVAL && !is_stopping() {
+            thread::sleep(TERM_POLL_RESOLUTION);
+        }
+    }
+}
+
+/// Waits until all workers have exited on their own, without restarting them, or until `term` is
+/// set. Returns whether all workers exited successfully.
+fn wait_for_completion(mut remaining: Vec<&mut WorkerProcess>, term: &AtomicBool) -> bool {
+    let mut success = true;
+    while !remaining.is_empty() {
+        if term.load(Ordering::Relaxed) {
+            log::info!(
+                "Interrupted while waiting for {} consumers",
+                remaining.len()
+            );
+            return false;
+        }
+        remaining.retain_mut(|worker| match worker.exit_success() {
+            Some(true) => {
+                log::info!("Consumer {} completed", worker.consumer());
+                false
+            }
+            Some(false) => {
+                log::warn!("Consumer {} exited unsuccessfully", worker.consumer());
+                success = false;
+                false
+            }
+            None => true,
+        });
+        thread::sleep(TERM_POLL_RESOLUTION);
+    }
+    success
+}
@@ -1,10 +1,1198 @@
-use std::process::{Command, ExitStatus};
-
-pub fn terminate_process_child(process: &std::process::Child) -> std::io::Result<ExitStatus> {
-    Command::new("kill")
-        .arg("-SIGTERM")
-        .arg(process.id().to_string())
-        .spawn()
-        .expect("failed to kill process")
-        .wait()
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Read,
+    os::unix::process::{CommandExt, ExitStatusExt},
+    process::{Command, ExitStatus, Output, Stdio},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+const OUTPUT_POLL_RESOLUTION: Duration = Duration::from_millis(20);
+
+pub const BYTES_PER_MB: u64 = 1024 * 1024;
+
+/// Sends SIGTERM to the process group of the child. The child has to be spawned as the leader of
+/// its own process group, see `spawn_in_process_group`.
+pub fn terminate_process_child(pid: u32) -> std::io::Result<()> {
+    signal_process_group(pid, libc::SIGTERM)
+}
+
+/// Sends the signal to the child and its descendants, like a step of `--kill-sequence`.
+pub fn signal_process_child(pid: u32, signal: libc::c_int) -> std::io::Result<()> {
+    signal_process_group(pid, signal)
+}
+
+/// Sends SIGKILL to the process group of the child, so descendants of the child are killed too.
+pub fn kill_process_group(pid: u32) -> std::io::Result<()> {
+    signal_process_group(pid, libc::SIGKILL)
+}
+
+fn signal_process_group(pid: u32, signal: libc::c_int) -> std::io::Result<()> {
+    let pgid = pid as libc::pid_t;
+    // SAFETY: kill has no memory safety preconditions, a negative pid targets the process group
+    if unsafe { libc::kill(-pgid, signal) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Spawns the command as the leader of a new process group, so the process and any processes it
+/// forks can be signalled together.
+pub fn spawn_in_process_group(command: &mut Command) -> std::io::Result<std::process::Child> {
+    command.process_group(0).spawn()
+}
+
+/// Sets the nice value and the CPU affinity of the process when it's spawned. Errors are ignored,
+/// as the spawned process can't report them, so `unapplied_scheduling` checks the result.
+pub fn set_scheduling(command: &mut Command, nice: Option<i32>, cpus: &[usize]) {
+    if nice.is_none() && cpus.is_empty() {
+        return;
+    }
+    #[cfg(target_os = "linux")]
+    let cpu_set = (!cpus.is_empty()).then(|| cpu_set(cpus));
+    // SAFETY: the closure only makes system calls, which is safe between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            if let Some(nice) = nice {
+                libc::setpriority(libc::PRIO_PROCESS as _, 0, nice);
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(ref cpu_set) = cpu_set {
+                libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), cpu_set);
+            }
+            Ok(())
+        });
+    }
+}
+
+/// The user and groups to run the Magento processes as, see `--run-as-user`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    // The supplementary groups, only set when the daemon runs as root
+    pub groups: Vec<u32>,
+    // The name and home directory of the user, set in the environment of the processes
+    pub user: Option<(String, String)>,
+}
+
+/// Looks up the user and the group to run the Magento processes as. Without a group the primary
+/// group of the user is used, and without a user only the group is changed. Fails when either
+/// doesn't exist, or when the daemon isn't allowed to switch to them.
+pub fn resolve_credentials(user: Option<&str>, group: Option<&str>) -> Result<Credentials, String> {
+    // SAFETY: geteuid and getegid have no memory safety preconditions
+    let (euid, egid) = unsafe { (libc::geteuid(), libc::getegid()) };
+    let mut credentials = Credentials {
+        uid: euid,
+        gid: egid,
+        groups: Vec::new(),
+        user: None,
+    };
+    if let Some(name) = user {
+        let (uid, gid, home) = lookup_user(name)
+            .ok_or_else(|| format!("user {} given with --run-as-user not found", name))?;
+        credentials.uid = uid;
+        credentials.gid = gid;
+        credentials.user = Some((name.to_owned(), home));
+    }
+    if let Some(name) = group {
+        credentials.gid = lookup_group(name)
+            .ok_or_else(|| format!("group {} given with --run-as-group not found", name))?;
+    }
+    if euid != 0 && (credentials.uid != euid || credentials.gid != egid) {
+        return Err(format!(
+            "the daemon has to run as root to run the consumers as {}",
+            user.or(group).unwrap_or_default()
+        ));
+    }
+    if euid == 0 {
+        credentials.groups = match user {
+            Some(name) => user_groups(name, credentials.gid),
+            None => vec![credentials.gid],
+        };
+    }
+    Ok(credentials)
+}
+
+/// The UID, primary GID and home directory of the user, by name or numeric ID.
+fn lookup_user(name: &str) -> Option<(u32, u32, String)> {
+    let c_name = std::ffi::CString::new(name).ok()?;
+    let mut buf = vec![0 as libc::c_char; 16384];
+    // SAFETY: passwd is a plain struct, and getpwnam_r and getpwuid_r only write to it and to
+    // the buffer, of which the size is passed
+    unsafe {
+        let mut passwd: libc::passwd = std::mem::zeroed();
+        let mut result = std::ptr::null_mut();
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        );
+        if result.is_null() {
+            if let Ok(uid) = name.parse() {
+                libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result);
+            }
+        }
+        if result.is_null() {
+            return None;
+        }
+        let home = std::ffi::CStr::from_ptr(passwd.pw_dir)
+            .to_string_lossy()
+            .into_owned();
+        Some((passwd.pw_uid, passwd.pw_gid, home))
+    }
+}
+
+/// The GID of the group, by name or numeric ID.
+fn lookup_group(name: &str) -> Option<u32> {
+    let c_name = std::ffi::CString::new(name).ok()?;
+    let mut buf = vec![0 as libc::c_char; 16384];
+    // SAFETY: group is a plain struct, and getgrnam_r only writes to it and to the buffer, of
+    // which the size is passed
+    unsafe {
+        let mut group: libc::group = std::mem::zeroed();
+        let mut result = std::ptr::null_mut();
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut group,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        );
+        if !result.is_null() {
+            return Some(group.gr_gid);
+        }
+    }
+    name.parse().ok()
+}
+
+/// The groups the user is a member of, including `gid`.
+#[cfg(target_os = "linux")]
+fn user_groups(name: &str, gid: u32) -> Vec<u32> {
+    let c_name = match std::ffi::CString::new(name) {
+        Ok(c_name) => c_name,
+        Err(_) => return vec![gid],
+    };
+    let mut groups = vec![0; 256];
+    let mut count = groups.len() as libc::c_int;
+    // SAFETY: getgrouplist writes at most `count` groups, and updates it to the number of groups
+    if unsafe { libc::getgrouplist(c_name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) } == -1 {
+        groups.resize(count as usize, 0);
+        // SAFETY: as above, with a buffer of the size getgrouplist asked for
+        unsafe { libc::getgrouplist(c_name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+    }
+    groups.truncate(count.max(0) as usize);
+    groups
+}
+
+/// Reading the supplementary groups is only supported on Linux.
+#[cfg(all(unix, not(target_os = "linux")))]
+fn user_groups(_name: &str, gid: u32) -> Vec<u32> {
+    vec![gid]
+}
+
+/// Runs the process as the user and groups. Failing to switch fails the spawn, so a process
+/// never runs with the privileges of the daemon by accident.
+pub fn set_credentials(command: &mut Command, credentials: &Credentials) {
+    if let Some((ref user, ref home)) = credentials.user {
+        command
+            .env("USER", user)
+            .env("LOGNAME", user)
+            .env("HOME", home);
+    }
+    let credentials = credentials.clone();
+    // SAFETY: the closure only makes system calls, which is safe between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            // The groups first, as changing them requires the privileges that setuid drops
+            if !credentials.groups.is_empty()
+                && libc::setgroups(credentials.groups.len() as _, credentials.groups.as_ptr()) == -1
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            if libc::setgid(credentials.gid) == -1 || libc::setuid(credentials.uid) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Returns the scheduling settings the process didn't get, for example because the daemon isn't
+/// allowed to lower the nice value, or because the CPUs aren't available.
+pub fn unapplied_scheduling(pid: u32, nice: Option<i32>, cpus: &[usize]) -> Vec<&'static str> {
+    let mut unapplied = Vec::new();
+    if let Some(nice) = nice {
+        // SAFETY: getpriority has no memory safety preconditions
+        if unsafe { libc::getpriority(libc::PRIO_PROCESS as _, pid as libc::id_t) } != nice {
+            unapplied.push("nice value");
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if !cpus.is_empty() {
+        // SAFETY: cpu_set_t is a plain bit set, and sched_getaffinity only writes to it
+        let applied = unsafe {
+            let mut actual: libc::cpu_set_t = std::mem::zeroed();
+            libc::sched_getaffinity(
+                pid as libc::pid_t,
+                std::mem::size_of::<libc::cpu_set_t>(),
+                &mut actual,
+            ) == 0
+                && libc::CPU_EQUAL(&actual, &cpu_set(cpus))
+        };
+        if !applied {
+            unapplied.push("CPU affinity");
+        }
+    }
+    // Setting the CPU affinity is only supported on Linux
+    #[cfg(not(target_os = "linux"))]
+    if !cpus.is_empty() {
+        unapplied.push("CPU affinity");
+    }
+    unapplied
+}
+
+/// The CPUs must be below `CPU_SETSIZE`, which `--cpu-affinity` checks.
+#[cfg(target_os = "linux")]
+fn cpu_set(cpus: &[usize]) -> libc::cpu_set_t {
+    // SAFETY: cpu_set_t is a plain bit set, which is valid when zeroed
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for cpu in cpus {
+            libc::CPU_SET(*cpu, &mut set);
+        }
+        set
+    }
+}
+
+/// Describes why starting a process or thread failed, with a hint when it ran into a limit of the
+/// operating system, like the number of open files.
+pub fn describe_spawn_error(err: &std::io::Error) -> String {
+    let hint = match err.raw_os_error() {
+        Some(libc::EMFILE) => Some(
+            "the daemon ran out of file descriptors, raise the limit with `ulimit -n` or --raise-fd-limit",
+        ),
+        Some(libc::ENFILE) => Some("the system ran out of file descriptors, see fs.file-max"),
+        Some(libc::EAGAIN) => {
+            Some("the daemon ran into the process or thread limit, raise it with `ulimit -u`")
+        }
+        Some(libc::EACCES) => {
+            Some("check that bin/magento is executable and the mount options of the Magento directory")
+        }
+        _ => None,
+    };
+    match hint {
+        Some(hint) => format!("{}, {}", err, hint),
+        None => err.to_string(),
+    }
+}
+
+/// Whether starting a process or thread failed on a limit of the operating system, which may go
+/// away when other processes exit.
+pub fn is_resource_limit(err: &std::io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::EAGAIN) | Some(libc::ENOMEM)
+    )
+}
+
+/// Raises the soft limit of open file descriptors to the hard limit, and returns the previous
+/// and the new limit.
+pub fn raise_fd_limit() -> std::io::Result<(u64, u64)> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit only writes to the passed struct
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let previous = limit.rlim_cur;
+    if limit.rlim_cur < limit.rlim_max {
+        limit.rlim_cur = limit.rlim_max;
+        // SAFETY: setrlimit only reads the passed struct
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok((previous, limit.rlim_cur))
+}
+
+/// Runs the command like `Command::output`, but kills it when it doesn't finish within `timeout`.
+pub fn output_with_timeout(command: &mut Command, timeout: Duration) -> std::io::Result<Output> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Read the output on separate threads, so a full pipe doesn't block the process.
+    fn read_all<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+        std::thread::spawn(move || {
+            let mut buffer = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut buffer);
+            }
+            buffer
+        })
+    }
+    let stdout = read_all(child.stdout.take());
+    let stderr = read_all(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("timed out after {}s", timeout.as_secs_f32()),
+            ));
+        }
+        std::thread::sleep(OUTPUT_POLL_RESOLUTION);
+    };
+
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// Returns the resident set size of the process in bytes, read from `/proc/<pid>/statm`.
+#[cfg(target_os = "linux")]
+pub fn process_rss_bytes(pid: u32) -> std::io::Result<u64> {
+    let statm = std::fs::read_to_string(format!("/proc/{}/statm", pid))?;
+    // The second field is the resident set size in pages
+    let pages: u64 = statm
+        .split_whitespace()
+        .nth(1)
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid statm"))?;
+    // SAFETY: sysconf has no preconditions
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Ok(pages * page_size as u64)
+}
+
+/// Reading the memory usage of a process is only supported on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn process_rss_bytes(_pid: u32) -> std::io::Result<u64> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Reading process memory usage is only supported on Linux",
+    ))
+}
+
+/// Returns the CPU time the process used in clock ticks, in user and kernel mode together, read
+/// from `/proc/<pid>/stat`.
+#[cfg(target_os = "linux")]
+pub fn process_cpu_ticks(pid: u32) -> std::io::Result<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid stat");
+    // The fields after the command name start at the state, so utime and stime are the 12th and
+    // 13th of them
+    let fields: Vec<&str> = stat[stat.rfind(')').ok_or_else(invalid)? + 1..]
+        .split_whitespace()
+        .collect();
+    let ticks = |i: usize| -> std::io::Result<u64> {
+        fields
+            .get(i)
+            .and_then(|x| x.parse().ok())
+            .ok_or_else(invalid)
+    };
+    Ok(ticks(11)? + ticks(12)?)
+}
+
+/// Reading the CPU time of a process is only supported on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn process_cpu_ticks(_pid: u32) -> std::io::Result<u64> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Reading process CPU time is only supported on Linux",
+    ))
+}
+
+/// Returns the CPU time the process used, in user and kernel mode together.
+pub fn process_cpu_time(pid: u32) -> std::io::Result<Duration> {
+    let ticks = process_cpu_ticks(pid)?;
+    // SAFETY: sysconf has no preconditions
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks_per_second <= 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(Duration::from_secs_f64(
+        ticks as f64 / ticks_per_second as f64,
+    ))
+}
+
+/// Returns the PIDs of the child processes of the daemon that have exited but were not reaped,
+/// read from `/proc/<pid>/stat`.
+#[cfg(target_os = "linux")]
+pub fn zombie_children() -> std::io::Result<Vec<u32>> {
+    let own_pid = std::process::id();
+    let mut zombies = Vec::new();
+    for pid in process_ids()? {
+        if let Some((state, ppid)) = process_stat(pid) {
+            if state == "Z" && ppid == own_pid {
+                zombies.push(pid);
+            }
+        }
+    }
+    Ok(zombies)
+}
+
+/// Returns the PIDs of the `bin/magento queue:consumers:start` processes running in the Magento
+/// directory that aren't children of a running daemon, like the consumers left behind by a
+/// daemon that was killed. Read from `/proc`.
+#[cfg(target_os = "linux")]
+pub fn orphaned_consumers(magento_dir: &str) -> std::io::Result<Vec<u32>> {
+    let own_pid = std::process::id();
+    let own_exe = std::fs::read_link("/proc/self/exe")?;
+    let mut orphans = Vec::new();
+    for pid in process_ids()? {
+        // Any of these fail when the process disappeared in the meantime
+        let cmdline = match std::fs::read(format!("/proc/{}/cmdline", pid)) {
+            Ok(cmdline) => cmdline,
+            Err(_) => continue,
+        };
+        let args: Vec<&[u8]> = cmdline.split(|x| *x == 0).collect();
+        let is_consumer = args.iter().any(|x| x.ends_with(b"bin/magento"))
+            && args.iter().any(|x| *x == b"queue:consumers:start");
+        let in_magento_dir = std::fs::read_link(format!("/proc/{}/cwd", pid))
+            .is_ok_and(|cwd| cwd == std::path::Path::new(magento_dir));
+        if !is_consumer || !in_magento_dir {
+            continue;
+        }
+        let ppid = match process_stat(pid) {
+            Some((_, ppid)) => ppid,
+            None => continue,
+        };
+        // The consumers of a running daemon, like this one, are left alone
+        let parent_exe = std::fs::read_link(format!("/proc/{}/exe", ppid)).ok();
+        if ppid != own_pid && parent_exe.as_ref() != Some(&own_exe) {
+            orphans.push(pid);
+        }
+    }
+    Ok(orphans)
+}
+
+/// Finding orphaned consumer processes is only supported on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn orphaned_consumers(_magento_dir: &str) -> std::io::Result<Vec<u32>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Finding orphaned consumer processes is only supported on Linux",
+    ))
+}
+
+/// Sends the signal to the process, and to its process group when it leads one, so processes it
+/// forked are signalled too. For processes that aren't children of the daemon.
+pub fn signal_process(pid: u32, signal: libc::c_int) -> std::io::Result<()> {
+    let pid = pid as libc::pid_t;
+    // SAFETY: getpgid and kill have no memory safety preconditions
+    let target = if unsafe { libc::getpgid(pid) } == pid {
+        -pid
+    } else {
+        pid
+    };
+    if unsafe { libc::kill(target, signal) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether the process is running, for processes that aren't children of the daemon. Exited
+/// processes that weren't reaped by their parent yet don't count as running.
+pub fn process_running(pid: u32) -> bool {
+    // SAFETY: kill with signal 0 only checks whether the process exists
+    let exists = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    #[cfg(target_os = "linux")]
+    let exists = exists && !matches!(process_stat(pid), Some((state, _)) if state == "Z");
+    exists
+}
+
+/// The PIDs of all processes, read from `/proc`.
+#[cfg(target_os = "linux")]
+fn process_ids() -> std::io::Result<Vec<u32>> {
+    let mut pids = Vec::new();
+    for entry in std::fs::read_dir("/proc")? {
+        if let Some(pid) = entry?
+            .file_name()
+            .to_str()
+            .and_then(|x| x.parse::<u32>().ok())
+        {
+            pids.push(pid);
+        }
+    }
+    Ok(pids)
+}
+
+/// The state and the parent PID of the process, read from `/proc/<pid>/stat`, or `None` when the
+/// process disappeared in the meantime.
+#[cfg(target_os = "linux")]
+fn process_stat(pid: u32) -> Option<(String, u32)> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name is wrapped in parentheses and may contain spaces, so the fields are read
+    // after the last one: the state and the parent PID.
+    let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace();
+    let state = fields.next()?.to_owned();
+    let ppid = fields.next()?.parse().ok()?;
+    Some((state, ppid))
+}
+
+/// Finding exited child processes is only supported on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn zombie_children() -> std::io::Result<Vec<u32>> {
+    Ok(Vec::new())
+}
+
+/// Reaps the exited child process, returning whether it was reaped.
+pub fn reap_child(pid: u32) -> bool {
+    let mut status = 0;
+    // SAFETY: waitpid only writes to the status integer, which lives for the duration of the call
+    let result = unsafe { libc::waitpid(pid as libc::pid_t, &mut status, libc::WNOHANG) };
+    result == pid as libc::pid_t
+}
+
+/// Waits for the child process without blocking, and returns its exit status once it exited.
+/// For children that have no `std::process::Child` handle, like the consumers adopted from the
+/// daemon that was replaced by an upgrade.
+pub fn try_wait_child(pid: u32) -> std::io::Result<Option<ExitStatus>> {
+    let mut status = 0;
+    // SAFETY: waitpid only writes to the status integer, which lives for the duration of the call
+    match unsafe { libc::waitpid(pid as libc::pid_t, &mut status, libc::WNOHANG) } {
+        -1 => Err(std::io::Error::last_os_error()),
+        0 => Ok(None),
+        _ => Ok(Some(ExitStatus::from_raw(status))),
+    }
+}
+
+/// Sets whether the file descriptor is closed when the daemon executes another binary.
+pub fn set_close_on_exec(fd: std::os::unix::io::RawFd, close: bool) -> std::io::Result<()> {
+    // SAFETY: fcntl with F_GETFD and F_SETFD only reads and writes the descriptor flags
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let flags = if close {
+        flags | libc::FD_CLOEXEC
+    } else {
+        flags & !libc::FD_CLOEXEC
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } == -1 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Removes the ANSI escape sequences from the text, like the colors and cursor movements of
+/// terminal output.
+pub fn strip_ansi_escapes(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            // Control sequences like colors end with a byte in the range @ to ~
+            Some('[') => {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            // Operating system commands like window titles end with BEL or ESC \
+            Some(']') => {
+                while let Some(c) = chars.next() {
+                    if c == '\x07' {
+                        break;
+                    }
+                    if c == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            // Other escape sequences are two characters long
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Matches `text` against a glob pattern, where `*` matches any sequence of characters and `?`
+/// matches any single character.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern, and the text position it's currently matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            // Let the last `*` match one more character and retry
+            backtrack = Some((star_p, star_t + 1));
+            p = star_p + 1;
+            t = star_t + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Spaces out actions, possibly coming from multiple threads, by at least `interval`.
+#[derive(Debug)]
+pub struct Stagger {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl Stagger {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Blocks until it's this caller's turn.
+    pub fn wait(&self) {
+        if self.interval.is_zero() {
+            return;
+        }
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        std::thread::sleep(slot.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// Limits the rate of restarts across all consumers, over a sliding window of a minute. When
+/// many consumers keep failing, the cause is likely systemic, like the database being down, and
+/// restarting them all the time only adds load.
+///
+/// It also correlates the crashes of the consumers, to report a restart storm when a large part
+/// of the consumers crash within a short window, rather than every single crash.
+#[derive(Debug)]
+pub struct RestartLimiter {
+    // The maximum number of restarts per minute, zero for no limit
+    max_per_minute: u32,
+    // The percentage of the consumers that has to crash within `storm_window` for a restart
+    // storm, zero to not detect them
+    storm_threshold: u32,
+    storm_window: Duration,
+    // The number of supervised consumers, which the storm threshold is a percentage of
+    consumers: AtomicUsize,
+    state: Mutex<RestartLimiterState>,
+}
+
+#[derive(Debug, Default)]
+struct RestartLimiterState {
+    // The times of the restarts in the last minute
+    restarts: VecDeque<Instant>,
+    // Whether restarts are being denied, to only log the changes
+    throttled: bool,
+    // The priority of the consumers that were denied a restart, and when they last asked
+    waiting: HashMap<String, (i32, Instant)>,
+    // The consumers that crashed within the storm window, and when they last did
+    crashes: HashMap<String, Instant>,
+    // Whether a restart storm is going on, to only log the changes
+    storm: bool,
+    // The number of restart storms so far
+    storms: u64,
+}
+
+impl RestartLimiter {
+    const WINDOW: Duration = Duration::from_secs(60);
+    // Consumers that stopped asking for a restart, like stopped ones, no longer hold back others
+    const WAITING_EXPIRY: Duration = Duration::from_secs(1);
+
+    // A storm takes crashes of at least this many consumers, so a daemon running only a few
+    // consumers doesn't report every crash as one
+    const STORM_MIN_CONSUMERS: usize = 3;
+
+    pub fn new(max_per_minute: u32) -> Self {
+        Self {
+            max_per_minute,
+            storm_threshold: 0,
+            storm_window: Duration::ZERO,
+            consumers: AtomicUsize::new(0),
+            state: Mutex::new(RestartLimiterState::default()),
+        }
+    }
+
+    /// Reports a restart storm when `threshold` percent of the consumers crash within `window`.
+    pub fn with_storm_detection(mut self, threshold: u32, window: Duration) -> Self {
+        self.storm_threshold = threshold;
+        self.storm_window = window;
+        self
+    }
+
+    /// Sets the number of supervised consumers, which the storm threshold is a percentage of.
+    pub fn set_consumers(&self, consumers: usize) {
+        self.consumers.store(consumers, Ordering::Relaxed);
+    }
+
+    /// Records a crash of the consumer, and returns whether it's part of a restart storm, in
+    /// which case the single crash isn't worth reporting. The start of a storm is logged once.
+    pub fn record_crash(&self, consumer: &str) -> bool {
+        if self.storm_threshold == 0 {
+            return false;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.crashes.insert(consumer.to_owned(), Instant::now());
+        self.update_storm(&mut state);
+        state.storm
+    }
+
+    /// Logs the end of a restart storm, once the crashes stopped for the storm window.
+    pub fn check_storm(&self) {
+        if self.storm_threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        self.update_storm(&mut state);
+    }
+
+    /// The number of restarts in the last minute, and whether they're being throttled.
+    pub fn recent_restarts(&self) -> (usize, bool) {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let restarts = state
+            .restarts
+            .iter()
+            .filter(|at| at.elapsed() < Self::WINDOW)
+            .count();
+        (restarts, state.throttled)
+    }
+
+    /// The number of restart storms so far.
+    pub fn storms(&self) -> u64 {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).storms
+    }
+
+    fn update_storm(&self, state: &mut RestartLimiterState) {
+        let now = Instant::now();
+        state
+            .crashes
+            .retain(|_, at| now.duration_since(*at) < self.storm_window);
+        let consumers = self.consumers.load(Ordering::Relaxed);
+        let crashed = state.crashes.len();
+        let threshold = (consumers * self.storm_threshold as usize)
+            .div_ceil(100)
+            .max(Self::STORM_MIN_CONSUMERS);
+        if crashed >= threshold && !state.storm {
+            log::error!(
+                "Restart storm: {}/{} consumers crashed within {}, likely an infrastructure outage like the database being down",
+                crashed,
+                consumers.max(crashed),
+                format_duration(self.storm_window)
+            );
+            state.storm = true;
+            state.storms += 1;
+        } else if crashed == 0 && state.storm {
+            log::info!(
+                "Restart storm is over, no consumer crashed for {}",
+                format_duration(self.storm_window)
+            );
+            state.storm = false;
+        }
+    }
+
+    /// Returns whether a restart of the consumer is allowed, and if so, counts it. While restarts
+    /// are throttled, consumers with a higher priority get the restarts first.
+    pub fn try_acquire(&self, consumer: &str, priority: i32) -> bool {
+        if self.max_per_minute == 0 {
+            return true;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        while state
+            .restarts
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= Self::WINDOW)
+        {
+            state.restarts.pop_front();
+        }
+        state
+            .waiting
+            .retain(|_, (_, at)| now.duration_since(*at) < Self::WAITING_EXPIRY);
+
+        let preceded = state
+            .waiting
+            .iter()
+            .any(|(waiting, (p, _))| *p > priority && waiting != consumer);
+        if preceded || state.restarts.len() >= self.max_per_minute as usize {
+            state.waiting.insert(consumer.to_owned(), (priority, now));
+        }
+        if preceded {
+            return false;
+        }
+        if state.restarts.len() >= self.max_per_minute as usize {
+            if !state.throttled {
+                log::warn!(
+                    "More than {} restarts in the last minute, pausing restarts until the rate recovers",
+                    self.max_per_minute
+                );
+                state.throttled = true;
+            }
+            return false;
+        }
+        if state.throttled {
+            log::info!("Restart rate recovered, resuming restarts");
+            state.throttled = false;
+        }
+        state.waiting.remove(consumer);
+        state.restarts.push_back(now);
+        true
+    }
+
+    /// Gives back a restart allowed by `try_acquire` that didn't happen, like when the consumer
+    /// backs off instead, so it doesn't count twice once the consumer is restarted.
+    pub fn release(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.restarts.pop_back();
+    }
+}
+
+/// Counts the messages processed by all consumers, to stop after `--max-total-messages`. The
+/// daemon can't count the messages, so every consumer process that exits successfully is assumed
+/// to have processed its max messages.
+#[derive(Debug)]
+pub struct MessageBudget {
+    // The number of messages to process, `None` for no limit
+    limit: Option<u64>,
+    processed: AtomicU64,
+}
+
+impl MessageBudget {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit,
+            processed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, messages: u32) {
+        if self.limit.is_some() {
+            self.processed.fetch_add(messages as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn processed(&self) -> u64 {
+        self.processed.load(Ordering::Relaxed)
+    }
+
+    /// Whether the budget is used up, after which consumers are not restarted anymore.
+    pub fn is_exhausted(&self) -> bool {
+        self.limit.is_some_and(|limit| self.processed() >= limit)
+    }
+}
+
+/// Limits the output lines of a consumer that are forwarded per second, for
+/// `--output-rate-limit`, so a consumer stuck in an error loop doesn't flood the logs. The lines
+/// above the limit are dropped and counted, to report them in a summary.
+#[derive(Debug, Default)]
+pub struct OutputRateLimiter {
+    // The maximum number of lines per second, zero for no limit
+    limit: u32,
+    state: Mutex<OutputRateState>,
+}
+
+#[derive(Debug, Default)]
+struct OutputRateState {
+    // When the current second started, and the lines forwarded in it
+    window_started: Option<Instant>,
+    lines: u32,
+    // The lines dropped since they were last reported
+    suppressed: u64,
+}
+
+impl OutputRateLimiter {
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    pub fn new(limit: u32) -> Self {
+        Self {
+            limit,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Counts a line at `now`, and returns whether to forward it. When a new second started, the
+    /// lines dropped in the ones before are returned as well, to report them.
+    pub fn allow(&self, now: Instant) -> (bool, Option<u64>) {
+        if self.limit == 0 {
+            return (true, None);
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let mut suppressed = None;
+        if state
+            .window_started
+            .is_none_or(|started| now.saturating_duration_since(started) >= Self::WINDOW)
+        {
+            state.window_started = Some(now);
+            state.lines = 0;
+            suppressed = Some(std::mem::take(&mut state.suppressed)).filter(|n| *n > 0);
+        }
+        if state.lines < self.limit {
+            state.lines += 1;
+            (true, suppressed)
+        } else {
+            state.suppressed += 1;
+            (false, suppressed)
+        }
+    }
+
+    /// Takes the lines dropped since they were last reported, like when the output ends.
+    pub fn take_suppressed(&self) -> u64 {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        std::mem::take(&mut state.suppressed)
+    }
+}
+
+/// Formats the duration in a short human readable form, like `1h2m3s`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}h{}m{}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m{}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// The name of the signal, like `SIGSEGV`, or `None` for uncommon signals.
+pub fn signal_name(signal: i32) -> Option<&'static str> {
+    let name = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/// The signal with the given name, like `SIGTERM` or `TERM`, or `None` for uncommon signals.
+pub fn signal_number(name: &str) -> Option<i32> {
+    let name = name.to_ascii_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    (1..32).find(|signal| signal_name(*signal).and_then(|x| x.strip_prefix("SIG")) == Some(name))
+}
+
+/// Describes how the process exited, like `exited with code 255` or `was killed by SIGSEGV (11)`.
+pub fn describe_exit_status(status: ExitStatus) -> String {
+    if let Some(code) = status.code() {
+        return format!("exited with code {}", code);
+    }
+    match status.signal() {
+        Some(signal) => format!(
+            "was killed by {} ({})",
+            signal_name(signal).unwrap_or("signal"),
+            signal
+        ),
+        None => "exited".to_owned(),
+    }
+}
+
+/// The number of messages a consumer reports to have processed in a line of its output, like
+/// `Processed 100 messages`. As the summary differs between Magento versions and queue modules,
+/// any line mentioning messages with a number next to "processed" matches, unless `pattern` is
+/// given. The pattern is a glob with `{n}` in place of the number, like `*handled {n} jobs*`.
+pub fn processed_messages(line: &str, pattern: Option<&str>) -> Option<u64> {
+    let line = strip_ansi_escapes(line);
+    match pattern {
+        Some(pattern) => match_processed_pattern(&line, pattern),
+        None => match_processed_summary(&line),
+    }
+}
+
+/// Whether a line of the output of a consumer reports that Magento doesn't know the consumer,
+/// like `Consumer "a.consumer" does not exist.` or `Consumer 'a.consumer' is not declared.`,
+/// which happens when a deployment removed it from the Magento configuration.
+pub fn reports_unknown_consumer(line: &str, consumer: &str) -> bool {
+    const UNKNOWN: [&str; 4] = [
+        "does not exist",
+        "is not exist",
+        "is not declared",
+        "not found",
+    ];
+    let line = line.to_lowercase();
+    line.contains("consumer")
+        && line.contains(&consumer.to_lowercase())
+        && UNKNOWN.iter().any(|x| line.contains(x))
+}
+
+fn match_processed_summary(line: &str) -> Option<u64> {
+    // The maximum distance between the number and "processed", like in `Processed messages: 100`
+    const MAX_DISTANCE: usize = 3;
+    let lower = line.to_lowercase();
+    let words: Vec<&str> = lower
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .collect();
+    if !words.iter().any(|x| x.starts_with("message")) {
+        return None;
+    }
+    let processed = words.iter().position(|x| *x == "processed")?;
+    words
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| i.abs_diff(processed) <= MAX_DISTANCE)
+        .filter_map(|(i, x)| Some((i.abs_diff(processed), x.parse::<u64>().ok()?)))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, n)| n)
+}
+
+fn match_processed_pattern(line: &str, pattern: &str) -> Option<u64> {
+    let (prefix, suffix) = pattern.split_once("{n}")?;
+    let mut start = 0;
+    while let Some(offset) = line[start..].find(|c: char| c.is_ascii_digit()) {
+        let begin = start + offset;
+        let end = line[begin..]
+            .find(|c: char| !c.is_ascii_digit())
+            .map_or(line.len(), |x| begin + x);
+        if glob_match(prefix, &line[..begin]) && glob_match(suffix, &line[end..]) {
+            if let Ok(n) = line[begin..end].parse() {
+                return Some(n);
+            }
+        }
+        start = end;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn matches_consumers_against_glob_patterns() {
+        assert!(glob_match("*", "async.operations.all"));
+        assert!(glob_match("async.*", "async.operations.all"));
+        assert!(glob_match("*.all", "async.operations.all"));
+        assert!(!glob_match("async.*", "product_action_attribute.update"));
+        assert!(glob_match("export?", "export1"));
+        assert!(!glob_match("export?", "export"));
+        assert!(!glob_match("export?", "export12"));
+        assert!(glob_match(
+            "codegeneratorProcessor",
+            "codegeneratorProcessor"
+        ));
+        assert!(!glob_match("codegenerator", "codegeneratorProcessor"));
+    }
+
+    #[test]
+    fn reports_a_restart_storm_when_most_consumers_crash() {
+        let limiter = RestartLimiter::new(0).with_storm_detection(50, Duration::from_millis(300));
+        limiter.set_consumers(4);
+        // At least 3 consumers have to crash, even though 2 is half of them
+        assert!(!limiter.record_crash("a"));
+        assert!(!limiter.record_crash("a"));
+        assert!(!limiter.record_crash("b"));
+        assert!(limiter.record_crash("c"));
+        assert!(limiter.record_crash("a"));
+        assert_eq!(limiter.storms(), 1);
+
+        // Over once the crashes stopped for the window
+        thread::sleep(Duration::from_millis(300));
+        limiter.check_storm();
+        assert!(!limiter.record_crash("a"));
+        assert_eq!(limiter.storms(), 1);
+
+        let limiter = RestartLimiter::new(0);
+        limiter.set_consumers(3);
+        for consumer in ["a", "b", "c"] {
+            assert!(!limiter.record_crash(consumer));
+        }
+    }
+
+    #[test]
+    fn parses_the_processed_messages_of_different_summaries() {
+        assert_eq!(
+            processed_messages("Processed 100 messages", None),
+            Some(100)
+        );
+        assert_eq!(
+            processed_messages("100 messages processed.", None),
+            Some(100)
+        );
+        assert_eq!(
+            processed_messages("\x1b[32mProcessed messages: 7\x1b[0m", None),
+            Some(7)
+        );
+        assert_eq!(processed_messages("Processed 3 orders", None), None);
+        assert_eq!(processed_messages("Processing message 12", None), None);
+
+        let pattern = Some("*handled {n} jobs*");
+        assert_eq!(
+            processed_messages("[info] handled 42 jobs in 3s", pattern),
+            Some(42)
+        );
+        assert_eq!(processed_messages("Processed 100 messages", pattern), None);
+    }
+
+    #[test]
+    fn drops_the_output_lines_above_the_rate_limit() {
+        let limiter = OutputRateLimiter::new(2);
+        let start = Instant::now();
+        assert_eq!(limiter.allow(start), (true, None));
+        assert_eq!(limiter.allow(start), (true, None));
+        assert_eq!(limiter.allow(start), (false, None));
+        assert_eq!(
+            limiter.allow(start + Duration::from_millis(500)),
+            (false, None)
+        );
+        // The next second forwards lines again, and reports the ones dropped before
+        let next = start + Duration::from_secs(1);
+        assert_eq!(limiter.allow(next), (true, Some(2)));
+        assert_eq!(limiter.allow(next), (true, None));
+        assert_eq!(limiter.allow(next), (false, None));
+        assert_eq!(limiter.take_suppressed(), 1);
+        assert_eq!(limiter.take_suppressed(), 0);
+
+        let unlimited = OutputRateLimiter::new(0);
+        for _ in 0..100 {
+            assert_eq!(unlimited.allow(start), (true, None));
+        }
+    }
 }
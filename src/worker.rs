@@ -1,49 +1,1341 @@
-use std::{process::Command, time::Duration};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    os::unix::{
+        io::{AsRawFd, FromRawFd, RawFd},
+        process::ExitStatusExt,
+    },
+    process::{Command, ExitStatus, Output, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
 
 use crate::{
-    config::{DaemonConfig, DaemonContext},
-    util::terminate_process_child,
+    cgroup::set_cgroup,
+    config::{
+        with_retries, DaemonConfig, DaemonContext, EnvironmentError, RabbitMqDetection,
+        RestartPolicy,
+    },
+    handoff::HandedOffProcess,
+    input::{KillStep, StuckWorkerAction},
+    logging::{self, log_event, Event},
+    util::{
+        describe_exit_status, describe_spawn_error, format_duration, is_resource_limit,
+        kill_process_group, orphaned_consumers, output_with_timeout, process_cpu_ticks,
+        process_cpu_time, process_rss_bytes, process_running, processed_messages,
+        reports_unknown_consumer, set_close_on_exec, set_scheduling, signal_name, signal_process,
+        signal_process_child, spawn_in_process_group, strip_ansi_escapes, try_wait_child,
+        unapplied_scheduling, MessageBudget, OutputRateLimiter, RestartLimiter, Stagger,
+        BYTES_PER_MB,
+    },
 };
 
-const RABBITMQ_CONSUMER_NAMES: [&str; 1] = ["async.operations.all"];
 const PROCESS_GRACEFUL_KILL_PERIOD: Duration = Duration::from_millis(500);
 const PROCESS_GRACEFUL_POLL_RESOLUTION: Duration = Duration::from_millis(20);
+// How long to wait for a killed process to exit. A process stuck in uninterruptible sleep, for
+// example blocked on NFS, doesn't exit until the kernel call returns, if ever.
+const PROCESS_KILL_TIMEOUT: Duration = Duration::from_secs(10);
+// How long to wait for the last output of a crashed process, which may tell why it crashed
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_millis(200);
+// The number of process exits kept for the SIGQUIT dump
+const RECENT_EXITS: usize = 10;
+const CONSUMER_LIST_ATTEMPTS: u32 = 4;
+const CONSUMER_LIST_RETRY_DELAY: Duration = Duration::from_secs(1);
+// The number of consecutive failures to start a consumer after which it's reported as failing
+const SPAWN_FAILURE_THRESHOLD: u32 = 5;
+const CRASH_HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+// Set when bin/magento queue:consumers:list doesn't support --format=json, so it's not tried on
+// every refresh. Installations of different Magento versions fall back to the plain text output.
+static JSON_CONSUMER_LIST_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+// Set when the nice value or CPU affinity couldn't be applied, to only warn about it once
+static SCHEDULING_UNAPPLIED: AtomicBool = AtomicBool::new(false);
 
 #[derive(Debug)]
 pub struct WorkerProcess {
     // The consumer name
     consumer: String,
+    // The label of the Magento installation, see `DaemonConfig::instance`
+    instance: Option<String>,
+    // The consumer name used in the logs, see `DaemonConfig::qualified_name`
+    name: String,
     // The process handles
-    processes: Vec<std::process::Child>,
+    processes: Vec<ConsumerProcess>,
+    // The number of times the consumer was restarted
+    restarts: u64,
+    // The number of processes recycled for exceeding the memory, CPU time or lifetime limit, or
+    // for stalling
+    recycles: u64,
+    // When the consumer was last (re)started
+    started_at: Instant,
+    // The exit status of the last process that exited unexpectedly
+    last_exit: Option<ExitStatus>,
+    // The number of consecutive failures to start the processes
+    spawn_failures: u32,
+    // When to retry starting the processes, after starting them failed
+    retry_at: Option<Instant>,
+    // The number of consecutive crashes, which are exits that were unsuccessful or sooner than
+    // --min-healthy-runtime after starting
+    crashes: u32,
+    // When to restart the consumer, after it crashed repeatedly
+    restart_at: Option<Instant>,
+    // When all processes exited successfully with --idle-shutdown, after which the consumer is
+    // not restarted during the idle window
+    drained_at: Option<Instant>,
+    // Whether the processes were stopped, so dropping the worker doesn't stop them again
+    terminated: bool,
+    // When the last process exited and was left down because of the restart policy
+    stopped_at: Option<Instant>,
+    // How the processes are stopped
+    stopping: StopPolicy,
+    // The PIDs of the processes left running with --stuck-worker-action leave, until they exit
+    stuck: Vec<u32>,
+    // What the processes reported in their output
+    output: Arc<OutputReports>,
+    // Whether a process reported that Magento doesn't know the consumer, and was left down until
+    // the consumer list is refreshed, and whether a refresh found it's still listed
+    unknown_reported: bool,
+    unknown_checked: bool,
+    // The last exits of the processes, for the SIGQUIT dump
+    exits: VecDeque<ProcessExit>,
+    // Whether the daemon drains, so exited processes are left down instead of restarted
+    draining: bool,
+}
+
+/// What the processes of a consumer reported in their output, shared with the threads forwarding
+/// it, and the rate limit of the forwarded output.
+#[derive(Debug, Default)]
+struct OutputReports {
+    // The messages the processes reported to have processed
+    processed: AtomicU64,
+    // Whether a process reported that Magento doesn't know the consumer
+    unknown_consumer: AtomicBool,
+    // Shared by all processes of the consumer, see --output-rate-limit
+    rate: OutputRateLimiter,
+}
+
+#[derive(Debug)]
+struct ProcessExit {
+    pid: u32,
+    status: ExitStatus,
+    // How long the process ran, and when it exited
+    uptime: Duration,
+    at: Instant,
+}
+
+#[derive(Debug)]
+struct ConsumerProcess {
+    // The process index, passed to --multi-process
+    index: u32,
+    // The process handle
+    child: ChildProcess,
+    // When the process was started
+    started_at: Instant,
+    // Whether the process exited and is not restarted because of the restart policy
+    left_down: bool,
+    // The threads forwarding the process output to the daemon log
+    output_threads: Vec<JoinHandle<()>>,
+    // The read ends of the output pipes, which are handed off on an upgrade
+    stdout_fd: Option<RawFd>,
+    stderr_fd: Option<RawFd>,
+    // The CPU time of the process at the last check for --stall-timeout, and when it last
+    // increased
+    cpu_ticks: Option<u64>,
+    progressed_at: Instant,
+}
+
+/// A consumer process, either started by the daemon or adopted from the daemon it replaced on an
+/// upgrade. An adopted process is still a child of the daemon, as `exec` keeps the PID, so it's
+/// waited for without a `std::process::Child`.
+#[derive(Debug)]
+enum ChildProcess {
+    Spawned(std::process::Child),
+    Adopted {
+        pid: u32,
+        status: Option<ExitStatus>,
+    },
+}
+
+impl ChildProcess {
+    fn id(&self) -> u32 {
+        match self {
+            Self::Spawned(child) => child.id(),
+            Self::Adopted { pid, .. } => *pid,
+        }
+    }
+
+    fn try_wait(&mut self) -> std::io::Result<Option<ExitStatus>> {
+        match self {
+            Self::Spawned(child) => child.try_wait(),
+            Self::Adopted {
+                status: Some(status),
+                ..
+            } => Ok(Some(*status)),
+            Self::Adopted { pid, status } => {
+                *status = try_wait_child(*pid)?;
+                Ok(*status)
+            }
+        }
+    }
 }
 
 impl WorkerProcess {
+    /// Adds the consumer to the records logged until the guard is dropped, for the work done on a
+    /// thread other than its supervisor thread.
+    pub fn log_context(&self) -> logging::ContextGuard {
+        logging::enter_context(self.instance.as_deref(), &self.consumer, None)
+    }
+
     pub fn terminate(&mut self) {
-        log::debug!("Terminating consumer: {}", self.consumer);
+        let _context = self.log_context();
+        log_event!(
+            log::Level::Debug,
+            Event::new("stop"),
+            "Terminating consumer: {}",
+            self.name
+        );
+        for p in self.processes.iter_mut() {
+            self.stuck
+                .extend(p.stop(&self.name, &self.stopping, self.restarts));
+        }
+        self.terminated = true;
+    }
+
+    /// Returns `None` while any of the processes is running, and otherwise whether all of them
+    /// exited successfully. A consumer that failed to start didn't exit successfully.
+    pub fn exit_success(&mut self) -> Option<bool> {
+        if self.retry_at.is_some() {
+            return Some(false);
+        }
+        let mut success = true;
+        for p in self.processes.iter_mut() {
+            match p.child.try_wait() {
+                Ok(Some(status)) => success &= status.success(),
+                Ok(None) => return None,
+                Err(_) => success = false,
+            }
+        }
+        Some(success)
+    }
+
+    /// Sends the signal to all running processes, without waiting for them to exit.
+    fn send_signal(&mut self, signal: i32) {
+        for p in self.processes.iter_mut() {
+            if p.has_exited() {
+                continue;
+            }
+            if signal_process_child(p.child.id(), signal).is_err() {
+                log::error!(
+                    "Failed to send {} to process {}",
+                    signal_name(signal).unwrap_or("signal"),
+                    p.child.id()
+                );
+            }
+        }
+    }
+
+    /// Force kills the processes that are still running, and adds them to `summary` as killed or
+    /// left running.
+    fn kill_remaining(&mut self, summary: &mut StopSummary) {
+        for p in self.processes.iter_mut() {
+            if p.has_exited() {
+                continue;
+            }
+            let pid = p.child.id();
+            if !self.stopping.kill_stuck(&self.name, pid, self.restarts) {
+                self.stuck.push(pid);
+                summary.left_running += 1;
+                continue;
+            }
+            log::warn!("Force killing process {} of consumer {}", pid, self.name);
+            if let Err(err) = kill_process_group(pid) {
+                log::error!("Failed to kill process {}: {}", pid, err);
+            }
+            summary.killed += 1;
+        }
+    }
+
+    /// Waits until `deadline` for all processes to exit, and reaps them, so they don't linger in
+    /// the process table. Processes that don't exit, or were left running, are left behind.
+    fn reap(&mut self, deadline: Instant) {
         for p in self.processes.iter_mut() {
-            p.try_stop_gracefully(PROCESS_GRACEFUL_KILL_PERIOD);
+            if self.stuck.contains(&p.child.id()) {
+                continue;
+            }
+            if p.child.wait_until(deadline) {
+                p.join_output_threads(&self.name);
+            }
         }
     }
 
-    pub fn ensure_running(&mut self, context: &DaemonContext) {
-        let is_running = self.processes.iter_mut().all(|p| p.is_running());
+    pub fn consumer(&self) -> &str {
+        &self.consumer
+    }
+
+    pub fn instance(&self) -> Option<&str> {
+        self.instance.as_deref()
+    }
+
+    /// The PIDs of the processes left running with `--stuck-worker-action leave` that are still
+    /// running.
+    pub fn stuck_pids(&self) -> Vec<u32> {
+        self.stuck
+            .iter()
+            .copied()
+            .filter(|pid| process_running(*pid))
+            .collect()
+    }
+
+    pub fn process_count(&self) -> usize {
+        self.processes.len()
+    }
+
+    pub fn restart_count(&self) -> u64 {
+        self.restarts
+    }
+
+    pub fn recycle_count(&self) -> u64 {
+        self.recycles
+    }
+
+    /// Describes the internal state of the consumer for the `SIGQUIT` dump: every process with
+    /// its resource usage, a backoff or retry, and the last exits of the processes.
+    pub fn diagnostics(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for p in self.processes.iter_mut() {
+            let pid = p.child.id();
+            let state = if p.left_down {
+                "left down"
+            } else if p.has_exited() {
+                "exited"
+            } else {
+                "running"
+            };
+            let mut line = format!(
+                "process {} (index {}): {}, started {} ago",
+                pid,
+                p.index,
+                state,
+                format_duration(p.started_at.elapsed())
+            );
+            if state == "running" {
+                if let Ok(rss) = process_rss_bytes(pid) {
+                    line.push_str(&format!(", {} MB", rss / BYTES_PER_MB));
+                }
+                if let Ok(cpu_time) = process_cpu_time(pid) {
+                    line.push_str(&format!(", {} CPU time", format_duration(cpu_time)));
+                }
+            }
+            lines.push(line);
+        }
+        if let Some(restart_at) = self.restart_at {
+            lines.push(format!(
+                "backing off after {} crashes in a row, restarting in {}",
+                self.crashes,
+                format_duration(restart_at.saturating_duration_since(Instant::now()))
+            ));
+        }
+        if let Some(retry_at) = self.retry_at {
+            lines.push(format!(
+                "failed to start {} times, retrying in {}",
+                self.spawn_failures,
+                format_duration(retry_at.saturating_duration_since(Instant::now()))
+            ));
+        }
+        for exit in self.exits.iter().rev() {
+            lines.push(format!(
+                "process {} {} after {}, {} ago",
+                exit.pid,
+                describe_exit_status(exit.status),
+                format_duration(exit.uptime),
+                format_duration(exit.at.elapsed())
+            ));
+        }
+        lines
+    }
+
+    /// The messages the processes reported to have processed in their output, see
+    /// `util::processed_messages`.
+    pub fn processed_messages(&self) -> u64 {
+        self.output.processed.load(Ordering::Relaxed)
+    }
+
+    pub fn last_exit(&self) -> Option<ExitStatus> {
+        self.last_exit
+    }
+
+    /// The time since the consumer was last (re)started.
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// Whether a process reported that Magento doesn't know the consumer, and was left down until
+    /// the consumer list is refreshed, see `restart_unknown`.
+    pub fn reported_unknown(&self) -> bool {
+        self.unknown_reported
+    }
+
+    /// Restarts the consumer after it reported that Magento doesn't know it, when the refreshed
+    /// consumer list still contains it. Later reports count as crashes, so a consumer that keeps
+    /// reporting it backs off instead of refreshing the list on every exit.
+    pub fn restart_unknown(&mut self, context: &DaemonContext) -> Result<(), EnvironmentError> {
+        self.unknown_checked = true;
+        self.restart(context)
+    }
+
+    /// The number of consecutive failures to start the consumer, zero when it's running.
+    pub fn spawn_failures(&self) -> u32 {
+        self.spawn_failures
+    }
+
+    /// When the consumer drained its queue, or stopped and is left down because of its restart
+    /// policy. Either way it isn't expected to be running.
+    pub fn drained_at(&self) -> Option<Instant> {
+        self.drained_at.or(self.stopped_at)
+    }
+
+    /// Whether the daemon drains, see `drain`.
+    pub fn draining(&self) -> bool {
+        self.draining
+    }
+
+    /// Whether restarting the consumer is delayed with a backoff, after it crashed repeatedly.
+    pub fn backing_off(&self) -> bool {
+        self.restart_at.is_some()
+    }
+
+    /// The number of processes that exited and are left down because of the restart policy.
+    pub fn left_down_count(&self) -> usize {
+        self.processes.iter().filter(|p| p.left_down).count()
+    }
+
+    /// The PIDs of all processes, including the ones that exited but were not reaped yet.
+    pub fn pids(&self) -> Vec<u32> {
+        self.processes.iter().map(|p| p.child.id()).collect()
+    }
+
+    /// The PIDs of the processes that are still running.
+    pub fn running_pids(&mut self) -> Vec<u32> {
+        self.processes
+            .iter_mut()
+            .filter_map(|p| (!p.has_exited()).then(|| p.child.id()))
+            .collect()
+    }
+
+    /// The running processes, to hand them off to a new daemon on an upgrade. The processes are
+    /// left running and the worker is left as it is, so it can keep supervising them when the
+    /// upgrade fails.
+    pub fn hand_off(&mut self, config: &DaemonConfig) -> Vec<HandedOffProcess> {
+        self.processes
+            .iter_mut()
+            .filter_map(|p| (!p.has_exited()).then_some(&*p))
+            .map(|p| HandedOffProcess {
+                magento_dir: config.magento_dir.clone(),
+                consumer: self.consumer.clone(),
+                index: p.index,
+                pid: p.child.id(),
+                uptime: p.started_at.elapsed().as_secs(),
+                stdout: p.stdout_fd,
+                stderr: p.stderr_fd,
+            })
+            .collect()
+    }
+
+    /// Checks the processes while the daemon drains, instead of `ensure_running`: the running
+    /// processes finish their batch and exit on their own, and the ones that exited are left down,
+    /// without restarting or recycling any of them.
+    pub fn drain(&mut self) {
+        self.draining = true;
+        self.restart_at = None;
+        self.retry_at = None;
+        for p in self.processes.iter_mut().filter(|p| !p.left_down) {
+            let status = match p.child.try_wait() {
+                Ok(Some(status)) => status,
+                Ok(None) => continue,
+                Err(err) => {
+                    log::debug!("Process has error {:?}", err);
+                    continue;
+                }
+            };
+            log_event!(
+                if status.success() {
+                    log::Level::Info
+                } else {
+                    log::Level::Warn
+                },
+                Event::new("exit")
+                    .pid(p.child.id())
+                    .exit_status(status)
+                    .restarts(self.restarts),
+                "Process {} of consumer {} {}, not restarting it while draining",
+                p.child.id(),
+                self.name,
+                describe_exit_status(status)
+            );
+            self.last_exit = Some(status);
+            if self.exits.len() == RECENT_EXITS {
+                self.exits.pop_front();
+            }
+            self.exits.push_back(ProcessExit {
+                pid: p.child.id(),
+                status,
+                uptime: p.started_at.elapsed(),
+                at: Instant::now(),
+            });
+            p.left_down = true;
+        }
+    }
+
+    /// Restarts the consumer if any of its processes have exited, and recycles processes exceeding
+    /// the configured memory or CPU time limit. Every restart has to be allowed by `limiter`, and
+    /// waits for its turn in `stagger`.
+    ///
+    /// With `--idle-shutdown`, a consumer of which all processes exited successfully has drained
+    /// its queue, and is only restarted after the idle window when `idle` isn't set, which means
+    /// other consumers are still running.
+    ///
+    /// Processes that exit successfully are counted in `budget`, and once it's exhausted the
+    /// consumer isn't restarted anymore.
+    pub fn ensure_running(
+        &mut self,
+        context: &DaemonContext,
+        stagger: &Stagger,
+        limiter: &RestartLimiter,
+        idle: &AtomicBool,
+        budget: &MessageBudget,
+    ) {
+        let name = &self.name;
+        self.stuck.retain(|pid| {
+            let running = process_running(*pid);
+            if !running {
+                log::info!(
+                    "Process {} of consumer {}, which was left running, has exited",
+                    pid,
+                    name
+                );
+            }
+            running
+        });
+        if budget.is_exhausted() {
+            return;
+        }
+        let priority = context.consumer_config.priority_for(&self.consumer);
+        let policy = context.consumer_config.restart_policy_for(&self.consumer);
+        // Consumers that aren't always restarted stay down when they drain their queue
+        let idle_shutdown = context
+            .daemon_config
+            .idle_shutdown
+            .filter(|_| policy == RestartPolicy::Always);
+        if let Some(idle_shutdown) = idle_shutdown {
+            if let Some(drained_at) = self.drained_at {
+                if drained_at.elapsed() < idle_shutdown
+                    || idle.load(Ordering::Relaxed)
+                    || !limiter.try_acquire(&self.name, priority)
+                {
+                    return;
+                }
+                stagger.wait();
+                log::info!(
+                    "Starting consumer {}: checking for new messages after being idle for {}",
+                    self.name,
+                    format_duration(drained_at.elapsed())
+                );
+                let _ = self.restart(context);
+                return;
+            }
+            match self.drained() {
+                Some(true) => {
+                    log_event!(
+                        log::Level::Info,
+                        Event::new("drained").restarts(self.restarts),
+                        "Consumer {} drained its queue, not restarting it for {}",
+                        self.name,
+                        format_duration(idle_shutdown)
+                    );
+                    self.drained_at = Some(Instant::now());
+                    return;
+                }
+                // The other processes are waited for, so the consumer isn't restarted while
+                // they're finishing the remaining messages.
+                Some(false) => return,
+                None => {}
+            }
+        }
+
+        if let Some(retry_at) = self.retry_at {
+            if Instant::now() < retry_at || !limiter.try_acquire(&self.name, priority) {
+                return;
+            }
+            stagger.wait();
+            log_event!(
+                log::Level::Info,
+                Event::new("retry").restarts(self.restarts),
+                "Starting consumer {}: retrying after {} failures",
+                self.name,
+                self.spawn_failures
+            );
+            let _ = self.restart(context);
+            return;
+        }
+
+        if let Some(restart_at) = self.restart_at {
+            if Instant::now() < restart_at || !limiter.try_acquire(&self.name, priority) {
+                return;
+            }
+            stagger.wait();
+            log_event!(
+                log::Level::Info,
+                Event::new("restart").restarts(self.restarts + 1),
+                "Restarting consumer {}: backed off after {} crashes",
+                self.name,
+                self.crashes
+            );
+            let _ = self.restart(context);
+            return;
+        }
+
+        // The exited processes are reported once the restart is allowed, so they're not reported
+        // on every check while restarts are throttled. When the consumer isn't restarted right
+        // away, the restart is given back.
+        let acquired = self
+            .processes
+            .iter_mut()
+            .any(|p| !p.left_down && p.has_exited());
+        if acquired && !limiter.try_acquire(&self.name, priority) {
+            return;
+        }
+
+        let backoff = context
+            .consumer_config
+            .backoff_for(&context.daemon_config, &self.consumer);
+        let min_healthy_runtime = backoff.min_healthy_runtime;
+        let startup_grace = context.daemon_config.startup_grace;
+        let mut is_running = true;
+        let mut crashed = false;
+        // Whether any exit counts towards the backoff, which exits during --startup-grace don't
+        let mut counted = false;
+        let mut storm = false;
+        for p in self.processes.iter_mut().filter(|p| !p.left_down) {
+            match p.child.try_wait() {
+                Ok(None) => continue,
+                Ok(Some(status)) => {
+                    let graced = p.started_at.elapsed() < startup_grace;
+                    let crash = !graced
+                        && (!status.success() || p.started_at.elapsed() < min_healthy_runtime);
+                    // The crashes of a restart storm are reported together by the limiter
+                    let in_storm = crash && limiter.record_crash(&self.name);
+                    storm |= in_storm;
+                    let level = if in_storm {
+                        log::Level::Debug
+                    } else if graced {
+                        log::Level::Info
+                    } else {
+                        log::Level::Warn
+                    };
+                    log_event!(
+                        level,
+                        Event::new("exit")
+                            .pid(p.child.id())
+                            .exit_status(status)
+                            .restarts(self.restarts),
+                        "Process {} of consumer {} {}{}",
+                        p.child.id(),
+                        self.name,
+                        describe_exit_status(status),
+                        if graced {
+                            ", within its --startup-grace"
+                        } else {
+                            ""
+                        }
+                    );
+                    self.last_exit = Some(status);
+                    if self.exits.len() == RECENT_EXITS {
+                        self.exits.pop_front();
+                    }
+                    self.exits.push_back(ProcessExit {
+                        pid: p.child.id(),
+                        status,
+                        uptime: p.started_at.elapsed(),
+                        at: Instant::now(),
+                    });
+                    // A consumer removed by a deployment since the consumer list was read would
+                    // crash on every restart, so the daemon refreshes the list first
+                    if !status.success() && !self.unknown_checked {
+                        p.wait_for_output(OUTPUT_DRAIN_TIMEOUT);
+                        if self.output.unknown_consumer.swap(false, Ordering::Relaxed) {
+                            log::warn!(
+                                "Magento doesn't know consumer {}, refreshing the consumer list before restarting it",
+                                self.name
+                            );
+                            p.left_down = true;
+                            self.unknown_reported = true;
+                            continue;
+                        }
+                    }
+                    if status.success() {
+                        budget.record(context.consumer_config.max_messages_for(&self.consumer));
+                    } else if let Some(hook) =
+                        CrashHook::new(&context.daemon_config, &self.consumer)
+                    {
+                        hook.run(p.child.id(), Some(status), self.restarts);
+                    }
+                    let restart = match policy {
+                        RestartPolicy::Always => true,
+                        RestartPolicy::OnFailure => !status.success(),
+                        RestartPolicy::Never => false,
+                    };
+                    if !restart {
+                        log::info!(
+                            "Not restarting process {} of consumer {}, as its restart policy is {}",
+                            p.child.id(),
+                            self.name,
+                            policy
+                        );
+                        p.left_down = true;
+                        continue;
+                    }
+                    crashed |= crash;
+                    counted |= !graced;
+                }
+                Err(err) => log::debug!("Process has error {:?}", err),
+            }
+            is_running = false;
+        }
+        if self.stopped_at.is_none()
+            && !self.processes.is_empty()
+            && self.processes.iter().all(|p| p.left_down)
+        {
+            self.stopped_at = Some(Instant::now());
+        }
+        if acquired && (is_running || budget.is_exhausted()) {
+            limiter.release();
+        }
         if !is_running {
-            self.restart(context);
+            if budget.is_exhausted() {
+                return;
+            }
+            // Exiting successfully after running for a while is the normal end of a batch of
+            // --max-messages, so only repeated crashes back off. Exits during --startup-grace
+            // leave the crashes as they are.
+            if counted {
+                self.crashes = if crashed { self.crashes + 1 } else { 0 };
+            }
+            if counted && self.crashes > 1 {
+                let delay = backoff.delay(self.crashes - 1);
+                self.restart_at = Some(Instant::now() + delay);
+                if acquired {
+                    limiter.release();
+                }
+                log_event!(
+                    if storm {
+                        log::Level::Debug
+                    } else {
+                        log::Level::Warn
+                    },
+                    Event::new("backoff").restarts(self.restarts),
+                    "Consumer {} crashed {} times in a row, restarting it in {}s",
+                    self.name,
+                    self.crashes,
+                    delay.as_secs()
+                );
+                return;
+            }
+            stagger.wait();
+            log_event!(
+                log::Level::Info,
+                Event::new("restart").restarts(self.restarts + 1),
+                "Restarting consumer {}: a process has exited",
+                self.name
+            );
+            let _ = self.restart(context);
+            return;
+        }
+        if self.started_at.elapsed() >= min_healthy_runtime {
+            self.crashes = 0;
+        }
+
+        if let Some(max_memory) = context.daemon_config.max_memory {
+            for p in self.processes.iter_mut() {
+                if p.left_down || p.has_exited() {
+                    continue;
+                }
+                let rss = match process_rss_bytes(p.child.id()) {
+                    Ok(rss) => rss,
+                    Err(err) => {
+                        log::debug!(
+                            "Failed to read memory usage of process {}: {}",
+                            p.child.id(),
+                            err
+                        );
+                        continue;
+                    }
+                };
+                if rss <= max_memory || !limiter.try_acquire(&self.name, priority) {
+                    continue;
+                }
+                stagger.wait();
+                log_event!(
+                    log::Level::Info,
+                    Event::new("recycle").pid(p.child.id()),
+                    "Recycling process {} of consumer {}: memory usage of {} MB exceeds the limit of {} MB",
+                    p.child.id(),
+                    self.name,
+                    rss / BYTES_PER_MB,
+                    max_memory / BYTES_PER_MB
+                );
+                self.stuck.extend(p.respawn(
+                    context,
+                    &self.consumer,
+                    &self.name,
+                    &self.stopping,
+                    &self.output,
+                    self.restarts,
+                ));
+                self.recycles += 1;
+            }
+        }
+
+        if let Some(max_cpu_time) = context.daemon_config.max_cpu_time {
+            for p in self.processes.iter_mut() {
+                if p.left_down || p.has_exited() {
+                    continue;
+                }
+                let cpu_time = match process_cpu_time(p.child.id()) {
+                    Ok(cpu_time) => cpu_time,
+                    Err(err) => {
+                        log::debug!(
+                            "Failed to read CPU time of process {}: {}",
+                            p.child.id(),
+                            err
+                        );
+                        continue;
+                    }
+                };
+                if cpu_time <= max_cpu_time || !limiter.try_acquire(&self.name, priority) {
+                    continue;
+                }
+                stagger.wait();
+                log_event!(
+                    log::Level::Warn,
+                    Event::new("recycle").pid(p.child.id()),
+                    "Recycling process {} of consumer {}: used {} of CPU time, exceeding the limit of {}",
+                    p.child.id(),
+                    self.name,
+                    format_duration(cpu_time),
+                    format_duration(max_cpu_time)
+                );
+                self.stuck.extend(p.respawn(
+                    context,
+                    &self.consumer,
+                    &self.name,
+                    &self.stopping,
+                    &self.output,
+                    self.restarts,
+                ));
+                self.recycles += 1;
+            }
+        }
+
+        if let Some(max_lifetime) = context.daemon_config.max_lifetime {
+            // Only the oldest process is recycled per check, so the processes of a multi-process
+            // consumer, which were started together, don't all stop at the same time.
+            if let Some(p) = self
+                .processes
+                .iter_mut()
+                .filter_map(|p| {
+                    (!p.left_down && !p.has_exited() && p.started_at.elapsed() >= max_lifetime)
+                        .then_some(p)
+                })
+                .min_by_key(|p| p.started_at)
+                .filter(|_| limiter.try_acquire(&self.name, priority))
+            {
+                stagger.wait();
+                log_event!(
+                    log::Level::Info,
+                    Event::new("recycle").pid(p.child.id()),
+                    "Recycling process {} of consumer {}: running for {}, exceeding the max lifetime of {}",
+                    p.child.id(),
+                    self.name,
+                    format_duration(p.started_at.elapsed()),
+                    format_duration(max_lifetime)
+                );
+                self.stuck.extend(p.respawn(
+                    context,
+                    &self.consumer,
+                    &self.name,
+                    &self.stopping,
+                    &self.output,
+                    self.restarts,
+                ));
+                self.recycles += 1;
+            }
         }
     }
 
-    pub fn restart(&mut self, context: &DaemonContext) {
+    /// The indices of the running processes that used no CPU time for `timeout`, which may be
+    /// stalled, like deadlocked or stuck on a connection, or may be waiting for messages.
+    /// Processes of which the CPU time can't be read are never reported.
+    pub fn stalled_processes(&mut self, timeout: Duration) -> Vec<u32> {
+        let mut stalled = Vec::new();
+        for p in self.processes.iter_mut() {
+            if p.left_down || p.has_exited() {
+                continue;
+            }
+            let ticks = match process_cpu_ticks(p.child.id()) {
+                Ok(ticks) => ticks,
+                Err(err) => {
+                    log::debug!(
+                        "Failed to read CPU time of process {}: {}",
+                        p.child.id(),
+                        err
+                    );
+                    continue;
+                }
+            };
+            if p.cpu_ticks != Some(ticks) {
+                p.cpu_ticks = Some(ticks);
+                p.progressed_at = Instant::now();
+            } else if p.progressed_at.elapsed() >= timeout {
+                stalled.push(p.index);
+            }
+        }
+        stalled
+    }
+
+    /// Restarts the processes reported by `stalled_processes`, where `backlog` is the number of
+    /// messages waiting in the queue, when it's known. Without waiting messages the processes are
+    /// idle rather than stalled, and they're checked again after the timeout.
+    pub fn restart_stalled(
+        &mut self,
+        indices: &[u32],
+        context: &DaemonContext,
+        limiter: &RestartLimiter,
+        backlog: Option<u64>,
+    ) {
+        let _context = self.log_context();
+        let priority = context.consumer_config.priority_for(&self.consumer);
+        for p in self
+            .processes
+            .iter_mut()
+            .filter(|p| indices.contains(&p.index))
+        {
+            if backlog == Some(0) {
+                p.progressed_at = Instant::now();
+                continue;
+            }
+            // Checked again on the next check, as the process is still stalled
+            if !limiter.try_acquire(&self.name, priority) {
+                continue;
+            }
+            let waiting = match backlog {
+                Some(backlog) => format!(" while {} messages are waiting", backlog),
+                None => String::new(),
+            };
+            log_event!(
+                log::Level::Warn,
+                Event::new("stalled").pid(p.child.id()),
+                "Restarting stalled process {} of consumer {}: no CPU time used for {}{}",
+                p.child.id(),
+                self.name,
+                format_duration(p.progressed_at.elapsed()),
+                waiting
+            );
+            self.stuck.extend(p.respawn(
+                context,
+                &self.consumer,
+                &self.name,
+                &self.stopping,
+                &self.output,
+                self.restarts,
+            ));
+            self.recycles += 1;
+        }
+    }
+
+    /// Whether the consumer drained its queue, which is when processes exited and all of them
+    /// successfully. Returns `Some(false)` while the other processes are still running, and
+    /// `None` when no process exited or any of them crashed.
+    fn drained(&mut self) -> Option<bool> {
+        let mut exited = false;
+        let mut running = false;
+        for p in self.processes.iter_mut() {
+            match p.child.try_wait() {
+                Ok(Some(status)) if status.success() => exited = true,
+                Ok(None) => running = true,
+                _ => return None,
+            }
+        }
+        exited.then_some(!running)
+    }
+
+    /// Restarts all processes of the consumer. When starting them fails, for example because the
+    /// Magento directory is gone, the error is logged and the start is retried by
+    /// `ensure_running` with an exponential backoff.
+    pub fn restart(&mut self, context: &DaemonContext) -> Result<(), EnvironmentError> {
+        self.restarts += 1;
+        self.resume(context)
+    }
+
+    /// Starts the processes of the consumer again after they were stopped, like `restart`, but
+    /// without counting a restart, as it's done on request after pausing the consumer.
+    pub fn resume(&mut self, context: &DaemonContext) -> Result<(), EnvironmentError> {
+        let _context = self.log_context();
+        // Terminating waits for every process, so none of them are left as zombies when their
+        // handles are replaced.
         self.terminate();
-        self.processes = run_worker(&context, &self.consumer).processes;
+        self.terminated = false;
+        self.stopped_at = None;
+        self.started_at = Instant::now();
+        self.drained_at = None;
+        self.restart_at = None;
+        self.unknown_reported = false;
+        match spawn_processes(context, &self.consumer, &self.output) {
+            Ok(processes) => {
+                self.processes = processes;
+                self.spawn_failures = 0;
+                self.retry_at = None;
+                Ok(())
+            }
+            Err(err) => {
+                let err = spawn_error(&self.name, &err);
+                self.processes.clear();
+                self.schedule_retry(context, &err);
+                Err(err)
+            }
+        }
+    }
+
+    /// Scales the consumer to `processes` processes, without restarting the processes that keep
+    /// running. Processes with the highest indices are stopped first, and the missing indices are
+    /// started, so every process keeps its `--multi-process` index. When starting a process
+    /// fails, the consumer is restarted by `ensure_running` with an exponential backoff.
+    pub fn scale_to(
+        &mut self,
+        processes: u32,
+        context: &DaemonContext,
+    ) -> Result<(), EnvironmentError> {
+        let _context = self.log_context();
+        log::info!(
+            "Scaling consumer {} from {} to {} processes",
+            self.name,
+            self.processes.len(),
+            processes
+        );
+        self.terminated = false;
+        let (mut kept, mut removed): (Vec<_>, Vec<_>) =
+            self.processes.drain(..).partition(|p| p.index < processes);
+        for p in removed.iter_mut() {
+            self.stuck
+                .extend(p.stop(&self.name, &self.stopping, self.restarts));
+        }
+
+        let mut result = Ok(());
+        for index in 0..processes {
+            if kept.iter().any(|p| p.index == index) {
+                continue;
+            }
+            match ConsumerProcess::spawn(context, &self.consumer, index, &self.output) {
+                Ok(process) => kept.push(process),
+                Err(err) => {
+                    result = Err(spawn_error(&self.name, &err));
+                    break;
+                }
+            }
+        }
+        kept.sort_by_key(|p| p.index);
+        self.processes = kept;
+        if let Err(ref err) = result {
+            self.schedule_retry(context, err);
+        }
+        result
+    }
+
+    /// Schedules starting the consumer again after starting it failed, with an exponential
+    /// backoff.
+    fn schedule_retry(&mut self, context: &DaemonContext, err: &EnvironmentError) {
+        self.spawn_failures += 1;
+        let retry_delay = context
+            .consumer_config
+            .backoff_for(&context.daemon_config, &self.consumer)
+            .delay(self.spawn_failures);
+        self.retry_at = Some(Instant::now() + retry_delay);
+        log_event!(
+            log::Level::Error,
+            Event::new("spawn_failed").restarts(self.restarts),
+            "{}, retrying in {}s",
+            err.message,
+            retry_delay.as_secs()
+        );
+        if self.spawn_failures == SPAWN_FAILURE_THRESHOLD {
+            log::error!(
+                "Consumer {} failed to start {} times in a row, check that the Magento directory {} still exists",
+                self.name,
+                self.spawn_failures,
+                context.daemon_config.magento_dir
+            );
+        }
+    }
+}
+
+impl Drop for WorkerProcess {
+    /// Stops the processes that are still running when the worker is dropped without being
+    /// terminated, like on a panic or an early return, so they aren't left running detached.
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        crate::otlp::end_consumer(self.instance.as_deref(), &self.consumer);
+        if self.terminated || self.processes.iter_mut().all(|p| p.has_exited()) {
+            return;
+        }
+        let _context = self.log_context();
+        log::debug!("Stopping consumer {}, as its worker was dropped", self.name);
+        let steps = kill_steps(&self.stopping.kill_sequence, PROCESS_GRACEFUL_KILL_PERIOD);
+        signal_until_exited(std::slice::from_mut(self), &steps);
+        self.kill_remaining(&mut StopSummary::default());
+        self.reap(Instant::now() + PROCESS_KILL_TIMEOUT);
+    }
+}
+
+impl ConsumerProcess {
+    fn spawn(
+        context: &DaemonContext,
+        consumer: &str,
+        index: u32,
+        reports: &Arc<OutputReports>,
+    ) -> std::io::Result<Self> {
+        let mut command = context.daemon_config.magento_command();
+        command
+            .args(worker_command_args(context, consumer, index))
+            .envs(context.worker_env())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let config = &context.daemon_config;
+        set_scheduling(&mut command, config.nice, &config.cpu_affinity);
+        set_cgroup(&mut command, config, consumer)?;
+        let mut child = spawn_in_process_group(&mut command)?;
+
+        let mut output_threads = Vec::new();
+        let pid = child.id();
+        log_event!(
+            log::Level::Debug,
+            Event::new("spawn").pid(pid),
+            "Started process {} of consumer {}",
+            pid,
+            config.qualified_name(consumer)
+        );
+        let unapplied = unapplied_scheduling(pid, config.nice, &config.cpu_affinity);
+        if !unapplied.is_empty() && !SCHEDULING_UNAPPLIED.swap(true, Ordering::Relaxed) {
+            log::warn!(
+                "Failed to set the {} of the consumer processes, running them with the defaults",
+                unapplied.join(" and ")
+            );
+        }
+        let stdout_fd = child.stdout.as_ref().map(|x| x.as_raw_fd());
+        let stderr_fd = child.stderr.as_ref().map(|x| x.as_raw_fd());
+        let mut result = Ok(());
+        if let Some(stdout) = child.stdout.take() {
+            let level = stdout_level(config);
+            result = forward_output(config, consumer, pid, stdout, level, reports)
+                .map(|thread| output_threads.push(thread));
+        }
+        if let (Ok(()), Some(stderr)) = (&result, child.stderr.take()) {
+            let level = Some(log::Level::Warn);
+            result = forward_output(config, consumer, pid, stderr, level, reports)
+                .map(|thread| output_threads.push(thread));
+        }
+        if let Err(err) = result {
+            // Without a thread reading its output, the process would block once the pipe is full
+            let _ = kill_process_group(child.id());
+            let _ = child.wait();
+            return Err(err);
+        }
+
+        Ok(Self {
+            index,
+            child: ChildProcess::Spawned(child),
+            started_at: Instant::now(),
+            left_down: false,
+            output_threads,
+            stdout_fd,
+            stderr_fd,
+            cpu_ticks: None,
+            progressed_at: Instant::now(),
+        })
+    }
+
+    /// Adopts a process handed off by the daemon this one replaced, and forwards its output.
+    /// Returns `None` when the process exited in the meantime, or isn't a child of the daemon.
+    fn adopt(
+        config: &DaemonConfig,
+        process: &HandedOffProcess,
+        reports: &Arc<OutputReports>,
+    ) -> Option<Self> {
+        let name = config.qualified_name(&process.consumer);
+        let mut child = ChildProcess::Adopted {
+            pid: process.pid,
+            status: None,
+        };
+        match child.try_wait() {
+            Ok(None) => {}
+            Ok(Some(status)) => {
+                log::warn!(
+                    "Process {} of consumer {} {} during the upgrade",
+                    process.pid,
+                    name,
+                    describe_exit_status(status)
+                );
+                return None;
+            }
+            Err(err) => {
+                log::warn!(
+                    "Failed to adopt process {} of consumer {}: {}",
+                    process.pid,
+                    name,
+                    err
+                );
+                return None;
+            }
+        }
+
+        let mut output_threads = Vec::new();
+        for (fd, level) in [
+            (process.stdout, stdout_level(config)),
+            (process.stderr, Some(log::Level::Warn)),
+        ] {
+            // A descriptor that isn't open was not handed off correctly, and is left alone
+            let fd = match fd.filter(|fd| set_close_on_exec(*fd, true).is_ok()) {
+                Some(fd) => fd,
+                None => continue,
+            };
+            // SAFETY: the descriptor was handed off for this process, and is owned by the thread
+            let output = unsafe { File::from_raw_fd(fd) };
+            match forward_output(
+                config,
+                &process.consumer,
+                process.pid,
+                output,
+                level,
+                reports,
+            ) {
+                Ok(thread) => output_threads.push(thread),
+                Err(err) => log::error!(
+                    "Failed to forward the output of process {} of consumer {}: {}",
+                    process.pid,
+                    name,
+                    err
+                ),
+            }
+        }
+        log_event!(
+            log::Level::Debug,
+            Event::new("adopt").pid(process.pid),
+            "Adopted process {} of consumer {}",
+            process.pid,
+            name
+        );
+        let uptime = Duration::from_secs(process.uptime);
+        Some(Self {
+            index: process.index,
+            child,
+            started_at: Instant::now()
+                .checked_sub(uptime)
+                .unwrap_or_else(Instant::now),
+            left_down: false,
+            output_threads,
+            stdout_fd: process.stdout,
+            stderr_fd: process.stderr,
+            cpu_ticks: None,
+            progressed_at: Instant::now(),
+        })
+    }
+
+    /// Stops the process and starts it again, and returns the PID of the stopped process when
+    /// it was left running, see `stop`.
+    fn respawn(
+        &mut self,
+        context: &DaemonContext,
+        consumer: &str,
+        name: &str,
+        stopping: &StopPolicy,
+        reports: &Arc<OutputReports>,
+        restarts: u64,
+    ) -> Option<u32> {
+        let stuck = self.stop(name, stopping, restarts);
+        match ConsumerProcess::spawn(context, consumer, self.index, reports) {
+            Ok(process) => *self = process,
+            // The stopped process is left in place and restarts the consumer on the next check,
+            // which backs off when it keeps failing.
+            Err(err) => log::error!(
+                "Failed to start process {} of consumer {}: {}",
+                self.index,
+                name,
+                describe_spawn_error(&err)
+            ),
+        }
+        stuck
+    }
+
+    /// Stops the process, and returns its PID when it's still running after the last step and
+    /// `--stuck-worker-action leave` left it running.
+    fn stop(&mut self, name: &str, stopping: &StopPolicy, restarts: u64) -> Option<u32> {
+        let steps = kill_steps(&stopping.kill_sequence, PROCESS_GRACEFUL_KILL_PERIOD);
+        let pid = self.child.id();
+        let mut left = false;
+        let exited = self.child.try_stop_gracefully(&steps, || {
+            left = !stopping.kill_stuck(name, pid, restarts);
+            !left
+        });
+        // The output of a process that didn't exit may never end, so its threads are left behind
+        if exited {
+            self.join_output_threads(name);
+            log_event!(
+                log::Level::Debug,
+                Event::new("stopped").pid(pid),
+                "Stopped process {} of consumer {}",
+                pid,
+                name
+            );
+        }
+        left.then_some(pid)
+    }
+
+    /// Waits until `timeout` for the threads forwarding the output of the exited process to read
+    /// what it wrote last.
+    fn wait_for_output(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        while self.output_threads.iter().any(|t| !t.is_finished()) && Instant::now() < deadline {
+            std::thread::sleep(PROCESS_GRACEFUL_POLL_RESOLUTION);
+        }
+    }
+
+    /// Checks whether the process has exited, without logging its exit status.
+    fn has_exited(&mut self) -> bool {
+        !matches!(self.child.try_wait(), Ok(None))
+    }
+
+    fn join_output_threads(&mut self, consumer: &str) {
+        // The process has exited, so the pipes are closed and the threads will finish.
+        for t in self.output_threads.drain(..) {
+            if t.join().is_err() {
+                log::error!("Output thread of consumer {} panicked", consumer);
+            }
+        }
     }
 }
 
 trait WorkerChildProcess {
     fn is_running(&mut self) -> bool;
-    fn try_stop_gracefully(&mut self, grace_period: Duration);
+    fn try_stop_gracefully(&mut self, steps: &[KillStep], kill: impl FnOnce() -> bool) -> bool;
+    fn wait_until(&mut self, deadline: Instant) -> bool;
 }
 
-impl WorkerChildProcess for std::process::Child {
+impl WorkerChildProcess for ChildProcess {
     fn is_running(&mut self) -> bool {
         match self.try_wait() {
             Ok(Some(status)) => {
@@ -51,104 +1343,1400 @@ impl WorkerChildProcess for std::process::Child {
                     Some(code) => log::debug!("Process {} exited with status {}", self.id(), code),
                     None => log::debug!("Process {} was terminated", self.id()),
                 }
-                return false;
+                false
             }
             Ok(None) => true,
             Err(err) => {
                 log::debug!("Process has error {:?}", err);
-                return false;
+                false
             }
         }
     }
 
-    fn try_stop_gracefully(&mut self, grace_period: Duration) {
+    /// Stops the process by sending the signal of every step and waiting for it to exit, killing
+    /// it when it's still running after the last step and `kill` returns true, and returns
+    /// whether it exited.
+    fn try_stop_gracefully(&mut self, steps: &[KillStep], kill: impl FnOnce() -> bool) -> bool {
         if !self.is_running() {
-            return;
+            // Clean up any descendants that outlived the process. The group is gone when there
+            // are none, so the error is expected.
+            let _ = kill_process_group(self.id());
+            return true;
         }
 
-        let terminate_result = terminate_process_child(self);
-        if terminate_result.is_err() {
-            log::error!("Failed to SIGTERM process");
+        let mut running = true;
+        for step in steps {
+            if let Err(err) = signal_process_child(self.id(), step.signal) {
+                log::error!(
+                    "Failed to send {} to process {}: {}",
+                    signal_name(step.signal).unwrap_or("signal"),
+                    self.id(),
+                    err
+                );
+            }
+            let deadline = Instant::now() + step.wait;
+            loop {
+                running = self.is_running();
+                if !running || Instant::now() >= deadline {
+                    break;
+                }
+                std::thread::sleep(PROCESS_GRACEFUL_POLL_RESOLUTION);
+            }
+            if !running {
+                break;
+            }
+        }
+        if running {
+            if !kill() {
+                return false;
+            }
+            if let Err(err) = kill_process_group(self.id()) {
+                log::error!("Failed to kill process {}: {}", self.id(), err);
+            }
+            log::debug!("Force killing process");
         }
 
-        let mut waiting_time = 0;
-        while self.is_running() {
-            if waiting_time >= grace_period.as_millis() {
-                self.kill().unwrap();
-                log::debug!("Force killing process");
+        // After it's killed, we need to call wait for the process to be removed from the process
+        // table. For more information, see NOTES in man waitpid(2).
+        self.wait_until(Instant::now() + PROCESS_KILL_TIMEOUT)
+    }
+
+    /// Waits until `deadline` for the process to exit, and returns whether it exited. Waiting is
+    /// bounded, so a process that is stuck in the kernel doesn't hang the daemon. When it exits
+    /// later, it's reaped as an untracked zombie.
+    fn wait_until(&mut self, deadline: Instant) -> bool {
+        loop {
+            match self.try_wait() {
+                Ok(Some(_)) => return true,
+                Ok(None) if Instant::now() >= deadline => {
+                    log::warn!(
+                        "Process {} did not exit after being killed, it may be stuck in uninterruptible sleep. Leaving it behind",
+                        self.id()
+                    );
+                    return false;
+                }
+                Ok(None) => std::thread::sleep(PROCESS_GRACEFUL_POLL_RESOLUTION),
+                Err(err) => {
+                    log::error!("Failed to wait for process {}: {}", self.id(), err);
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+/// How the processes of a consumer are stopped, from the configuration of the daemon, which is
+/// kept with the worker as it's also stopped where the configuration isn't at hand.
+#[derive(Clone, Debug)]
+struct StopPolicy {
+    // The --kill-sequence to stop the processes with, empty for SIGTERM with a grace period
+    kill_sequence: Vec<KillStep>,
+    stuck_action: StuckWorkerAction,
+    // Run for the stuck processes with --stuck-worker-action alert
+    crash_hook: Option<CrashHook>,
+}
+
+impl StopPolicy {
+    fn new(config: &DaemonConfig, consumer: &str) -> Self {
+        Self {
+            kill_sequence: config.kill_sequence.clone(),
+            stuck_action: config.stuck_worker_action,
+            crash_hook: CrashHook::new(config, consumer),
+        }
+    }
+
+    /// Handles a process that is still running after the last step of stopping it, according to
+    /// `--stuck-worker-action`, and returns whether to kill it.
+    fn kill_stuck(&self, name: &str, pid: u32, restarts: u64) -> bool {
+        match self.stuck_action {
+            StuckWorkerAction::Kill => true,
+            StuckWorkerAction::Leave => {
+                log_event!(
+                    log::Level::Error,
+                    Event::new("stuck").pid(pid),
+                    "Process {} of consumer {} did not exit after it was asked to stop, leaving it running for manual handling, see --stuck-worker-action",
+                    pid,
+                    name
+                );
+                false
+            }
+            StuckWorkerAction::Alert => {
+                log_event!(
+                    log::Level::Error,
+                    Event::new("stuck").pid(pid),
+                    "Process {} of consumer {} did not exit after it was asked to stop, running the crash hook and killing it",
+                    pid,
+                    name
+                );
+                if let Some(ref hook) = self.crash_hook {
+                    hook.run(pid, None, restarts);
+                }
+                true
+            }
+        }
+    }
+}
+
+/// The steps to stop a process with: the `--kill-sequence`, or SIGTERM and `grace_period` to
+/// exit without one.
+fn kill_steps(kill_sequence: &[KillStep], grace_period: Duration) -> Vec<KillStep> {
+    if kill_sequence.is_empty() {
+        return vec![KillStep {
+            signal: libc::SIGTERM,
+            wait: grace_period,
+        }];
+    }
+    kill_sequence.to_vec()
+}
+
+/// Sends the signal of every step to the running processes of the workers at once, and waits
+/// for them to exit before the next step. Returns whether they all exited.
+fn signal_until_exited(workers: &mut [WorkerProcess], steps: &[KillStep]) -> bool {
+    let running = |workers: &mut [WorkerProcess]| {
+        workers
+            .iter_mut()
+            .any(|w| w.processes.iter_mut().any(|p| !p.has_exited()))
+    };
+    for (i, step) in steps.iter().enumerate() {
+        for w in workers.iter_mut() {
+            w.send_signal(step.signal);
+        }
+        let deadline = Instant::now() + step.wait;
+        while running(workers) {
+            if Instant::now() >= deadline {
                 break;
             }
             std::thread::sleep(PROCESS_GRACEFUL_POLL_RESOLUTION);
-            waiting_time += PROCESS_GRACEFUL_POLL_RESOLUTION.as_millis();
         }
+        if !running(workers) {
+            return true;
+        }
+        if let Some(next) = steps.get(i + 1) {
+            log::warn!(
+                "Consumers did not exit within {}s of {}, sending {}",
+                step.wait.as_secs_f32(),
+                signal_name(step.signal).unwrap_or("the signal"),
+                signal_name(next.signal).unwrap_or("the next signal")
+            );
+        }
+    }
+    false
+}
 
-        // After it's killed, we need to call wait for the process to be removed from the process
-        // table. For more information, see NOTES in man waitpid(2).
-        self.wait().expect("Failed to wait for process to exit");
+/// How the processes stopped by `drain_workers` went.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct StopSummary {
+    // The processes that were running
+    pub stopped: usize,
+    // The processes that exited within the grace period or the steps of --kill-sequence
+    pub exited: usize,
+    pub killed: usize,
+    // The processes left running with --stuck-worker-action leave
+    pub left_running: usize,
+}
+
+/// Stops all workers. Every process gets SIGTERM at once, and they share a grace period of
+/// `timeout` to finish their current message and exit, after which the remaining ones are killed.
+/// With `--kill-sequence` its steps are taken instead, for all processes at once.
+pub fn drain_workers(workers: &mut [WorkerProcess], timeout: Duration) -> StopSummary {
+    let mut summary = StopSummary::default();
+    for w in workers.iter_mut() {
+        let _context = w.log_context();
+        log_event!(
+            log::Level::Debug,
+            Event::new("stop"),
+            "Terminating consumer: {}",
+            w.name
+        );
+        summary.stopped += w.running_pids().len();
+    }
+
+    // The workers share the configuration of the daemon
+    let kill_sequence = match workers.first() {
+        Some(w) => w.stopping.kill_sequence.clone(),
+        None => Vec::new(),
+    };
+    if !signal_until_exited(workers, &kill_steps(&kill_sequence, timeout)) {
+        if kill_sequence.is_empty() {
+            log::warn!(
+                "Consumers did not exit within the shutdown timeout of {}s",
+                timeout.as_secs_f32()
+            );
+        } else {
+            log::warn!("Consumers did not exit after the steps of --kill-sequence");
+        }
+    }
+
+    for w in workers.iter_mut() {
+        let _context = w.log_context();
+        w.kill_remaining(&mut summary);
+    }
+    let deadline = Instant::now() + PROCESS_KILL_TIMEOUT;
+    for w in workers.iter_mut() {
+        let _context = w.log_context();
+        w.reap(deadline);
+        w.terminated = true;
+    }
+    summary.exited = summary.stopped - summary.killed - summary.left_running;
+    summary
+}
+
+/// Stops the consumer processes in the Magento directory that were left behind by a daemon that
+/// was killed, so they don't process messages next to the consumers started by this daemon. They
+/// get SIGTERM and the shutdown timeout to exit, after which the remaining ones are killed.
+pub fn reclaim_orphans(config: &DaemonConfig) {
+    let orphans = match orphaned_consumers(&config.magento_dir) {
+        Ok(orphans) => orphans,
+        Err(err) => {
+            log::warn!("Failed to look for orphaned consumer processes: {}", err);
+            return;
+        }
+    };
+    if orphans.is_empty() {
+        return;
+    }
+    log::warn!(
+        "Found {} orphaned consumer processes in {}, stopping them: {}",
+        orphans.len(),
+        config.magento_dir,
+        orphans
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    for pid in orphans.iter() {
+        if let Err(err) = signal_process(*pid, libc::SIGTERM) {
+            log::error!("Failed to SIGTERM orphaned process {}: {}", pid, err);
+        }
+    }
+
+    let mut remaining = orphans;
+    let deadline = Instant::now() + config.shutdown_timeout;
+    while Instant::now() < deadline {
+        remaining.retain(|pid| process_running(*pid));
+        if remaining.is_empty() {
+            return;
+        }
+        std::thread::sleep(PROCESS_GRACEFUL_POLL_RESOLUTION);
+    }
+    for pid in remaining.iter() {
+        log::warn!(
+            "Orphaned process {} did not exit within the shutdown timeout, killing it",
+            pid
+        );
+        if let Err(err) = signal_process(*pid, libc::SIGKILL) {
+            log::error!("Failed to kill orphaned process {}: {}", pid, err);
+        }
+    }
+}
+
+/// The consumers given with `--consumer`, or else the consumers read from Magento.
+pub fn known_consumers(config: &DaemonConfig) -> Result<Vec<String>, EnvironmentError> {
+    if !config.consumers.is_empty() {
+        return Ok(config.consumers.clone());
+    }
+    read_consumer_list(config)
+}
+
+/// Warns about the consumers given with `--consumer` that the configuration would skip, as they
+/// are run anyway.
+pub fn warn_about_given_consumers(context: &DaemonContext) {
+    for consumer in context.daemon_config.consumers.iter() {
+        if let Some(reason) = configured_skip_reason(context, consumer) {
+            log::warn!(
+                "Running consumer {} given with --consumer, although it would be skipped: {}",
+                context.daemon_config.qualified_name(consumer),
+                reason
+            );
+        }
     }
 }
 
-pub fn read_consumer_list(config: &DaemonConfig) -> Vec<String> {
-    let output = Command::new("bin/magento")
-        .current_dir(&config.magento_dir)
-        .arg("queue:consumers:list")
-        .output()
-        .expect("Failed to run bin/magento queue:consumers:list");
+/// Reads the consumer list from Magento, retrying with backoff when the command fails or times out,
+/// for example during a deployment or when the database is briefly unavailable.
+pub fn read_consumer_list(config: &DaemonConfig) -> Result<Vec<String>, EnvironmentError> {
+    with_retries(CONSUMER_LIST_ATTEMPTS, CONSUMER_LIST_RETRY_DELAY, || {
+        try_read_consumer_list(config)
+    })
+}
 
-    // Split output by newline and convert from u8 sequences to String
-    output
-        .stdout
-        .split(|&x| x == b'\n')
-        .map(|x| String::from_utf8(x.to_vec()).unwrap())
+fn try_read_consumer_list(config: &DaemonConfig) -> Result<Vec<String>, EnvironmentError> {
+    if !JSON_CONSUMER_LIST_UNSUPPORTED.load(Ordering::Relaxed) {
+        let output = run_consumer_list(config, &["--format=json"])?;
+        if output.status.success() {
+            match serde_json::from_slice::<Vec<String>>(&output.stdout) {
+                Ok(consumers) => {
+                    return Ok(consumers
+                        .into_iter()
+                        .filter(|x| is_consumer_name(x))
+                        .collect())
+                }
+                // Like a banner printed before the list, which doesn't mean the option isn't
+                // supported, so JSON is tried again on the next read
+                Err(err) => log::debug!(
+                    "Failed to parse the JSON output of bin/magento queue:consumers:list, using the plain text output: {}",
+                    err
+                ),
+            }
+        } else if String::from_utf8_lossy(&output.stderr).contains("--format")
+            || String::from_utf8_lossy(&output.stdout).contains("--format")
+        {
+            log::debug!("bin/magento queue:consumers:list doesn't support --format=json, using the plain text output");
+            JSON_CONSUMER_LIST_UNSUPPORTED.store(true, Ordering::Relaxed);
+        } else {
+            return Err(EnvironmentError::new(format!(
+                "bin/magento queue:consumers:list failed with {}",
+                output.status
+            ))
+            .with_stderr(&output.stderr));
+        }
+    }
+
+    let output = run_consumer_list(config, &["--no-ansi"])?;
+    if !output.status.success() {
+        return Err(EnvironmentError::new(format!(
+            "bin/magento queue:consumers:list failed with {}",
+            output.status
+        ))
+        .with_stderr(&output.stderr));
+    }
+
+    Ok(parse_consumer_list(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Parses the plain text output of `bin/magento queue:consumers:list`, of which every line is a
+/// consumer name. Other lines, like headers or the warnings and maintenance banners Magento may
+/// print while still exiting successfully, are skipped. ANSI escape sequences are removed, as
+/// not every banner respects `--no-ansi`.
+pub fn parse_consumer_list(output: &str) -> Vec<String> {
+    strip_ansi_escapes(output)
+        .lines()
+        .map(str::trim)
         .filter(|x| !x.is_empty())
         .filter(|x| {
-            // Filter out rabbitmq consumers when rabbitmq is not configured
-            if !config.rabbitmq_configured {
-                !RABBITMQ_CONSUMER_NAMES.contains(&x.as_str())
-            } else {
-                true
+            let is_name = is_consumer_name(x);
+            if !is_name {
+                log::debug!("Skipping consumer list line: {}", x);
             }
+            is_name
         })
+        .map(|x| x.to_owned())
         .collect()
 }
 
-pub fn run_worker(context: &DaemonContext, consumer: &String) -> WorkerProcess {
-    log::debug!("Running consumer: {}", consumer);
+/// Whether the text looks like a consumer name, like `async.operations.all`, which consists of
+/// letters, digits, dots, underscores and dashes.
+pub fn is_consumer_name(text: &str) -> bool {
+    !text.is_empty()
+        && text
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || matches!(x, '.' | '_' | '-'))
+}
+
+fn run_consumer_list(config: &DaemonConfig, args: &[&str]) -> Result<Output, EnvironmentError> {
+    let mut command = config.magento_command();
+    command.arg("queue:consumers:list").args(args);
+    output_with_timeout(&mut command, config.consumer_list_timeout).map_err(|e| {
+        EnvironmentError::new(format!(
+            "Failed to run bin/magento queue:consumers:list: {}",
+            e
+        ))
+    })
+}
+
+/// The reason a consumer from the consumer list is not run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    RabbitMqNotConfigured,
+    RabbitMqUnknown,
+    NotInConsumerConfig,
+    NotInEnvConsumers,
+    ExcludedByPattern,
+    NotInConsumerListFile,
+    InvalidConfig,
+}
 
-    let mut number_of_processes = 1;
-    if let Some(processes) = context.consumer_config.multiple_processes.get(consumer) {
-        number_of_processes = *processes;
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            SkipReason::RabbitMqNotConfigured => "RabbitMQ not configured",
+            SkipReason::RabbitMqUnknown => "RabbitMQ detection failed, see --rabbitmq-on-unknown",
+            SkipReason::NotInConsumerConfig => "not in Magento consumers config",
+            SkipReason::NotInEnvConsumers => "not in MWD_CONSUMERS",
+            SkipReason::ExcludedByPattern => "excluded by --include/--exclude",
+            SkipReason::NotInConsumerListFile => "disabled in --consumer-list-file",
+            SkipReason::InvalidConfig => "invalid configuration",
+        })
     }
+}
 
-    let mut processes = Vec::<std::process::Child>::new();
+/// Why the consumer is not run, or `None` when it is. Consumers given with `--consumer` are run
+/// regardless of the configuration, but not of `--consumer-list-file` and `--skip-invalid`.
+pub fn skip_reason(context: &DaemonContext, consumer: &str) -> Option<SkipReason> {
+    if let Some(ref consumer_list) = context.consumer_list {
+        if !consumer_list.allows(consumer) {
+            return Some(SkipReason::NotInConsumerListFile);
+        }
+    }
+    let config = &context.daemon_config;
+    if config.skip_invalid
+        && context
+            .consumer_config
+            .consumer_issue(config, consumer)
+            .is_some()
+    {
+        return Some(SkipReason::InvalidConfig);
+    }
+    if !context.daemon_config.consumers.is_empty() {
+        return None;
+    }
+    configured_skip_reason(context, consumer)
+}
 
-    for i in 0..number_of_processes {
-        let mut command = Command::new("bin/magento");
-        let command = command
-            .current_dir(&context.daemon_config.magento_dir)
-            .arg("queue:consumers:start")
-            .arg(consumer)
-            .arg("--max-messages")
-            .arg(context.consumer_config.max_messages.to_string());
+/// Why the configuration skips the consumer, or `None` when it doesn't.
+fn configured_skip_reason(context: &DaemonContext, consumer: &str) -> Option<SkipReason> {
+    let config = &context.daemon_config;
+    // The consumers of the environment take precedence over the ones of app/etc/env.php
+    let (consumers, not_in_consumers) = if config.env_consumers.is_empty() {
+        (
+            &context.consumer_config.consumers,
+            SkipReason::NotInConsumerConfig,
+        )
+    } else {
+        (&config.env_consumers, SkipReason::NotInEnvConsumers)
+    };
+    if !config.rabbitmq_configured && config.is_amqp_consumer(consumer) {
+        match config.rabbitmq_detection {
+            RabbitMqDetection::Unknown => Some(SkipReason::RabbitMqUnknown),
+            _ => Some(SkipReason::RabbitMqNotConfigured),
+        }
+    } else if !consumers.is_empty() && !consumers.iter().any(|x| x == consumer) {
+        Some(not_in_consumers)
+    } else if !config.consumer_matches_patterns(consumer) {
+        Some(SkipReason::ExcludedByPattern)
+    } else {
+        None
+    }
+}
 
-        // We could disable the --multi-process or --single-thread options with a --no-strict-mode flag,
-        // but not sure if users need that, so this is the default for now.
-        if number_of_processes > 1 {
-            command.arg("--multi-process");
-            command.arg(i.to_string());
-        } else {
-            command.arg("--single-thread");
+/// The consumers to run, read from Magento and filtered by the configuration, in the order of
+/// `supervision_order`.
+pub fn applicable_consumers(context: &DaemonContext) -> Result<Vec<String>, EnvironmentError> {
+    let consumers = known_consumers(&context.daemon_config)?;
+    Ok(filter_applicable(context, consumers))
+}
+
+/// The given consumers that are run according to the configuration, in the order of
+/// `supervision_order`.
+pub fn filter_applicable(context: &DaemonContext, consumers: Vec<String>) -> Vec<String> {
+    let mut consumers: Vec<String> = consumers
+        .into_iter()
+        .filter(|x| skip_reason(context, x).is_none())
+        .collect();
+    consumers.sort_by(|a, b| supervision_order(context, a).cmp(&supervision_order(context, b)));
+    consumers
+}
+
+/// The key the consumers are started, restarted and capped in: the highest priority first, and
+/// by name within the same priority. It doesn't depend on the order Magento lists them in, which
+/// differs between runs and versions, so daemons with the same configuration start the same
+/// consumers in the same order.
+pub fn supervision_order<'a>(
+    context: &DaemonContext,
+    consumer: &'a str,
+) -> (Reverse<i32>, &'a str) {
+    (
+        Reverse(context.consumer_config.priority_for(consumer)),
+        consumer,
+    )
+}
+
+/// The number of processes to run for the given consumer, which is zero when it doesn't fit in
+/// `--max-total-processes`.
+pub fn number_of_processes(context: &DaemonContext, consumer: &str) -> u32 {
+    match context.autoscaled.get(consumer) {
+        Some(processes) => *processes,
+        None => unscaled_processes(context, consumer),
+    }
+}
+
+/// The number of processes of the given consumer without `--autoscale`.
+fn unscaled_processes(context: &DaemonContext, consumer: &str) -> u32 {
+    match context.process_caps.get(consumer) {
+        Some(processes) => *processes,
+        None => configured_processes(context, consumer),
+    }
+}
+
+/// The number of processes configured in `multiple_processes` for the given consumer.
+fn configured_processes(context: &DaemonContext, consumer: &str) -> u32 {
+    match context.consumer_config.multiple_processes.get(consumer) {
+        Some(processes) => *processes,
+        None => 1,
+    }
+}
+
+/// Scales the consumers down to stay within `--max-total-processes`, where the consumers are
+/// given with the highest priority first. The scaled down numbers of processes are stored in
+/// `process_caps`, and the consumers that don't fit at all are removed from `consumers`.
+pub fn cap_processes(context: &mut DaemonContext, consumers: &mut Vec<String>) {
+    let max_total = match context.daemon_config.max_total_processes {
+        Some(max_total) => max_total,
+        None => return,
+    };
+    let requested: Vec<u32> = consumers
+        .iter()
+        .map(|x| configured_processes(context, x))
+        .collect();
+    let counts = cap_process_counts(&requested, max_total);
+    let caps: HashMap<String, u32> = consumers
+        .iter()
+        .zip(requested.iter().zip(counts.iter()))
+        .filter(|(_, (requested, count))| requested != count)
+        .map(|(consumer, (_, count))| (consumer.clone(), *count))
+        .collect();
+    if caps != context.process_caps {
+        if caps.is_empty() {
+            log::info!(
+                "The consumers fit in --max-total-processes {} again",
+                max_total
+            );
+        }
+        for (consumer, (requested, count)) in consumers.iter().zip(requested.iter().zip(&counts)) {
+            if requested != count {
+                log::warn!(
+                    "Running {} of the {} processes of consumer {} to stay within --max-total-processes {}",
+                    count,
+                    requested,
+                    context.daemon_config.qualified_name(consumer),
+                    max_total
+                );
+            }
+        }
+    }
+    context.process_caps = caps;
+    consumers.retain(|x| number_of_processes(context, x) > 0);
+}
+
+/// The range a consumer is scaled in with `--autoscale`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AutoscaleLimits {
+    pub min_processes: u32,
+    pub max_processes: u32,
+    pub messages_per_process: u64,
+}
+
+/// The autoscale range of the given consumer, where the maximum defaults to the number of
+/// processes it runs without `--autoscale`, and which is lowered for `--max-total-processes`.
+pub fn autoscale_limits(context: &DaemonContext, consumer: &str) -> AutoscaleLimits {
+    let config = context
+        .consumer_config
+        .autoscale
+        .get(consumer)
+        .copied()
+        .unwrap_or_default();
+    let mut max_processes = config
+        .max_processes
+        .unwrap_or_else(|| configured_processes(context, consumer));
+    if let Some(cap) = context.process_caps.get(consumer) {
+        max_processes = max_processes.min(*cap);
+    }
+    let max_processes = max_processes.max(1);
+    AutoscaleLimits {
+        min_processes: config.min_processes.unwrap_or(1).clamp(1, max_processes),
+        max_processes,
+        messages_per_process: config
+            .messages_per_process
+            .unwrap_or(context.daemon_config.messages_per_process),
+    }
+}
+
+/// The number of processes to run for `backlog` waiting messages, one per `messages_per_process`
+/// messages within the limits. It's scaled up at once, but down by one process at a time, so a
+/// queue that is briefly empty doesn't stop all processes.
+pub fn autoscale_processes(backlog: u64, current: u32, limits: AutoscaleLimits) -> u32 {
+    let wanted = backlog.div_ceil(limits.messages_per_process.max(1));
+    let wanted = u32::try_from(wanted)
+        .unwrap_or(u32::MAX)
+        .clamp(limits.min_processes, limits.max_processes);
+    if wanted < current {
+        (current - 1).clamp(limits.min_processes, limits.max_processes)
+    } else {
+        wanted
+    }
+}
+
+/// The number of processes of a consumer as configured in `multiple_processes`, and as run after
+/// the validation, `--max-total-processes` and `--autoscale`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ProcessCount {
+    // Left out when multiple_processes isn't set for the consumer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub configured: Option<i64>,
+    pub effective: u32,
+    // Why the effective number differs from the configured one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// The configured and the effective number of processes of the given consumer.
+pub fn process_count(context: &DaemonContext, consumer: &str) -> ProcessCount {
+    let configured = context.consumer_config.configured_processes_for(consumer);
+    let config = &context.daemon_config;
+    let (effective, reason) = if let Some(reason) = skip_reason(context, consumer) {
+        (0, Some(format!("skipped: {}", reason)))
+    } else if config.autoscale_interval.is_some() {
+        let limits = autoscale_limits(context, consumer);
+        (
+            number_of_processes(context, consumer),
+            Some(format!(
+                "scaled by --autoscale between {} and {}",
+                limits.min_processes, limits.max_processes
+            )),
+        )
+    } else if let Some(cap) = context.process_caps.get(consumer) {
+        let reason = match config.max_total_processes {
+            Some(max_total) if *cap == 0 => format!("exceeds --max-total-processes {}", max_total),
+            Some(max_total) => format!("scaled down for --max-total-processes {}", max_total),
+            None => "scaled down".to_owned(),
+        };
+        (*cap, Some(reason))
+    } else if configured.is_some_and(|x| x < 0) {
+        (1, Some("negative value ignored".to_owned()))
+    } else {
+        (configured_processes(context, consumer), None)
+    };
+    ProcessCount {
+        configured,
+        effective,
+        reason,
+    }
+}
+
+/// The configured and the effective numbers of processes of the given consumers, and of the
+/// consumers `multiple_processes` is set for.
+pub fn process_counts(
+    context: &DaemonContext,
+    consumers: &[String],
+) -> BTreeMap<String, ProcessCount> {
+    consumers
+        .iter()
+        .map(String::as_str)
+        .chain(context.consumer_config.configured_process_consumers())
+        .map(|consumer| (consumer.to_owned(), process_count(context, consumer)))
+        .collect()
+}
+
+/// Scales the numbers of processes down proportionally so they add up to at most `max_total`.
+/// Every consumer keeps at least one process, unless there are more consumers than `max_total`,
+/// in which case the first ones get one. The processes that are left after rounding down go to
+/// the consumers with the largest remainders, and ties go to the first consumer.
+pub fn cap_process_counts(requested: &[u32], max_total: u32) -> Vec<u32> {
+    let total: u32 = requested.iter().sum();
+    if total <= max_total {
+        return requested.to_vec();
+    }
+    let mut left = max_total;
+    let mut counts: Vec<u32> = requested
+        .iter()
+        .map(|x| {
+            let count = (*x).min(1).min(left);
+            left -= count;
+            count
+        })
+        .collect();
+    let extra: Vec<u32> = requested.iter().zip(&counts).map(|(r, c)| r - c).collect();
+    let total_extra: u32 = extra.iter().sum();
+    if left == 0 || total_extra == 0 {
+        return counts;
+    }
+    let mut remainders = Vec::new();
+    for (index, extra) in extra.iter().enumerate() {
+        let share = u64::from(*extra) * u64::from(left);
+        counts[index] += (share / u64::from(total_extra)) as u32;
+        remainders.push((share % u64::from(total_extra), index));
+    }
+    let rest = max_total - counts.iter().sum::<u32>();
+    // Stable, so ties go to the first consumer
+    remainders.sort_by_key(|(remainder, _)| Reverse(*remainder));
+    for (_, index) in remainders.into_iter().take(rest as usize) {
+        counts[index] += 1;
+    }
+    counts
+}
+
+/// Builds the `bin/magento` arguments for process `index` of the given consumer.
+pub fn worker_command_args(context: &DaemonContext, consumer: &str, index: u32) -> Vec<String> {
+    let mut args = vec!["queue:consumers:start".to_owned(), consumer.to_owned()];
+
+    // Without a limit the flag is omitted, as Magento doesn't define what a limit of 0 means
+    let max_messages = context.consumer_config.max_messages_for(consumer);
+    if max_messages > 0 {
+        args.push("--max-messages".to_owned());
+        args.push(max_messages.to_string());
+    }
+
+    // Without strict mode the processes are still started, but Magento doesn't enforce them.
+    // Consumers that may be scaled up by --autoscale start with --multi-process right away, so
+    // the arguments of their processes don't change with the scaling. The `single_thread`
+    // setting takes precedence over the strict mode for consumers running a single process.
+    let autoscaled = context.daemon_config.autoscale_interval.is_some()
+        && autoscale_limits(context, consumer).max_processes > 1;
+    if number_of_processes(context, consumer) > 1 || autoscaled {
+        if context.daemon_config.strict_mode {
+            args.push("--multi-process".to_owned());
+            args.push(index.to_string());
         }
+    } else if context
+        .consumer_config
+        .single_thread_for(&context.daemon_config, consumer)
+    {
+        args.push("--single-thread".to_owned());
+    }
 
-        let process = command
-            .spawn()
-            .expect("Failed to run bin/magento queue:consumers:start");
+    // The extra arguments are passed as they are, after the arguments of the daemon
+    args.extend(context.daemon_config.consumer_args.iter().cloned());
+    if let Some(consumer_args) = context.consumer_config.consumer_args.get(consumer) {
+        args.extend(consumer_args.iter().cloned());
+    }
 
-        processes.push(process);
+    args
+}
+
+/// Starts the consumer. When the daemon runs into a limit of the operating system, like the
+/// number of open files, the consumer is started later by `ensure_running` with a backoff, so the
+/// daemon keeps running the consumers it could start.
+pub fn run_worker(
+    context: &DaemonContext,
+    consumer: &str,
+) -> Result<WorkerProcess, EnvironmentError> {
+    let mut worker = new_worker(context, consumer);
+    let _context = worker.log_context();
+    match spawn_processes(context, consumer, &worker.output) {
+        Ok(processes) => worker.processes = processes,
+        Err(err) if is_resource_limit(&err) => {
+            worker.schedule_retry(context, &spawn_error(&worker.name, &err))
+        }
+        Err(err) => return Err(spawn_error(&worker.name, &err)),
     }
+    Ok(worker)
+}
 
+/// Adopts the processes of the consumer that were handed off by the daemon this one replaced on
+/// an upgrade, see `handoff`. The consumer is scaled to its configured number of processes, so
+/// processes that exited during the upgrade are started again. When starting them fails, they're
+/// retried by `ensure_running` with a backoff.
+pub fn adopt_worker(
+    context: &DaemonContext,
+    consumer: &str,
+    handed_off: &[HandedOffProcess],
+) -> WorkerProcess {
+    let mut worker = new_worker(context, consumer);
+    let _context = worker.log_context();
+    worker.processes = handed_off
+        .iter()
+        .filter_map(|p| ConsumerProcess::adopt(&context.daemon_config, p, &worker.output))
+        .collect();
+    worker.processes.sort_by_key(|p| p.index);
+    let processes = number_of_processes(context, consumer);
+    let complete = worker.processes.iter().map(|p| p.index).eq(0..processes);
+    if !complete {
+        let _ = worker.scale_to(processes, context);
+    }
+    worker
+}
+
+fn new_worker(context: &DaemonContext, consumer: &str) -> WorkerProcess {
     WorkerProcess {
-        consumer: consumer.clone(),
-        processes,
+        consumer: consumer.to_owned(),
+        instance: context.daemon_config.instance.clone(),
+        name: context.daemon_config.qualified_name(consumer),
+        processes: Vec::new(),
+        restarts: 0,
+        recycles: 0,
+        started_at: Instant::now(),
+        last_exit: None,
+        spawn_failures: 0,
+        retry_at: None,
+        crashes: 0,
+        restart_at: None,
+        drained_at: None,
+        terminated: false,
+        stopped_at: None,
+        stopping: StopPolicy::new(&context.daemon_config, consumer),
+        stuck: Vec::new(),
+        output: Arc::new(OutputReports {
+            rate: OutputRateLimiter::new(context.daemon_config.output_rate_limit),
+            ..Default::default()
+        }),
+        unknown_reported: false,
+        unknown_checked: false,
+        exits: VecDeque::new(),
+        draining: false,
+    }
+}
+
+fn spawn_error(name: &str, err: &std::io::Error) -> EnvironmentError {
+    EnvironmentError::new(format!(
+        "Failed to start consumer {}: {}",
+        name,
+        describe_spawn_error(err)
+    ))
+}
+
+/// Starts all processes of the consumer. When any of them fails to start, the ones that did are
+/// stopped again.
+fn spawn_processes(
+    context: &DaemonContext,
+    consumer: &str,
+    reports: &Arc<OutputReports>,
+) -> std::io::Result<Vec<ConsumerProcess>> {
+    let mut processes = Vec::new();
+    for i in 0..number_of_processes(context, consumer) {
+        match ConsumerProcess::spawn(context, consumer, i, reports) {
+            Ok(process) => processes.push(process),
+            Err(err) => {
+                let stopping = StopPolicy::new(&context.daemon_config, consumer);
+                for p in processes.iter_mut() {
+                    p.stop(
+                        &context.daemon_config.qualified_name(consumer),
+                        &stopping,
+                        0,
+                    );
+                }
+                return Err(err);
+            }
+        }
+    }
+    Ok(processes)
+}
+
+/// The `--on-crash` hook of a consumer.
+#[derive(Clone, Debug)]
+struct CrashHook {
+    command: String,
+    magento_dir: String,
+    instance: Option<String>,
+    consumer: String,
+    // The consumer name used in the logs
+    name: String,
+}
+
+impl CrashHook {
+    fn new(config: &DaemonConfig, consumer: &str) -> Option<Self> {
+        Some(Self {
+            command: config.on_crash.clone()?,
+            magento_dir: config.magento_dir.clone(),
+            instance: config.instance.clone(),
+            consumer: consumer.to_owned(),
+            name: config.qualified_name(consumer),
+        })
+    }
+
+    /// Runs the hook on a separate thread, so a slow hook doesn't delay the restart. The event is
+    /// described by environment variables: a crash with the exit status, or a process that is
+    /// stuck while stopping it without one.
+    fn run(&self, pid: u32, status: Option<ExitStatus>, restarts: u64) {
+        let signal = status.and_then(|x| x.signal()).map(|signal| {
+            signal_name(signal)
+                .map(|name| name.to_owned())
+                .unwrap_or_else(|| signal.to_string())
+        });
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(&self.command)
+            .current_dir(&self.magento_dir)
+            .env("CONSUMER_NAME", &self.consumer)
+            .env("PID", pid.to_string())
+            .env(
+                "EXIT_CODE",
+                status
+                    .and_then(|x| x.code())
+                    .map(|x| x.to_string())
+                    .unwrap_or_default(),
+            )
+            .env("SIGNAL", signal.unwrap_or_default())
+            .env("RESTART_COUNT", restarts.to_string())
+            .env("REASON", if status.is_some() { "crash" } else { "stuck" });
+        if let Some(ref instance) = self.instance {
+            command.env("INSTANCE", instance);
+        }
+
+        let name = self.name.clone();
+        let result = std::thread::Builder::new()
+            .name("crash hook".to_owned())
+            .spawn(
+                move || match output_with_timeout(&mut command, CRASH_HOOK_TIMEOUT) {
+                    Ok(output) if output.status.success() => {
+                        log::debug!("Crash hook for consumer {} succeeded", name)
+                    }
+                    Ok(output) => {
+                        log::error!(
+                            "Crash hook for consumer {} {}",
+                            name,
+                            describe_exit_status(output.status)
+                        );
+                        let stderr = String::from_utf8_lossy(&output.stderr);
+                        if !stderr.trim().is_empty() {
+                            log::error!("Error output:\n{}", stderr.trim());
+                        }
+                    }
+                    Err(err) => {
+                        log::error!("Failed to run crash hook for consumer {}: {}", name, err)
+                    }
+                },
+            );
+        if let Err(err) = result {
+            log::error!("Failed to start crash hook thread: {}", err);
+        }
+    }
+}
+
+/// The level the standard output of the consumers is logged at, `None` to discard it with
+/// `--forward-stderr-only`.
+fn stdout_level(config: &DaemonConfig) -> Option<log::Level> {
+    (!config.forward_stderr_only).then_some(log::Level::Info)
+}
+
+fn log_suppressed(name: &str, lines: u64) {
+    log::warn!(
+        "Suppressed {} lines of output from consumer {}, exceeding --output-rate-limit",
+        lines,
+        name
+    );
+}
+
+/// Forwards the output of a consumer process to the log at `level`, on a thread that ends with
+/// the output. Discarded output, without a level, is still read for the reports.
+fn forward_output<R>(
+    config: &DaemonConfig,
+    consumer: &str,
+    pid: u32,
+    output: R,
+    level: Option<log::Level>,
+    reports: &Arc<OutputReports>,
+) -> std::io::Result<JoinHandle<()>>
+where
+    R: Read + Send + 'static,
+{
+    let instance = config.instance.clone();
+    let consumer = consumer.to_owned();
+    let name = config.qualified_name(&consumer);
+    let pattern = config.processed_pattern.clone();
+    let reports = Arc::clone(reports);
+    std::thread::Builder::new()
+        .name(format!("output {}", name))
+        .spawn(move || {
+            logging::set_context(instance.as_deref(), &consumer, Some(pid));
+            // Lines are split manually, so non-UTF-8 output doesn't stop the forwarding.
+            for line in BufReader::new(output).split(b'\n') {
+                match line {
+                    Ok(line) => {
+                        let line = String::from_utf8_lossy(&line);
+                        if let Some(n) = processed_messages(&line, pattern.as_deref()) {
+                            reports.processed.fetch_add(n, Ordering::Relaxed);
+                        }
+                        if reports_unknown_consumer(&line, &consumer) {
+                            reports.unknown_consumer.store(true, Ordering::Relaxed);
+                        }
+                        let level = match level {
+                            Some(level) => level,
+                            None => continue,
+                        };
+                        let (forward, suppressed) = reports.rate.allow(Instant::now());
+                        if let Some(suppressed) = suppressed {
+                            log_suppressed(&name, suppressed);
+                        }
+                        if forward {
+                            log::log!(level, "[{}] {}", name, line)
+                        }
+                    }
+                    Err(err) => {
+                        log::debug!("Failed to read output of consumer {}: {:?}", name, err);
+                        break;
+                    }
+                }
+            }
+            // Otherwise the last lines dropped would only be reported with the next output
+            match reports.rate.take_suppressed() {
+                0 => {}
+                suppressed => log_suppressed(&name, suppressed),
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt, path::PathBuf, thread};
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::control::ConsumerStatus;
+    use crate::input::Args;
+
+    // Only handles the PHP that is run by the daemon: the configuration queries, which get the
+    // config file as the Magento configuration, and bin/magento.
+    const FAKE_PHP: &str = r#"#!/bin/sh
+case "$1" in
+  --version) echo "PHP 8.2.0 (cli)";;
+  -r) case "$2" in
+        *queue_message_status*) echo '{}';;
+        *amqp*) echo false;;
+        *getConnection*) echo '{}';;
+        *) cat config;;
+      esac;;
+  bin/magento) shift; exec ./bin/magento "$@";;
+esac
+"#;
+
+    // Every started process appends its PID to the pids file. The JSON consumer list is the output
+    // of the list-json script, without it the plain text list is printed, which isn't JSON.
+    const FAKE_MAGENTO: &str = r#"#!/bin/sh
+case "$1" in
+  queue:consumers:list)
+    if [ "$2" = --format=json ] && [ -f list-json ]; then exec sh ./list-json; fi
+    cat consumers;;
+  queue:consumers:start)
+    echo "$$" >> pids
+    case "$2" in
+      forks) sleep 60 & echo "$!" >> pids; exec sleep 60;;
+      *) exec sleep 60;;
+    esac;;
+esac
+"#;
+
+    /// A Magento installation in a temporary directory with the given Magento configuration, in
+    /// which PHP and bin/magento are shell scripts. The consumers run until they're terminated,
+    /// and `forks` forks a child first.
+    struct FakeMagento {
+        dir: PathBuf,
+    }
+
+    impl FakeMagento {
+        fn new(name: &str, config: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "magento2-worker-daemon-unit-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("bin")).unwrap();
+            for (path, script) in [("php", FAKE_PHP), ("bin/magento", FAKE_MAGENTO)] {
+                fs::write(dir.join(path), script).unwrap();
+                fs::set_permissions(dir.join(path), fs::Permissions::from_mode(0o755)).unwrap();
+            }
+            fs::write(dir.join("config"), config).unwrap();
+            fs::write(dir.join("consumers"), "").unwrap();
+            fs::write(dir.join("pids"), "").unwrap();
+            Self { dir }
+        }
+
+        /// The context with the given command line options.
+        fn context(&self, args: &[&str]) -> DaemonContext {
+            let php = self.dir.join("php");
+            let mut command_line = vec![
+                "magento2-worker-daemon",
+                "--working-directory",
+                self.dir.to_str().unwrap(),
+                "--php-binary",
+                php.to_str().unwrap(),
+            ];
+            command_line.extend(args);
+            DaemonContext::from_args(&Args::parse_from(command_line))
+                .unwrap()
+                .remove(0)
+        }
+
+        /// Waits until the consumers started `count` processes, and returns their PIDs.
+        fn started_pids(&self, count: usize) -> Vec<u32> {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                let pids: Vec<u32> = fs::read_to_string(self.dir.join("pids"))
+                    .unwrap()
+                    .lines()
+                    .map(|x| x.parse().unwrap())
+                    .collect();
+                if pids.len() >= count || Instant::now() > deadline {
+                    return pids;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+    }
+
+    impl Drop for FakeMagento {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn appends_the_extra_consumer_arguments() {
+        let magento = FakeMagento::new(
+            "consumer-args",
+            r#"{"cron_run":false,"consumer_args":{"second":["--area-code=crontab"]}}"#,
+        );
+        let context = magento.context(&["--consumer-arg", "--batch-size=50"]);
+        assert_eq!(
+            worker_command_args(&context, "first", 0),
+            [
+                "queue:consumers:start",
+                "first",
+                "--max-messages",
+                "10000",
+                "--single-thread",
+                "--batch-size=50"
+            ]
+        );
+        // The arguments of the consumer come after the ones given to the daemon
+        assert_eq!(
+            worker_command_args(&context, "second", 0)[4..],
+            ["--single-thread", "--batch-size=50", "--area-code=crontab"]
+        );
+    }
+
+    #[test]
+    fn scales_a_consumer_without_restarting_the_running_processes() {
+        let magento = FakeMagento::new("scale-to", r#"{"cron_run":false}"#);
+        let context = magento.context(&[]);
+        let mut worker = run_worker(&context, "runs.forever").unwrap();
+        let first = worker.running_pids();
+        assert_eq!(first.len(), 1);
+
+        // Scaling up keeps the running process, and starts the missing ones
+        worker.scale_to(3, &context).unwrap();
+        let scaled_up = worker.running_pids();
+        assert_eq!(scaled_up.len(), 3);
+        assert_eq!(scaled_up[0], first[0]);
+
+        // Scaling down stops the process with the highest index
+        worker.scale_to(2, &context).unwrap();
+        assert_eq!(worker.running_pids(), scaled_up[..2]);
+
+        // Scaling to the same count leaves the processes alone
+        worker.scale_to(2, &context).unwrap();
+        assert_eq!(worker.running_pids(), scaled_up[..2]);
+        assert_eq!(worker.restart_count(), 0);
+        drain_workers(std::slice::from_mut(&mut worker), Duration::from_secs(1));
+        assert!(worker.running_pids().is_empty());
+    }
+
+    #[test]
+    fn counts_the_restarts_and_the_uptime() {
+        let magento = FakeMagento::new("restarts", r#"{"cron_run":false}"#);
+        let context = magento.context(&[]);
+        let mut worker = run_worker(&context, "runs.forever").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(worker.uptime() >= Duration::from_millis(100));
+
+        // A restart starts the uptime over
+        worker.restart(&context).unwrap();
+        assert!(worker.uptime() < Duration::from_millis(100));
+        let status = serde_json::to_value(ConsumerStatus::new(
+            None,
+            &mut worker,
+            process_count(&context, "runs.forever"),
+        ))
+        .unwrap();
+        assert_eq!(status["restarts"], 1);
+        assert_eq!(status["uptime_secs"], 0);
+        drain_workers(std::slice::from_mut(&mut worker), Duration::from_secs(1));
+        assert!(worker.running_pids().is_empty());
+    }
+
+    #[test]
+    fn reads_the_consumer_list_as_json_or_plain_text() {
+        let magento = FakeMagento::new("consumer-list-format", r#"{"cron_run":false}"#);
+        fs::write(magento.dir.join("consumers"), "plain.consumer").unwrap();
+        let list_json = |script: &str| fs::write(magento.dir.join("list-json"), script).unwrap();
+        let supported = r#"echo '["json.consumer"]'"#;
+        list_json(supported);
+        let context = magento.context(&[]);
+        let read = || read_consumer_list(&context.daemon_config).unwrap();
+        assert_eq!(read(), ["json.consumer"]);
+
+        // Output that isn't JSON falls back to the plain text output for this read only
+        list_json(&format!(
+            "echo 'Maintenance mode is enabled'\n{}",
+            supported
+        ));
+        assert_eq!(read(), ["plain.consumer"]);
+        list_json(supported);
+        assert_eq!(read(), ["json.consumer"]);
+
+        // A Magento version without --format=json uses the plain text output from then on
+        list_json("echo 'The \"--format\" option does not exist.' >&2\nexit 1");
+        assert_eq!(read(), ["plain.consumer"]);
+        list_json(supported);
+        assert_eq!(read(), ["plain.consumer"]);
+    }
+
+    #[test]
+    fn passes_max_messages_only_with_a_limit() {
+        let magento = FakeMagento::new(
+            "max-messages",
+            r#"{"cron_run":false,"max_messages":100,"max_messages_per_consumer":{"unlimited":0}}"#,
+        );
+        let context = magento.context(&[]);
+        assert_eq!(
+            worker_command_args(&context, "limited", 0),
+            [
+                "queue:consumers:start",
+                "limited",
+                "--max-messages",
+                "100",
+                "--single-thread"
+            ]
+        );
+        assert_eq!(
+            worker_command_args(&context, "unlimited", 0),
+            ["queue:consumers:start", "unlimited", "--single-thread"]
+        );
+    }
+
+    #[test]
+    fn dropping_a_worker_stops_its_processes() {
+        let magento = FakeMagento::new(
+            "drop-worker",
+            r#"{"cron_run":false,"multiple_processes":{"forks":2}}"#,
+        );
+        let context = magento.context(&[]);
+        let forks = run_worker(&context, "forks").unwrap();
+        let runs = run_worker(&context, "runs.forever").unwrap();
+        // Every process of forks writes its own PID and the one of its forked child
+        let pids = magento.started_pids(5);
+        assert_eq!(pids.len(), 5);
+
+        // Without terminating them first, like on an early return
+        drop(forks);
+        drop(runs);
+        // Forked processes aren't children of the test, so they're reaped by init
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while pids.iter().any(|x| process_running(*x)) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let running: Vec<_> = pids.into_iter().filter(|x| process_running(*x)).collect();
+        assert!(running.is_empty(), "processes left running: {:?}", running);
+    }
+
+    #[test]
+    fn keeps_the_processes_within_the_maximum() {
+        assert_eq!(cap_process_counts(&[4, 2, 1], 8), [4, 2, 1]);
+        assert_eq!(cap_process_counts(&[4, 2, 1], 7), [4, 2, 1]);
+    }
+
+    #[test]
+    fn scales_the_processes_down_proportionally() {
+        // Every consumer keeps one process, and the 3 left are shared 6:2:0
+        assert_eq!(cap_process_counts(&[7, 3, 1], 6), [3, 2, 1]);
+        assert_eq!(cap_process_counts(&[8, 8], 4), [2, 2]);
+        // The process left after rounding down goes to the largest remainder
+        assert_eq!(cap_process_counts(&[2, 5, 3], 6), [1, 3, 2]);
+        // And ties go to the first consumer, which has the highest priority
+        assert_eq!(cap_process_counts(&[3, 3], 5), [3, 2]);
+        assert_eq!(cap_process_counts(&[2, 2, 2], 3), [1, 1, 1]);
+        for max_total in 1..=20 {
+            let counts = cap_process_counts(&[10, 1, 6, 3], max_total);
+            assert_eq!(counts.iter().sum::<u32>(), max_total.min(20));
+        }
+    }
+
+    #[test]
+    fn runs_the_first_consumers_when_not_all_of_them_fit() {
+        assert_eq!(cap_process_counts(&[2, 1, 3], 2), [1, 1, 0]);
+        assert_eq!(cap_process_counts(&[2, 1, 3], 1), [1, 0, 0]);
+    }
+
+    #[test]
+    fn skips_the_banners_in_the_consumer_list() {
+        let output = "\x1b[37;41m                                           \x1b[39;49m\n\
+                      \x1b[37;41m  Application is in maintenance mode.  \x1b[39;49m\n\
+                      Warning: Undefined array key \"db\" in app/etc/env.php on line 12\n\
+                      Deprecated:Creation_of_dynamic_property\n\
+                      \x1b]0;bin/magento\x07\x1b[32masync.operations.all\x1b[0m\n\
+                      \n\
+                      \x20 product_action_attribute.update\r\n\
+                      inventory.mass-update\n";
+        assert_eq!(
+            parse_consumer_list(output),
+            [
+                "async.operations.all",
+                "product_action_attribute.update",
+                "inventory.mass-update"
+            ]
+        );
+    }
+
+    const LIMITS: AutoscaleLimits = AutoscaleLimits {
+        min_processes: 1,
+        max_processes: 4,
+        messages_per_process: 100,
+    };
+
+    #[test]
+    fn scales_up_to_the_backlog_at_once() {
+        assert_eq!(autoscale_processes(0, 1, LIMITS), 1);
+        assert_eq!(autoscale_processes(100, 1, LIMITS), 1);
+        assert_eq!(autoscale_processes(101, 1, LIMITS), 2);
+        assert_eq!(autoscale_processes(350, 1, LIMITS), 4);
+        // Up to the maximum
+        assert_eq!(autoscale_processes(100_000, 2, LIMITS), 4);
+        assert_eq!(autoscale_processes(u64::MAX, 1, LIMITS), 4);
+    }
+
+    #[test]
+    fn scales_down_one_process_at_a_time() {
+        assert_eq!(autoscale_processes(0, 4, LIMITS), 3);
+        assert_eq!(autoscale_processes(150, 4, LIMITS), 3);
+        assert_eq!(autoscale_processes(150, 3, LIMITS), 2);
+        assert_eq!(autoscale_processes(150, 2, LIMITS), 2);
+        // Down to the minimum
+        let limits = AutoscaleLimits {
+            min_processes: 2,
+            ..LIMITS
+        };
+        assert_eq!(autoscale_processes(0, 2, limits), 2);
+        // And at once when the current number is outside the limits
+        assert_eq!(autoscale_processes(0, 8, limits), 4);
+        assert_eq!(autoscale_processes(0, 1, limits), 2);
+    }
+
+    #[test]
+    fn stops_the_processes_with_the_kill_sequence() {
+        let grace_period = Duration::from_secs(3);
+        // Without a kill sequence the processes get SIGTERM, and the grace period to exit
+        assert_eq!(
+            kill_steps(&[], grace_period),
+            [KillStep {
+                signal: libc::SIGTERM,
+                wait: grace_period
+            }]
+        );
+        let args = Args::parse_from([
+            "magento2-worker-daemon",
+            "--kill-sequence",
+            "INT:5,TERM:0.5",
+        ]);
+        assert_eq!(
+            kill_steps(&args.kill_sequence.unwrap().0, grace_period),
+            [
+                KillStep {
+                    signal: libc::SIGINT,
+                    wait: Duration::from_secs(5)
+                },
+                KillStep {
+                    signal: libc::SIGTERM,
+                    wait: Duration::from_millis(500)
+                }
+            ]
+        );
+        // SIGKILL is always sent after the last step
+        let args = ["magento2-worker-daemon", "--kill-sequence", "TERM:5,KILL:1"];
+        assert!(Args::try_parse_from(args).is_err());
     }
 }
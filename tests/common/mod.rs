@@ -0,0 +1,165 @@
+//! A fake Magento installation for the tests, in which PHP and `bin/magento` are shell scripts.
+#![allow(dead_code)]
+
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use magento2_worker_daemon::{
+    config::{DaemonContext, EnvironmentError},
+    input::Args,
+    util::process_running,
+};
+
+// Only handles the PHP that is run by the daemon: the configuration queries and bin/magento. The
+// queue backlogs are read from the backlogs file.
+const FAKE_PHP: &str = r#"#!/bin/sh
+case "$1" in
+  --version) echo "PHP 8.2.0 (cli)";;
+  -r) case "$2" in
+        *queue_message_status*) cat backlogs 2>/dev/null || echo '{}';;
+        *amqp*) echo false;;
+        *getConnection*) cat connections 2>/dev/null || echo '{}';;
+        *) echo '{"cron_run":false}';;
+      esac;;
+  bin/magento) shift; exec ./bin/magento "$@";;
+esac
+"#;
+
+// Every started process appends its PID and consumer to the pids file, so tests can check none
+// are left running.
+const FAKE_MAGENTO: &str = r#"#!/bin/sh
+case "$1" in
+  queue:consumers:list) cat consumers;;
+  queue:consumers:start)
+    echo "$$ $2" >> pids
+    case "$2" in
+      exits.immediately) exit 1;;
+      runs.then.exits) sleep 1; exit 0;;
+      ignores.term) trap '' TERM; exec sleep 60;;
+      forks) sleep 60 & echo "$! $2" >> pids; exec sleep 60;;
+      talks) while true; do echo "$$ is running"; sleep 0.1; done;;
+      reports) sleep 0.2; echo "Processed 5 messages"; exit 0;;
+      unknown) echo "Consumer '$2' is not declared." >&2; exit 1;;
+      spins) while true; do :; done;;
+      *) exec sleep 60;;
+    esac;;
+esac
+"#;
+
+/// A Magento installation in a temporary directory, with the given consumers. The consumers
+/// `exits.immediately`, `runs.then.exits`, `ignores.term`, `forks`, `talks`, `reports`, `spins`
+/// and `unknown` behave like their name, and any other consumer runs until it's terminated.
+pub struct FakeMagento {
+    pub dir: PathBuf,
+}
+
+impl FakeMagento {
+    pub fn new(name: &str, consumers: &[&str]) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "magento2-worker-daemon-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        write_executable(&dir.join("php"), FAKE_PHP);
+        write_executable(&dir.join("bin/magento"), FAKE_MAGENTO);
+        fs::write(dir.join("consumers"), consumers.join("\n")).unwrap();
+        fs::write(dir.join("pids"), "").unwrap();
+        Self { dir }
+    }
+
+    pub fn context(&self) -> DaemonContext {
+        self.try_context(&[]).unwrap()
+    }
+
+    /// The context with the given command line options on top of the defaults of the tests.
+    pub fn try_context(&self, args: &[&str]) -> Result<DaemonContext, EnvironmentError> {
+        let mut command_line = vec!["magento2-worker-daemon".to_owned()];
+        command_line.extend(self.default_args());
+        command_line.extend(args.iter().map(|x| x.to_string()));
+        let args = Args::parse_from(command_line);
+        Ok(DaemonContext::from_args(&args)?.remove(0))
+    }
+
+    /// The daemon binary with the defaults of the tests.
+    pub fn daemon_command(&self) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_magento2-worker-daemon"));
+        command.args(self.default_args());
+        command
+    }
+
+    fn default_args(&self) -> Vec<String> {
+        let php = self.dir.join("php");
+        [
+            "--working-directory",
+            self.dir.to_str().unwrap(),
+            "--php-binary",
+            php.to_str().unwrap(),
+            "--shutdown-timeout",
+            "1",
+        ]
+        .map(String::from)
+        .to_vec()
+    }
+
+    /// Replaces the fake PHP binary.
+    pub fn set_php(&self, script: &str) {
+        write_executable(&self.dir.join("php"), script);
+    }
+
+    /// The PIDs of all processes started by the consumers so far.
+    pub fn started_pids(&self) -> Vec<u32> {
+        self.started().into_iter().map(|(pid, _)| pid).collect()
+    }
+
+    /// The PIDs of the processes started by the consumer so far.
+    pub fn started_pids_of(&self, consumer: &str) -> Vec<u32> {
+        self.started()
+            .into_iter()
+            .filter(|(_, x)| x == consumer)
+            .map(|(pid, _)| pid)
+            .collect()
+    }
+
+    fn started(&self) -> Vec<(u32, String)> {
+        fs::read_to_string(self.dir.join("pids"))
+            .unwrap()
+            .lines()
+            .map(|x| {
+                let (pid, consumer) = x.split_once(' ').unwrap();
+                (pid.parse().unwrap(), consumer.to_owned())
+            })
+            .collect()
+    }
+
+    pub fn assert_no_processes_left(&self) {
+        let pids = self.started_pids();
+        assert!(!pids.is_empty());
+        // Processes that are no children of the test, like forked ones, are reaped by init
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while pids.iter().any(|pid| process_running(*pid)) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let running: Vec<_> = pids.into_iter().filter(|x| process_running(*x)).collect();
+        assert!(running.is_empty(), "processes left running: {:?}", running);
+    }
+}
+
+impl Drop for FakeMagento {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn write_executable(path: &Path, contents: &str) {
+    fs::write(path, contents).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
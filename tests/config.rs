@@ -0,0 +1,558 @@
+//! Exercises loading the configuration from a fake Magento installation.
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::{
+    fs,
+    time::{Duration, Instant},
+};
+
+use common::FakeMagento;
+use magento2_worker_daemon::{
+    config::{Backoff, RabbitMqDetection},
+    worker::{self, AutoscaleLimits},
+};
+
+#[test]
+fn fails_when_a_configuration_query_hangs() {
+    let magento = FakeMagento::new("query-hangs", &[]);
+    magento.set_php(
+        r#"#!/bin/sh
+case "$1" in
+  --version) echo "PHP 8.2.0 (cli)";;
+  *) exec sleep 60;;
+esac
+"#,
+    );
+
+    let loading_at = Instant::now();
+    let err = match magento.try_context(&["--startup-timeout", "1"]) {
+        Ok(_) => panic!("the configuration was loaded"),
+        Err(err) => err,
+    };
+    assert!(err.message.contains("timed out"), "{}", err.message);
+    // Every attempt of the query times out
+    assert!(loading_at.elapsed() < Duration::from_secs(15));
+}
+
+#[test]
+fn reads_the_consumer_list_with_a_banner_from_magento() {
+    let magento = FakeMagento::new(
+        "consumer-list-banner",
+        &[
+            "Warning: Magento is in maintenance mode, consumers may not run.",
+            "\x1b[33mexportProcessor\x1b[0m",
+            "async.operations.all",
+        ],
+    );
+    let context = magento.context();
+    let consumers = worker::read_consumer_list(&context.daemon_config).unwrap();
+    assert_eq!(consumers, ["exportProcessor", "async.operations.all"]);
+}
+
+#[test]
+fn skips_the_consumers_with_an_amqp_connection_without_rabbitmq() {
+    let consumers = [
+        "inventory.mass.update",
+        "product_alert",
+        "async.operations.all",
+    ];
+    let magento = FakeMagento::new("connections", &consumers);
+    fs::write(
+        magento.dir.join("connections"),
+        r#"{"inventory.mass.update":"db","product_alert":"amqp","async.operations.all":"db"}"#,
+    )
+    .unwrap();
+    let context = magento.context();
+    let consumers = worker::applicable_consumers(&context).unwrap();
+    assert_eq!(consumers, ["async.operations.all", "inventory.mass.update"]);
+}
+
+#[test]
+fn reads_the_backoff_of_the_consumers() {
+    let magento = FakeMagento::new("backoff", &["a", "b", "c", "d"]);
+    magento.set_php(
+        r#"#!/bin/sh
+case "$1" in
+  --version) echo "PHP 8.2.0 (cli)";;
+  -r) case "$2" in
+        *amqp*) echo false;;
+        *getConnection*) echo '{}';;
+        *) echo '{"cron_run":false,"backoff":{"b":{"initial_delay":0.5,"multiplier":3,"max_delay":10,"min_healthy_runtime":30},"c":{"initial_delay":120},"d":{"multiplier":0.5}}}';;
+      esac;;
+  bin/magento) shift; exec ./bin/magento "$@";;
+esac
+"#,
+    );
+
+    let err = magento.try_context(&[]).unwrap_err();
+    assert_eq!(
+        err.message,
+        "Magento consumer backoff max_delay for c is less than its initial_delay. Use --skip-invalid to skip invalid consumers and run the others"
+    );
+
+    let context = magento.try_context(&["--skip-invalid"]).unwrap();
+    let consumers = worker::applicable_consumers(&context).unwrap();
+    assert_eq!(consumers, ["a", "b"]);
+    let config = &context.consumer_config;
+    // The defaults
+    assert_eq!(
+        config.backoff_for(&context.daemon_config, "a"),
+        Backoff {
+            initial_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(60),
+            min_healthy_runtime: Duration::from_secs(10),
+        }
+    );
+    assert_eq!(
+        config.backoff_for(&context.daemon_config, "b"),
+        Backoff {
+            initial_delay: Duration::from_millis(500),
+            multiplier: 3.0,
+            max_delay: Duration::from_secs(10),
+            min_healthy_runtime: Duration::from_secs(30),
+        }
+    );
+}
+
+#[test]
+fn falls_back_to_the_rabbitmq_consumers_without_connections() {
+    let consumers = [
+        "inventory.mass.update",
+        "product_alert",
+        "async.operations.all",
+    ];
+    let magento = FakeMagento::new("no-connections", &consumers);
+    fs::write(magento.dir.join("connections"), "Fatal error").unwrap();
+    let context = magento
+        .try_context(&["--rabbitmq-consumer", "product_alert"])
+        .unwrap();
+    assert!(context.daemon_config.consumer_connections.is_empty());
+    let consumers = worker::applicable_consumers(&context).unwrap();
+    assert_eq!(consumers, ["inventory.mass.update"]);
+}
+
+#[test]
+fn reads_the_autoscale_limits() {
+    let magento = FakeMagento::new("autoscale", &["a", "b", "c"]);
+    magento.set_php(
+        r#"#!/bin/sh
+case "$1" in
+  --version) echo "PHP 8.2.0 (cli)";;
+  -r) case "$2" in
+        *amqp*) echo false;;
+        *getConnection*) echo '{}';;
+        *) echo '{"cron_run":false,"multiple_processes":{"a":4},"autoscale":{"b":{"max_processes":6,"messages_per_process":50},"c":{"min_processes":3,"max_processes":2}}}';;
+      esac;;
+  bin/magento) shift; exec ./bin/magento "$@";;
+esac
+"#,
+    );
+
+    let err = magento.try_context(&["--autoscale"]).unwrap_err();
+    assert_eq!(
+        err.message,
+        "Magento consumer autoscale min_processes for c exceeds its max_processes. Use --skip-invalid to skip invalid consumers and run the others"
+    );
+
+    let context = magento
+        .try_context(&["--autoscale", "--skip-invalid"])
+        .unwrap();
+    let consumers = worker::applicable_consumers(&context).unwrap();
+    assert_eq!(consumers, ["a", "b"]);
+    assert_eq!(
+        worker::autoscale_limits(&context, "a"),
+        AutoscaleLimits {
+            min_processes: 1,
+            max_processes: 4,
+            messages_per_process: 100,
+        }
+    );
+    assert_eq!(
+        worker::autoscale_limits(&context, "b"),
+        AutoscaleLimits {
+            min_processes: 1,
+            max_processes: 6,
+            messages_per_process: 50,
+        }
+    );
+}
+
+#[test]
+fn sets_single_thread_per_consumer() {
+    let magento = FakeMagento::new("single-thread", &["default", "resident", "multi"]);
+    magento.set_php(
+        r#"#!/bin/sh
+case "$1" in
+  --version) echo "PHP 8.2.0 (cli)";;
+  -r) case "$2" in
+        *amqp*) echo false;;
+        *getConnection*) echo '{}';;
+        *) echo '{"cron_run":false,"max_messages":0,"multiple_processes":{"multi":2},"single_thread":{"resident":false,"multi":true}}';;
+      esac;;
+  bin/magento) shift; exec ./bin/magento "$@";;
+esac
+"#,
+    );
+
+    let context = magento.context();
+    let args = |consumer| worker::worker_command_args(&context, consumer, 0)[2..].join(" ");
+    assert_eq!(args("default"), "--single-thread");
+    assert_eq!(args("resident"), "");
+    // Multiple processes always run with --multi-process
+    assert_eq!(args("multi"), "--multi-process 0");
+
+    // And the setting overrides --no-strict-mode
+    let context = magento.try_context(&["--no-strict-mode"]).unwrap();
+    let args = |consumer| worker::worker_command_args(&context, consumer, 0)[2..].join(" ");
+    assert_eq!(args("default"), "");
+    assert_eq!(args("resident"), "");
+    assert_eq!(args("multi"), "");
+    let mut config = context.clone();
+    config
+        .consumer_config
+        .single_thread
+        .insert("default".to_owned(), true);
+    assert_eq!(
+        worker::worker_command_args(&config, "default", 0)[2..].join(" "),
+        "--single-thread"
+    );
+}
+
+#[test]
+fn skips_the_consumers_with_an_invalid_configuration() {
+    let consumers = [
+        "inventory.mass.update",
+        "product_alert",
+        "async.operations.all",
+    ];
+    let magento = FakeMagento::new("invalid", &consumers);
+    magento.set_php(
+        r#"#!/bin/sh
+case "$1" in
+  --version) echo "PHP 8.2.0 (cli)";;
+  -r) case "$2" in
+        *amqp*) echo true;;
+        *getConnection*) echo '{}';;
+        *) echo '{"cron_run":false,"multiple_processes":{"inventory.mass.update":-1,"product_alert":100,"async.operations.all":2}}';;
+      esac;;
+  bin/magento) shift; exec ./bin/magento "$@";;
+esac
+"#,
+    );
+
+    let err = magento.try_context(&[]).unwrap_err();
+    assert_eq!(
+        err.message,
+        "Magento consumer multiple_processes value -1 for inventory.mass.update must not be negative. Use --skip-invalid to skip invalid consumers and run the others"
+    );
+
+    let context = magento.try_context(&["--skip-invalid"]).unwrap();
+    let consumers = worker::applicable_consumers(&context).unwrap();
+    assert_eq!(consumers, ["async.operations.all"]);
+    assert_eq!(
+        worker::number_of_processes(&context, "async.operations.all"),
+        2
+    );
+}
+
+#[test]
+fn fails_when_the_user_to_run_as_does_not_exist() {
+    let magento = FakeMagento::new("unknown-user", &[]);
+    let err = match magento.try_context(&["--run-as-user", "no-such-user"]) {
+        Ok(_) => panic!("the configuration was loaded"),
+        Err(err) => err,
+    };
+    assert!(err.message.contains("no-such-user"), "{}", err.message);
+}
+
+#[test]
+fn fails_when_the_cgroup_parent_is_not_a_cgroup() {
+    let magento = FakeMagento::new("no-cgroup", &[]);
+    let parent = magento.dir.join("cgroup");
+    let err = match magento.try_context(&["--cgroup-parent", parent.to_str().unwrap()]) {
+        Ok(_) => panic!("the configuration was loaded"),
+        Err(err) => err,
+    };
+    assert!(
+        err.message.contains("isn't a cgroup v2 directory"),
+        "{}",
+        err.message
+    );
+}
+
+#[test]
+fn diagnoses_the_environment() {
+    let magento = FakeMagento::new("doctor", &["first", "second"]);
+    fs::create_dir_all(magento.dir.join("app/etc")).unwrap();
+    fs::write(magento.dir.join("app/etc/env.php"), "<?php return [];").unwrap();
+    fs::write(magento.dir.join("connections"), r#"{"first":"db"}"#).unwrap();
+
+    let output = magento.daemon_command().arg("doctor").output().unwrap();
+    let report = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", report);
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(
+        lines[1..],
+        [
+            "[PASS] PHP: PHP 8.2.0 (cli)",
+            "[PASS] app/etc/env.php: readable",
+            "[PASS] cron_consumers_runner: cron_run is disabled",
+            "[PASS] RabbitMQ: not configured, the consumers with an amqp connection are skipped",
+            "[PASS] Consumer connections: detected for 1 consumers",
+            "[PASS] Consumer list: 2 consumers found",
+            "[PASS] Applicable consumers: 2 consumers would be run",
+        ]
+    );
+
+    magento.set_php(
+        r#"#!/bin/sh
+case "$1" in
+  --version) echo "PHP 8.2.0 (cli)";;
+  -r) case "$2" in
+        *amqp*) echo false;;
+        *getConnection*) echo '{}';;
+        *) echo '{"cron_run":true}';;
+      esac;;
+  bin/magento) shift; exec ./bin/magento "$@";;
+esac
+"#,
+    );
+    let output = magento.daemon_command().arg("doctor").output().unwrap();
+    let report = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(report.contains(
+        "[FAIL] cron_consumers_runner: cron_run is enabled, so Magento cron starts the consumers too\n       hint: Set cron_consumers_runner.cron_run to false in app/etc/env.php"
+    ));
+    // The other checks still run
+    assert!(report.contains("[PASS] Consumer list: 2 consumers found"));
+}
+
+#[test]
+fn checks_the_configuration_without_starting_consumers() {
+    let magento = FakeMagento::new("config-check", &["first", "second"]);
+    let output = magento
+        .daemon_command()
+        .arg("--config-check")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Configuration OK, 2 applicable consumers would be started\n"
+    );
+    assert!(magento.started_pids().is_empty());
+
+    let output = magento
+        .daemon_command()
+        .args(["--config-check", "--include", "no.such.consumer"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No applicable consumers found"));
+
+    magento.set_php(
+        r#"#!/bin/sh
+case "$1" in
+  --version) echo "PHP 8.2.0 (cli)";;
+  -r) echo '{"cron_run":true}';;
+esac
+"#,
+    );
+    let output = magento
+        .daemon_command()
+        .arg("--config-check")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Magento cron worker is enabled"));
+}
+
+#[test]
+fn prints_the_configured_and_effective_processes() {
+    let magento = FakeMagento::new("print-processes", &["big", "small", "broken"]);
+    magento.set_php(
+        r#"#!/bin/sh
+case "$1" in
+  --version) echo "PHP 8.2.0 (cli)";;
+  -r) case "$2" in
+        *amqp*) echo false;;
+        *getConnection*) echo '{}';;
+        *) echo '{"cron_run":false,"multiple_processes":{"big":6,"small":2,"broken":-1}}';;
+      esac;;
+  bin/magento) shift; exec ./bin/magento "$@";;
+esac
+"#,
+    );
+
+    let output = magento
+        .daemon_command()
+        .args([
+            "--print-config",
+            "--skip-invalid",
+            "--max-total-processes",
+            "4",
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let printed: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        printed[0]["processes"],
+        serde_json::json!({
+            "big": {
+                "configured": 6,
+                "effective": 3,
+                "reason": "scaled down for --max-total-processes 4"
+            },
+            "small": {"configured": 2, "effective": 1, "reason": "scaled down for --max-total-processes 4"},
+            "broken": {"configured": -1, "effective": 0, "reason": "skipped: invalid configuration"},
+        })
+    );
+}
+
+#[test]
+fn leaves_out_the_consumers_capped_to_no_processes() {
+    let magento = FakeMagento::new("print-capped", &["first", "second"]);
+    let output = magento
+        .daemon_command()
+        .args(["--print-config", "--max-total-processes", "1"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let printed: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let processes = printed[0]["processes"].as_object().unwrap();
+    assert_eq!(processes.keys().collect::<Vec<_>>(), ["first"]);
+}
+
+#[test]
+fn keeps_the_rabbitmq_consumers_when_the_detection_fails() {
+    let magento = FakeMagento::new("rabbitmq-unknown", &["async.operations.all", "other"]);
+    magento.set_php(
+        r#"#!/bin/sh
+case "$1" in
+  --version) echo "PHP 8.2.0 (cli)";;
+  -r) case "$2" in
+        *amqp*) echo "PHP Fatal error: out of memory" >&2; exit 255;;
+        *getConnection*) echo '{}';;
+        *) echo '{"cron_run":false}';;
+      esac;;
+  bin/magento) shift; exec ./bin/magento "$@";;
+esac
+"#,
+    );
+
+    let context = magento.try_context(&[]).unwrap();
+    assert_eq!(
+        context.daemon_config.rabbitmq_detection,
+        RabbitMqDetection::Unknown
+    );
+    assert_eq!(
+        worker::applicable_consumers(&context).unwrap(),
+        ["async.operations.all", "other"]
+    );
+
+    let context = magento
+        .try_context(&["--rabbitmq-on-unknown", "exclude"])
+        .unwrap();
+    assert_eq!(worker::applicable_consumers(&context).unwrap(), ["other"]);
+    assert_eq!(
+        worker::skip_reason(&context, "async.operations.all"),
+        Some(worker::SkipReason::RabbitMqUnknown)
+    );
+}
+
+#[test]
+fn prefers_the_options_over_the_environment_over_env_php() {
+    let magento = FakeMagento::new("env-precedence", &["first", "second", "third", "other"]);
+    magento.set_php(
+        r#"#!/bin/sh
+case "$1" in
+  --version) echo "PHP 8.2.0 (cli)";;
+  -r) case "$2" in
+        *amqp*) echo false;;
+        *getConnection*) echo '{}';;
+        *) echo '{"cron_run":false,"max_messages":100,"multiple_processes":{"first":2,"second":2}}';;
+      esac;;
+  bin/magento) shift; exec ./bin/magento "$@";;
+esac
+"#,
+    );
+    let print_config = |args: &[&str]| {
+        let output = magento
+            .daemon_command()
+            .arg("--print-config")
+            .args(args)
+            .env("MWD_MAX_MESSAGES", "50")
+            .env("MWD_CONSUMERS", "first,second,third")
+            .env("MWD_MULTIPLE_PROCESSES", "second=3,third=4")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let printed: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        printed[0].clone()
+    };
+    let applicable = |args: &[&str]| -> Vec<String> {
+        let output = magento
+            .daemon_command()
+            .arg("--dry-run")
+            .args(args)
+            .env("MWD_CONSUMERS", "first,second,third")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .filter(|x| !x.starts_with(' '))
+            .map(|x| x.split(' ').next().unwrap().to_owned())
+            .collect()
+    };
+
+    let printed = print_config(&["--processes", "third=5"]);
+    assert_eq!(printed["consumer_config"]["max_messages"], 50);
+    assert_eq!(
+        printed["daemon_config"]["env_consumers"],
+        serde_json::json!(["first", "second", "third"])
+    );
+    assert_eq!(applicable(&[]), ["first", "second", "third"]);
+    let effective = |consumer: &str| printed["processes"][consumer]["effective"].clone();
+    assert_eq!(effective("first"), 2);
+    assert_eq!(effective("second"), 3);
+    assert_eq!(effective("third"), 5);
+
+    // The patterns filter the consumers of the environment further, rather than replacing them
+    assert_eq!(applicable(&["--exclude", "second"]), ["first", "third"]);
+
+    let printed = print_config(&["--max-messages", "20", "--consumer", "other"]);
+    assert_eq!(printed["consumer_config"]["max_messages"], 20);
+    assert_eq!(
+        printed["daemon_config"]["consumers"],
+        serde_json::json!(["other"])
+    );
+    assert_eq!(applicable(&["--consumer", "other"]), ["other"]);
+}
+
+#[test]
+fn orders_the_consumers_by_priority_then_name() {
+    let magento = FakeMagento::new("ordering", &[]);
+    let mut context = magento.context();
+    context
+        .consumer_config
+        .priority
+        .insert("inventory.reservations.update".to_owned(), 10);
+    let ordered = [
+        "inventory.reservations.update",
+        "codegeneratorProcessor",
+        "exportProcessor",
+        "product_action_attribute.update",
+    ];
+    // Every order Magento could list them in
+    for shuffled in [[0, 1, 2, 3], [3, 2, 1, 0], [2, 0, 3, 1], [1, 3, 0, 2]] {
+        let consumers = shuffled.iter().map(|i| ordered[*i].to_owned()).collect();
+        assert_eq!(worker::filter_applicable(&context, consumers), ordered);
+    }
+}
@@ -0,0 +1,170 @@
+//! Exercises the export of the consumer lifecycle as OpenTelemetry traces, against a fake
+//! collector that records the spans posted to it.
+#![cfg(all(target_os = "linux", feature = "otlp"))]
+
+mod common;
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::FakeMagento;
+use magento2_worker_daemon::{
+    otlp::{self, Endpoint},
+    supervisor::{Daemon, TICK_INTERVAL},
+    worker,
+};
+use serde_json::Value;
+
+/// Serves a collector on a free port, and returns its URL and the bodies of the requests to it.
+fn fake_collector() -> (String, mpsc::Receiver<(String, Value)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some(length) = header.to_lowercase().strip_prefix("content-length:") {
+                    content_length = length.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            let _ = sender.send((request_line, serde_json::from_slice(&body).unwrap()));
+        }
+    });
+    (url, receiver)
+}
+
+fn attribute<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    value["attributes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|x| x["key"] == key)
+        .map(|x| &x["value"])
+}
+
+fn event_names(span: &Value) -> Vec<&str> {
+    span["events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|x| x["name"].as_str().unwrap())
+        .collect()
+}
+
+#[test]
+fn exports_a_span_per_consumer_with_a_span_per_process() {
+    let (url, requests) = fake_collector();
+    otlp::init(Endpoint::parse(&url).unwrap()).unwrap();
+
+    let magento = FakeMagento::new("otlp", &["runs.forever", "exits.immediately"]);
+    let context = magento.context();
+    let consumers = worker::applicable_consumers(&context).unwrap();
+    let term = Arc::new(AtomicBool::new(false));
+    let mut daemon = Daemon::new(vec![(context, consumers)], Arc::clone(&term));
+    daemon.start().unwrap();
+    daemon.supervise();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while magento.started_pids_of("exits.immediately").len() < 2 && Instant::now() < deadline {
+        assert!(daemon.tick());
+        thread::sleep(TICK_INTERVAL);
+    }
+    term.store(true, Ordering::Relaxed);
+    daemon.shutdown();
+    otlp::shutdown(Duration::from_secs(5));
+    magento.assert_no_processes_left();
+
+    let mut spans = Vec::new();
+    while let Ok((request_line, body)) = requests.try_recv() {
+        assert!(request_line.starts_with("POST /v1/traces "));
+        let resource_spans = &body["resourceSpans"][0];
+        assert_eq!(
+            attribute(&resource_spans["resource"], "service.name").unwrap()["stringValue"],
+            "magento2-worker-daemon"
+        );
+        spans.extend(
+            resource_spans["scopeSpans"][0]["spans"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .cloned(),
+        );
+    }
+    let consumer_span = |name: &str| {
+        spans
+            .iter()
+            .find(|x| x["name"] == name && x.get("parentSpanId").is_none())
+            .unwrap_or_else(|| panic!("no span of consumer {}", name))
+    };
+    let process_spans = |consumer: &Value| -> Vec<&Value> {
+        spans
+            .iter()
+            .filter(|x| x["parentSpanId"] == consumer["spanId"])
+            .collect()
+    };
+    for span in spans.iter() {
+        assert!(span["endTimeUnixNano"].is_string());
+    }
+
+    // The process that ran until the daemon stopped it is a child of the span of its consumer
+    let running = consumer_span("runs.forever");
+    assert_eq!(
+        attribute(running, "consumer").unwrap()["stringValue"],
+        "runs.forever"
+    );
+    let processes = process_spans(running);
+    assert_eq!(processes.len(), 1);
+    assert_eq!(processes[0]["traceId"], running["traceId"]);
+    assert_eq!(processes[0]["name"], "process");
+    let pid = magento.started_pids_of("runs.forever")[0];
+    assert_eq!(
+        attribute(processes[0], "pid").unwrap()["intValue"],
+        pid.to_string()
+    );
+    assert_eq!(event_names(processes[0])[0], "spawn");
+    assert!(processes[0].get("status").is_none());
+
+    // Every crash ends the span of its process as an error, with the exit code of the process
+    let crashing = consumer_span("exits.immediately");
+    let processes = process_spans(crashing);
+    assert!(processes.len() >= 2);
+    let crashed: Vec<_> = processes
+        .iter()
+        .filter(|x| event_names(x) == ["spawn", "exit"])
+        .collect();
+    assert!(!crashed.is_empty());
+    for span in crashed {
+        assert_eq!(span["status"]["code"], 2);
+        let exit = &span["events"][1];
+        assert_eq!(attribute(exit, "exit_code").unwrap()["intValue"], "1");
+        assert!(attribute(exit, "message").unwrap()["stringValue"]
+            .as_str()
+            .unwrap()
+            .contains("exited with code 1"));
+    }
+    assert!(event_names(crashing)
+        .iter()
+        .any(|x| ["restart", "backoff"].contains(x)));
+}
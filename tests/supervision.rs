@@ -0,0 +1,799 @@
+//! Exercises the supervision of consumers against a fake Magento installation. The consumers
+//! behave according to their name, see `FakeMagento`.
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::FakeMagento;
+use magento2_worker_daemon::{
+    config::DaemonContext,
+    control::{Command, ConsumerStatus, ControlRequest},
+    supervisor::{Daemon, ShutdownReason, TICK_INTERVAL},
+    util::{kill_process_group, process_running, MessageBudget, RestartLimiter, Stagger},
+    worker::{self, WorkerProcess},
+};
+
+/// Calls `ensure_running` until `f` returns true, and returns whether it did within `timeout`.
+fn supervise_until<F>(
+    context: &DaemonContext,
+    worker: &mut WorkerProcess,
+    timeout: Duration,
+    mut f: F,
+) -> bool
+where
+    F: FnMut(&WorkerProcess) -> bool,
+{
+    let stagger = Stagger::new(Duration::ZERO);
+    let limiter = RestartLimiter::new(0);
+    let idle = AtomicBool::new(false);
+    let budget = MessageBudget::new(None);
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        worker.ensure_running(context, &stagger, &limiter, &idle, &budget);
+        if f(worker) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+#[test]
+fn restarts_a_consumer_that_exits_immediately() {
+    let magento = FakeMagento::new("exits-immediately", &["exits.immediately"]);
+    let context = magento.context();
+    let mut worker = worker::run_worker(&context, "exits.immediately").unwrap();
+
+    // The first crash is restarted right away, later ones back off
+    let restarted = supervise_until(&context, &mut worker, Duration::from_secs(5), |w| {
+        w.restart_count() >= 1
+    });
+    assert!(restarted);
+    assert!(!worker.last_exit().unwrap().success());
+    worker::drain_workers(std::slice::from_mut(&mut worker), Duration::from_secs(1));
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn counts_the_messages_the_consumer_reports_to_have_processed() {
+    let magento = FakeMagento::new("reports", &["reports"]);
+    let context = magento.context();
+    let mut worker = worker::run_worker(&context, "reports").unwrap();
+
+    // Every run of the consumer reports 5 messages before it exits
+    let counted = supervise_until(&context, &mut worker, Duration::from_secs(5), |w| {
+        w.processed_messages() >= 10
+    });
+    assert!(counted);
+    let count = worker::process_count(&context, "reports");
+    let status = serde_json::to_value(ConsumerStatus::new(None, &mut worker, count)).unwrap();
+    assert!(status["processed_messages"].as_u64().unwrap() >= 10);
+    worker::drain_workers(std::slice::from_mut(&mut worker), Duration::from_secs(1));
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn reports_a_crashing_consumer_as_backing_off() {
+    let magento = FakeMagento::new("backing-off", &["exits.immediately"]);
+    let context = magento.context();
+    let mut worker = worker::run_worker(&context, "exits.immediately").unwrap();
+    let state = |worker: &mut WorkerProcess| {
+        let count = worker::process_count(&context, "exits.immediately");
+        serde_json::to_value(ConsumerStatus::new(None, worker, count)).unwrap()["state"].clone()
+    };
+    assert_eq!(state(&mut worker), "running");
+
+    let backing_off = supervise_until(&context, &mut worker, Duration::from_secs(5), |w| {
+        w.backing_off()
+    });
+    assert!(backing_off);
+    assert_eq!(state(&mut worker), "backing_off");
+    worker::drain_workers(std::slice::from_mut(&mut worker), Duration::from_secs(1));
+}
+
+#[test]
+fn throttles_the_restarts_after_a_backoff_and_the_recycles() {
+    let magento = FakeMagento::new("throttled-backoff", &["exits.immediately", "runs.forever"]);
+    magento.set_php(
+        r#"#!/bin/sh
+case "$1" in
+  --version) echo "PHP 8.2.0 (cli)";;
+  -r) case "$2" in
+        *amqp*) echo false;;
+        *getConnection*) echo '{}';;
+        *) echo '{"cron_run":false,"backoff":{"exits.immediately":{"initial_delay":0.5,"multiplier":1}}}';;
+      esac;;
+  bin/magento) shift; exec ./bin/magento "$@";;
+esac
+"#,
+    );
+    let context = magento.try_context(&["--max-lifetime", "1"]).unwrap();
+    let stagger = Stagger::new(Duration::ZERO);
+    let limiter = RestartLimiter::new(10);
+    let idle = AtomicBool::new(false);
+    let budget = MessageBudget::new(None);
+    let supervise = |worker: &mut WorkerProcess, duration: Duration| {
+        let deadline = Instant::now() + duration;
+        while Instant::now() < deadline {
+            worker.ensure_running(&context, &stagger, &limiter, &idle, &budget);
+            thread::sleep(Duration::from_millis(50));
+        }
+    };
+    let mut crashing = worker::run_worker(&context, "exits.immediately").unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !crashing.backing_off() && Instant::now() < deadline {
+        supervise(&mut crashing, Duration::from_millis(50));
+    }
+    assert!(crashing.backing_off());
+    let restarts = crashing.restart_count();
+    // Only the restart right after the first crash is counted, not the one backing off
+    assert_eq!(limiter.recent_restarts(), (restarts as usize, false));
+
+    // Other consumers use up the restarts, like in a mass failure
+    while limiter.try_acquire("other", 0) {}
+    let mut recycling = worker::run_worker(&context, "runs.forever").unwrap();
+    supervise(&mut crashing, Duration::from_secs(1));
+    supervise(&mut recycling, Duration::from_millis(1500));
+    assert_eq!(crashing.restart_count(), restarts);
+    assert!(crashing.backing_off());
+    assert_eq!(recycling.recycle_count(), 0);
+    assert_eq!(magento.started_pids_of("runs.forever").len(), 1);
+
+    let mut workers = [crashing, recycling];
+    worker::drain_workers(&mut workers, Duration::from_secs(1));
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn restarts_a_consumer_that_exits_successfully() {
+    let magento = FakeMagento::new("runs-then-exits", &["runs.then.exits"]);
+    let context = magento.context();
+    let mut worker = worker::run_worker(&context, "runs.then.exits").unwrap();
+
+    let restarted = supervise_until(&context, &mut worker, Duration::from_secs(5), |w| {
+        w.restart_count() >= 1
+    });
+    assert!(restarted);
+    assert!(worker.last_exit().unwrap().success());
+    assert_eq!(worker.running_pids().len(), 1);
+    worker::drain_workers(std::slice::from_mut(&mut worker), Duration::from_secs(1));
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn keeps_a_consumer_left_down_past_its_max_lifetime() {
+    let magento = FakeMagento::new("left-down-lifetime", &["runs.then.exits"]);
+    magento.set_php(
+        r#"#!/bin/sh
+case "$1" in
+  --version) echo "PHP 8.2.0 (cli)";;
+  -r) case "$2" in
+        *amqp*) echo false;;
+        *getConnection*) echo '{}';;
+        *) echo '{"cron_run":false,"restart_policy":{"runs.then.exits":"never"}}';;
+      esac;;
+  bin/magento) shift; exec ./bin/magento "$@";;
+esac
+"#,
+    );
+    let context = magento.try_context(&["--max-lifetime", "2"]).unwrap();
+    let mut worker = worker::run_worker(&context, "runs.then.exits").unwrap();
+
+    // The process exits after a second, and isn't recycled once it's older than its max lifetime
+    let recycled = supervise_until(&context, &mut worker, Duration::from_secs(4), |w| {
+        w.recycle_count() > 0 || w.restart_count() > 0
+    });
+    assert!(!recycled);
+    assert_eq!(worker.left_down_count(), 1);
+    assert!(worker.running_pids().is_empty());
+    assert_eq!(magento.started_pids_of("runs.then.exits").len(), 1);
+    worker::drain_workers(std::slice::from_mut(&mut worker), Duration::from_secs(1));
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn kills_a_consumer_that_ignores_sigterm() {
+    let magento = FakeMagento::new("ignores-term", &["ignores.term"]);
+    let context = magento.context();
+    let mut worker = worker::run_worker(&context, "ignores.term").unwrap();
+    // Gives the consumer the time to ignore SIGTERM
+    thread::sleep(Duration::from_millis(300));
+
+    let timeout = Duration::from_millis(500);
+    let stopping_at = Instant::now();
+    worker::drain_workers(std::slice::from_mut(&mut worker), timeout);
+    assert!(stopping_at.elapsed() >= timeout);
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn escalates_the_signals_of_the_kill_sequence() {
+    let magento = FakeMagento::new("kill-sequence", &["ignores.term"]);
+    let context = magento
+        .try_context(&["--kill-sequence", "TERM:0.3,INT:5"])
+        .unwrap();
+    let mut worker = worker::run_worker(&context, "ignores.term").unwrap();
+    // Gives the consumer the time to ignore SIGTERM
+    thread::sleep(Duration::from_millis(300));
+
+    // SIGINT stops it long before it would be killed
+    let stopping_at = Instant::now();
+    worker::drain_workers(std::slice::from_mut(&mut worker), Duration::from_secs(10));
+    assert!(stopping_at.elapsed() >= Duration::from_millis(300));
+    assert!(stopping_at.elapsed() < Duration::from_secs(5));
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn stops_the_processes_a_consumer_forked() {
+    let magento = FakeMagento::new("forks", &["forks"]);
+    let context = magento.context();
+    let mut worker = worker::run_worker(&context, "forks").unwrap();
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(magento.started_pids().len(), 2);
+
+    worker::drain_workers(std::slice::from_mut(&mut worker), Duration::from_secs(1));
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn daemon_leaves_no_processes_behind() {
+    let consumers = ["runs.forever", "runs.then.exits", "ignores.term", "forks"];
+    let magento = FakeMagento::new("daemon", &consumers);
+    let context = magento.context();
+    let consumers = worker::applicable_consumers(&context).unwrap();
+    assert_eq!(consumers.len(), 4);
+
+    let term = Arc::new(AtomicBool::new(false));
+    let mut daemon = Daemon::new(vec![(context, consumers)], Arc::clone(&term));
+    daemon.start().unwrap();
+    daemon.supervise();
+    let supervising_until = Instant::now() + Duration::from_secs(2);
+    while Instant::now() < supervising_until {
+        assert!(daemon.tick());
+        thread::sleep(TICK_INTERVAL);
+    }
+
+    term.store(true, Ordering::Relaxed);
+    assert!(!daemon.tick());
+    assert_eq!(daemon.shutdown(), ShutdownReason::Terminated);
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn applies_changes_to_the_consumer_list_file() {
+    let magento = FakeMagento::new("consumer-list-file", &["first", "second"]);
+    let consumer_list_file = magento.dir.join("consumer-list");
+    fs::write(&consumer_list_file, "!second\n").unwrap();
+    let context = magento
+        .try_context(&["--consumer-list-file", consumer_list_file.to_str().unwrap()])
+        .unwrap();
+    let consumers = worker::applicable_consumers(&context).unwrap();
+    assert_eq!(consumers, ["first"]);
+
+    let term = Arc::new(AtomicBool::new(false));
+    let mut daemon = Daemon::new(vec![(context, consumers)], Arc::clone(&term));
+    daemon.start().unwrap();
+    daemon.supervise();
+    let started = Instant::now() + Duration::from_secs(1);
+    while magento.started_pids_of("first").is_empty() && Instant::now() < started {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let first = magento.started_pids_of("first");
+    assert_eq!(first.len(), 1);
+
+    fs::write(&consumer_list_file, "# Only the second one\nsecond\n").unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while (magento.started_pids_of("second").is_empty() || process_running(first[0]))
+        && Instant::now() < deadline
+    {
+        assert!(daemon.tick());
+        thread::sleep(TICK_INTERVAL);
+    }
+    assert_eq!(magento.started_pids_of("second").len(), 1);
+    assert!(!process_running(first[0]));
+
+    // An invalid file keeps the consumers as they are
+    fs::write(&consumer_list_file, "first second\n").unwrap();
+    let supervising_until = Instant::now() + Duration::from_secs(2);
+    while Instant::now() < supervising_until {
+        assert!(daemon.tick());
+        thread::sleep(TICK_INTERVAL);
+    }
+    assert_eq!(magento.started_pids_of("first"), first);
+    assert_eq!(magento.started_pids_of("second").len(), 1);
+
+    term.store(true, Ordering::Relaxed);
+    daemon.shutdown();
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn scales_the_consumers_to_their_backlog() {
+    let magento = FakeMagento::new("autoscale", &["scaled"]);
+    magento.set_php(
+        r#"#!/bin/sh
+case "$1" in
+  --version) echo "PHP 8.2.0 (cli)";;
+  -r) case "$2" in
+        *queue_message_status*) cat backlogs;;
+        *amqp*) echo false;;
+        *getConnection*) echo '{}';;
+        *) echo '{"cron_run":false,"autoscale":{"scaled":{"max_processes":3,"messages_per_process":10}}}';;
+      esac;;
+  bin/magento) shift; exec ./bin/magento "$@";;
+esac
+"#,
+    );
+    fs::write(magento.dir.join("backlogs"), r#"{"scaled":25}"#).unwrap();
+    let context = magento
+        .try_context(&["--autoscale", "--autoscale-interval", "1"])
+        .unwrap();
+    let consumers = worker::applicable_consumers(&context).unwrap();
+
+    let term = Arc::new(AtomicBool::new(false));
+    let mut daemon = Daemon::new(vec![(context, consumers)], Arc::clone(&term));
+    daemon.start().unwrap();
+    daemon.supervise();
+    let running = |magento: &FakeMagento| {
+        magento
+            .started_pids_of("scaled")
+            .into_iter()
+            .filter(|x| process_running(*x))
+            .count()
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while running(&magento) != 3 && Instant::now() < deadline {
+        assert!(daemon.tick());
+        thread::sleep(TICK_INTERVAL);
+    }
+    assert_eq!(running(&magento), 3);
+
+    // Scaled down one process at a time
+    fs::write(magento.dir.join("backlogs"), r#"{"scaled":0}"#).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while running(&magento) != 2 && Instant::now() < deadline {
+        assert!(daemon.tick());
+        thread::sleep(TICK_INTERVAL);
+    }
+    assert_eq!(running(&magento), 2);
+    assert_eq!(magento.started_pids_of("scaled").len(), 3);
+
+    term.store(true, Ordering::Relaxed);
+    daemon.shutdown();
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn runs_the_consumers_as_the_given_user() {
+    // Switching the user requires root
+    if unsafe { libc::geteuid() } != 0 {
+        return;
+    }
+    let magento = FakeMagento::new("run-as-user", &["runs.forever"]);
+    // The consumers run as nobody have to be able to write their PID and to enter the directory
+    fs::set_permissions(magento.dir.join("pids"), fs::Permissions::from_mode(0o666)).unwrap();
+    fs::set_permissions(&magento.dir, fs::Permissions::from_mode(0o755)).unwrap();
+    let context = magento.try_context(&["--run-as-user", "nobody"]).unwrap();
+    let mut worker = worker::run_worker(&context, "runs.forever").unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while magento.started_pids().is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let pid = magento.started_pids()[0];
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap();
+    let uid = status.lines().find(|x| x.starts_with("Uid:")).unwrap();
+    assert_eq!(uid.split_whitespace().nth(1), Some("65534"));
+    worker::drain_workers(std::slice::from_mut(&mut worker), Duration::from_secs(1));
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn keeps_a_paused_consumer_paused_until_it_is_resumed() {
+    let magento = FakeMagento::new("pause", &["paused", "other"]);
+    let context = magento.context();
+    let consumers = worker::applicable_consumers(&context).unwrap();
+
+    let term = Arc::new(AtomicBool::new(false));
+    let mut daemon = Daemon::new(vec![(context, consumers)], Arc::clone(&term));
+    let (control, receiver) = mpsc::channel();
+    daemon.set_control(receiver);
+    daemon.start().unwrap();
+    daemon.supervise();
+    let send = |daemon: &mut Daemon, command: Command| {
+        let (reply, response) = mpsc::channel();
+        control.send(ControlRequest { command, reply }).unwrap();
+        assert!(daemon.tick());
+        serde_json::to_value(response.recv().unwrap()).unwrap()
+    };
+    let state = |status: &serde_json::Value, consumer: &str| {
+        status["consumers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|x| x["consumer"] == consumer)
+            .unwrap()["state"]
+            .clone()
+    };
+
+    let started = Instant::now() + Duration::from_secs(1);
+    while magento.started_pids_of("paused").is_empty() && Instant::now() < started {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let response = send(&mut daemon, Command::Pause("paused".to_owned()));
+    assert_eq!(response["ok"], true);
+    let paused = magento.started_pids_of("paused");
+    assert_eq!(paused.len(), 1);
+    assert!(!process_running(paused[0]));
+
+    // Neither the supervision nor a refresh starts it again
+    send(&mut daemon, Command::Reload);
+    let supervising_until = Instant::now() + Duration::from_secs(3);
+    while Instant::now() < supervising_until {
+        assert!(daemon.tick());
+        thread::sleep(TICK_INTERVAL);
+    }
+    assert_eq!(magento.started_pids_of("paused"), paused);
+    let status = send(&mut daemon, Command::Status);
+    assert_eq!(state(&status, "paused"), "paused");
+    assert_eq!(state(&status, "other"), "running");
+
+    let response = send(&mut daemon, Command::Resume("all".to_owned()));
+    assert_eq!(response["ok"], true);
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while magento.started_pids_of("paused").len() < 2 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let resumed = magento.started_pids_of("paused");
+    assert_eq!(resumed.len(), 2);
+    assert!(process_running(resumed[1]));
+    let status = send(&mut daemon, Command::Status);
+    assert_eq!(state(&status, "paused"), "running");
+    assert_eq!(status["consumers"][0]["restarts"], 0);
+
+    term.store(true, Ordering::Relaxed);
+    daemon.shutdown();
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn drains_the_consumers_without_restarting_them() {
+    let magento = FakeMagento::new("drain", &["reports", "runs.then.exits"]);
+    let context = magento.context();
+    let consumers = worker::applicable_consumers(&context).unwrap();
+
+    let term = Arc::new(AtomicBool::new(false));
+    let mut daemon = Daemon::new(vec![(context, consumers)], Arc::clone(&term));
+    let (control, receiver) = mpsc::channel();
+    daemon.set_control(receiver);
+    daemon.start().unwrap();
+    daemon.supervise();
+    let send = |daemon: &mut Daemon, command| {
+        let (reply, response) = mpsc::channel();
+        control.send(ControlRequest { command, reply }).unwrap();
+        assert!(daemon.tick());
+        serde_json::to_value(response.recv().unwrap()).unwrap()
+    };
+
+    assert_eq!(send(&mut daemon, Command::Drain)["ok"], true);
+    // Nothing is started anymore while draining
+    let response = send(&mut daemon, Command::Restart("reports".to_owned()));
+    assert_eq!(response["ok"], false);
+
+    // Both consumers exit on their own, after which the daemon stops
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while daemon.tick() && Instant::now() < deadline {
+        thread::sleep(TICK_INTERVAL);
+    }
+    let reason = daemon.shutdown();
+    assert_eq!(reason, ShutdownReason::Drained);
+    assert_eq!(reason.exit_code(), 0);
+    assert_eq!(magento.started_pids().len(), 2);
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn stops_a_consumer_that_magento_no_longer_knows() {
+    for listed in [false, true] {
+        let magento = FakeMagento::new(&format!("unknown-{}", listed), &["unknown", "other"]);
+        let context = magento.context();
+        let consumers = worker::applicable_consumers(&context).unwrap();
+        // Removed by a deployment after the daemon read the consumer list
+        if !listed {
+            fs::write(magento.dir.join("consumers"), "other").unwrap();
+        }
+
+        let term = Arc::new(AtomicBool::new(false));
+        let mut daemon = Daemon::new(vec![(context, consumers)], Arc::clone(&term));
+        let (control, receiver) = mpsc::channel();
+        daemon.set_control(receiver);
+        daemon.start().unwrap();
+        daemon.supervise();
+        let mut supervised = || {
+            let (reply, response) = mpsc::channel();
+            let command = Command::Status;
+            control.send(ControlRequest { command, reply }).unwrap();
+            assert!(daemon.tick());
+            let status = serde_json::to_value(response.recv().unwrap()).unwrap();
+            status["consumers"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|x| x["consumer"] == "unknown")
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut done = false;
+        while !done && Instant::now() < deadline {
+            // A consumer that is still listed is restarted, and one that isn't is dropped
+            done = match supervised() {
+                true => listed && magento.started_pids_of("unknown").len() >= 2,
+                false => !listed,
+            };
+            thread::sleep(TICK_INTERVAL);
+        }
+        assert!(done);
+        if !listed {
+            assert_eq!(magento.started_pids_of("unknown").len(), 1);
+        }
+
+        term.store(true, Ordering::Relaxed);
+        daemon.shutdown();
+        magento.assert_no_processes_left();
+    }
+}
+
+#[test]
+fn restarts_a_stalled_consumer_with_waiting_messages() {
+    let magento = FakeMagento::new("stalled", &["stalled", "idle"]);
+    fs::write(magento.dir.join("backlogs"), r#"{"stalled":3,"idle":0}"#).unwrap();
+    let context = magento.try_context(&["--stall-timeout", "1"]).unwrap();
+    let consumers = worker::applicable_consumers(&context).unwrap();
+
+    let term = Arc::new(AtomicBool::new(false));
+    let mut daemon = Daemon::new(vec![(context, consumers)], Arc::clone(&term));
+    daemon.start().unwrap();
+    daemon.supervise();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while magento.started_pids_of("stalled").len() < 2 && Instant::now() < deadline {
+        assert!(daemon.tick());
+        thread::sleep(TICK_INTERVAL);
+    }
+    let stalled = magento.started_pids_of("stalled");
+    assert_eq!(stalled.len(), 2);
+    assert!(!process_running(stalled[0]));
+    // Without waiting messages it's idle rather than stalled
+    assert_eq!(magento.started_pids_of("idle").len(), 1);
+
+    term.store(true, Ordering::Relaxed);
+    daemon.shutdown();
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn recycles_a_process_exceeding_the_cpu_time_limit() {
+    let magento = FakeMagento::new("max-cpu", &["spins"]);
+    let context = magento.try_context(&["--max-cpu-seconds", "1"]).unwrap();
+    let mut worker = worker::run_worker(&context, "spins").unwrap();
+
+    let recycled = supervise_until(&context, &mut worker, Duration::from_secs(5), |w| {
+        w.recycle_count() >= 1
+    });
+    assert!(recycled);
+    assert_eq!(worker.restart_count(), 0);
+    worker::drain_workers(std::slice::from_mut(&mut worker), Duration::from_secs(1));
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn exits_when_the_refresh_keeps_failing_and_no_consumer_runs() {
+    let magento = FakeMagento::new("refresh-failures", &["first"]);
+    let context = magento
+        .try_context(&[
+            "--consumer-refresh-interval",
+            "1",
+            "--refresh-failure-threshold",
+            "1",
+            "--exit-after-refresh-failures",
+            "2",
+        ])
+        .unwrap();
+    let consumers = worker::applicable_consumers(&context).unwrap();
+
+    let term = Arc::new(AtomicBool::new(false));
+    let mut daemon = Daemon::new(vec![(context, consumers)], Arc::clone(&term));
+    let (control, receiver) = mpsc::channel();
+    daemon.set_control(receiver);
+    daemon.start().unwrap();
+    daemon.supervise();
+    let started = Instant::now() + Duration::from_secs(1);
+    while magento.started_pids_of("first").is_empty() && Instant::now() < started {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let first = magento.started_pids_of("first");
+    assert_eq!(first.len(), 1);
+
+    // The configuration query fails from now on, which keeps the running consumer as it is
+    magento.set_php(
+        r#"#!/bin/sh
+case "$1" in
+  -r) exit 1;;
+  bin/magento) shift; exec ./bin/magento "$@";;
+esac
+"#,
+    );
+    let supervising_until = Instant::now() + Duration::from_secs(4);
+    while Instant::now() < supervising_until {
+        assert!(daemon.tick());
+        thread::sleep(TICK_INTERVAL);
+    }
+    assert_eq!(magento.started_pids_of("first"), first);
+    assert!(process_running(first[0]));
+
+    let (reply, response) = mpsc::channel();
+    control
+        .send(ControlRequest {
+            command: Command::Pause("first".to_owned()),
+            reply,
+        })
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while daemon.tick() && Instant::now() < deadline {
+        thread::sleep(TICK_INTERVAL);
+    }
+    assert_eq!(
+        serde_json::to_value(response.recv().unwrap()).unwrap()["ok"],
+        true
+    );
+    assert_eq!(daemon.shutdown(), ShutdownReason::RefreshFailed);
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn places_the_consumer_processes_in_their_cgroup() {
+    let magento = FakeMagento::new("cgroup", &["runs.forever"]);
+    // A directory mimicking cgroupfs, in which the cgroup of the consumer already has its files
+    let parent = magento.dir.join("cgroup");
+    fs::create_dir_all(parent.join("runs.forever")).unwrap();
+    fs::write(
+        parent.join("cgroup.controllers"),
+        "cpuset cpu io memory pids",
+    )
+    .unwrap();
+    fs::write(parent.join("cgroup.subtree_control"), "").unwrap();
+    fs::write(parent.join("runs.forever/cgroup.procs"), "").unwrap();
+    let context = magento
+        .try_context(&[
+            "--cgroup-parent",
+            parent.to_str().unwrap(),
+            "--worker-cpu-max",
+            "0.5",
+            "--worker-memory-max",
+            "256",
+        ])
+        .unwrap();
+    let subtree_control = fs::read_to_string(parent.join("cgroup.subtree_control")).unwrap();
+    assert_eq!(subtree_control, "+cpu +memory");
+
+    let mut worker = worker::run_worker(&context, "runs.forever").unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while magento.started_pids().is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(magento.started_pids().len(), 1);
+    let cgroup = parent.join("runs.forever");
+    assert_eq!(
+        fs::read_to_string(cgroup.join("cpu.max")).unwrap(),
+        "50000 100000"
+    );
+    assert_eq!(
+        fs::read_to_string(cgroup.join("memory.max")).unwrap(),
+        "268435456"
+    );
+    // The process wrote 0 to join the cgroup itself
+    assert_eq!(
+        fs::read_to_string(cgroup.join("cgroup.procs")).unwrap(),
+        "0"
+    );
+    worker::drain_workers(std::slice::from_mut(&mut worker), Duration::from_secs(1));
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn exits_when_too_few_consumer_processes_keep_running() {
+    let magento = FakeMagento::new("degraded", &["exits.immediately", "other"]);
+    let context = magento
+        .try_context(&[
+            "--min-running-percent",
+            "100",
+            "--min-running-grace",
+            "1",
+            "--exit-when-degraded",
+        ])
+        .unwrap();
+    let consumers = worker::applicable_consumers(&context).unwrap();
+
+    let term = Arc::new(AtomicBool::new(false));
+    let mut daemon = Daemon::new(vec![(context, consumers)], Arc::clone(&term));
+    daemon.start().unwrap();
+    daemon.supervise();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while daemon.tick() && Instant::now() < deadline {
+        thread::sleep(TICK_INTERVAL);
+    }
+    let reason = daemon.shutdown();
+    assert_eq!(reason, ShutdownReason::Degraded);
+    assert_eq!(reason.exit_code(), 6);
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn restarts_exits_within_the_startup_grace_without_backing_off() {
+    let magento = FakeMagento::new("startup-grace", &["exits.immediately"]);
+    let context = magento.try_context(&["--startup-grace", "60"]).unwrap();
+    let mut worker = worker::run_worker(&context, "exits.immediately").unwrap();
+
+    let restarted = supervise_until(&context, &mut worker, Duration::from_secs(5), |w| {
+        assert!(!w.backing_off());
+        w.restart_count() >= 3
+    });
+    assert!(restarted);
+    worker::drain_workers(std::slice::from_mut(&mut worker), Duration::from_secs(1));
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn leaves_a_stuck_consumer_running_or_alerts_before_killing_it() {
+    let magento = FakeMagento::new("stuck-worker", &["ignores.term"]);
+    let context = magento
+        .try_context(&["--stuck-worker-action", "leave"])
+        .unwrap();
+    let mut worker = worker::run_worker(&context, "ignores.term").unwrap();
+    // Gives the consumer the time to ignore SIGTERM
+    thread::sleep(Duration::from_millis(300));
+
+    worker::drain_workers(
+        std::slice::from_mut(&mut worker),
+        Duration::from_millis(300),
+    );
+    let pid = magento.started_pids_of("ignores.term")[0];
+    assert!(process_running(pid));
+    assert_eq!(worker.stuck_pids(), [pid]);
+    kill_process_group(pid).unwrap();
+    drop(worker);
+
+    let hook_output = magento.dir.join("hook");
+    let hook = format!("echo \"$REASON $PID\" > {}", hook_output.display());
+    let context = magento
+        .try_context(&["--stuck-worker-action", "alert", "--on-crash", &hook])
+        .unwrap();
+    let mut worker = worker::run_worker(&context, "ignores.term").unwrap();
+    thread::sleep(Duration::from_millis(300));
+    worker::drain_workers(
+        std::slice::from_mut(&mut worker),
+        Duration::from_millis(300),
+    );
+    let pid = magento.started_pids_of("ignores.term")[1];
+    assert!(!process_running(pid));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !hook_output.exists() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(20));
+    }
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(
+        fs::read_to_string(&hook_output).unwrap().trim(),
+        format!("stuck {}", pid)
+    );
+    magento.assert_no_processes_left();
+}
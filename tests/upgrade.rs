@@ -0,0 +1,302 @@
+//! Runs the daemon binary: upgrading it with SIGUSR2, which should keep the consumers running,
+//! running it in the background, the context its log lines carry, limiting the forwarded output,
+//! the dump on SIGQUIT, the actions of the signals, and the report logged on shutdown.
+#![cfg(target_os = "linux")]
+
+mod common;
+
+use std::{
+    fs,
+    process::Stdio,
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::FakeMagento;
+use magento2_worker_daemon::util::{process_running, signal_process};
+
+/// Waits until `f` returns true, and returns whether it did within `timeout`.
+fn wait_until<F: FnMut() -> bool>(timeout: Duration, mut f: F) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if f() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    false
+}
+
+#[test]
+fn upgrading_keeps_the_consumers_running() {
+    let magento = FakeMagento::new("upgrade", &["runs.forever", "talks"]);
+    let log = magento.dir.join("daemon.log");
+    let mut daemon = magento
+        .daemon_command()
+        .stderr(fs::File::create(&log).unwrap())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let read_log = || fs::read_to_string(&log).unwrap();
+    // The consumers write their PID once they run, which may be after the daemon started them
+    assert!(wait_until(Duration::from_secs(5), || {
+        read_log().contains("Started 2 consumers") && magento.started_pids().len() == 2
+    }));
+    let started = magento.started_pids();
+    assert_eq!(started.len(), 2);
+
+    for upgrades in 1..=2 {
+        signal_process(daemon.id(), libc::SIGUSR2).unwrap();
+        assert!(wait_until(Duration::from_secs(5), || {
+            read_log().matches("Started 2 consumers").count() == upgrades + 1
+        }));
+        assert!(read_log().contains("adopting 2 consumer processes"));
+        assert_eq!(magento.started_pids(), started);
+        assert!(started.iter().all(|pid| process_running(*pid)));
+    }
+
+    // The output of an adopted process is still forwarded
+    let lines = read_log().matches("is running").count();
+    assert!(wait_until(Duration::from_secs(2), || {
+        read_log().matches("is running").count() > lines
+    }));
+
+    signal_process(daemon.id(), libc::SIGTERM).unwrap();
+    assert!(daemon.wait().unwrap().success());
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn daemonizes_once_the_consumers_are_started() {
+    let magento = FakeMagento::new("daemonize", &["runs.forever"]);
+    let pid_file = magento.dir.join("daemon.pid");
+    let output = magento
+        .daemon_command()
+        .args(["--daemonize", "--pid-file", pid_file.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let pid: u32 = fs::read_to_string(&pid_file)
+        .unwrap()
+        .trim()
+        .parse()
+        .unwrap();
+    assert!(process_running(pid));
+    assert!(wait_until(Duration::from_secs(5), || {
+        magento.started_pids().len() == 1
+    }));
+
+    signal_process(pid, libc::SIGTERM).unwrap();
+    assert!(wait_until(Duration::from_secs(5), || !pid_file.exists()));
+    magento.assert_no_processes_left();
+
+    // The startup errors are still shown, and fail the command
+    let output = magento
+        .daemon_command()
+        .args(["--daemonize", "--include", "no.such.consumer"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No applicable consumers found"));
+}
+
+#[test]
+fn adds_the_consumer_and_process_to_the_log_lines() {
+    let magento = FakeMagento::new("log-context", &["talks", "runs.forever"]);
+    let log = magento.dir.join("daemon.log");
+    let mut daemon = magento
+        .daemon_command()
+        .args(["--log-level", "debug"])
+        .stderr(fs::File::create(&log).unwrap())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let read_log = || fs::read_to_string(&log).unwrap();
+    assert!(wait_until(Duration::from_secs(5), || {
+        read_log().contains("is running")
+    }));
+    signal_process(daemon.id(), libc::SIGTERM).unwrap();
+    assert!(daemon.wait().unwrap().success());
+
+    let pid = magento.started_pids_of("talks")[0];
+    let log = read_log();
+    let lines: Vec<_> = log
+        .lines()
+        .filter(|x| x.contains(&format!("consumer=talks pid={}", pid)))
+        .collect();
+    assert!(lines.iter().any(|x| x.contains("is running")));
+    // Also the lines logged while stopping it on the main thread
+    assert!(log
+        .lines()
+        .any(|x| x.contains("Terminating consumer: talks") && x.ends_with(" consumer=talks")));
+    assert!(!log
+        .lines()
+        .any(|x| x.contains("Started 2 consumers") && x.contains("consumer=")));
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn limits_the_forwarded_output() {
+    let magento = FakeMagento::new("output-rate-limit", &["talks"]);
+    let log = magento.dir.join("daemon.log");
+    let mut daemon = magento
+        .daemon_command()
+        .args(["--output-rate-limit", "2"])
+        .stderr(fs::File::create(&log).unwrap())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let read_log = || fs::read_to_string(&log).unwrap();
+    // It talks 10 lines per second
+    assert!(wait_until(Duration::from_secs(5), || {
+        read_log().contains("lines of output from consumer talks, exceeding --output-rate-limit")
+    }));
+    let started = Instant::now();
+    thread::sleep(Duration::from_secs(2));
+    signal_process(daemon.id(), libc::SIGTERM).unwrap();
+    assert!(daemon.wait().unwrap().success());
+    let seconds = started.elapsed().as_secs() + 3;
+    assert!(read_log().matches("is running").count() as u64 <= 2 * seconds);
+    magento.assert_no_processes_left();
+
+    // Only the error output is forwarded
+    let magento = FakeMagento::new("forward-stderr-only", &["talks", "unknown"]);
+    let log = magento.dir.join("daemon.log");
+    let mut daemon = magento
+        .daemon_command()
+        .arg("--forward-stderr-only")
+        .stderr(fs::File::create(&log).unwrap())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let read_log = || fs::read_to_string(&log).unwrap();
+    assert!(wait_until(Duration::from_secs(5), || {
+        read_log().contains("[unknown] Consumer 'unknown' is not declared.")
+    }));
+    thread::sleep(Duration::from_millis(500));
+    signal_process(daemon.id(), libc::SIGTERM).unwrap();
+    assert!(daemon.wait().unwrap().success());
+    assert!(!read_log().contains("is running"));
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn dumps_the_state_on_sigquit_and_keeps_running() {
+    let magento = FakeMagento::new("dump", &["runs.forever", "exits.immediately"]);
+    let log = magento.dir.join("daemon.log");
+    let mut daemon = magento
+        .daemon_command()
+        .stderr(fs::File::create(&log).unwrap())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let read_log = || fs::read_to_string(&log).unwrap();
+    assert!(wait_until(Duration::from_secs(5), || {
+        magento.started_pids_of("exits.immediately").len() >= 2
+    }));
+
+    signal_process(daemon.id(), libc::SIGQUIT).unwrap();
+    assert!(wait_until(Duration::from_secs(5), || {
+        read_log().contains("End of the diagnostic dump")
+    }));
+    let log = read_log();
+    assert!(log.contains(&format!("Diagnostic dump of daemon {}", daemon.id())));
+    let pid = magento.started_pids_of("runs.forever")[0];
+    assert!(log.contains(&format!("process {} (index 0): running", pid)));
+    assert!(log.contains("exited with code 1 after"));
+    assert!(matches!(daemon.try_wait(), Ok(None)));
+
+    signal_process(daemon.id(), libc::SIGTERM).unwrap();
+    assert!(daemon.wait().unwrap().success());
+    magento.assert_no_processes_left();
+}
+
+#[test]
+fn runs_the_configured_actions_of_the_signals() {
+    let magento = FakeMagento::new("signal-actions", &["runs.forever"]);
+    let log = magento.dir.join("daemon.log");
+    let mut daemon = magento
+        .daemon_command()
+        .args(["--signal", "INT=ignore", "--signal", "HUP=reload"])
+        .stderr(fs::File::create(&log).unwrap())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let read_log = || fs::read_to_string(&log).unwrap();
+    assert!(wait_until(Duration::from_secs(5), || {
+        read_log().contains("Started 1 consumers")
+    }));
+
+    signal_process(daemon.id(), libc::SIGHUP).unwrap();
+    assert!(wait_until(Duration::from_secs(5), || {
+        read_log().contains("Refreshing the consumers")
+    }));
+    signal_process(daemon.id(), libc::SIGINT).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    assert!(matches!(daemon.try_wait(), Ok(None)));
+    assert!(magento
+        .started_pids()
+        .iter()
+        .all(|pid| process_running(*pid)));
+
+    signal_process(daemon.id(), libc::SIGTERM).unwrap();
+    assert!(daemon.wait().unwrap().success());
+    magento.assert_no_processes_left();
+
+    let output = magento
+        .daemon_command()
+        .args(["--signal", "TERM=ignore", "--signal", "INT=status"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No signal terminates the daemon"));
+}
+
+#[test]
+fn logs_a_report_on_shutdown() {
+    let magento = FakeMagento::new("shutdown-report", &["exits.immediately", "ignores.term"]);
+    let log = magento.dir.join("daemon.log");
+    let mut daemon = magento
+        .daemon_command()
+        .args(["--log-format", "json"])
+        .stderr(fs::File::create(&log).unwrap())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    // Gives the consumers the time to crash and to ignore SIGTERM
+    assert!(wait_until(Duration::from_secs(5), || {
+        magento.started_pids_of("exits.immediately").len() >= 2
+    }));
+    thread::sleep(Duration::from_millis(300));
+    signal_process(daemon.id(), libc::SIGTERM).unwrap();
+    assert!(daemon.wait().unwrap().success());
+    magento.assert_no_processes_left();
+
+    let contents = fs::read_to_string(&log).unwrap();
+    let record: serde_json::Value = contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .find(|record: &serde_json::Value| record["event"] == "shutdown_report")
+        .expect("no shutdown report logged");
+    let report: serde_json::Value =
+        serde_json::from_str(record["message"].as_str().unwrap()).unwrap();
+    assert_eq!(report["reason"], "terminated");
+    assert_eq!(report["exit_code"], 0);
+    let consumers = report["consumers"].as_array().unwrap();
+    let restarts = |name: &str| {
+        let consumer = consumers.iter().find(|c| c["consumer"] == name).unwrap();
+        consumer["restarts"].as_u64().unwrap()
+    };
+    assert!(restarts("exits.immediately") >= 1, "{}", report);
+    assert_eq!(restarts("ignores.term"), 0);
+    assert_eq!(
+        report["restarts"].as_u64().unwrap(),
+        restarts("exits.immediately")
+    );
+    // The process ignoring SIGTERM was killed after the shutdown timeout
+    assert_eq!(report["processes"]["killed"], 1, "{}", report);
+    assert_eq!(
+        report["processes"]["stopped"].as_u64().unwrap(),
+        report["processes"]["exited"].as_u64().unwrap() + 1
+    );
+}